@@ -0,0 +1,150 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+
+use image::Rgba;
+use image::RgbaImage;
+
+use crate::util;
+
+struct Region {
+    size: usize,
+    rect: util::Rect,
+}
+
+/// Most common color along the edges of the image
+fn background(image: &RgbaImage) -> Rgba<u8> {
+    let (w, h) = image.dimensions();
+    let mut counts = HashMap::new();
+
+    let edges = (0..w).flat_map(|x| vec![(x, 0), (x, h - 1)])
+        .chain((0..h).flat_map(|y| vec![(0, y), (w - 1, y)]));
+    for (x, y) in edges {
+        *counts.entry(*image.get_pixel(x, y)).or_insert(0) += 1;
+    }
+
+    counts.into_iter().max_by_key(|&(_, n)| n).map(|(c, _)| c).unwrap_or(Rgba([0, 0, 0, 0]))
+}
+
+/// Find the bounding box of the content of `image`: the largest connected region of pixels that
+/// differ from the background color by more than `tolerance` on any channel
+/// Returns `None` when there is no clear answer
+pub fn detect(image: &RgbaImage, tolerance: u8) -> Option<util::Rect> {
+    let (w, h) = (image.width() as usize, image.height() as usize);
+    if w == 0 || h == 0 {
+        return None;
+    }
+
+    let bg = background(image);
+    let differs = |x: usize, y: usize| {
+        let p = image.get_pixel(x as u32, y as u32);
+        (0..4).any(|i| (p[i] as i16 - bg[i] as i16).abs() > tolerance as i16)
+    };
+
+    let mut visited = vec![false; w * h];
+    let mut regions = Vec::new();
+    let mut stack = Vec::new();
+
+    for start in 0..w * h {
+        if visited[start] || !differs(start % w, start / w) {
+            continue;
+        }
+
+        // Flood fill the region, 4-connected
+        let (mut x0, mut y0, mut x1, mut y1) = (w, h, 0, 0);
+        let mut size = 0;
+        visited[start] = true;
+        stack.push(start);
+        while let Some(i) = stack.pop() {
+            let (x, y) = (i % w, i / w);
+            size += 1;
+            x0 = x0.min(x);
+            y0 = y0.min(y);
+            x1 = x1.max(x);
+            y1 = y1.max(y);
+
+            let neighbors = [
+                (x > 0, i.wrapping_sub(1)),
+                (x + 1 < w, i + 1),
+                (y > 0, i.wrapping_sub(w)),
+                (y + 1 < h, i + w),
+            ];
+            for &(valid, n) in neighbors.iter() {
+                if valid && !visited[n] && differs(n % w, n / w) {
+                    visited[n] = true;
+                    stack.push(n);
+                }
+            }
+        }
+
+        regions.push(Region {
+            size,
+            rect: util::Rect {
+                x: x0 as i32,
+                y: y0 as i32,
+                w: (x1 - x0 + 1) as i32,
+                h: (y1 - y0 + 1) as i32,
+            },
+        });
+    }
+
+    regions.sort_by_key(|r| std::cmp::Reverse(r.size));
+    let largest = regions.first()?;
+
+    // Two regions of similar size: no way to tell which one is the content
+    if regions.get(1).is_some_and(|r| r.size * 2 >= largest.size) {
+        return None;
+    }
+    // Nothing to crop
+    if largest.rect.w as usize == w && largest.rect.h as usize == h {
+        return None;
+    }
+
+    Some(largest.rect)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canvas() -> RgbaImage {
+        RgbaImage::from_pixel(40, 30, Rgba([30, 30, 30, 255]))
+    }
+
+    fn fill(image: &mut RgbaImage, r: util::Rect, color: Rgba<u8>) {
+        for y in r.y..r.y + r.h {
+            for x in r.x..r.x + r.w {
+                image.put_pixel(x as u32, y as u32, color);
+            }
+        }
+    }
+
+    #[test]
+    fn a_centered_dialog_is_found() {
+        let mut image = canvas();
+        let dialog = util::Rect { x: 10, y: 8, w: 20, h: 14 };
+        fill(&mut image, dialog, Rgba([220, 220, 220, 255]));
+        // A small icon elsewhere does not compete with it
+        fill(&mut image, util::Rect { x: 1, y: 1, w: 2, h: 2 }, Rgba([255, 0, 0, 255]));
+        assert_eq!(detect(&image, 0), Some(dialog));
+    }
+
+    #[test]
+    fn two_regions_of_similar_size_are_ambiguous() {
+        let mut image = canvas();
+        fill(&mut image, util::Rect { x: 2, y: 5, w: 12, h: 10 }, Rgba([200, 200, 200, 255]));
+        fill(&mut image, util::Rect { x: 20, y: 5, w: 11, h: 10 }, Rgba([200, 200, 200, 255]));
+        assert_eq!(detect(&image, 0), None);
+    }
+
+    #[test]
+    fn uniform_images_have_nothing_to_crop() {
+        assert_eq!(detect(&canvas(), 0), None);
+        // Noise within the tolerance is still background
+        let mut image = canvas();
+        fill(&mut image, util::Rect { x: 5, y: 5, w: 10, h: 10 }, Rgba([34, 30, 30, 255]));
+        assert_eq!(detect(&image, 4), None);
+    }
+}
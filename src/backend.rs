@@ -0,0 +1,126 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fmt;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time;
+
+use image::imageops;
+use image::RgbaImage;
+use x11::xlib;
+
+use crate::draw;
+use crate::util;
+use crate::wayland;
+use crate::xwrap;
+use crate::xwrap::Display;
+
+/// Why a capture failed
+#[derive(Clone, Debug)]
+pub enum Error {
+    /// The server or compositor did not hand over the pixels, and what it said about it
+    Capture(String),
+    /// The pixels came in a layout we cannot convert
+    PixelFormat,
+    /// The area to capture lies outside of the window or screen
+    Geometry,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Capture(e) => write!(f, "{}", e),
+            Error::PixelFormat => write!(f, "Failed to convert captured framebuffer, only 8 bit \
+                                             colormapped, 15/16 bit RGB, 24/32 bit (A)RGB8 and 30 \
+                                             bit RGB are supported"),
+            Error::Geometry => write!(f, "The area to capture is not on the screen"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Capture = Result<(RgbaImage, Option<xwrap::Rgba16Image>), Error>;
+
+/// Where the pixels of a capture come from
+pub trait Backend {
+    /// Read back `area` of `window`, `origin` being the same area relative to the root
+    /// With `deep`, also give the image at 16 bits per channel if the source has more than 8
+    fn capture(&self, window: xlib::Window, area: util::Rect, origin: util::Rect, deep: bool)
+               -> Capture;
+}
+
+/// Time spent reading pixels back from X and converting them, in nanoseconds
+static READ_NANOS: AtomicU64 = AtomicU64::new(0);
+static CONVERT_NANOS: AtomicU64 = AtomicU64::new(0);
+
+fn add_time(counter: &AtomicU64, start: time::Instant) {
+    counter.fetch_add(start.elapsed().as_nanos() as u64, Ordering::SeqCst);
+}
+
+/// Time spent reading pixels back from X and converting them since the last call
+pub fn take_timings() -> (time::Duration, time::Duration) {
+    (time::Duration::from_nanos(READ_NANOS.swap(0, Ordering::SeqCst)),
+     time::Duration::from_nanos(CONVERT_NANOS.swap(0, Ordering::SeqCst)))
+}
+
+/// Captures of more pixels than this are read back in stripes, so that the image the server
+/// sends and ours are never both whole in memory
+/// Only the read-back is striped: the result is still one whole image, which the rest of the
+/// pipeline processes and encodes in one piece
+const STRIPE_PIXELS: i32 = 1 << 22;
+
+impl Backend for Display {
+    fn capture(&self, window: xlib::Window, area: util::Rect, _origin: util::Rect, deep: bool)
+               -> Capture {
+        let rows = (STRIPE_PIXELS / area.w.max(1)).max(1);
+        if area.h <= rows {
+            return convert(&self.get_stripe(window, area)?, deep);
+        }
+
+        let mut image = RgbaImage::new(area.w as u32, area.h as u32);
+        let mut deep_image = None;
+        for y in (0..area.h).step_by(rows as usize) {
+            let stripe = util::Rect { y: area.y + y, h: rows.min(area.h - y), ..area };
+            let (part, deep_part) = convert(&self.get_stripe(window, stripe)?, deep)?;
+            imageops::replace(&mut image, &part, 0, y as u32);
+            if let Some(d) = deep_part {
+                let whole = deep_image.get_or_insert_with(|| {
+                    xwrap::Rgba16Image::new(area.w as u32, area.h as u32)
+                });
+                imageops::replace(whole, &d, 0, y as u32);
+            }
+        }
+        Ok((image, deep_image))
+    }
+}
+
+impl Display {
+    fn get_stripe(&self, window: xlib::Window, area: util::Rect) -> Result<xwrap::Image, Error> {
+        let start = time::Instant::now();
+        let image = self.get_image(window, area, xwrap::ALL_PLANES, xlib::ZPixmap);
+        add_time(&READ_NANOS, start);
+        image.ok_or_else(|| Error::Capture("Failed to get image from X".to_string()))
+    }
+}
+
+/// Turn an image read from X into a buffer, along with a 16 bits per channel one with `deep`
+pub fn convert(image: &xwrap::Image, deep: bool) -> Capture {
+    let start = time::Instant::now();
+    let deep = if deep { image.to_image_buffer16() } else { None };
+    let converted = deep.as_ref().map(draw::narrow).or_else(|| image.to_image_buffer());
+    add_time(&CONVERT_NANOS, start);
+    converted.map(|i| (i, deep)).ok_or(Error::PixelFormat)
+}
+
+/// Windows are taken as they appear on screen, XWayland sharing the compositor's coordinates
+impl Backend for wayland::Connection {
+    fn capture(&self, _window: xlib::Window, _area: util::Rect, origin: util::Rect, _deep: bool)
+               -> Capture {
+        wayland::Connection::capture(self, origin).map(|i| (i, None)).map_err(|e| {
+            Error::Capture(format!("Failed to capture through wlr-screencopy: {}", e))
+        })
+    }
+}
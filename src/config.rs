@@ -0,0 +1,312 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Defaults from a configuration file, in the subset of TOML needed to give options values
+// Each key is the long name of an option: `format = "jpeg"`, `delay = 2`, `cursor = true`
+// The same goes for environment variables, SHOTGUN_FORMAT=jpeg, SHOTGUN_CURSOR=true and so on
+// `[profiles.NAME]` tables hold more of them, which only apply when --profile picks one
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub enum Value {
+    Flag(bool),
+    Text(String),
+}
+
+pub struct Setting {
+    pub name: String,
+    pub value: Value,
+}
+
+/// Settings of a configuration file
+#[derive(Default)]
+pub struct File {
+    /// Those at the top, before any table
+    pub settings: Vec<Setting>,
+    /// Those of each `[profiles.NAME]` table, in the order of the file
+    pub profiles: Vec<(String, Vec<Setting>)>,
+}
+
+impl File {
+    pub fn profile(&self, name: &str) -> Option<&[Setting]> {
+        self.profiles.iter().find(|(n, _)| n == name).map(|(_, s)| &s[..])
+    }
+}
+
+impl Setting {
+    /// As it would be given on the command line, none for a flag turned off
+    pub fn to_arg(&self) -> Option<String> {
+        match &self.value {
+            Value::Flag(true) => Some(format!("--{}", self.name)),
+            Value::Flag(false) => None,
+            Value::Text(v) => Some(format!("--{}={}", self.name, v)),
+        }
+    }
+}
+
+/// Contents of a basic string, the opening quote already consumed
+fn parse_string(s: &str) -> Option<String> {
+    let mut value = String::new();
+    let mut chars = s.chars();
+    loop {
+        match chars.next()? {
+            '"' => break,
+            '\\' => value.push(match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                c @ ('"' | '\\') => c,
+                _ => return None,
+            }),
+            c => value.push(c),
+        }
+    }
+
+    // Only a comment may follow
+    let rest = chars.as_str().trim_start();
+    if rest.is_empty() || rest.starts_with('#') {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn is_name(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// The settings of the file at `path`, none if there is no such file
+pub fn load(path: &Path) -> Result<File, String> {
+    match fs::read_to_string(path) {
+        Ok(c) => parse(&c),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(File::default()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse(contents: &str) -> Result<File, String> {
+    let mut file = File::default();
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let invalid = || format!("line {}: expected `[profiles.NAME]`", n + 1);
+            let (header, rest) = header.split_once(']').ok_or_else(invalid)?;
+            let rest = rest.trim_start();
+            if !rest.is_empty() && !rest.starts_with('#') {
+                return Err(invalid());
+            }
+            let name = header.trim().strip_prefix("profiles.").filter(|n| is_name(n))
+                .ok_or_else(invalid)?;
+            if file.profile(name).is_some() {
+                return Err(format!("line {}: profile {} is defined twice", n + 1, name));
+            }
+            file.profiles.push((name.to_string(), Vec::new()));
+            continue;
+        }
+
+        let invalid = || format!("line {}: expected `option = value`", n + 1);
+
+        let (name, value) = line.split_once('=').ok_or_else(invalid)?;
+        let name = name.trim();
+        if !is_name(name) {
+            return Err(invalid());
+        }
+
+        let value = value.trim_start();
+        let value = match value.strip_prefix('"') {
+            Some(s) => Value::Text(parse_string(s).ok_or_else(invalid)?),
+            None => {
+                let value = value.split('#').next().unwrap_or_default().trim();
+                match value {
+                    "true" => Value::Flag(true),
+                    "false" => Value::Flag(false),
+                    v if v.parse::<f64>().is_ok() => Value::Text(v.to_string()),
+                    _ => return Err(invalid()),
+                }
+            },
+        };
+        let settings = match file.profiles.last_mut() {
+            Some((_, s)) => s,
+            None => &mut file.settings,
+        };
+        settings.push(Setting {
+            name: name.to_string(),
+            value,
+        });
+    }
+
+    Ok(file)
+}
+
+/// Settings from `SHOTGUN_OPTION_NAME` variables naming an option `known` accepts, sorted by
+/// name, along with the variables that name none
+pub fn from_env(known: &dyn Fn(&str) -> bool) -> (Vec<Setting>, Vec<String>) {
+    let mut unknown = Vec::new();
+    let mut settings: Vec<Setting> = env::vars().filter_map(|(k, v)| {
+        let name = k.strip_prefix("SHOTGUN_")?.to_lowercase().replace('_', "-");
+        if !known(&name) {
+            unknown.push(k);
+            return None;
+        }
+        let value = match v.as_ref() {
+            "true" => Value::Flag(true),
+            "false" | "" => Value::Flag(false),
+            _ => Value::Text(v),
+        };
+        Some(Setting {
+            name,
+            value,
+        })
+    }).collect();
+    settings.sort_by(|a, b| a.name.cmp(&b.name));
+    unknown.sort();
+    (settings, unknown)
+}
+
+/// Take the `--no-NAME` arguments turning off a flag named `NAME` out of `args`, giving the
+/// remaining arguments and the names of the flags turned off
+/// Arguments from a `--` on are left alone
+pub fn split_turned_off(args: &[String], known: &dyn Fn(&str) -> bool,
+                        is_flag: &dyn Fn(&str) -> bool) -> (Vec<String>, Vec<String>) {
+    let mut turned_off = Vec::new();
+    let mut rest = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        if arg == "--" {
+            rest.extend_from_slice(&args[i..]);
+            break;
+        }
+        match arg.strip_prefix("--no-") {
+            Some(name) if !known(&arg[2..]) && is_flag(name) => turned_off.push(name.to_string()),
+            _ => rest.push(arg.clone()),
+        }
+    }
+    (rest, turned_off)
+}
+
+/// Put `layer` over `settings`, replacing the settings of the same names
+pub fn apply_layer(settings: &mut Vec<Setting>, layer: Vec<Setting>) {
+    settings.retain(|s| !layer.iter().any(|l| l.name == s.name));
+    settings.extend(layer);
+}
+
+/// Arguments for the settings of options neither `given` on the command line nor turned off
+pub fn default_args(settings: &[Setting], given: &dyn Fn(&str) -> bool, turned_off: &[String])
+                    -> Vec<String> {
+    settings.iter()
+        .filter(|s| !given(&s.name) && !turned_off.contains(&s.name))
+        .filter_map(|s| s.to_arg())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(settings: &[Setting]) -> Vec<String> {
+        settings.iter().filter_map(|s| s.to_arg()).collect()
+    }
+
+    #[test]
+    fn profiles_hold_their_own_settings() {
+        let file = parse("format = \"jpeg\"\n\
+                          \n\
+                          [profiles.left-sidebar]  # the file tree\n\
+                          geometry = \"400x1080+0+0\"\n\
+                          cursor = true\n\
+                          [ profiles.main ]\n\
+                          geometry = \"1520x1080+400+0\"\n").unwrap();
+        assert_eq!(args(&file.settings), ["--format=jpeg"]);
+        assert_eq!(file.profiles.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>(),
+                   ["left-sidebar", "main"]);
+        assert_eq!(args(file.profile("left-sidebar").unwrap()),
+                   ["--geometry=400x1080+0+0", "--cursor"]);
+        assert_eq!(args(file.profile("main").unwrap()), ["--geometry=1520x1080+400+0"]);
+        assert!(file.profile("missing").is_none());
+    }
+
+    #[test]
+    fn invalid_tables_are_refused() {
+        for contents in ["[profiles]", "[other.name]", "[profiles.]", "[profiles.a b]",
+                         "[profiles.a", "[profiles.a] x", "[profiles.a]\n[profiles.a]"] {
+            assert!(parse(contents).is_err(), "{:?}", contents);
+        }
+    }
+
+    fn setting(name: &str, value: Value) -> Setting {
+        Setting {
+            name: name.to_string(),
+            value,
+        }
+    }
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn settings_turn_into_arguments() {
+        assert_eq!(setting("cursor", Value::Flag(true)).to_arg().as_deref(), Some("--cursor"));
+        assert_eq!(setting("cursor", Value::Flag(false)).to_arg(), None);
+        assert_eq!(setting("format", Value::Text("jpeg".to_string())).to_arg().as_deref(),
+                   Some("--format=jpeg"));
+        // Values are passed on whole, without the shell splitting them
+        assert_eq!(setting("exec", Value::Text("notify-send 'a b'".to_string())).to_arg()
+                       .as_deref(),
+                   Some("--exec=notify-send 'a b'"));
+    }
+
+    #[test]
+    fn the_environment_names_options() {
+        env::set_var("SHOTGUN_TEST_DELAY", "2");
+        env::set_var("SHOTGUN_TEST_CURSOR", "true");
+        env::set_var("SHOTGUN_TEST_MASK", "");
+        env::set_var("SHOTGUN_TEST_BOGUS", "1");
+        let known = |name: &str| name.starts_with("test-") && name != "test-bogus";
+        let (settings, unknown) = from_env(&known);
+        for name in ["DELAY", "CURSOR", "MASK", "BOGUS"] {
+            env::remove_var(format!("SHOTGUN_TEST_{}", name));
+        }
+
+        // Variables set outside of the test are left out
+        let ours: Vec<&Setting> = settings.iter().filter(|s| s.name.starts_with("test-")).collect();
+        let args: Vec<Option<String>> = ours.iter().map(|s| s.to_arg()).collect();
+        assert_eq!(args, [Some("--test-cursor".to_string()), Some("--test-delay=2".to_string()),
+                          None]);
+        assert!(unknown.contains(&"SHOTGUN_TEST_BOGUS".to_string()));
+        assert!(!unknown.iter().any(|k| k == "SHOTGUN_TEST_DELAY"));
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let mut settings = Vec::new();
+        apply_layer(&mut settings, vec![setting("format", Value::Text("png".to_string())),
+                                        setting("cursor", Value::Flag(true))]);
+        apply_layer(&mut settings, vec![setting("format", Value::Text("jpeg".to_string()))]);
+        assert_eq!(args(&settings), ["--cursor", "--format=jpeg"]);
+
+        let given = |name: &str| name == "format";
+        assert_eq!(default_args(&settings, &given, &[]), ["--cursor"]);
+        assert!(default_args(&settings, &given, &["cursor".to_string()]).is_empty());
+    }
+
+    #[test]
+    fn no_prefixes_turn_flags_off() {
+        let known = |name: &str| ["cursor", "no-clobber", "format"].contains(&name);
+        let is_flag = |name: &str| ["cursor", "no-clobber"].contains(&name);
+        let (rest, off) = split_turned_off(
+            &strings(&["--no-cursor", "--no-clobber", "--no-format", "--no-such", "-f", "png",
+                       "--", "--no-cursor"]),
+            &known, &is_flag);
+        // Options named no-* are kept, and only flags can be turned off
+        assert_eq!(rest, ["--no-clobber", "--no-format", "--no-such", "-f", "png", "--",
+                          "--no-cursor"]);
+        assert_eq!(off, ["cursor"]);
+    }
+}
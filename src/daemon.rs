@@ -0,0 +1,126 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Resident mode taking capture requests on a Unix socket
+// A request is one command line argument per line, ended by an empty line, and is run as if it
+// had been given to shotgun, with its standard output and error going back to the client
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::panic;
+use std::path::Path;
+use std::path::PathBuf;
+use std::ptr;
+use std::time;
+
+/// Time a client has to send its request, not to hold up the others
+const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// `$XDG_RUNTIME_DIR/shotgun.sock`, none without a runtime directory, as the temporary
+/// directory is shared with other users
+pub fn default_socket() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR").map(|d| PathBuf::from(d).join("shotgun.sock"))
+}
+
+/// The arguments of a request, none if the client hung up before the end of it
+fn read_request(stream: &UnixStream) -> io::Result<Option<Vec<String>>> {
+    let mut args = Vec::new();
+    for line in io::BufReader::new(stream).lines() {
+        let line = line?;
+        if line.is_empty() {
+            return Ok(Some(args));
+        }
+        args.push(line);
+    }
+    Ok(None)
+}
+
+/// Run `f` with the standard output and error connected to `stream`
+fn redirected(stream: UnixStream, f: impl FnOnce() -> i32) -> i32 {
+    let _ = io::stdout().flush();
+    let fd = stream.into_raw_fd();
+    let saved = unsafe { [libc::dup(libc::STDOUT_FILENO), libc::dup(libc::STDERR_FILENO)] };
+    unsafe {
+        libc::dup2(fd, libc::STDOUT_FILENO);
+        libc::dup2(fd, libc::STDERR_FILENO);
+        // Only the standard streams are left, so the client sees the end of the reply as soon as
+        // they are restored, even if a child process (e.g. for --clipboard) outlives the request
+        libc::close(fd);
+    }
+
+    // Printing panics if the client hangs up early, that should not take the daemon down
+    let status = panic::catch_unwind(panic::AssertUnwindSafe(f)).unwrap_or(1);
+
+    let _ = io::stdout().flush();
+    unsafe {
+        libc::dup2(saved[0], libc::STDOUT_FILENO);
+        libc::dup2(saved[1], libc::STDERR_FILENO);
+        libc::close(saved[0]);
+        libc::close(saved[1]);
+    }
+    status
+}
+
+/// Listen on `path` and handle requests one at a time with `run`, which gets them with a program
+/// name prepended like `env::args()`
+pub fn serve(path: &Path, run: impl Fn(&[String]) -> i32) -> i32 {
+    // A socket file nobody answers on is left over from a daemon that did not exit cleanly
+    if UnixStream::connect(path).is_ok() {
+        eprintln!("A daemon is already listening on {}", path.display());
+        return 1;
+    }
+    let _ = fs::remove_file(path);
+    // Only for us to connect to, the mode being set through the umask so that there is no time
+    // when others could
+    let umask = unsafe { libc::umask(0o177) };
+    let bound = UnixListener::bind(path);
+    unsafe {
+        libc::umask(umask);
+    }
+    let listener = match bound {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Failed to listen on {}: {}", path.display(), e);
+            return 1;
+        },
+    };
+    info!("Listening on {}", path.display());
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Failed to accept a connection: {}", e);
+                continue;
+            },
+        };
+        if let Err(e) = stream.set_read_timeout(Some(REQUEST_TIMEOUT)) {
+            eprintln!("Failed to set a timeout on a connection: {}", e);
+            continue;
+        }
+        let mut args = match read_request(&stream) {
+            Ok(Some(a)) => a,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("Failed to read a request: {}", e);
+                continue;
+            },
+        };
+        args.insert(0, "shotgun".to_string());
+
+        let status = redirected(stream, || run(&args));
+        if status != 0 {
+            eprintln!("Request {:?} failed", &args[1..]);
+        }
+        // Reap the --clipboard children that are done serving the selection
+        while unsafe { libc::waitpid(-1, ptr::null_mut(), libc::WNOHANG) } > 0 {}
+    }
+    0
+}
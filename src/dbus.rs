@@ -0,0 +1,116 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Bindings for the parts of libdbus used by the portal backend and notifications
+
+#![allow(non_camel_case_types)]
+
+use std::os::raw::c_char;
+use std::os::raw::c_int;
+use std::os::raw::c_uint;
+use std::os::raw::c_void;
+use std::ptr;
+
+pub enum DBusConnection {}
+pub enum DBusMessage {}
+
+pub type dbus_bool_t = c_uint;
+
+#[repr(C)]
+pub struct DBusError {
+    pub name: *const c_char,
+    pub message: *const c_char,
+    dummy: c_uint,
+    padding: *mut c_void,
+}
+
+/// Opaque to us, sized generously for libdbus' own layout
+#[repr(C)]
+pub struct DBusMessageIter {
+    dummy: [u64; 12],
+}
+
+impl DBusError {
+    pub fn new() -> DBusError {
+        let mut e = DBusError {
+            name: ptr::null(),
+            message: ptr::null(),
+            dummy: 0,
+            padding: ptr::null_mut(),
+        };
+        unsafe {
+            dbus_error_init(&mut e);
+        }
+        e
+    }
+}
+
+impl DBusMessageIter {
+    pub fn new() -> DBusMessageIter {
+        DBusMessageIter {
+            dummy: [0; 12],
+        }
+    }
+}
+
+pub const DBUS_BUS_SESSION: c_int = 0;
+pub const DBUS_NAME_FLAG_DO_NOT_QUEUE: c_uint = 4;
+pub const DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER: c_int = 1;
+
+pub const DBUS_TYPE_ARRAY: c_int = b'a' as c_int;
+pub const DBUS_TYPE_BOOLEAN: c_int = b'b' as c_int;
+pub const DBUS_TYPE_DICT_ENTRY: c_int = b'e' as c_int;
+pub const DBUS_TYPE_INT32: c_int = b'i' as c_int;
+pub const DBUS_TYPE_STRING: c_int = b's' as c_int;
+pub const DBUS_TYPE_UINT32: c_int = b'u' as c_int;
+pub const DBUS_TYPE_VARIANT: c_int = b'v' as c_int;
+
+#[link(name = "dbus-1")]
+extern "C" {
+    pub fn dbus_error_init(error: *mut DBusError);
+    pub fn dbus_error_free(error: *mut DBusError);
+    pub fn dbus_error_is_set(error: *const DBusError) -> dbus_bool_t;
+
+    pub fn dbus_bus_get(type_: c_int, error: *mut DBusError) -> *mut DBusConnection;
+    pub fn dbus_bus_request_name(connection: *mut DBusConnection, name: *const c_char,
+                                 flags: c_uint, error: *mut DBusError) -> c_int;
+    pub fn dbus_connection_read_write(connection: *mut DBusConnection, timeout: c_int)
+                                      -> dbus_bool_t;
+    pub fn dbus_connection_pop_message(connection: *mut DBusConnection) -> *mut DBusMessage;
+    pub fn dbus_connection_send(connection: *mut DBusConnection, message: *mut DBusMessage,
+                                serial: *mut u32) -> dbus_bool_t;
+    pub fn dbus_connection_flush(connection: *mut DBusConnection);
+    pub fn dbus_connection_send_with_reply_and_block(connection: *mut DBusConnection,
+                                                     message: *mut DBusMessage,
+                                                     timeout: c_int, error: *mut DBusError)
+                                                     -> *mut DBusMessage;
+
+    pub fn dbus_message_is_method_call(message: *mut DBusMessage, iface: *const c_char,
+                                       method: *const c_char) -> dbus_bool_t;
+    pub fn dbus_message_get_type(message: *mut DBusMessage) -> c_int;
+    pub fn dbus_message_new_method_call(destination: *const c_char, path: *const c_char,
+                                        iface: *const c_char, method: *const c_char)
+                                        -> *mut DBusMessage;
+    pub fn dbus_message_new_method_return(call: *mut DBusMessage) -> *mut DBusMessage;
+    pub fn dbus_message_new_error(reply_to: *mut DBusMessage, name: *const c_char,
+                                  message: *const c_char) -> *mut DBusMessage;
+    pub fn dbus_message_unref(message: *mut DBusMessage);
+
+    pub fn dbus_message_iter_init(message: *mut DBusMessage, iter: *mut DBusMessageIter)
+                                  -> dbus_bool_t;
+    pub fn dbus_message_iter_get_arg_type(iter: *mut DBusMessageIter) -> c_int;
+    pub fn dbus_message_iter_get_basic(iter: *mut DBusMessageIter, value: *mut c_void);
+    pub fn dbus_message_iter_next(iter: *mut DBusMessageIter) -> dbus_bool_t;
+    pub fn dbus_message_iter_recurse(iter: *mut DBusMessageIter, sub: *mut DBusMessageIter);
+    pub fn dbus_message_iter_init_append(message: *mut DBusMessage, iter: *mut DBusMessageIter);
+    pub fn dbus_message_iter_append_basic(iter: *mut DBusMessageIter, type_: c_int,
+                                          value: *const c_void) -> dbus_bool_t;
+    pub fn dbus_message_iter_open_container(iter: *mut DBusMessageIter, type_: c_int,
+                                            signature: *const c_char,
+                                            sub: *mut DBusMessageIter) -> dbus_bool_t;
+    pub fn dbus_message_iter_close_container(iter: *mut DBusMessageIter,
+                                             sub: *mut DBusMessageIter) -> dbus_bool_t;
+}
+
+pub const DBUS_MESSAGE_TYPE_METHOD_CALL: c_int = 1;
@@ -0,0 +1,596 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::Path;
+use std::sync::OnceLock;
+
+use image::imageops;
+use image::ImageBuffer;
+use image::Primitive;
+use image::Rgb;
+use image::RgbImage;
+use image::Rgba;
+use image::RgbaImage;
+
+use crate::palette;
+use crate::util;
+use crate::xwrap::Rgba16Image;
+
+fn bounds(image: &RgbaImage) -> util::Rect {
+    util::Rect {
+        x: 0,
+        y: 0,
+        w: image.width() as i32,
+        h: image.height() as i32,
+    }
+}
+
+pub fn fill_rect(image: &mut RgbaImage, rect: util::Rect, color: Rgba<u8>) {
+    if let Some(r) = rect.intersection(bounds(image)) {
+        for y in r.y..r.y + r.h {
+            for x in r.x..r.x + r.w {
+                image.put_pixel(x as u32, y as u32, color);
+            }
+        }
+    }
+}
+
+/// Blur the part of the image within `rect`
+pub fn blur_rect(image: &mut RgbaImage, rect: util::Rect, radius: f32) {
+    if let Some(r) = rect.intersection(bounds(image)) {
+        let part = imageops::crop(image, r.x as u32, r.y as u32, r.w as u32, r.h as u32);
+        let blurred = imageops::blur(&part.to_image(), radius);
+        imageops::replace(image, &blurred, r.x as u32, r.y as u32);
+    }
+}
+
+/// Cover the part of the image within `rect` with blocks of `size` pixels, each the average
+/// color of the pixels it covers
+pub fn pixelate_rect(image: &mut RgbaImage, rect: util::Rect, size: u32) {
+    let r = match rect.intersection(bounds(image)) {
+        Some(r) => r,
+        None => return,
+    };
+    let size = size.max(1) as i32;
+    for y in (r.y..r.y + r.h).step_by(size as usize) {
+        for x in (r.x..r.x + r.w).step_by(size as usize) {
+            let block = util::Rect {
+                x,
+                y,
+                w: size.min(r.x + r.w - x),
+                h: size.min(r.y + r.h - y),
+            };
+            let mut sum = [0u64; 4];
+            for py in block.y..block.y + block.h {
+                for px in block.x..block.x + block.w {
+                    let p = image.get_pixel(px as u32, py as u32);
+                    for (s, &v) in sum.iter_mut().zip(p.0.iter()) {
+                        *s += v as u64;
+                    }
+                }
+            }
+            let n = (block.w * block.h) as u64;
+            fill_rect(image, block, Rgba(sum.map(|s| (s / n) as u8)));
+        }
+    }
+}
+
+fn checker_pixel(x: u32, y: u32) -> Rgba<u8> {
+    let v = if (x / 8 + y / 8) % 2 == 0 { 0xCC } else { 0xFF };
+    Rgba([v, v, v, 0xFF])
+}
+
+/// Gray and white squares of 8 pixels, the usual stand-in for transparency
+pub fn checkerboard(w: u32, h: u32) -> RgbaImage {
+    RgbaImage::from_fn(w, h, checker_pixel)
+}
+
+/// Fill a rectangle with the squares of a checkerboard covering the whole image
+pub fn fill_checkerboard(image: &mut RgbaImage, rect: util::Rect) {
+    if let Some(r) = rect.intersection(bounds(image)) {
+        for y in r.y as u32..(r.y + r.h) as u32 {
+            for x in r.x as u32..(r.x + r.w) as u32 {
+                image.put_pixel(x, y, checker_pixel(x, y));
+            }
+        }
+    }
+}
+
+/// Swap the red and blue channels, for sources that come out in BGR order
+pub fn swap_rb<T: Primitive + 'static>(image: &mut ImageBuffer<Rgba<T>, Vec<T>>) {
+    for p in image.pixels_mut() {
+        p.0.swap(0, 2);
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Flip {
+    Horizontal,
+    Vertical,
+}
+
+/// Turn an image clockwise by a multiple of 90 degrees, then mirror it
+pub fn orient<T: Primitive + 'static>(image: &ImageBuffer<Rgba<T>, Vec<T>>, degrees: u32,
+                                      flip: Option<Flip>) -> ImageBuffer<Rgba<T>, Vec<T>> {
+    let turned = match degrees % 360 {
+        90 => imageops::rotate90(image),
+        180 => imageops::rotate180(image),
+        270 => imageops::rotate270(image),
+        _ => image.clone(),
+    };
+    match flip {
+        Some(Flip::Horizontal) => imageops::flip_horizontal(&turned),
+        Some(Flip::Vertical) => imageops::flip_vertical(&turned),
+        None => turned,
+    }
+}
+
+fn narrow_pixel(p: Rgba<u16>) -> Rgba<u8> {
+    Rgba(p.0.map(|v| ((v as u32 * 255 + 32767) / 65535) as u8))
+}
+
+/// Round a 16 bits per channel image down to 8
+pub fn narrow(image: &Rgba16Image) -> RgbaImage {
+    RgbaImage::from_fn(image.width(), image.height(), |x, y| narrow_pixel(*image.get_pixel(x, y)))
+}
+
+/// Widen an image to 16 bits per channel
+/// Pixels that still match `deep`, the full precision capture it came from, are taken from it
+/// as long as the geometry is unchanged
+pub fn widen(image: &RgbaImage, deep: Option<&Rgba16Image>) -> Rgba16Image {
+    let deep = deep.filter(|d| d.dimensions() == image.dimensions());
+    Rgba16Image::from_fn(image.width(), image.height(), |x, y| {
+        let p = *image.get_pixel(x, y);
+        match deep.map(|d| *d.get_pixel(x, y)) {
+            Some(d) if narrow_pixel(d) == p => d,
+            _ => Rgba(p.0.map(|v| v as u16 * 257)),
+        }
+    })
+}
+
+/// sRGB transfer function, both ways, over the 16 bit range
+fn srgb_tables() -> &'static (Vec<u16>, Vec<u16>) {
+    static TABLES: OnceLock<(Vec<u16>, Vec<u16>)> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let table = |f: fn(f64) -> f64| {
+            (0..=u16::MAX).map(|v| (f(v as f64 / 65535.0) * 65535.0).round() as u16).collect()
+        };
+        let to_linear = |c: f64| {
+            if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+        };
+        let to_srgb = |l: f64| {
+            if l <= 0.0031308 { l * 12.92 } else { 1.055 * l.powf(1.0 / 2.4) - 0.055 }
+        };
+        (table(to_linear), table(to_srgb))
+    })
+}
+
+/// `imageops::resize` in linear light rather than on the sRGB encoded values, which darkens
+/// fine detail such as text when scaling down
+pub fn resize_linear16(image: &Rgba16Image, w: u32, h: u32, filter: imageops::FilterType)
+                       -> Rgba16Image {
+    let (to_linear, to_srgb) = srgb_tables();
+    let mut linear = image.clone();
+    for p in linear.pixels_mut() {
+        for c in p.0[..3].iter_mut() {
+            *c = to_linear[*c as usize];
+        }
+    }
+    let mut resized = imageops::resize(&linear, w, h, filter);
+    for p in resized.pixels_mut() {
+        for c in p.0[..3].iter_mut() {
+            *c = to_srgb[*c as usize];
+        }
+    }
+    resized
+}
+
+/// `resize_linear16` for an 8 bit image, scaled at 16 bits so that dark shades are kept apart
+pub fn resize_linear(image: &RgbaImage, w: u32, h: u32, filter: imageops::FilterType)
+                     -> RgbaImage {
+    narrow(&resize_linear16(&widen(image, None), w, h, filter))
+}
+
+/// Put `image` at `x`, `y` on a transparent canvas of `w` by `h`, for the full precision
+/// capture to follow the 8 bit one onto a larger canvas
+pub fn place<T: Primitive + 'static>(image: &ImageBuffer<Rgba<T>, Vec<T>>, w: u32, h: u32,
+                                    x: u32, y: u32) -> ImageBuffer<Rgba<T>, Vec<T>> {
+    let mut out = ImageBuffer::new(w, h);
+    imageops::replace(&mut out, image, x, y);
+    out
+}
+
+/// Share of the pixels that differ between two images, all of them if their sizes differ
+pub fn changed_share(a: &RgbaImage, b: &RgbaImage) -> f64 {
+    if a.dimensions() != b.dimensions() || a.width() == 0 || a.height() == 0 {
+        return 1.0;
+    }
+    let changed = a.pixels().zip(b.pixels()).filter(|(p, q)| p != q).count();
+    changed as f64 / (a.width() as f64 * a.height() as f64)
+}
+
+/// Whether every pixel of the image has the same color
+pub fn is_uniform(image: &RgbaImage) -> bool {
+    let mut pixels = image.pixels();
+    match pixels.next() {
+        Some(first) => pixels.all(|p| p == first),
+        None => true,
+    }
+}
+
+/// Sharpen edges by adding back `amount` times the difference with a Gaussian blur of radius
+/// `radius`, leaving alpha untouched
+pub fn unsharp_mask(image: &RgbaImage, amount: f32, radius: f32) -> RgbaImage {
+    let blurred = imageops::blur(image, radius);
+    let mut out = image.clone();
+    for (p, b) in out.pixels_mut().zip(blurred.pixels()) {
+        for i in 0..3 {
+            let v = p[i] as f32;
+            p[i] = (v + amount * (v - b[i] as f32)).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    out
+}
+
+/// Blend the image onto an opaque background, for formats without alpha
+pub fn flatten(image: &RgbaImage, background: Rgba<u8>) -> RgbImage {
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let p = blend_pixel(background, *image.get_pixel(x, y));
+        Rgb([p[0], p[1], p[2]])
+    })
+}
+
+/// Blend the image onto an opaque background in place, keeping its alpha channel fully opaque
+pub fn fill_behind(image: &mut RgbaImage, background: Rgba<u8>) {
+    for p in image.pixels_mut() {
+        *p = blend_pixel(background, *p);
+    }
+}
+
+/// Gray level of a color, with the Rec. 601 weights
+fn luma(p: Rgba<u8>) -> u8 {
+    ((p[0] as u32 * 299 + p[1] as u32 * 587 + p[2] as u32 * 114 + 500) / 1000) as u8
+}
+
+/// Replace every color by its gray level, keeping alpha
+pub fn grayscale(image: &RgbaImage) -> RgbaImage {
+    RgbaImage::from_fn(image.width(), image.height(), |x, y| {
+        let p = *image.get_pixel(x, y);
+        let l = luma(p);
+        Rgba([l, l, l, p[3]])
+    })
+}
+
+/// Black and white rendering of the image blended onto `background`, each pixel thresholded at
+/// half brightness or, with `dither`, its error spread to the next ones (Floyd-Steinberg)
+pub fn black_and_white(image: &RgbaImage, background: Rgba<u8>, dither: bool) -> RgbaImage {
+    let (w, h) = (image.width() as usize, image.height() as usize);
+    let mut levels: Vec<i32> = image.pixels()
+        .map(|&p| luma(blend_pixel(background, p)) as i32)
+        .collect();
+    let mut out = RgbaImage::new(w as u32, h as u32);
+    for (i, p) in out.pixels_mut().enumerate() {
+        let level = if levels[i] >= 128 { 0xFF } else { 0 };
+        *p = Rgba([level as u8, level as u8, level as u8, 0xFF]);
+        if !dither {
+            continue;
+        }
+
+        let error = levels[i] - level;
+        let (x, y) = (i % w, i / w);
+        for (dx, dy, weight) in [(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)] {
+            let nx = x as isize + dx;
+            if nx >= 0 && (nx as usize) < w && y + dy < h {
+                levels[(y + dy) * w + nx as usize] += error * weight / 16;
+            }
+        }
+    }
+    out
+}
+
+/// Alpha-blend a single pixel over another (straight alpha, source over)
+pub fn blend_pixel(dst: Rgba<u8>, src: Rgba<u8>) -> Rgba<u8> {
+    let sa = src[3] as u32;
+    let da = dst[3] as u32 * (255 - sa) / 255;
+    let a = sa + da;
+    if a == 0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+
+    let mut out = [0, 0, 0, a as u8];
+    for i in 0..3 {
+        out[i] = ((src[i] as u32 * sa + dst[i] as u32 * da) / a) as u8;
+    }
+    Rgba(out)
+}
+
+/// Alpha-blend `src` onto `dst` with its top-left corner at (x, y), clipping as needed
+/// `opacity` further scales the alpha of `src`
+pub fn blend(dst: &mut RgbaImage, src: &RgbaImage, x: i32, y: i32, opacity: f32) {
+    let rect = util::Rect {
+        x,
+        y,
+        w: src.width() as i32,
+        h: src.height() as i32,
+    };
+
+    if let Some(r) = rect.intersection(bounds(dst)) {
+        for dy in r.y..r.y + r.h {
+            for dx in r.x..r.x + r.w {
+                let mut s = *src.get_pixel((dx - x) as u32, (dy - y) as u32);
+                s[3] = (s[3] as f32 * opacity).round() as u8;
+                let d = dst.get_pixel_mut(dx as u32, dy as u32);
+                *d = blend_pixel(*d, s);
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+pub struct Watermark {
+    image: RgbaImage,
+    /// Repeated over the whole image when none
+    corner: Option<Corner>,
+    opacity: f32,
+}
+
+impl Corner {
+    pub fn parse(s: &str) -> Option<Corner> {
+        match s {
+            "tl" => Some(Corner::TopLeft),
+            "tr" => Some(Corner::TopRight),
+            "bl" => Some(Corner::BottomLeft),
+            "br" => Some(Corner::BottomRight),
+            _ => None,
+        }
+    }
+
+    /// Position of a `w`x`h` box in this corner of `outer`, `margin` pixels from the edges
+    pub fn place(self, outer: (u32, u32), w: u32, h: u32, margin: i32) -> (i32, i32) {
+        let right = outer.0 as i32 - w as i32 - margin;
+        let bottom = outer.1 as i32 - h as i32 - margin;
+        match self {
+            Corner::TopLeft => (margin, margin),
+            Corner::TopRight => (right, margin),
+            Corner::BottomLeft => (margin, bottom),
+            Corner::BottomRight => (right, bottom),
+        }
+    }
+}
+
+impl Watermark {
+    /// Parse `PATH[:corner[:opacity]]`, the corner being `tile` to cover the image
+    /// The fields are taken from the end, so that the path may hold colons of its own as long as
+    /// what follows the last one is not a corner
+    fn parse_spec(spec: &str) -> Result<(&str, Option<Corner>, f32), String> {
+        let corner = |c| match c {
+            "tile" => Some(None),
+            c => Corner::parse(c).map(Some),
+        };
+        let mut fields = spec.rsplitn(3, ':');
+        let (last, middle) = (fields.next().unwrap_or_default(), fields.next());
+        if let (Some(c), Some(path)) = (middle.and_then(corner), fields.next()) {
+            let opacity = last.parse::<f32>().ok().filter(|o| (0.0..=1.0).contains(o))
+                .ok_or(format!("invalid opacity {:?}, expected 0 to 1", last))?;
+            return Ok((path, c, opacity));
+        }
+        match (corner(last), middle) {
+            (Some(c), Some(_)) => Ok((&spec[..spec.len() - last.len() - 1], c, 0.5)),
+            _ => Ok((spec, Some(Corner::BottomRight), 0.5)),
+        }
+    }
+
+    /// Parse `PATH[:corner[:opacity]]` and load the image it refers to
+    pub fn load(spec: &str) -> Result<Watermark, String> {
+        let (path, corner, opacity) = Watermark::parse_spec(spec)?;
+        let image = image::open(Path::new(path)).map_err(|e| e.to_string())?.to_rgba();
+
+        Ok(Watermark {
+            image,
+            corner,
+            opacity,
+        })
+    }
+
+    /// Blend the watermark onto `image`, optionally scaled to a fraction of its width
+    /// Tiles are `margin` pixels apart
+    pub fn apply(&self, image: &mut RgbaImage, margin: i32, scale: Option<f64>) {
+        let scaled;
+        let mark = match scale {
+            Some(s) => {
+                let w = (image.width() as f64 * s).round().max(1.0);
+                let h = (w * self.image.height() as f64 / self.image.width() as f64)
+                        .round().max(1.0);
+                scaled = imageops::resize(&self.image, w as u32, h as u32,
+                                          imageops::FilterType::Lanczos3);
+                &scaled
+            },
+            None => &self.image,
+        };
+
+        match self.corner {
+            Some(c) => {
+                let (x, y) = c.place(image.dimensions(), mark.width(), mark.height(), margin);
+                blend(image, mark, x, y, self.opacity);
+            },
+            None => {
+                let step_x = (mark.width() as i32 + margin).max(1) as usize;
+                let step_y = (mark.height() as i32 + margin).max(1) as usize;
+                for y in (0..image.height() as i32).step_by(step_y) {
+                    for x in (0..image.width() as i32).step_by(step_x) {
+                        blend(image, mark, x, y, self.opacity);
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Put the image on a larger transparent canvas over a soft shadow of its shape, blurred by
+/// `radius` and dropped by half of that
+pub fn drop_shadow(image: &RgbaImage, radius: u32) -> RgbaImage {
+    let pad = shadow_offset(radius);
+    let mut shadow = RgbaImage::new(image.width() + 2 * pad, image.height() + 2 * pad);
+    for (x, y, p) in image.enumerate_pixels() {
+        shadow.put_pixel(x + pad, y + pad + radius / 2, Rgba([0, 0, 0, p[3] / 2]));
+    }
+
+    let mut out = imageops::blur(&shadow, radius as f32 / 2.0);
+    blend(&mut out, image, pad as i32, pad as i32, 1.0);
+    out
+}
+
+/// Where `drop_shadow` puts the image on its canvas, across and down
+pub fn shadow_offset(radius: u32) -> u32 {
+    radius * 2
+}
+
+pub struct Border {
+    // Top, right, bottom, left
+    widths: [u32; 4],
+    color: Rgba<u8>,
+}
+
+impl Border {
+    /// Parse `PX:COLOR` or `TOP,RIGHT,BOTTOM,LEFT:COLOR`
+    pub fn parse(spec: &str) -> Result<Border, String> {
+        let (widths, color) = spec.split_once(':').ok_or("expected PX:RRGGBB")?;
+
+        let widths = widths.split(',').map(|w| w.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("invalid border width {:?}", widths))?;
+        let widths = match widths[..] {
+            [w] => [w; 4],
+            [t, r, b, l] => [t, r, b, l],
+            _ => return Err("expected one width or four comma-separated widths".to_string()),
+        };
+        let color = palette::parse_hex_color(color)
+            .ok_or(format!("invalid color {:?}, expected RRGGBB or RRGGBBAA", color))?;
+
+        Ok(Border {
+            widths,
+            color: Rgba(color),
+        })
+    }
+
+    /// Where `apply` puts the image on its canvas
+    pub fn offset(&self) -> (u32, u32) {
+        (self.widths[3], self.widths[0])
+    }
+
+    /// Grow the canvas around `image`, filling the new area with the border color
+    pub fn apply(&self, image: &RgbaImage) -> RgbaImage {
+        let [top, right, bottom, left] = self.widths;
+        let mut out = RgbaImage::from_pixel(image.width() + left + right,
+                                            image.height() + top + bottom, self.color);
+        imageops::replace(&mut out, image, left, top);
+        out
+    }
+}
+
+/// Lay images out left to right on a grid of `columns` columns, `spacing` pixels apart
+/// Each column is as wide as its widest image and each row as tall as its tallest one
+pub fn grid(images: &[RgbaImage], columns: u32, spacing: u32, fill: Rgba<u8>) -> RgbaImage {
+    let columns = (columns as usize).clamp(1, images.len().max(1));
+    let mut widths = vec![0; columns];
+    let mut heights = vec![0; images.len().div_ceil(columns)];
+    for (i, image) in images.iter().enumerate() {
+        widths[i % columns] = widths[i % columns].max(image.width());
+        heights[i / columns] = heights[i / columns].max(image.height());
+    }
+    let starts = |sizes: &[u32]| -> Vec<u32> {
+        sizes.iter().scan(0, |next, &s| {
+            let start = *next;
+            *next += s + spacing;
+            Some(start)
+        }).collect()
+    };
+    let total = |sizes: &[u32]| {
+        sizes.iter().sum::<u32>() + spacing * (sizes.len() as u32).saturating_sub(1)
+    };
+    let (xs, ys) = (starts(&widths), starts(&heights));
+
+    let mut out = RgbaImage::from_pixel(total(&widths), total(&heights), fill);
+    for (i, image) in images.iter().enumerate() {
+        imageops::replace(&mut out, image, xs[i % columns], ys[i / columns]);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swap_rb_exchanges_red_and_blue() {
+        let mut image = RgbaImage::from_pixel(2, 1, Rgba([0x11, 0x22, 0x33, 0x44]));
+        image.put_pixel(1, 0, Rgba([0xFF, 0x00, 0x00, 0xFF]));
+
+        swap_rb(&mut image);
+
+        assert_eq!(*image.get_pixel(0, 0), Rgba([0x33, 0x22, 0x11, 0x44]));
+        assert_eq!(*image.get_pixel(1, 0), Rgba([0x00, 0x00, 0xFF, 0xFF]));
+    }
+
+    #[test]
+    fn watermark_spec_fields_come_from_the_end() {
+        let parse = Watermark::parse_spec;
+        assert_eq!(parse("logo.png"), Ok(("logo.png", Some(Corner::BottomRight), 0.5)));
+        assert_eq!(parse("logo.png:tl"), Ok(("logo.png", Some(Corner::TopLeft), 0.5)));
+        assert_eq!(parse("logo.png:tile:0.25"), Ok(("logo.png", None, 0.25)));
+        assert_eq!(parse("a:b/logo.png"), Ok(("a:b/logo.png", Some(Corner::BottomRight), 0.5)));
+        assert_eq!(parse("a:b.png:tr:1"), Ok(("a:b.png", Some(Corner::TopRight), 1.0)));
+        assert_eq!(parse("12:00.png:bl"), Ok(("12:00.png", Some(Corner::BottomLeft), 0.5)));
+        assert!(parse("logo.png:br:2").is_err());
+        assert!(parse("logo.png:br:x").is_err());
+    }
+
+    #[test]
+    fn unsharp_mask_restores_edge_contrast() {
+        // Vertical black and white stripes, 3 pixels wide, blurred by downscaling
+        let pattern = RgbaImage::from_fn(24, 24, |x, _| {
+            let v = if x / 3 % 2 == 0 { 0 } else { 0xFF };
+            Rgba([v, v, v, 0xFF])
+        });
+        let scaled = imageops::resize(&pattern, 16, 16, imageops::FilterType::Triangle);
+        let sharpened = unsharp_mask(&scaled, 1.0, 1.0);
+
+        // Sum of the differences between neighbors along a row
+        let contrast = |image: &RgbaImage| -> u32 {
+            (1..image.width()).map(|x| {
+                (image.get_pixel(x, 8)[0] as i32 - image.get_pixel(x - 1, 8)[0] as i32)
+                    .unsigned_abs()
+            }).sum()
+        };
+        assert!(contrast(&sharpened) > contrast(&scaled));
+        assert!(sharpened.pixels().all(|p| p[3] == 0xFF));
+    }
+
+    #[test]
+    fn linear_resize_keeps_brightness() {
+        // Alternating black and white pixels average to half the light, not half the value
+        let stripes = RgbaImage::from_fn(8, 8, |x, _| {
+            let v = if x % 2 == 0 { 0 } else { 255 };
+            Rgba([v, v, v, 255])
+        });
+        let linear = resize_linear(&stripes, 1, 1, imageops::FilterType::Triangle);
+        assert!((186..=190).contains(&linear.get_pixel(0, 0)[0]));
+        let naive = imageops::resize(&stripes, 1, 1, imageops::FilterType::Triangle);
+        assert!((126..=129).contains(&naive.get_pixel(0, 0)[0]));
+
+        // Flat colors, and alpha, come out as they went in
+        for v in 0..=255 {
+            let flat = RgbaImage::from_pixel(4, 4, Rgba([v, 255 - v, v / 2, v]));
+            let resized = resize_linear(&flat, 2, 2, imageops::FilterType::Lanczos3);
+            assert_eq!(*resized.get_pixel(1, 1), Rgba([v, 255 - v, v / 2, v]));
+        }
+    }
+}
@@ -0,0 +1,22 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// farbfeld encoder, see https://tools.suckless.org/farbfeld/
+
+use std::io;
+
+use crate::xwrap::Rgba16Image;
+
+/// Encode `image` as a farbfeld file, each channel 16 bits big-endian
+pub fn write_farbfeld(w: &mut dyn io::Write, image: &Rgba16Image) -> io::Result<()> {
+    let (width, height) = image.dimensions();
+    let mut out = Vec::with_capacity(16 + image.len() * 2);
+    out.extend_from_slice(b"farbfeld");
+    out.extend_from_slice(&width.to_be_bytes());
+    out.extend_from_slice(&height.to_be_bytes());
+    for &v in image.iter() {
+        out.extend_from_slice(&v.to_be_bytes());
+    }
+    w.write_all(&out)
+}
@@ -0,0 +1,240 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Shell completions and a man page written from the options --help lists, so that packagers
+// never have to keep them in sync by hand
+
+use getopts::Fail;
+use getopts::Options;
+
+/// How an option takes its value
+#[derive(Copy, Clone, PartialEq)]
+enum Arg {
+    No,
+    Yes,
+    /// Only as --name=VALUE
+    Maybe,
+}
+
+struct Opt {
+    short: Option<char>,
+    long: String,
+    arg: Arg,
+    hint: String,
+    /// Whether it can be given more than once
+    multi: bool,
+    desc: String,
+}
+
+impl Opt {
+    /// Whether the value names a file, for shells to complete it as one
+    fn takes_path(&self) -> bool {
+        ["FILE", "DIR", "PATH", "SOCKET"].iter().any(|h| self.hint.starts_with(h))
+    }
+
+    /// Values to pick from, for hints that list them such as `fast/default/best`
+    fn choices(&self) -> Option<Vec<&str>> {
+        let choices: Vec<&str> = self.hint.split('/').collect();
+        let word = |c: &&str| !c.is_empty() && c.chars().all(|c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'
+        });
+        Some(choices).filter(|c| c.len() > 1 && c.iter().all(word))
+    }
+
+    /// Its names as given on the command line
+    fn names(&self) -> Vec<String> {
+        self.short.map(|s| format!("-{}", s)).into_iter().chain(Some(format!("--{}", self.long)))
+            .collect()
+    }
+}
+
+/// Read the options back from the rows of --help, getopts keeping the rest to itself
+/// How each one takes its value is found by trying it out
+fn options(opts: &Options) -> Vec<Opt> {
+    let rows = opts.usage_with_format(|rows| rows.collect::<Vec<String>>().join("\0"));
+    rows.split('\0').filter_map(|row| {
+        let mut rest = row.trim_start();
+        let mut short = None;
+        if !rest.starts_with("--") {
+            short = rest.chars().nth(1);
+            rest = rest.get(2..)?.trim_start_matches(',').trim_start();
+        }
+        let rest = rest.strip_prefix("--")?;
+        let end = rest.find(|c: char| c.is_whitespace()).unwrap_or(rest.len());
+        let (long, mut rest) = rest.split_at(end);
+
+        let parse = |args: &[String]| opts.parse(args);
+        let arg = match parse(&[format!("--{}", long)]) {
+            Err(Fail::ArgumentMissing(_)) => Arg::Yes,
+            _ => match parse(&[format!("--{}=x", long)]) {
+                Err(Fail::UnexpectedArgument(_)) => Arg::No,
+                _ => Arg::Maybe,
+            },
+        };
+        let value = if arg == Arg::No { String::new() } else { "=x".to_string() };
+        let twice = [format!("--{}{}", long, value), format!("--{}{}", long, value)];
+        let multi = !matches!(parse(&twice), Err(Fail::OptionDuplicated(_)));
+
+        let mut hint = String::new();
+        if arg != Arg::No {
+            rest = rest.trim_start();
+            let end = rest.find(|c: char| c.is_whitespace()).unwrap_or(rest.len());
+            hint = rest[..end].trim_start_matches('[').trim_end_matches(']').to_string();
+            rest = &rest[end..];
+        }
+        let desc = rest.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        Some(Opt { short, long: long.to_string(), arg, hint, multi, desc })
+    }).collect()
+}
+
+fn bash(opts: &[Opt]) -> String {
+    let mut cases = String::new();
+    let mut free = Vec::new();
+    for o in opts.iter().filter(|o| o.arg == Arg::Yes) {
+        match o.choices() {
+            Some(c) => cases.push_str(&format!("        {})\n            COMPREPLY=($(compgen -W \
+                                                '{}' -- \"$cur\"))\n            return;;\n",
+                                               o.names().join("|"), c.join(" "))),
+            // Paths are left to the default completion
+            None if o.takes_path() => {},
+            None => free.extend(o.names()),
+        }
+    }
+    cases.push_str(&format!("        {})\n            COMPREPLY=()\n            return;;\n",
+                            free.join("|")));
+    let names: Vec<String> = opts.iter().flat_map(Opt::names).collect();
+
+    format!("_shotgun() {{
+    local cur=${{COMP_WORDS[COMP_CWORD]}} prev=${{COMP_WORDS[COMP_CWORD-1]}}
+    case $prev in
+{}    esac
+    if [[ $cur == -* ]]; then
+        COMPREPLY=($(compgen -W '{}' -- \"$cur\"))
+    fi
+}}
+complete -o default -F _shotgun shotgun
+", cases, names.join(" "))
+}
+
+fn zsh(opts: &[Opt]) -> String {
+    let mut out = "#compdef shotgun\n\n_arguments -s -S \\\n".to_string();
+    for o in opts {
+        let desc = o.desc.replace('\\', "\\\\").replace('\'', "'\\''").replace('[', "\\[")
+            .replace(']', "\\]");
+        let (suffix, value) = match o.arg {
+            Arg::No => ("", String::new()),
+            Arg::Yes => ("=", format!(":{}:", o.hint.replace(':', "\\:"))),
+            Arg::Maybe => ("=-", format!("::{}:", o.hint.replace(':', "\\:"))),
+        };
+        let action = match o.choices() {
+            _ if o.arg == Arg::No => String::new(),
+            Some(c) => format!("({})", c.join(" ")),
+            None if o.hint == "DIR" => "_files -/".to_string(),
+            None if o.takes_path() => "_files".to_string(),
+            None => " ".to_string(),
+        };
+        let spec = format!("[{}]{}{}'", desc, value, action);
+        let repeat = if o.multi { "*" } else { "" };
+        match o.short {
+            Some(s) => {
+                let exclusive = if o.multi {
+                    "'*'".to_string()
+                } else {
+                    format!("'(-{} --{})'", s, o.long)
+                };
+                let plus = if o.arg == Arg::Yes { "+" } else { "" };
+                out.push_str(&format!("    {}{{-{}{},--{}{}}}'{} \\\n", exclusive, s, plus,
+                                      o.long, suffix, spec));
+            },
+            None => out.push_str(&format!("    '{}--{}{}{} \\\n", repeat, o.long, suffix, spec)),
+        }
+    }
+    out.push_str("    '*:file:_files'\n");
+    out
+}
+
+fn fish(opts: &[Opt]) -> String {
+    let mut out = String::new();
+    for o in opts {
+        let mut line = "complete -c shotgun".to_string();
+        if let Some(s) = o.short {
+            line.push_str(&format!(" -s {}", s));
+        }
+        line.push_str(&format!(" -l {}", o.long));
+        match o.choices() {
+            _ if o.arg == Arg::No => {},
+            Some(c) => line.push_str(&format!(" -xa '{}'", c.join(" "))),
+            None if o.takes_path() => line.push_str(" -r -F"),
+            None => line.push_str(" -x"),
+        }
+        let desc = o.desc.replace('\\', "\\\\").replace('\'', "\\'");
+        line.push_str(&format!(" -d '{}'\n", desc));
+        out.push_str(&line);
+    }
+    out
+}
+
+/// Text escaped for roff, `-` being a hyphen and a leading `.` or `'` a request otherwise
+fn roff(text: &str) -> String {
+    let text = text.replace('\\', "\\e").replace('-', "\\-");
+    if text.starts_with('.') || text.starts_with('\'') {
+        format!("\\&{}", text)
+    } else {
+        text
+    }
+}
+
+fn man(opts: &[Opt], version: &str) -> String {
+    let mut out = format!(".TH SHOTGUN 1 \"\" \"shotgun {}\" \"User Commands\"
+.SH NAME
+shotgun \\- minimal X screenshot utility
+.SH SYNOPSIS
+.B shotgun
+[\\fIoptions\\fR] [\\fIfile\\fR]
+.SH DESCRIPTION
+Captures the screen, a window or an area of either and writes it to \\fIfile\\fR, \\fB\\-\\fR
+standing for stdout, or to a time\\-stamped file in \\fB\\-\\-dir\\fR when none is given.
+.SH OPTIONS
+", roff(version));
+    for o in opts {
+        out.push_str(".TP\n");
+        if let Some(s) = o.short {
+            out.push_str(&format!("\\fB\\-{}\\fR, ", s));
+        }
+        out.push_str(&format!("\\fB\\-\\-{}\\fR", roff(&o.long)));
+        match o.arg {
+            Arg::No => {},
+            Arg::Yes => out.push_str(&format!(" \\fI{}\\fR", roff(&o.hint))),
+            Arg::Maybe => out.push_str(&format!("[=\\fI{}\\fR]", roff(&o.hint))),
+        }
+        out.push('\n');
+        out.push_str(&roff(&o.desc));
+        out.push('\n');
+    }
+    out.push_str(".SH FILES
+.TP
+\\fI$XDG_CONFIG_HOME/shotgun/config.toml\\fR
+Defaults for options not given on the command line, each key being the long name of an option.
+Environment variables such as \\fBSHOTGUN_FORMAT\\fR do the same and take precedence.
+");
+    out
+}
+
+/// Print the completion script for `shell`, or the man page
+pub fn run(opts: &Options, args: &[String], version: &str) -> i32 {
+    let opts = options(opts);
+    let text = match (args.first().map(String::as_str), args.get(1).map(String::as_str)) {
+        (Some("completions"), Some("bash")) => bash(&opts),
+        (Some("completions"), Some("zsh")) => zsh(&opts),
+        (Some("completions"), Some("fish")) => fish(&opts),
+        (Some("man"), None) => man(&opts, version),
+        _ => {
+            eprintln!("Expected --generate completions bash|zsh|fish or --generate man");
+            return 1;
+        },
+    };
+    print!("{}", text);
+    0
+}
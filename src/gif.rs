@@ -0,0 +1,365 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// GIF89a encoder, each frame with a palette of its own
+
+use std::collections::HashMap;
+use std::io;
+
+use image::RgbaImage;
+
+use crate::palette;
+
+/// A frame ready to go after its Graphic Control Extension
+pub struct Frame {
+    width: u16,
+    height: u16,
+    transparent: Option<u8>,
+    /// Image descriptor, local color table and image data
+    data: Vec<u8>,
+}
+
+/// Pack LZW codes into bytes, least significant bit first
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u32) {
+        self.acc |= (code as u32) << self.bits;
+        self.bits += size;
+        while self.bits >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+}
+
+/// Compress palette indices, codes starting at `min_size` + 1 bits and growing up to 12
+fn lzw(indices: &[u8], min_size: u8) -> Vec<u8> {
+    let clear = 1u16 << min_size;
+    let end = clear + 1;
+    let mut w = BitWriter {
+        out: Vec::new(),
+        acc: 0,
+        bits: 0,
+    };
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = end + 1;
+    let mut size = min_size as u32 + 1;
+
+    w.write(clear, size);
+    let mut prefix = match indices.first() {
+        Some(&i) => i as u16,
+        None => {
+            w.write(end, size);
+            return w.finish();
+        },
+    };
+    for &i in &indices[1..] {
+        if let Some(&code) = table.get(&(prefix, i)) {
+            prefix = code;
+            continue;
+        }
+
+        w.write(prefix, size);
+        // The decoder widens its codes once the table grows past them, one code behind us
+        if next >= 1 << size && size < 12 {
+            size += 1;
+        }
+        if next >= 4095 {
+            w.write(clear, size);
+            table.clear();
+            next = end + 1;
+            size = min_size as u32 + 1;
+        } else {
+            table.insert((prefix, i), next);
+            next += 1;
+        }
+        prefix = i as u16;
+    }
+    w.write(prefix, size);
+    if next >= 1 << size && size < 12 {
+        size += 1;
+    }
+    w.write(end, size);
+    w.finish()
+}
+
+/// Reduce `image` to 256 colors and compress it
+pub fn encode_frame(image: &RgbaImage) -> Frame {
+    let indexed = palette::Palette::quantize(image, 256).remap(image, palette::ColorSpace::Rgb);
+
+    // Color tables hold a power of two entries, at least 2
+    let bits = (1..=8).find(|b| 1 << b >= indexed.palette.len()).unwrap_or(8);
+    let mut data = Vec::new();
+    data.push(0x2C);
+    data.extend_from_slice(&[0, 0, 0, 0]);
+    data.extend_from_slice(&(image.width() as u16).to_le_bytes());
+    data.extend_from_slice(&(image.height() as u16).to_le_bytes());
+    // Local color table, not interlaced
+    data.push(0x80 | (bits - 1) as u8);
+    for i in 0..1 << bits {
+        data.extend_from_slice(indexed.palette.get(i).unwrap_or(&[0, 0, 0]));
+    }
+
+    let min_size = bits.max(2) as u8;
+    data.push(min_size);
+    for block in lzw(&indexed.indices, min_size).chunks(255) {
+        data.push(block.len() as u8);
+        data.extend_from_slice(block);
+    }
+    data.push(0);
+
+    Frame {
+        width: image.width() as u16,
+        height: image.height() as u16,
+        transparent: indexed.transparent,
+        data,
+    }
+}
+
+/// Write `frames`, each shown for its delay in milliseconds, looping forever
+/// The first frame gives the size of the whole image
+pub fn write_gif(w: &mut dyn io::Write, frames: &[(Frame, u32)]) -> io::Result<()> {
+    let (width, height) = frames.first().map_or((1, 1), |(f, _)| (f.width, f.height));
+    let mut out = Vec::new();
+    out.extend_from_slice(b"GIF89a");
+    out.extend_from_slice(&width.to_le_bytes());
+    out.extend_from_slice(&height.to_le_bytes());
+    // No global color table
+    out.extend_from_slice(&[0, 0, 0]);
+    if frames.len() > 1 {
+        out.extend_from_slice(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00");
+    }
+
+    for (frame, delay) in frames {
+        // Delays are in hundredths of a second
+        let delay = ((delay + 5) / 10).min(u16::MAX as u32) as u16;
+        // Transparent frames replace what was there rather than show it through
+        let (disposal, index) = match frame.transparent {
+            Some(t) => (2 << 2 | 1, t),
+            None => (0, 0),
+        };
+        out.extend_from_slice(&[0x21, 0xF9, 4, disposal]);
+        out.extend_from_slice(&delay.to_le_bytes());
+        out.extend_from_slice(&[index, 0]);
+        out.extend_from_slice(&frame.data);
+    }
+    out.push(0x3B);
+
+    w.write_all(&out)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    /// Undo `lzw`, the way decoders widen their codes
+    fn unlzw(data: &[u8], min_size: u8) -> Vec<u8> {
+        let clear = 1usize << min_size;
+        let end = clear + 1;
+        let reset = || (0..clear).map(|i| vec![i as u8]).chain(vec![vec![], vec![]]).collect();
+        let mut table: Vec<Vec<u8>> = reset();
+        let mut size = min_size as u32 + 1;
+        let mut prev: Option<Vec<u8>> = None;
+        let mut out = Vec::new();
+
+        let (mut acc, mut bits) = (0u32, 0);
+        let mut bytes = data.iter();
+        loop {
+            while bits < size {
+                acc |= (*bytes.next().expect("no end code") as u32) << bits;
+                bits += 8;
+            }
+            let code = (acc & ((1 << size) - 1)) as usize;
+            acc >>= size;
+            bits -= size;
+
+            if code == clear {
+                table = reset();
+                size = min_size as u32 + 1;
+                prev = None;
+                continue;
+            }
+            if code == end {
+                return out;
+            }
+            let entry = match (table.get(code), &prev) {
+                (Some(e), _) => e.clone(),
+                // The code being defined, its prefix followed by its first index
+                (None, Some(p)) if code == table.len() => [&p[..], &p[..1]].concat(),
+                _ => panic!("code {} out of the table", code),
+            };
+            out.extend_from_slice(&entry);
+            if let Some(p) = prev {
+                if table.len() < 4096 {
+                    table.push([&p[..], &entry[..1]].concat());
+                }
+            }
+            if table.len() == 1 << size && size < 12 {
+                size += 1;
+            }
+            prev = Some(entry);
+        }
+    }
+
+    /// Sub-blocks until the terminator, joined
+    fn sub_blocks(data: &[u8], pos: &mut usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let len = data[*pos] as usize;
+            *pos += 1;
+            if len == 0 {
+                return out;
+            }
+            out.extend_from_slice(&data[*pos..*pos + len]);
+            *pos += len;
+        }
+    }
+
+    struct Decoded {
+        width: u16,
+        height: u16,
+        looping: bool,
+        /// Delay in hundredths of a second, disposal method and pixels of each frame
+        frames: Vec<(u16, u8, RgbaImage)>,
+    }
+
+    fn decode(data: &[u8]) -> Decoded {
+        assert_eq!(&data[..6], b"GIF89a");
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let mut gif = Decoded {
+            width: u16_at(6),
+            height: u16_at(8),
+            looping: false,
+            frames: Vec::new(),
+        };
+        assert_eq!(data[10] & 0x80, 0, "no global color table");
+
+        let mut pos = 13;
+        let mut control = None;
+        loop {
+            match data[pos] {
+                0x21 if data[pos + 1] == 0xFF => {
+                    assert_eq!(&data[pos + 2..pos + 14], b"\x0BNETSCAPE2.0");
+                    pos += 14;
+                    assert_eq!(sub_blocks(data, &mut pos), [1, 0, 0]);
+                    gif.looping = true;
+                },
+                0x21 if data[pos + 1] == 0xF9 => {
+                    assert_eq!(data[pos + 2], 4);
+                    let flags = data[pos + 3];
+                    let transparent = Some(data[pos + 6]).filter(|_| flags & 1 != 0);
+                    control = Some((u16_at(pos + 4), flags >> 2 & 7, transparent));
+                    assert_eq!(data[pos + 7], 0);
+                    pos += 8;
+                },
+                0x2C => {
+                    let (delay, disposal, transparent) = control.take().expect("no control");
+                    let (w, h) = (u16_at(pos + 5), u16_at(pos + 7));
+                    let flags = data[pos + 9];
+                    assert_eq!(flags & 0xC0, 0x80, "local color table, not interlaced");
+                    let entries = 2 << (flags & 7);
+                    let table = &data[pos + 10..pos + 10 + entries * 3];
+                    pos += 10 + entries * 3;
+                    let min_size = data[pos];
+                    pos += 1;
+                    let indices = unlzw(&sub_blocks(data, &mut pos), min_size);
+                    assert_eq!(indices.len(), w as usize * h as usize);
+
+                    let image = RgbaImage::from_fn(w as u32, h as u32, |x, y| {
+                        let i = indices[(y * w as u32 + x) as usize];
+                        let c = &table[i as usize * 3..i as usize * 3 + 3];
+                        let a = if Some(i) == transparent { 0 } else { 255 };
+                        Rgba([c[0], c[1], c[2], a])
+                    });
+                    gif.frames.push((delay, disposal, image));
+                },
+                0x3B => {
+                    assert_eq!(pos + 1, data.len());
+                    return gif;
+                },
+                b => panic!("unexpected block 0x{:02x}", b),
+            }
+        }
+    }
+
+    /// What a frame decodes to, fully transparent pixels being all zeros
+    fn same_pixels(a: &RgbaImage, b: &RgbaImage) -> bool {
+        let clear = |p: &Rgba<u8>| if p[3] == 0 { Rgba([0, 0, 0, 0]) } else { *p };
+        a.dimensions() == b.dimensions() && a.pixels().zip(b.pixels()).all(|(p, q)| {
+            clear(p) == clear(q)
+        })
+    }
+
+    fn encode(frames: &[(RgbaImage, u32)]) -> Decoded {
+        let frames: Vec<(Frame, u32)> = frames.iter().map(|(f, d)| (encode_frame(f), *d))
+            .collect();
+        let mut out = Vec::new();
+        write_gif(&mut out, &frames).unwrap();
+        decode(&out)
+    }
+
+    #[test]
+    fn lzw_round_trips() {
+        // Enough codes to fill the table several times, at every starting code size
+        let mut state = 0x1357_9BDFu32;
+        let noise: Vec<u8> = (0..30_000).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect();
+        for min_size in 2..=8 {
+            let indices: Vec<u8> = noise.iter().map(|&i| (i as u32 % (1 << min_size)) as u8)
+                .collect();
+            assert_eq!(unlzw(&lzw(&indices, min_size), min_size), indices);
+            // Long runs grow codes quickly
+            let runs: Vec<u8> = indices.iter().flat_map(|&i| vec![i; i as usize % 37]).collect();
+            assert_eq!(unlzw(&lzw(&runs, min_size), min_size), runs);
+        }
+        assert!(unlzw(&lzw(&[], 2), 2).is_empty());
+        assert_eq!(unlzw(&lzw(&[3], 2), 2), [3]);
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        // Few enough colors for the palette to hold them exactly
+        let first = RgbaImage::from_fn(50, 30, |x, y| {
+            Rgba([(x / 10 * 50) as u8, (y / 10 * 100) as u8, ((x + y) % 2 * 255) as u8, 255])
+        });
+        let second = RgbaImage::from_fn(50, 30, |x, y| {
+            if x < y { Rgba([0, 0, 0, 0]) } else { Rgba([200, 10, 10, 255]) }
+        });
+        let gif = encode(&[(first.clone(), 100), (second.clone(), 33)]);
+
+        assert_eq!((gif.width, gif.height), (50, 30));
+        assert!(gif.looping);
+        assert_eq!(gif.frames.len(), 2);
+        assert_eq!((gif.frames[0].0, gif.frames[0].1), (10, 0));
+        assert!(same_pixels(&gif.frames[0].2, &first));
+        // Transparent frames clear what was behind them
+        assert_eq!((gif.frames[1].0, gif.frames[1].1), (3, 2));
+        assert!(same_pixels(&gif.frames[1].2, &second));
+    }
+
+    #[test]
+    fn single_frames_do_not_loop() {
+        let image = RgbaImage::from_pixel(300, 200, Rgba([1, 2, 3, 255]));
+        let gif = encode(&[(image.clone(), 0)]);
+        assert!(!gif.looping);
+        assert!(same_pixels(&gif.frames[0].2, &image));
+    }
+}
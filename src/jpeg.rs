@@ -0,0 +1,620 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Baseline JPEG encoder, as the image crate's cannot be built without its JPEG decoder
+
+use std::f32::consts::PI;
+use std::io;
+
+use image::Rgba;
+use image::RgbaImage;
+
+use crate::util;
+
+// Tables from annex K of the standard, quantization tables in natural order
+const LUMA_QUANT: [u8; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+const CHROMA_QUANT: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+const LUMA_DC_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const CHROMA_DC_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+const LUMA_AC_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D];
+const LUMA_AC_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61,
+    0x07, 0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52,
+    0xD1, 0xF0, 0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25,
+    0x26, 0x27, 0x28, 0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45,
+    0x46, 0x47, 0x48, 0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64,
+    0x65, 0x66, 0x67, 0x68, 0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83,
+    0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99,
+    0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6,
+    0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3,
+    0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8,
+    0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA,
+];
+
+const CHROMA_AC_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const CHROMA_AC_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61,
+    0x71, 0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33,
+    0x52, 0xF0, 0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18,
+    0x19, 0x1A, 0x26, 0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44,
+    0x45, 0x46, 0x47, 0x48, 0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63,
+    0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A,
+    0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97,
+    0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4,
+    0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA,
+    0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7,
+    0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA,
+];
+
+/// Natural order index of each coefficient, in zigzag order
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10,
+    17, 24, 32, 25, 18, 11, 4, 5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13, 6, 7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Code and length in bits for each symbol
+type Codes = [(u16, u8); 256];
+
+fn huffman_codes(bits: &[u8; 16], values: &[u8]) -> Codes {
+    let mut codes = [(0, 0); 256];
+    let mut code = 0;
+    let mut values = values.iter();
+    for (len, &n) in bits.iter().enumerate() {
+        for &v in values.by_ref().take(n as usize) {
+            codes[v as usize] = (code, len as u8 + 1);
+            code += 1;
+        }
+        code <<= 1;
+    }
+    codes
+}
+
+/// IJG quality scaling of a quantization table
+fn scale_quant(table: &[u8; 64], quality: u8) -> [u8; 64] {
+    let q = quality.clamp(1, 100) as u32;
+    let scale = if q < 50 { 5000 / q } else { 200 - 2 * q };
+    let mut out = [0; 64];
+    for (o, &t) in out.iter_mut().zip(table.iter()) {
+        *o = ((t as u32 * scale + 50) / 100).clamp(1, 255) as u8;
+    }
+    out
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    n: u8,
+}
+
+impl BitWriter {
+    fn put(&mut self, bits: u16, len: u8) {
+        self.acc = (self.acc << len) | bits as u32;
+        self.n += len;
+        while self.n >= 8 {
+            let byte = (self.acc >> (self.n - 8)) as u8;
+            self.out.push(byte);
+            // Stuff a zero so that data cannot be mistaken for a marker
+            if byte == 0xFF {
+                self.out.push(0);
+            }
+            self.n -= 8;
+        }
+        self.acc &= (1 << self.n) - 1;
+    }
+
+    /// Pad the last byte with ones
+    fn flush(&mut self) {
+        if self.n > 0 {
+            self.put((1 << (8 - self.n)) - 1, 8 - self.n);
+        }
+    }
+
+    /// Magnitude category of a coefficient, followed by its bits
+    fn put_value(&mut self, codes: &Codes, run: u8, v: i32) {
+        let size = 32 - v.unsigned_abs().leading_zeros() as u8;
+        let (code, len) = codes[(run << 4 | size) as usize];
+        self.put(code, len);
+        if size > 0 {
+            let bits = if v < 0 { v - 1 } else { v };
+            self.put((bits & ((1 << size) - 1)) as u16, size);
+        }
+    }
+}
+
+struct Component {
+    quant: [u8; 64],
+    dc: Codes,
+    ac: Codes,
+    prev_dc: i32,
+}
+
+impl Component {
+    /// Transform, quantize and encode one 8x8 block of level-shifted samples
+    fn encode_block(&mut self, w: &mut BitWriter, block: &[f32; 64], cos: &[[f32; 8]; 8]) {
+        // Separable DCT, columns then rows
+        let mut cols = [0.0; 64];
+        for v in 0..8 {
+            for x in 0..8 {
+                cols[v * 8 + x] = (0..8).map(|y| block[y * 8 + x] * cos[v][y]).sum();
+            }
+        }
+
+        let mut coefs = [0; 64];
+        for (i, &n) in ZIGZAG.iter().enumerate() {
+            let (u, v) = (n % 8, n / 8);
+            let sum: f32 = (0..8).map(|x| cols[v * 8 + x] * cos[u][x]).sum();
+            coefs[i] = (sum / self.quant[n] as f32).round() as i32;
+        }
+
+        w.put_value(&self.dc, 0, coefs[0] - self.prev_dc);
+        self.prev_dc = coefs[0];
+
+        let mut run = 0;
+        for &c in coefs[1..].iter() {
+            if c == 0 {
+                run += 1;
+                continue;
+            }
+            while run > 15 {
+                let (code, len) = self.ac[0xF0];
+                w.put(code, len);
+                run -= 16;
+            }
+            w.put_value(&self.ac, run, c);
+            run = 0;
+        }
+        if run > 0 {
+            let (code, len) = self.ac[0x00];
+            w.put(code, len);
+        }
+    }
+}
+
+fn segment(out: &mut Vec<u8>, marker: u8, data: &[u8]) {
+    out.extend_from_slice(&[0xFF, marker]);
+    out.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+/// Capture details for the EXIF segment
+pub struct Exif {
+    /// Seconds since the epoch
+    pub time: u64,
+    pub software: String,
+    pub description: Option<String>,
+}
+
+const ASCII: u16 = 2;
+const LONG: u16 = 4;
+
+/// A little-endian TIFF directory placed at `offset`, values too long for their entry follow it
+fn ifd(offset: u32, entries: &[(u16, u16, Vec<u8>)]) -> Vec<u8> {
+    let data_offset = offset + 2 + entries.len() as u32 * 12 + 4;
+    let mut out = (entries.len() as u16).to_le_bytes().to_vec();
+    let mut data = Vec::new();
+    for (tag, kind, value) in entries {
+        let count = if *kind == LONG { value.len() / 4 } else { value.len() };
+        out.extend_from_slice(&tag.to_le_bytes());
+        out.extend_from_slice(&kind.to_le_bytes());
+        out.extend_from_slice(&(count as u32).to_le_bytes());
+        if value.len() <= 4 {
+            let mut inline = value.clone();
+            inline.resize(4, 0);
+            out.extend_from_slice(&inline);
+        } else {
+            out.extend_from_slice(&(data_offset + data.len() as u32).to_le_bytes());
+            data.extend_from_slice(value);
+            // Values start on word boundaries
+            if value.len() % 2 == 1 {
+                data.push(0);
+            }
+        }
+    }
+    // No next directory
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend(data);
+    out
+}
+
+impl Exif {
+    /// Contents of the APP1 segment
+    fn to_app1(&self) -> Vec<u8> {
+        let ascii = |s: &str| [s.as_bytes(), b"\0"].concat();
+        // EXIF dates have no time zone, the offset tag records that they are UTC
+        let date = util::iso8601(self.time).replace('-', ":").replace('T', " ").replace('Z', "");
+
+        let ifd0 = |exif_offset: u32| {
+            let mut entries = Vec::new();
+            if let Some(d) = &self.description {
+                entries.push((0x010E, ASCII, ascii(d)));
+            }
+            entries.push((0x0131, ASCII, ascii(&self.software)));
+            entries.push((0x0132, ASCII, ascii(&date)));
+            entries.push((0x8769, LONG, exif_offset.to_le_bytes().to_vec()));
+            ifd(8, &entries)
+        };
+        let exif_offset = 8 + ifd0(0).len() as u32;
+        let exif_ifd = ifd(exif_offset, &[(0x9003, ASCII, ascii(&date)),
+                                          (0x9011, ASCII, ascii("+00:00"))]);
+
+        [&b"Exif\0\0II*\0"[..], &8u32.to_le_bytes(), &ifd0(exif_offset), &exif_ifd].concat()
+    }
+}
+
+/// Encode `image` as a baseline JPEG without chroma subsampling, with transparent areas
+/// blended onto `background`
+pub fn write_jpeg(w: &mut dyn io::Write, image: &RgbaImage, quality: u8, background: Rgba<u8>,
+                  exif: Option<&Exif>, icc: Option<&[u8]>) -> io::Result<()> {
+    let (width, height) = image.dimensions();
+    if width > 0xFFFF || height > 0xFFFF {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "JPEG images are limited to 65535x65535 pixels"));
+    }
+
+    let luma_quant = scale_quant(&LUMA_QUANT, quality);
+    let chroma_quant = scale_quant(&CHROMA_QUANT, quality);
+
+    let mut out = vec![0xFF, 0xD8];
+    segment(&mut out, 0xE0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+    if let Some(e) = exif {
+        segment(&mut out, 0xE1, &e.to_app1());
+    }
+    if let Some(icc) = icc {
+        // Split across APP2 segments, numbered from 1
+        let chunks: Vec<&[u8]> = icc.chunks(65519).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let data = [&b"ICC_PROFILE\0"[..], &[i as u8 + 1, chunks.len() as u8], chunk].concat();
+            segment(&mut out, 0xE2, &data);
+        }
+    }
+
+    let mut dqt = vec![0];
+    dqt.extend(ZIGZAG.iter().map(|&n| luma_quant[n]));
+    dqt.push(1);
+    dqt.extend(ZIGZAG.iter().map(|&n| chroma_quant[n]));
+    segment(&mut out, 0xDB, &dqt);
+
+    let mut sof = vec![8];
+    sof.extend_from_slice(&(height as u16).to_be_bytes());
+    sof.extend_from_slice(&(width as u16).to_be_bytes());
+    sof.extend_from_slice(&[3, 1, 0x11, 0, 2, 0x11, 1, 3, 0x11, 1]);
+    segment(&mut out, 0xC0, &sof);
+
+    let mut dht = Vec::new();
+    for &(class, bits, values) in [(0x00, &LUMA_DC_BITS, &DC_VALUES[..]),
+                                   (0x10, &LUMA_AC_BITS, &LUMA_AC_VALUES[..]),
+                                   (0x01, &CHROMA_DC_BITS, &DC_VALUES[..]),
+                                   (0x11, &CHROMA_AC_BITS, &CHROMA_AC_VALUES[..])].iter() {
+        dht.push(class);
+        dht.extend_from_slice(bits);
+        dht.extend_from_slice(values);
+    }
+    segment(&mut out, 0xC4, &dht);
+
+    segment(&mut out, 0xDA, &[3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0]);
+
+    let mut components = [
+        Component {
+            quant: luma_quant,
+            dc: huffman_codes(&LUMA_DC_BITS, &DC_VALUES),
+            ac: huffman_codes(&LUMA_AC_BITS, &LUMA_AC_VALUES),
+            prev_dc: 0,
+        },
+        Component {
+            quant: chroma_quant,
+            dc: huffman_codes(&CHROMA_DC_BITS, &DC_VALUES),
+            ac: huffman_codes(&CHROMA_AC_BITS, &CHROMA_AC_VALUES),
+            prev_dc: 0,
+        },
+        Component {
+            quant: chroma_quant,
+            dc: huffman_codes(&CHROMA_DC_BITS, &DC_VALUES),
+            ac: huffman_codes(&CHROMA_AC_BITS, &CHROMA_AC_VALUES),
+            prev_dc: 0,
+        },
+    ];
+
+    // DCT basis, with the normalization factors folded in
+    let mut cos = [[0.0; 8]; 8];
+    for (u, row) in cos.iter_mut().enumerate() {
+        let c = if u == 0 { 0.5 / 2f32.sqrt() } else { 0.5 };
+        for (x, v) in row.iter_mut().enumerate() {
+            *v = c * ((2 * x + 1) as f32 * u as f32 * PI / 16.0).cos();
+        }
+    }
+
+    let mut bits = BitWriter {
+        out,
+        acc: 0,
+        n: 0,
+    };
+    for by in (0..height).step_by(8) {
+        for bx in (0..width).step_by(8) {
+            let samples: [[f32; 3]; 64] = std::array::from_fn(|i| {
+                // Repeat the last row and column past the edges
+                let x = (bx + i as u32 % 8).min(width - 1);
+                let y = (by + i as u32 / 8).min(height - 1);
+                let p = image.get_pixel(x, y);
+                let a = p[3] as f32 / 255.0;
+                let [r, g, b] = [0, 1, 2].map(|c| {
+                    p[c] as f32 * a + background[c] as f32 * (1.0 - a)
+                });
+
+                [0.299 * r + 0.587 * g + 0.114 * b - 128.0,
+                 -0.168736 * r - 0.331264 * g + 0.5 * b,
+                 0.5 * r - 0.418688 * g - 0.081312 * b]
+            });
+            for (i, c) in components.iter_mut().enumerate() {
+                c.encode_block(&mut bits, &std::array::from_fn(|n| samples[n][i]), &cos);
+            }
+        }
+    }
+    bits.flush();
+
+    let mut out = bits.out;
+    out.extend_from_slice(&[0xFF, 0xD9]);
+    w.write_all(&out)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    /// Segments of a JPEG file up to the scan, and the entropy-coded data after it
+    fn split(data: &[u8]) -> (Vec<(u8, &[u8])>, &[u8]) {
+        assert_eq!(&data[..2], &[0xFF, 0xD8]);
+        let mut segments = Vec::new();
+        let mut pos = 2;
+        loop {
+            assert_eq!(data[pos], 0xFF);
+            let marker = data[pos + 1];
+            let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+            segments.push((marker, &data[pos + 4..pos + 2 + len]));
+            pos += 2 + len;
+            if marker == 0xDA {
+                assert_eq!(&data[data.len() - 2..], &[0xFF, 0xD9]);
+                return (segments, &data[pos..data.len() - 2]);
+            }
+        }
+    }
+
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+        acc: u32,
+        n: u8,
+    }
+
+    impl BitReader<'_> {
+        fn bit(&mut self) -> u16 {
+            if self.n == 0 {
+                self.acc = self.data[self.pos] as u32;
+                // Skip the stuffed zero
+                self.pos += if self.acc == 0xFF { 2 } else { 1 };
+                self.n = 8;
+            }
+            self.n -= 1;
+            ((self.acc >> self.n) & 1) as u16
+        }
+
+        fn bits(&mut self, len: u8) -> u16 {
+            (0..len).fold(0, |v, _| v << 1 | self.bit())
+        }
+
+        fn symbol(&mut self, codes: &Codes) -> u8 {
+            let mut code = 0;
+            for len in 1..=16 {
+                code = code << 1 | self.bit();
+                if let Some(s) = codes.iter().position(|&c| c == (code, len)) {
+                    return s as u8;
+                }
+            }
+            panic!("invalid Huffman code");
+        }
+
+        fn value(&mut self, size: u8) -> i32 {
+            if size == 0 {
+                return 0;
+            }
+            let v = self.bits(size) as i32;
+            if v < 1 << (size - 1) { v - (1 << size) + 1 } else { v }
+        }
+    }
+
+    /// Decoder for what write_jpeg produces, opaque RGB without subsampling
+    fn decode(data: &[u8]) -> RgbaImage {
+        let (segments, scan) = split(data);
+        let find = |marker| segments.iter().find(|s| s.0 == marker).unwrap().1;
+
+        let dqt = find(0xDB);
+        let mut quant = [[0u16; 64]; 2];
+        for (t, table) in quant.iter_mut().enumerate() {
+            assert_eq!(dqt[t * 65], t as u8);
+            for (i, &n) in ZIGZAG.iter().enumerate() {
+                table[n] = dqt[t * 65 + 1 + i] as u16;
+            }
+        }
+
+        let mut dht = find(0xC4);
+        let mut tables = Vec::new();
+        while !dht.is_empty() {
+            let bits: [u8; 16] = dht[1..17].try_into().unwrap();
+            let n = bits.iter().map(|&b| b as usize).sum::<usize>();
+            tables.push((dht[0], huffman_codes(&bits, &dht[17..17 + n])));
+            dht = &dht[17 + n..];
+        }
+        let table = |class| tables.iter().find(|t| t.0 == class).unwrap().1;
+
+        let sof = find(0xC0);
+        let height = u16::from_be_bytes([sof[1], sof[2]]) as u32;
+        let width = u16::from_be_bytes([sof[3], sof[4]]) as u32;
+        assert_eq!(sof[5], 3);
+
+        let mut r = BitReader {
+            data: scan,
+            pos: 0,
+            acc: 0,
+            n: 0,
+        };
+        let mut prev_dc = [0; 3];
+        let mut image = RgbaImage::new(width, height);
+        for by in (0..height).step_by(8) {
+            for bx in (0..width).step_by(8) {
+                let mut planes = [[0.0f32; 64]; 3];
+                for (c, plane) in planes.iter_mut().enumerate() {
+                    let id = (c > 0) as u8;
+                    let mut coefs = [0i32; 64];
+                    let size = r.symbol(&table(id));
+                    prev_dc[c] += r.value(size);
+                    coefs[0] = prev_dc[c];
+                    let mut i = 1;
+                    while i < 64 {
+                        let rs = r.symbol(&table(0x10 | id));
+                        if rs == 0 {
+                            break;
+                        }
+                        i += (rs >> 4) as usize;
+                        if rs & 0xF != 0 {
+                            coefs[ZIGZAG[i]] = r.value(rs & 0xF);
+                        }
+                        i += 1;
+                    }
+                    for (y, x) in (0..8).flat_map(|y| (0..8).map(move |x| (y, x))) {
+                        let mut sum = 0.0;
+                        for (v, u) in (0..8).flat_map(|v| (0..8).map(move |u| (v, u))) {
+                            let cu = if u == 0 { 0.5 / 2f32.sqrt() } else { 0.5 };
+                            let cv = if v == 0 { 0.5 / 2f32.sqrt() } else { 0.5 };
+                            sum += cu * cv * (coefs[v * 8 + u] * quant[id as usize][v * 8 + u]
+                                              as i32) as f32
+                                   * ((2 * x + 1) as f32 * u as f32 * PI / 16.0).cos()
+                                   * ((2 * y + 1) as f32 * v as f32 * PI / 16.0).cos();
+                        }
+                        plane[y * 8 + x] = sum;
+                    }
+                }
+                let [luma, blue, red] = planes;
+                for (i, ((l, cb), cr)) in luma.iter().zip(&blue).zip(&red).enumerate() {
+                    let (x, y) = (bx + i as u32 % 8, by + i as u32 / 8);
+                    if x >= width || y >= height {
+                        continue;
+                    }
+                    let l = l + 128.0;
+                    let rgb = [l + 1.402 * cr, l - 0.344136 * cb - 0.714136 * cr, l + 1.772 * cb];
+                    let p = rgb.map(|v| v.round().clamp(0.0, 255.0) as u8);
+                    image.put_pixel(x, y, Rgba([p[0], p[1], p[2], 255]));
+                }
+            }
+        }
+        image
+    }
+
+    fn encode(image: &RgbaImage, quality: u8, exif: Option<&Exif>, icc: Option<&[u8]>)
+              -> Vec<u8> {
+        let mut out = Vec::new();
+        write_jpeg(&mut out, image, quality, Rgba([255, 255, 255, 255]), exif, icc).unwrap();
+        out
+    }
+
+    fn max_error(a: &RgbaImage, b: &RgbaImage) -> u8 {
+        assert_eq!(a.dimensions(), b.dimensions());
+        a.pixels().zip(b.pixels())
+            .flat_map(|(p, q)| (0..4).map(move |c| (p[c] as i32 - q[c] as i32).unsigned_abs()))
+            .max().unwrap_or(0) as u8
+    }
+
+    #[test]
+    fn round_trip_is_close() {
+        // Not a multiple of the block size, to go through the repeated edges
+        let image = RgbaImage::from_fn(37, 21, |x, y| {
+            Rgba([(x * 7) as u8, (y * 12) as u8, ((x + y) * 4) as u8, 255])
+        });
+        assert!(max_error(&decode(&encode(&image, 95, None, None)), &image) <= 12);
+        // Lower quality loses more but still decodes to the same picture
+        assert!(max_error(&decode(&encode(&image, 30, None, None)), &image) <= 48);
+    }
+
+    #[test]
+    fn flat_colors_survive() {
+        for &color in &[[0, 0, 0], [255, 255, 255], [200, 30, 90]] {
+            let image = RgbaImage::from_pixel(16, 16, Rgba([color[0], color[1], color[2], 255]));
+            assert!(max_error(&decode(&encode(&image, 90, None, None)), &image) <= 3);
+        }
+    }
+
+    #[test]
+    fn transparency_is_blended_onto_the_background() {
+        let image = RgbaImage::from_pixel(8, 8, Rgba([0, 0, 0, 0]));
+        let white = RgbaImage::from_pixel(8, 8, Rgba([255, 255, 255, 255]));
+        assert!(max_error(&decode(&encode(&image, 90, None, None)), &white) <= 2);
+    }
+
+    #[test]
+    fn metadata_segments() {
+        let exif = Exif {
+            time: 0,
+            software: "shotgun".to_string(),
+            description: Some("a window".to_string()),
+        };
+        let icc = vec![7; 70000];
+        let image = RgbaImage::from_pixel(8, 8, Rgba([10, 20, 30, 255]));
+        let data = encode(&image, 90, Some(&exif), Some(&icc));
+        let (segments, _) = split(&data);
+
+        let app1 = segments.iter().find(|s| s.0 == 0xE1).unwrap().1;
+        assert!(app1.starts_with(b"Exif\0\0II*\0"));
+        let contains = |s: &[u8]| app1.windows(s.len()).any(|w| w == s);
+        assert!(contains(b"1970:01:01 00:00:00\0"));
+        assert!(contains(b"a window\0"));
+
+        // Numbered chunks that add up to the profile
+        let chunks: Vec<&[u8]> = segments.iter().filter(|s| s.0 == 0xE2).map(|s| s.1).collect();
+        assert_eq!(chunks.len(), 2);
+        for (i, c) in chunks.iter().enumerate() {
+            assert!(c.starts_with(b"ICC_PROFILE\0"));
+            assert_eq!(&c[12..14], &[i as u8 + 1, 2]);
+        }
+        assert_eq!(chunks.iter().map(|c| c.len() - 14).sum::<usize>(), icc.len());
+
+        assert!(max_error(&decode(&data), &image) <= 3);
+    }
+
+    #[test]
+    fn oversized_images_are_refused() {
+        let image = RgbaImage::new(0x10000, 1);
+        let mut out = Vec::new();
+        let background = Rgba([0, 0, 0, 255]);
+        assert!(write_jpeg(&mut out, &image, 90, background, None, None).is_err());
+    }
+}
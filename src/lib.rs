@@ -0,0 +1,342 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The capture path of shotgun: reading windows and areas of the screen back from X, or
+//! wlr-screencopy under XWayland, into RGBA image buffers
+//!
+//! The display is also the backend that reads the pixels, unless they come through
+//! `wayland::Connection`
+//!
+//! ```no_run
+//! let display = shotgun::xwrap::Display::open(None, None).expect("Failed to open display");
+//! let root = display.get_default_root();
+//! let (screen, _) = shotgun::capture_rect(&display, &display, display.get_window_rect(root),
+//!                                         false)
+//!     .expect("Failed to capture the screen");
+//! for monitor in shotgun::monitors(&display) {
+//!     println!("{} {:?}", monitor.name, monitor.rect);
+//! }
+//! # drop(screen);
+//! ```
+
+use image::GenericImage;
+use image::GenericImageView;
+use image::imageops;
+use image::ImageBuffer;
+use image::Pixel;
+use image::Primitive;
+use image::RgbaImage;
+use image::Rgba;
+use x11::xlib;
+
+pub mod backend;
+pub mod draw;
+pub mod palette;
+pub mod pngenc;
+pub mod util;
+pub mod wayland;
+pub mod xext;
+pub mod xwrap;
+
+use crate::backend::Backend;
+use crate::xwrap::Display;
+
+/// The part of `area` (relative to a window at `window_rect`) that lies on a root window of
+/// `root_size`, relative to the window and then to the root
+fn visible_area(window_rect: util::Rect, root_size: (i32, i32), area: util::Rect)
+                -> Option<(util::Rect, util::Rect)> {
+    let root = util::Rect { x: 0, y: 0, w: root_size.0, h: root_size.1 };
+    let origin = area.translate(window_rect.x, window_rect.y)
+        .intersection(window_rect)?.intersection(root)?;
+    Some((origin.translate(-window_rect.x, -window_rect.y), origin))
+}
+
+/// Capture `area` of `window` through `backend`, relative to the window and clipped to the
+/// part of it on the screen, which is given along with the image relative to the root
+/// With `deep`, also give the image at 16 bits per channel if the source has more than 8
+pub fn capture_area(display: &Display, backend: &dyn Backend, window: xlib::Window,
+                    area: util::Rect, deep: bool)
+                    -> Result<(RgbaImage, Option<xwrap::Rgba16Image>, util::Rect),
+                              backend::Error> {
+    let root = display.get_window_rect(display.get_default_root());
+    let (area, origin) = visible_area(display.get_window_rect(window), (root.w, root.h), area)
+        .ok_or(backend::Error::Geometry)?;
+    let (image, deep) = backend.capture(window, area, origin, deep)?;
+    Ok((image, deep, origin))
+}
+
+/// Capture `window` as it is drawn, the parts that lie off the screen left out
+pub fn capture_window(display: &Display, backend: &dyn Backend, window: xlib::Window,
+                      deep: bool) -> backend::Capture {
+    let rect = display.get_window_rect(window);
+    let area = util::Rect { x: 0, y: 0, w: rect.w, h: rect.h };
+    capture_area(display, backend, window, area, deep).map(|(image, deep, _)| (image, deep))
+}
+
+/// Leave the parts of a capture of `area` that none of `monitors` show transparent
+fn mask_capture(capture: (RgbaImage, Option<xwrap::Rgba16Image>), area: util::Rect,
+                monitors: &[util::Rect]) -> (RgbaImage, Option<xwrap::Rgba16Image>) {
+    let screens: Vec<util::Rect> = monitors.iter().filter_map(|m| m.intersection(area)).collect();
+    if screens.len() < 2 {
+        return capture;
+    }
+    let (mut image, mut deep) = capture;
+    mask_screens_in_place(&mut image, area, &screens, Some(Rgba::from_channels(0, 0, 0, 0)));
+    if let Some(d) = &mut deep {
+        mask_screens16_in_place(d, area, &screens);
+    }
+    (image, deep)
+}
+
+/// Capture `rect` of the screen, in root window coordinates and clipped to it
+/// Parts that no monitor shows are left transparent
+pub fn capture_rect(display: &Display, backend: &dyn Backend, rect: util::Rect, deep: bool)
+                    -> backend::Capture {
+    let (image, deep, area) = capture_area(display, backend, display.get_default_root(), rect,
+                                           deep)?;
+    let monitors: Vec<util::Rect> = monitors(display).iter().map(|m| m.rect).collect();
+    Ok(mask_capture((image, deep), area, &monitors))
+}
+
+/// The monitors of the default screen, empty without RandR
+pub fn monitors(display: &Display) -> Vec<xwrap::Output> {
+    display.get_outputs(display.get_default_root()).unwrap_or_default()
+}
+
+/// Edges where one screen ends and another begins, across and down
+fn seams(screens: &[util::Rect]) -> (Vec<i32>, Vec<i32>) {
+    let seams = |start: fn(&util::Rect) -> i32, len: fn(&util::Rect) -> i32| {
+        let mut seams: Vec<i32> = screens.iter().map(start)
+            .filter(|&s| screens.iter().any(|o| start(o) + len(o) == s))
+            .collect();
+        seams.sort_unstable();
+        seams.dedup();
+        seams
+    };
+    (seams(|r| r.x, |r| r.w), seams(|r| r.y, |r| r.h))
+}
+
+/// Size of `area` once the seams between screens are spread apart by `gap`
+fn masked_size(area: util::Rect, seams: &(Vec<i32>, Vec<i32>), gap: u32) -> (u32, u32) {
+    (area.w as u32 + seams.0.len() as u32 * gap, area.h as u32 + seams.1.len() as u32 * gap)
+}
+
+/// Copy the parts of `image` visible on `screens` to `masked`, spread apart at the seams
+fn copy_screens<T: Primitive + 'static>(image: &ImageBuffer<Rgba<T>, Vec<T>>,
+                                        masked: &mut ImageBuffer<Rgba<T>, Vec<T>>,
+                                        area: util::Rect, screens: &[util::Rect],
+                                        seams: &(Vec<i32>, Vec<i32>), gap: u32) {
+    let shift = |seams: &[i32], pos: i32| seams.iter().filter(|&&s| s <= pos).count() as u32 * gap;
+    for screen in screens {
+        // Subimage is relative to the captured area
+        let sub = util::Rect {
+            x: screen.x - area.x,
+            y: screen.y - area.y,
+            w: screen.w,
+            h: screen.h,
+        };
+
+        let sub_src = image.view(sub.x as u32, sub.y as u32, sub.w as u32, sub.h as u32);
+        masked.copy_from(&sub_src, sub.x as u32 + shift(&seams.0, screen.x),
+                         sub.y as u32 + shift(&seams.1, screen.y))
+            .expect("Failed to copy sub-image");
+    }
+}
+
+/// Copy the parts of `image` (covering `area`) that are visible on `screens` to a canvas of
+/// `fill`, or a checkerboard without one, optionally spreading adjacent screens apart with a gap
+/// of the given width and color
+pub fn mask_screens(image: &RgbaImage, area: util::Rect, screens: &[util::Rect],
+                    bezel: Option<(u32, Rgba<u8>)>, fill: Option<Rgba<u8>>) -> RgbaImage {
+    let (gap, color) = bezel.unwrap_or((0, Rgba::from_channels(0, 0, 0, 0)));
+    let seams = seams(screens);
+    let (w, h) = masked_size(area, &seams, gap);
+    let mut masked = match fill {
+        Some(c) => RgbaImage::from_pixel(w, h, c),
+        None => draw::checkerboard(w, h),
+    };
+    let (w, h) = (masked.width() as i32, masked.height() as i32);
+    for (i, &s) in seams.0.iter().enumerate() {
+        let x = s - area.x + (i as u32 * gap) as i32;
+        draw::fill_rect(&mut masked, util::Rect { x, y: 0, w: gap as i32, h }, color);
+    }
+    for (i, &s) in seams.1.iter().enumerate() {
+        let y = s - area.y + (i as u32 * gap) as i32;
+        draw::fill_rect(&mut masked, util::Rect { x: 0, y, w, h: gap as i32 }, color);
+    }
+
+    copy_screens(image, &mut masked, area, screens, &seams, gap);
+    masked
+}
+
+/// Parts of `area` that none of `screens` cover, relative to `area`
+fn uncovered(area: util::Rect, screens: &[util::Rect]) -> Vec<util::Rect> {
+    let mut parts = vec![area];
+    for &s in screens {
+        parts = parts.iter().flat_map(|p| p.subtract(s)).collect();
+    }
+    parts.iter().map(|p| p.translate(-area.x, -area.y)).collect()
+}
+
+/// `mask_screens` without a bezel, filling the parts of `image` outside of `screens` in place
+/// rather than copying the rest onto a new canvas
+pub fn mask_screens_in_place(image: &mut RgbaImage, area: util::Rect, screens: &[util::Rect],
+                             fill: Option<Rgba<u8>>) {
+    for r in uncovered(area, screens) {
+        match fill {
+            Some(c) => draw::fill_rect(image, r, c),
+            None => draw::fill_checkerboard(image, r),
+        }
+    }
+}
+
+/// `mask_screens_in_place` for a 16 bits per channel capture, the masked areas left transparent
+pub fn mask_screens16_in_place(image: &mut xwrap::Rgba16Image, area: util::Rect,
+                               screens: &[util::Rect]) {
+    for r in uncovered(area, screens) {
+        for y in r.y..r.y + r.h {
+            for x in r.x..r.x + r.w {
+                image.put_pixel(x as u32, y as u32, Rgba([0; 4]));
+            }
+        }
+    }
+}
+
+/// `mask_screens` for a 16 bits per channel capture, the masked areas and bezels left
+/// transparent for `draw::widen` to take from the 8 bit one
+pub fn mask_screens16(image: &xwrap::Rgba16Image, area: util::Rect, screens: &[util::Rect],
+                      gap: u32) -> xwrap::Rgba16Image {
+    let seams = seams(screens);
+    let (w, h) = masked_size(area, &seams, gap);
+    let mut masked = xwrap::Rgba16Image::new(w, h);
+    copy_screens(image, &mut masked, area, screens, &seams, gap);
+    masked
+}
+
+/// Scale each of `screens` (the parts of `area` on each monitor) by its factor and lay them out
+/// again, so that monitors of different densities come out at the same size, on a canvas of
+/// `fill` or a checkerboard without one
+pub fn normalize_screens(image: &RgbaImage, area: util::Rect, screens: &[(util::Rect, f64)],
+                         fill: Option<Rgba<u8>>, filter: imageops::FilterType) -> RgbaImage {
+    // Each stretch between monitor edges shrinks by the largest factor of the monitors over it,
+    // so that monitors side by side stay so without overlapping
+    let axis = |start: fn(&util::Rect) -> i32, len: fn(&util::Rect) -> i32| {
+        let mut edges: Vec<i32> = screens.iter()
+            .flat_map(|(r, _)| vec![start(r), start(r) + len(r)])
+            .chain(Some(start(&area)))
+            .collect();
+        edges.sort_unstable();
+        edges.dedup();
+        let mut mapped = vec![0.0];
+        for pair in edges.windows(2) {
+            let factor = screens.iter()
+                .filter(|(r, _)| start(r) <= pair[0] && pair[1] <= start(r) + len(r))
+                .map(|s| s.1)
+                .fold(0.0, f64::max);
+            mapped.push(mapped[mapped.len() - 1] + (pair[1] - pair[0]) as f64 * factor);
+        }
+        move |pos: i32| mapped[edges.binary_search(&pos).unwrap_or(0)]
+    };
+    let map_x = axis(|r| r.x, |r| r.w);
+    let map_y = axis(|r| r.y, |r| r.h);
+
+    let placed: Vec<(util::Rect, util::Rect)> = screens.iter().map(|&(r, factor)| {
+        (r, util::Rect {
+            x: map_x(r.x).round() as i32,
+            y: map_y(r.y).round() as i32,
+            w: (r.w as f64 * factor).round().max(1.0) as i32,
+            h: (r.h as f64 * factor).round().max(1.0) as i32,
+        })
+    }).collect();
+    let w = placed.iter().map(|(_, p)| p.x + p.w).max().unwrap_or(1) as u32;
+    let h = placed.iter().map(|(_, p)| p.y + p.h).max().unwrap_or(1) as u32;
+    let mut out = match fill {
+        Some(c) => RgbaImage::from_pixel(w, h, c),
+        None => draw::checkerboard(w, h),
+    };
+    for (r, p) in placed {
+        let part = image.view((r.x - area.x) as u32, (r.y - area.y) as u32, r.w as u32,
+                              r.h as u32).to_image();
+        let part = if (p.w, p.h) == (r.w, r.h) {
+            part
+        } else {
+            imageops::resize(&part, p.w as u32, p.h as u32, filter)
+        };
+        imageops::replace(&mut out, &part, p.x as u32, p.y as u32);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: i32, y: i32, w: i32, h: i32) -> util::Rect {
+        util::Rect { x, y, w, h }
+    }
+
+    #[test]
+    fn visible_area_is_clipped_to_the_window_and_root() {
+        let root = (1920, 1080);
+        // Whole window, on screen
+        let window = rect(100, 50, 400, 300);
+        assert_eq!(visible_area(window, root, rect(0, 0, 400, 300)),
+                   Some((rect(0, 0, 400, 300), rect(100, 50, 400, 300))));
+        // Hanging off the top left corner
+        let window = rect(-100, -20, 400, 300);
+        assert_eq!(visible_area(window, root, rect(0, 0, 400, 300)),
+                   Some((rect(100, 20, 300, 280), rect(0, 0, 300, 280))));
+        // Part of a window hanging off the bottom right corner, reaching past the window too
+        let window = rect(1800, 1000, 400, 300);
+        assert_eq!(visible_area(window, root, rect(50, 50, 500, 20)),
+                   Some((rect(50, 50, 70, 20), rect(1850, 1050, 70, 20))));
+        // The root window itself
+        let window = rect(0, 0, 1920, 1080);
+        assert_eq!(visible_area(window, root, rect(10, 20, 30, 40)),
+                   Some((rect(10, 20, 30, 40), rect(10, 20, 30, 40))));
+        // Nothing left
+        assert_eq!(visible_area(rect(2000, 0, 100, 100), root, rect(0, 0, 100, 100)), None);
+        assert_eq!(visible_area(rect(0, 0, 100, 100), root, rect(100, 0, 10, 10)), None);
+    }
+
+    #[test]
+    fn captures_are_masked_to_the_monitors() {
+        let area = rect(0, 0, 30, 20);
+        let image = RgbaImage::from_pixel(30, 20, Rgba([1, 2, 3, 255]));
+        let deep = xwrap::Rgba16Image::from_pixel(30, 20, Rgba([1, 2, 3, 0xFFFF]));
+
+        // A single monitor is left alone, even when smaller than the capture
+        let (masked, _) = mask_capture((image.clone(), None), area, &[rect(0, 0, 30, 10)]);
+        assert_eq!(masked.get_pixel(0, 19)[3], 255);
+
+        // Two monitors of different heights leave the corner below the smaller one out
+        let monitors = [rect(0, 0, 10, 20), rect(10, 0, 40, 10)];
+        let (masked, deep) = mask_capture((image, Some(deep)), area, &monitors);
+        let deep = deep.unwrap();
+        assert_eq!(masked.dimensions(), (30, 20));
+        assert_eq!(*masked.get_pixel(5, 15), Rgba([1, 2, 3, 255]));
+        assert_eq!(*masked.get_pixel(25, 5), Rgba([1, 2, 3, 255]));
+        assert_eq!(masked.get_pixel(25, 15)[3], 0);
+        assert_eq!(deep.get_pixel(5, 15)[3], 0xFFFF);
+        assert_eq!(deep.get_pixel(25, 15)[3], 0);
+    }
+
+    #[test]
+    fn masking_in_place_matches_masking_a_copy() {
+        let area = rect(-5, 3, 70, 50);
+        let screens = [rect(-5, 3, 20, 50), rect(15, 3, 30, 20), rect(45, 30, 20, 23)];
+        let image = RgbaImage::from_fn(70, 50, |x, y| Rgba([x as u8, y as u8, 7, 255]));
+        let deep = xwrap::Rgba16Image::from_fn(70, 50, |x, y| Rgba([x as u16, y as u16, 7, 9]));
+        for &fill in [None, Some(Rgba([1, 2, 3, 4]))].iter() {
+            let mut masked = image.clone();
+            mask_screens_in_place(&mut masked, area, &screens, fill);
+            let copy = mask_screens(&image, area, &screens, None, fill);
+            assert_eq!(masked.into_raw(), copy.into_raw());
+        }
+        let mut masked = deep.clone();
+        mask_screens16_in_place(&mut masked, area, &screens);
+        assert_eq!(masked.into_raw(), mask_screens16(&deep, area, &screens, 0).into_raw());
+    }
+}
@@ -0,0 +1,48 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Leveled messages on stderr, errors always going through
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+pub enum Level {
+    /// Something went wrong but the capture goes on, possibly degraded
+    Warn,
+    /// Progress and outcome of successful runs
+    Info,
+}
+
+/// Number of times -q was given
+static QUIET: AtomicUsize = AtomicUsize::new(0);
+
+/// -q silences info, -qq warnings as well
+pub fn set_quiet(count: usize) {
+    QUIET.store(count, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    match QUIET.load(Ordering::Relaxed) {
+        0 => true,
+        1 => level == Level::Warn,
+        _ => false,
+    }
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Warn) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Info) {
+            eprintln!($($arg)*);
+        }
+    };
+}
@@ -2,45 +2,902 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::convert::TryFrom;
 use std::env;
 use std::ffi::CString;
-use std::fs::File;
+use std::fs;
 use std::io;
+use std::io::Write;
+use std::iter;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::RawFd;
 use std::path::Path;
+use std::path::PathBuf;
 use std::process;
+use std::ptr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
 use std::time;
 
+use getopts::Fail;
 use getopts::Options;
 use image::GenericImage;
+use image::GenericImageView;
+use image::imageops;
 use image::Pixel;
 use image::RgbaImage;
 use image::Rgba;
+use x11::dpms;
 use x11::xlib;
 
-mod util;
-mod xwrap;
-use crate::xwrap::Display;
+#[macro_use]
+mod log;
+mod autocrop;
+mod config;
+mod daemon;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod farbfeld;
+mod generate;
+mod gif;
+mod jpeg;
+#[cfg(feature = "dbus")]
+mod notify;
+#[cfg(feature = "dbus")]
+mod portal;
+mod qoi;
+mod terminal;
+mod tiff;
+mod vp8;
+mod webp;
+mod y4m;
+use shotgun::backend;
+use shotgun::draw;
+use shotgun::mask_screens;
+use shotgun::palette;
+use shotgun::pngenc;
+use shotgun::util;
+use shotgun::wayland;
+use shotgun::xwrap;
+use shotgun::backend::Backend;
+use shotgun::xwrap::Display;
 
 fn usage(progname: &str, opts: getopts::Options) {
     let brief = format!("Usage: {} [options] [file]", progname);
     eprint!("{}", opts.usage(&brief));
 }
 
-fn run() -> i32 {
-    let args: Vec<String> = env::args().collect();
+// Exit statuses for the failures scripts may want to tell apart, 1 being for invalid arguments
+// and anything else
+const EXIT_CANCELLED: i32 = 2;
+const EXIT_DISPLAY: i32 = 3;
+const EXIT_WINDOW: i32 = 4;
+const EXIT_GEOMETRY: i32 = 5;
+const EXIT_CAPTURE: i32 = 6;
+const EXIT_PIXEL_FORMAT: i32 = 7;
+const EXIT_IO: i32 = 8;
+/// Not a failure, nothing was written as asked by --if-changed
+const EXIT_UNCHANGED: i32 = 9;
+
+/// Report a failed capture, returning the exit status for it
+fn capture_status(e: backend::Error) -> i32 {
+    eprintln!("{}", e);
+    match e {
+        backend::Error::Capture(_) => EXIT_CAPTURE,
+        backend::Error::PixelFormat => EXIT_PIXEL_FORMAT,
+        backend::Error::Geometry => EXIT_GEOMETRY,
+    }
+}
+
+/// Somewhere to write encoded images to
+enum Sink {
+    Stdout,
+    File(String),
+    /// Inherited file descriptor, left open
+    Fd(RawFd),
+    Clipboard,
+}
+
+/// What the last capture did, for --notify and --json
+#[derive(Default)]
+struct Report {
+    paths: Vec<String>,
+    /// Captured area, relative to the root
+    origin: Option<util::Rect>,
+    time: Option<time::SystemTime>,
+    /// Time spent in XGetImage, pixel conversion, masking and encoding, for --verbose
+    read: time::Duration,
+    convert: time::Duration,
+    masking: time::Duration,
+    encoding: time::Duration,
+}
+
+/// What was changed on the display for the capture, to be put back afterwards
+#[derive(Clone, Copy, Default)]
+struct Changes {
+    /// DPMS level the monitors were in before --wake turned them on
+    dpms: Option<u16>,
+    /// Where the pointer was before --hover moved it, with --hover-restore
+    pointer: Option<(i32, i32)>,
+}
+
+impl Changes {
+    fn undo(&self, display: &Display) {
+        if let Some((x, y)) = self.pointer {
+            display.fake_motion(x, y);
+        }
+        if let Some(level) = self.dpms {
+            display.set_dpms_level(level);
+        }
+    }
+
+    /// Also undo them on SIGINT or SIGTERM, then exit as the signal would have
+    /// Xlib is not thread safe, so that is done from another connection to the display
+    fn undo_on_signals(self, name: Option<CString>, screen: Option<i32>) {
+        let mut signals = unsafe { mem::zeroed::<libc::sigset_t>() };
+        unsafe {
+            libc::sigemptyset(&mut signals);
+            libc::sigaddset(&mut signals, libc::SIGINT);
+            libc::sigaddset(&mut signals, libc::SIGTERM);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+        }
+        thread::spawn(move || {
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+                return;
+            }
+            if let Some(display) = Display::open(name, screen) {
+                self.undo(&display);
+            }
+            process::exit(128 + signal);
+        });
+    }
+}
+
+/// Undoes its changes however run() returns
+struct Restore<'a> {
+    display: &'a Display,
+    changes: Changes,
+}
+
+impl Drop for Restore<'_> {
+    fn drop(&mut self) {
+        self.changes.undo(self.display);
+    }
+}
+
+/// Size to scale captures to
+#[derive(Copy, Clone)]
+enum Resize {
+    Factor(f64),
+    /// A side left out follows the aspect ratio
+    Size(Option<u32>, Option<u32>),
+}
+
+impl Resize {
+    /// Parse 50% or 1280x720, either side of which may be left out
+    fn parse(s: &str) -> Option<Resize> {
+        if let Some(p) = s.strip_suffix('%') {
+            return p.parse::<f64>().ok().filter(|&p| p > 0.0 && p.is_finite())
+                .map(|p| Resize::Factor(p / 100.0));
+        }
+        let (w, h) = s.split_once('x')?;
+        let side = |v: &str| match v {
+            "" => Ok(None),
+            v => v.parse::<u32>().ok().filter(|&v| v > 0).map(Some).ok_or(()),
+        };
+        match (side(w).ok()?, side(h).ok()?) {
+            (None, None) => None,
+            (w, h) => Some(Resize::Size(w, h)),
+        }
+    }
+
+    fn apply(self, w: u32, h: u32) -> (u32, u32) {
+        let scaled = |v: u32, f: f64| (v as f64 * f).round().max(1.0) as u32;
+        match self {
+            Resize::Factor(f) => (scaled(w, f), scaled(h, f)),
+            Resize::Size(Some(nw), Some(nh)) => (nw, nh),
+            Resize::Size(Some(nw), None) => (nw, scaled(h, nw as f64 / w as f64)),
+            Resize::Size(None, Some(nh)) => (scaled(w, nh as f64 / h as f64), nh),
+            Resize::Size(None, None) => (w, h),
+        }
+    }
+}
+
+/// What to do when a file to write already exists
+#[derive(Copy, Clone, PartialEq)]
+enum Existing {
+    Overwrite,
+    Fail,
+    /// Suffix the name with -1, -2 and so on until it is free
+    Rename,
+}
+
+/// Write `data` to a temporary file next to `path` and move it into place once complete, so that
+/// the file never appears half written, returning the name it ended up with
+/// With `sync`, the data is flushed to disk before it replaces anything
+fn write_file(path: &str, data: &[u8], existing: Existing, sync: bool) -> io::Result<String> {
+    // Devices and pipes cannot be replaced, only written to
+    if fs::metadata(path).is_ok_and(|m| !m.is_file()) {
+        fs::write(path, data)?;
+        return Ok(path.to_string());
+    }
+
+    let path_ref = Path::new(path);
+    let dir = path_ref.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path_ref.file_name().unwrap_or_default().to_string_lossy();
+    let mut n = 0;
+    let (temp, mut file) = loop {
+        let temp = dir.join(format!(".{}.{}.{}.tmp", name, process::id(), n));
+        match fs::OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(f) => break (temp, f),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    };
+    let written = file.write_all(data).and_then(|_| if sync { file.sync_all() } else { Ok(()) });
+    drop(file);
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+
+    // Linking fails if the name is taken, unlike renaming, which is left for file systems
+    // without links
+    let link = |to: &str| match fs::hard_link(&temp, to) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists && !Path::new(to).exists() => {
+            fs::rename(&temp, to)
+        },
+        r => r,
+    };
+    let placed = match existing {
+        Existing::Overwrite => fs::rename(&temp, path).map(|_| path.to_string()),
+        Existing::Fail => link(path).map(|_| path.to_string()),
+        Existing::Rename => {
+            let mut n = 0;
+            loop {
+                let candidate = if n == 0 {
+                    path.to_string()
+                } else {
+                    suffixed_path(path, &format!("-{}", n))
+                };
+                match link(&candidate) {
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                    r => break r.map(|_| candidate),
+                }
+            }
+        },
+    };
+    let _ = fs::remove_file(&temp);
+
+    if sync && placed.is_ok() {
+        fs::File::open(dir)?.sync_all()?;
+    }
+    placed
+}
+
+type FormatInfo = (Option<image::ImageOutputFormat>, Option<image::ImageFormat>, &'static str);
+
+/// Encoder of the image crate for an output format, the kind to decode it back with for
+/// --verify, and its MIME type, none if the format is unknown
+/// Formats without an image crate encoder are left to our own, and those it cannot decode back
+/// have no kind to verify against
+fn format_info(ext: &str) -> Option<FormatInfo> {
+    Some(match ext {
+        "png" | "kitty" | "sixel" => (Some(image::ImageOutputFormat::Png),
+                                      Some(image::ImageFormat::Png), "image/png"),
+        "pam" => (Some(image::ImageOutputFormat::Pnm(image::pnm::PNMSubtype::ArbitraryMap)),
+                  Some(image::ImageFormat::Pnm), "image/x-portable-arbitrarymap"),
+        "ppm" => (None, None, "image/x-portable-pixmap"),
+        "raw" => (None, None, "application/octet-stream"),
+        "y4m" => (None, None, "video/x-yuv4mpeg"),
+        "bmp" => (Some(image::ImageOutputFormat::Bmp), Some(image::ImageFormat::Bmp), "image/bmp"),
+        "tif" | "tiff" => (None, None, "image/tiff"),
+        "ff" => (None, None, "image/x-farbfeld"),
+        "qoi" => (None, None, "image/qoi"),
+        "jpg" | "jpeg" => (None, None, "image/jpeg"),
+        "webp" => (None, None, "image/webp"),
+        "gif" => (None, None, "image/gif"),
+        "apng" => (None, None, "image/apng"),
+        _ => return None,
+    })
+}
+
+/// Output format named by the extension of a file, if it is one we can write to files
+fn format_from_path(path: &str) -> Option<String> {
+    let ext = Path::new(path).extension()?.to_string_lossy().to_lowercase();
+    Some(ext).filter(|e| format_info(e).is_some() && e != "kitty" && e != "sixel")
+}
+
+/// Rebuild the contents of `area` (relative to the root) from the backing pixmaps of windows,
+/// in the stacking order of the top-level windows
+/// `pick` chooses which window, if any, to draw for each top-level window
+fn composite_top_level(display: &Display, root: xlib::Window, area: util::Rect,
+                       pick: &dyn Fn(xlib::Window) -> Option<xlib::Window>) -> RgbaImage {
+    let mut image = RgbaImage::from_pixel(area.w as u32, area.h as u32,
+                                          Rgba::from_channels(0, 0, 0, 0));
+
+    for top in display.get_children(root) {
+        let window = match pick(top) {
+            Some(w) if display.is_viewable(w) => w,
+            _ => continue,
+        };
+
+        let rect = display.get_window_rect(window);
+        let contents = display.get_window_pixmap_image(window, util::Rect {
+            x: 0,
+            y: 0,
+            w: rect.w,
+            h: rect.h,
+        }).and_then(|i| i.to_image_buffer());
+        match contents {
+            Some(c) => draw::blend(&mut image, &c, rect.x - area.x, rect.y - area.y, 1.0),
+            None => warn!("Failed to capture window 0x{:x}, skipping", window),
+        }
+    }
+
+    image
+}
+
+/// Read back a written file and check it against the image that was encoded
+fn verify_output(path: &Path, format: image::ImageFormat, expected: &RgbaImage)
+                 -> Result<(), String> {
+    let (w, h) = expected.dimensions();
+    if w == 0 || h == 0 {
+        return Err(format!("cannot verify an empty {}x{} image", w, h));
+    }
+
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+    let decoded = image::load_from_memory_with_format(&data, format)
+                  .map_err(|e| e.to_string())?.to_rgba();
+
+    if decoded.dimensions() != (w, h) {
+        return Err(format!("expected {}x{} pixels, found {}x{}", w, h,
+                           decoded.width(), decoded.height()));
+    }
+
+    // Spot-check a grid of pixels, corners included
+    const STEPS: u32 = 16;
+    for j in 0..STEPS {
+        for i in 0..STEPS {
+            let x = (w - 1) * i / (STEPS - 1);
+            let y = (h - 1) * j / (STEPS - 1);
+            if decoded.get_pixel(x, y) != expected.get_pixel(x, y) {
+                return Err(format!("pixel at {},{} does not match", x, y));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_window_id(s: &str) -> Option<xlib::Window> {
+    match util::parse_int::<xlib::Window>(s) {
+        Ok(w) => Some(w),
+        Err(_) => {
+            eprintln!("Window ID is not a valid integer");
+            eprintln!("Accepted values are decimal, hex (0x*), octal (0o*) and binary (0b*)");
+            None
+        },
+    }
+}
+
+/// A non-negative number of seconds, none if it is not one or is too long to be held
+fn parse_seconds(s: &str) -> Option<time::Duration> {
+    time::Duration::try_from_secs_f64(s.parse().ok()?).ok()
+}
+
+/// Milliseconds each frame of an animation lasts, until the next one was taken and `last` for
+/// the last one
+fn frame_delays(times: &[time::Instant], last: time::Duration) -> Vec<u32> {
+    times.windows(2).map(|t| (t[1] - t[0]).as_millis() as u32)
+        .chain(iter::once(last.as_millis() as u32)).collect()
+}
+
+/// Replace the percentages in a geometry with pixels of an area of size `within`, widths and X
+/// offsets being fractions of its width and the rest of its height
+fn resolve_percentages(geometry: &str, within: (i32, i32)) -> Option<String> {
+    let mut resolved = String::new();
+    let mut number = String::new();
+    // Width, height, X offset then Y offset
+    let mut part = 0;
+    for c in geometry.chars() {
+        match c {
+            '0'..='9' | '.' => number.push(c),
+            '%' => {
+                let size = if part % 2 == 0 { within.0 } else { within.1 };
+                let pixels = number.parse::<f64>().ok()? * size as f64 / 100.0;
+                resolved.push_str(&(pixels.round() as i32).to_string());
+                number.clear();
+            },
+            _ => {
+                resolved.push_str(&number);
+                number.clear();
+                part = match c {
+                    'x' | 'X' => 1,
+                    '+' | '-' if part < 2 => 2,
+                    '+' | '-' => 3,
+                    _ => part,
+                };
+                resolved.push(c);
+            },
+        }
+    }
+    resolved.push_str(&number);
+    Some(resolved)
+}
+
+/// Insert `suffix` in a file name, right before the extension
+fn suffixed_path(path: &str, suffix: &str) -> String {
+    if suffix.is_empty() || path == "-" {
+        return path.to_string();
+    }
+
+    let p = Path::new(path);
+    match (p.file_stem(), p.extension()) {
+        (Some(stem), Some(ext)) => p.with_file_name(format!("{}{}.{}", stem.to_string_lossy(),
+                                                             suffix, ext.to_string_lossy()))
+                                    .to_string_lossy().into_owned(),
+        _ => format!("{}{}", path, suffix),
+    }
+}
+
+/// The current time, optionally down to milliseconds
+fn timestamp(millis: bool) -> String {
+    let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap_or_default();
+    if millis {
+        format!("{}.{:03}", now.as_secs(), now.subsec_millis())
+    } else {
+        now.as_secs().to_string()
+    }
+}
+
+/// Default file name, the current time optionally down to milliseconds
+fn timestamp_path(ext: &str, millis: bool) -> String {
+    format!("{}.{}", timestamp(millis), ext)
+}
+
+/// File name from a --template, with the `%{name}` tokens filled in, `%c` replaced with what
+/// `counter` gives, and then expanded by strftime in local time
+/// `counter` is only called if the template has a `%c`
+fn template_path(template: &str, ext: &str, tokens: &[(&str, String)],
+                 counter: &mut dyn FnMut() -> String) -> String {
+    let mut format = template.to_string();
+    for (name, value) in tokens {
+        // Values are not to be taken as directories or conversions
+        let value = value.replace('/', "_").replace('%', "%%");
+        format = format.replace(&format!("%{{{}}}", name), &value);
+    }
+
+    // Taking the place of strftime's own %c, a date with spaces and colons
+    let mut expanded = String::with_capacity(format.len());
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('c') => expanded.push_str(&counter().replace('%', "%%")),
+            Some(c) => {
+                expanded.push('%');
+                expanded.push(c);
+            },
+            None => expanded.push('%'),
+        }
+    }
+
+    format!("{}.{}", util::local_time(&expanded), ext)
+}
+
+/// Increment the counter kept for `key` in the file at `path`, from 0 if there is none yet,
+/// returning its new value
+/// The file holds a `count<TAB>key` line for each key, and is locked while it is updated so that
+/// captures taken at the same time get different counts
+fn next_count(path: &Path, key: &str) -> io::Result<u64> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false)
+        .open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut contents = String::new();
+    io::Read::read_to_string(&mut file, &mut contents)?;
+    let mut count = 1;
+    let mut lines: Vec<String> = Vec::new();
+    for line in contents.lines() {
+        match line.split_once('\t') {
+            Some((n, k)) if k == key => count = n.parse::<u64>().unwrap_or(0) + 1,
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines.push(format!("{}\t{}", count, key));
+
+    io::Seek::seek(&mut file, io::SeekFrom::Start(0))?;
+    file.set_len(0)?;
+    file.write_all((lines.join("\n") + "\n").as_bytes())?;
+    // Unlocked on closing
+    Ok(count)
+}
+
+/// Run an --exec command through the shell, with `%f`, `%g` and `%t` standing for the file, the
+/// geometry and the window title, and `%%` for a `%`
+/// The values are passed as positional parameters rather than pasted into the command, as any X
+/// client can set a window title to something the shell would run
+/// Its output goes to stderr with `to_stderr`, not to mix with image data on stdout
+fn exec_hook(command: &str, path: &str, geometry: &str, title: &str, to_stderr: bool)
+             -> Result<(), String> {
+    let mut line = String::new();
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            line.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('f') => line.push_str("\"$1\""),
+            Some('g') => line.push_str("\"$2\""),
+            Some('t') => line.push_str("\"$3\""),
+            Some('%') => line.push('%'),
+            Some(c) => return Err(format!("unknown placeholder %{}", c)),
+            None => return Err("trailing %".to_string()),
+        }
+    }
+
+    let mut command = process::Command::new("sh");
+    command.arg("-c").arg(&line).args(["sh", path, geometry, title]);
+    if to_stderr {
+        let stderr = io::stderr().as_raw_fd();
+        command.stdout(unsafe { process::Stdio::from_raw_fd(libc::dup(stderr)) });
+    }
+    match command.status() {
+        Ok(s) if s.success() => Ok(()),
+        Ok(s) => Err(format!("command {}", s)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// `XDG_CONFIG_HOME`, or its default under the home directory
+fn config_home() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".config")))
+}
+
+/// `XDG_STATE_HOME`, or its default under the home directory
+fn state_home() -> Option<PathBuf> {
+    env::var_os("XDG_STATE_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".local").join("state")))
+}
+
+/// `XDG_PICTURES_DIR`, from the environment or from the user-dirs.dirs file xdg-user-dirs keeps
+fn pictures_dir() -> Option<PathBuf> {
+    let home = env::var("HOME").ok()?;
+    let dir = env::var("XDG_PICTURES_DIR").ok().or_else(|| {
+        let dirs = fs::read_to_string(config_home()?.join("user-dirs.dirs")).ok()?;
+        dirs.lines().find_map(|l| l.trim().strip_prefix("XDG_PICTURES_DIR="))
+            .map(|d| d.trim_matches('"').to_string())
+    })?;
+    Some(PathBuf::from(dir.replace("$HOME", &home))).filter(|d| d.is_absolute())
+}
+
+/// Fill in a --hold or --every file name, {n} being the number of the capture and {t} the time
+fn hold_path(template: &str, n: u64) -> String {
+    template.replace("{n}", &n.to_string()).replace("{t}", &timestamp(true))
+}
+
+fn version() -> &'static str {
+    option_env!("GIT_VERSION").unwrap_or(env!("CARGO_PKG_VERSION"))
+}
+
+/// Parse `args` and capture accordingly
+/// `shared` is the display of the daemon running the request, if any
+fn run(args: &[String], shared: Option<&Display>) -> i32 {
     let progname = args[0].clone();
 
     let mut opts = Options::new();
-    opts.optopt("i", "id", "Window to capture", "ID");
-    opts.optopt("g", "geometry", "Area to capture", "WxH+X+Y");
-    opts.optopt("f", "format", "Output format", "png/pam");
+    opts.optopt("", "display", "X display to connect to (default $DISPLAY)", "NAME");
+    opts.optopt("", "x-screen", "X screen of the display to capture, on servers with several \
+                                 (default: the one the display name gives)", "N");
+    opts.optmulti("o", "output", "Also write the capture to this file, or stdout for -", "FILE");
+    opts.optopt("", "dir", "Directory of the file written when none is given (default \
+                $XDG_PICTURES_DIR/Screenshots, or the current directory)", "DIR");
+    opts.optopt("", "template", "Name of the file written when none is given, expanded with \
+                strftime and %{wid}, %{wm_class}, %{wm_name} and %{geometry}, and %c for a \
+                count kept across runs, the extension being appended (default: the Unix time)",
+                "TEMPLATE");
+    opts.optopt("", "counter-width", "Digits the %c count of --template is padded to with \
+                zeros (default 3)", "N");
+    opts.optmulti("i", "id", "Window to capture, pick to click on it, active for the \
+                                  focused window, under-cursor for the one beneath the \
+                                  pointer, or root", "ID");
+    opts.optopt("", "class", "Capture the top-most window with this WM_CLASS name", "CLASS");
+    opts.optopt("", "name", "Capture the top-most window with a title containing this text",
+                "TEXT");
+    opts.optopt("", "pid", "Capture the top-most window owned by this process", "PID");
+    opts.optflagopt("", "wait-for-window", "Wait for a window matching --class, --name or \
+                     --pid to appear, for at most this long", "SECONDS");
+    opts.optopt("", "nth", "Capture the Nth matching window instead, starting from 0 at the \
+                            top", "N");
+    opts.optmulti("g", "geometry", "Area to capture, in pixels or percent of the target, given \
+                   several times to write each to its own file, suffixing file names with its \
+                   index", "WxH+X+Y");
+    opts.optflag("", "relative", "Take -g relative to the window selected with -i instead of \
+                  the root");
+    opts.optflag("s", "select", "Drag a rectangle with the mouse to choose the area to capture");
+    opts.optflag("", "freeze", "Show a still image of the screen while selecting with --select, \
+                  and capture from it");
+    opts.optflag("", "dim", "Darken the screen outside of the --select rectangle and show its \
+                  geometry, implies --freeze");
+    opts.optflagopt("", "snap", "Snap --select corners to window and monitor edges within this \
+                     distance (default 8), except while Shift is held", "PX");
+    opts.optopt("", "ratio", "Keep the --select rectangle to an aspect ratio", "W:H");
+    opts.optopt("", "fixed", "Move a rectangle of this size around with --select and click to \
+                              place it", "WxH");
+    opts.optopt("", "window-rect", "Capture the area covered by a window, as seen on screen",
+                "ID");
+    opts.optopt("", "between", "Capture the smallest area covering two windows", "ID,ID");
+    opts.optopt("", "center-fraction", "Capture the middle of the monitor under the pointer, \
+                                        this fraction of its size", "FRACTION");
+    opts.optopt("", "around-cursor", "Capture an area of this size centered on the pointer, \
+                kept within its monitor", "WxH");
+    opts.optopt("", "screen", "Capture one monitor, by RandR output name or by index from 0",
+                "NAME/N");
+    opts.optflagopt("", "montage", "Capture every window given with -i and lay them out on a \
+                     grid, in one row unless a column count is given", "COLUMNS");
+    opts.optopt("", "montage-spacing", "Gap between the windows of a montage, filled with \
+                 --background if given (default 0)", "PX");
+    opts.optflag("", "with-shadow", "Capture the window selected with -i from the root, along \
+                                     with the shadow drawn around it by the compositor");
+    opts.optopt("", "shadow-margin", "Room left around the window for its shadow (default 32)",
+                "PX");
+    opts.optopt("f", "format", "Output format: png (default), pam, ppm, raw, y4m, bmp, tiff, ff, \
+                                qoi, jpg, webp, gif, apng, sixel or kitty", "FORMAT");
+    opts.optopt("", "quality", "JPEG quality (default 90), or lossy WebP at this quality",
+                "1-100");
+    opts.optopt("", "png-compression", "PNG compression effort, fast trades file size for speed",
+                "fast/default/best");
+    opts.optopt("", "png-filter", "PNG scanline filter (default sub)", "none/sub/up/avg/paeth");
+    opts.optopt("", "threads", "Compress PNG images over N threads, in as many bands (default 1)",
+                "N");
+    opts.optflag("", "no-metadata", "Leave the capture time, window and geometry out of PNG \
+                                     and JPEG files");
+    opts.optflag("", "deterministic", "Same as --no-metadata, and name the output shotgun.EXT \
+                                       instead of after the time when none is given");
+    opts.optflag("", "icc", "Embed the ICC profile of the captured screen in PNG and JPEG \
+                             files");
+    opts.optflag("", "16-bit", "Write 16 bits per channel PNG, PAM, TIFF or farbfeld, keeping \
+                                the extra precision of deep color framebuffers");
+    opts.optflagopt("", "grayscale", "Convert the capture to 8 bit gray, or to black and white \
+                                      with 1, and write PNG files as such", "BITS");
+    opts.optflag("", "dither", "Spread the error of --grayscale=1 over neighbouring pixels rather \
+                                than threshold each on its own");
+    opts.optopt("", "background", "Color that transparent areas are blended onto for formats \
+                                   without alpha, and for all of them when given \
+                                   (default ffffff)", "RRGGBB");
+    opts.optflag("", "keep-alpha", "Keep the alpha channel in formats that have one even when \
+                                    a --background is given");
+    opts.optflag("", "inhibit-compositor-effects", "Rebuild a root capture from the \
+                                                    top-level windows' own contents, without \
+                                                    compositor effects");
+    opts.optflag("", "frame", "Capture the window selected with -i along with its window manager \
+                               decorations");
+    opts.optopt("", "pad", "Grow the captured area by this much on every side, or shrink it if \
+                            negative, within the captured window", "PX");
+    opts.optflag("", "trim-extents", "Leave out the shadow margins that client-side decorated \
+                                      windows report in _GTK_FRAME_EXTENTS");
+    opts.optflag("", "composite", "Capture the window selected with -i from its own contents, \
+                                   without the windows covering it");
+    opts.optopt("", "planes", "Only read the bit planes set in MASK, the others coming back as \
+                               zero", "MASK");
+    opts.optflag("", "monochrome", "Capture white where any of the planes read is set and black \
+                                    elsewhere, e.g. a single bit plane with --planes");
+    opts.optopt("", "if-changed", "Only write the capture if it differs from this PNG, PAM or \
+                                   BMP file, or from the last one written by --every or --hold",
+                "FILE");
+    opts.optopt("", "threshold", "Share of pixels that may differ for --if-changed to still \
+                                  take a capture as unchanged (default 0%)", "N%");
+    opts.optopt("", "max-area", "Refuse to capture more than this many pixels", "PIXELS");
+    opts.optopt("", "include-classes", "Rebuild a root capture from only the top-level \
+                                          windows with these WM_CLASS names", "CLASS,...");
+    opts.optopt("", "exclude-classes", "Rebuild a root capture from all top-level windows \
+                                          except those with these WM_CLASS names", "CLASS,...");
+    opts.optopt("", "exclude-top", "Rebuild a root capture from all top-level windows \
+                                      except the N top-most ones", "N");
+    opts.optmulti("", "exclude-window", "Fill the visible part of a top-level window with \
+                                         --mask-color in root captures", "ID");
+    opts.optmulti("", "redact-class", "Cover the visible parts of top-level windows with this \
+                                       WM_CLASS name in root captures", "CLASS");
+    opts.optopt("", "redact-style", "How --redact-class covers windows (default black)",
+                "black/blur/pixelate");
+    opts.optflag("", "hide-docks", "Leave panels and docks out of a root capture, showing the \
+                                    wallpaper in their place without the Composite extension");
+    opts.optflag("", "with-transients", "Also draw the dialogs that are transient for the \
+                                         captured window");
+    opts.optflag("", "with-popups", "Also draw the dialogs, menus and tooltips of the captured \
+                                     window's application");
+    opts.optopt("d", "delay", "Wait this long before capturing", "SECONDS");
+    opts.optopt("", "settle", "Wait for the target to go this long without redrawing before \
+                               capturing", "MS");
+    opts.optflag("", "countdown", "Print the seconds left during --delay");
+    opts.optflag("", "abortable", "Hold the keyboard during --delay, Escape cancelling the \
+                                   capture");
+    opts.optflag("", "wake", "Turn the display on through DPMS before capturing, and back off \
+                              afterwards");
+    opts.optflag("", "reset-screensaver", "Deactivate the screen saver before capturing, as \
+                                           input would");
+    opts.optopt("", "hover", "Move the pointer to a root position before capturing", "X,Y");
+    opts.optopt("", "hover-delay", "Time to wait after moving the pointer (default 1000)", "MS");
+    opts.optflag("", "hover-restore", "Move the pointer back after capturing");
+    opts.optopt("", "skip-blank", "Capture again, up to N times, while the capture is a \
+                                   single color", "N");
+    opts.optopt("", "retry", "Capture again, up to N times, after an X error that may not \
+                              happen again, such as the window being remapped (waiting 100 ms \
+                              by default)", "N[:MS]");
+    opts.optflag("", "allow-uniform", "Keep a single color capture once --skip-blank runs out \
+                                       of retries");
+    opts.optopt("", "trigger-pixel", "Wait until a pixel of the root window has (=) or no \
+                                      longer has (!=) a color before capturing",
+                "X,Y=RRGGBB");
+    opts.optopt("", "trigger-timeout", "Give up waiting for --trigger-pixel after this long \
+                                        (default 10000)", "MS");
+    opts.optflag("", "cursor", "Draw the mouse cursor onto the capture");
+    opts.optflag("", "swap-rb", "Swap the red and blue channels of the capture");
+    opts.optmulti("", "blur", "Blur an area of the capture, relative to it, before writing it",
+                  "WxH+X+Y");
+    opts.optmulti("", "pixelate", "Cover an area of the capture, relative to it, with large \
+                                   blocks of color before writing it", "WxH+X+Y");
+    opts.optopt("", "blur-radius", "Strength of --blur (default 12)", "PX");
+    opts.optopt("", "pixel-size", "Block size of --pixelate (default 16)", "PX");
+    opts.optmulti("", "exclude-child", "Fill a descendant of the captured window, given as \
+                                        dot-separated child indices, with the --background \
+                                        color", "INDICES");
+    opts.optopt("", "palette", "Map colors to the nearest entry of a palette file", "PATH");
+    opts.optopt("", "palette-space", "Color space used for palette matching", "rgb/lab");
+    opts.optflagopt("", "quantize", "Reduce the image to a palette of its own most \
+                                     representative colors (default 256)", "COLORS");
+    opts.optflag("", "autocrop", "Crop to the largest region that differs from the \
+                                  background color");
+    opts.optopt("", "autocrop-tolerance", "Largest channel difference still considered \
+                                           background (default 16)", "N");
+    opts.optopt("", "watermark", "Blend an image into a corner (tl/tr/bl/br, default br) or \
+                                  over the whole capture (tile) with the given opacity \
+                                  (default 0.5)",
+                "PATH[:CORNER[:OPACITY]]");
+    opts.optopt("", "watermark-margin", "Distance of the watermark from the edges, or between \
+                                         tiles (default 16)", "PX");
+    opts.optopt("", "watermark-scale", "Scale the watermark to a fraction of the capture \
+                                        width", "FRACTION");
+    opts.optflag("", "no-mask", "Keep whatever the framebuffer holds outside of the monitors of \
+                                 a root capture");
+    opts.optopt("", "mask-color", "Fill for the parts of root captures outside of every \
+                                   monitor (default transparent)", "RRGGBB[AA]/checkerboard");
+    opts.optopt("", "bezel", "Leave a gap between adjacent monitors in root captures",
+                "PX[:RRGGBB]");
+    opts.optflagopt("", "normalize-dpi", "Scale the monitors of a root capture down to the \
+                                          density of the lowest DPI one, guessing the scale of \
+                                          those not listed", "OUTPUT=SCALE,...");
+    opts.optflagopt("", "shadow", "Draw a soft drop shadow of this size under the capture, on \
+                                   a larger transparent canvas (default 16)", "PX");
+    opts.optopt("", "border", "Grow the canvas with a solid border, one width for all sides \
+                               or top,right,bottom,left", "PX[,PX,PX,PX]:RRGGBB");
+    opts.optflag("", "dominant-color", "Print the color of the capture as hex, only writing \
+                                        the image if a file is given");
+    opts.optopt("", "dominant-method", "How to pick the color (default average)",
+                "average/mode/kmeans");
+    opts.optopt("", "dominant-count", "Print up to this many colors with the mode and kmeans \
+                                       methods, the most widespread first (default 1)", "N");
+    opts.optopt("", "split", "Write the capture as a grid of tiles, suffixing file names with \
+                              the row and column", "ROWSxCOLS");
+    opts.optopt("", "rotate", "Turn the capture clockwise", "90/180/270");
+    opts.optopt("", "flip", "Mirror the capture, after --rotate", "h/v");
+    opts.optflag("", "natural-orientation", "Turn a --screen capture back from the rotation \
+                                             RandR applies to the monitor");
+    opts.optopt("", "resize", "Scale the capture by a percentage or to a size, a left out side \
+                               keeping the aspect ratio", "N%/WxH");
+    opts.optopt("", "resize-filter", "Filter used by --resize (default lanczos)",
+                "nearest/triangle/catmull-rom/gaussian/lanczos");
+    opts.optflag("", "linear-resize", "Scale in linear light rather than on the sRGB values, \
+                                       for --resize and --sizes");
+    opts.optopt("", "sizes", "Write the capture at several scales, suffixing file names \
+                              with the factor", "1x,0.5x,...");
+    opts.optopt("", "thumb", "Also write a copy scaled down to fit within this size, suffixing \
+                              file names with -thumb, or to PATH where {name} stands for the \
+                              name of each file without its extension", "WxH[:PATH]");
+    opts.optflag("", "all-screens", "Write each monitor of a root capture to its own file, \
+                  suffixing file names with the output name");
+    opts.optflag("", "sharp-downscale", "Sharpen reduced --sizes copies with an unsharp mask");
+    opts.optopt("", "sharpen-amount", "Strength of the unsharp mask (default 0.5)", "AMOUNT");
+    opts.optopt("", "sharpen-radius", "Blur radius of the unsharp mask (default 1)", "PX");
+    opts.optflag("", "print-im-geometry", "Print the captured area as an ImageMagick crop \
+                                           geometry");
+    opts.optflag("", "verify", "Read written files back and check that they decode to the \
+                                captured image");
+    opts.optflag("", "no-clobber", "Fail rather than overwrite existing files");
+    opts.optflag("", "unique", "Suffix file names with -1, -2 and so on until they are free \
+                  rather than overwrite existing files");
+    opts.optopt("", "fd", "Also write the capture to this inherited file descriptor", "N");
+    opts.optflag("", "mkdir", "Create missing parent directories of output files");
+    opts.optflag("", "sync", "Flush written files to disk before moving them into place");
+    opts.optflag("", "print-path", "Print the names of the files written");
+    #[cfg(feature = "dbus")]
+    opts.optflag("", "notify", "Send a desktop notification once the capture is written");
+    opts.optopt("", "exec", "Run this shell command after writing each file, %f, %g and %t \
+                             standing for its path, the geometry and the window title", "CMD");
+    opts.optflag("", "mime-header", "Precede image data written to stdout with a \
+                                     Content-Type header");
+    opts.optflag("", "length-prefix", "Precede image data written to stdout with its length, \
+                                       as a 64-bit big-endian integer");
+    opts.optflag("", "clipboard", "Also copy the image to the clipboard, served in the \
+                                   background until another program takes it over");
+    opts.optflag("", "on-change", "Keep running and save a time-stamped capture every time \
+                                   the target changes");
+    opts.optopt("", "debounce", "Time the target must stay unchanged before an \
+                                 --on-change capture (default 500)", "MS");
+    opts.optflag("", "hold", "Keep running and capture every time SIGUSR1 is received, {n} and \
+                              {t} in file names standing for the count and time");
+    opts.optopt("", "every", "Keep running and capture at this interval, {n} and {t} in file \
+                              names standing for the count and time", "SECONDS");
+    opts.optopt("", "count", "Stop after this many --every captures", "N");
+    opts.optopt("", "duration", "Stop taking --every captures after this long", "SECONDS");
+    opts.optflag("", "drop-late", "Skip the --every captures whose time has passed while an \
+                                   earlier one ran late, to keep to the schedule");
+    opts.optopt("", "webp-anim", "Also put the --every captures together into an animated WebP",
+                "FILE");
+    opts.optflag("", "stream", "Keep capturing the same area and write the frames to stdout, as \
+                                raw or y4m, until interrupted");
+    opts.optopt("", "record", "Capture the same area for this long and write the frames as \
+                               an animated GIF or PNG", "SECONDS");
+    opts.optopt("", "fps", "Frame rate of --stream (default 30) and --record (default 10)", "N");
+    #[cfg(feature = "dbus")]
+    opts.optflag("", "portal", "Serve screenshot requests as an xdg-desktop-portal backend on \
+                                the session bus");
+    opts.optflagopt("", "daemon", "Keep the display open and take capture requests on a Unix \
+                                   socket (default $XDG_RUNTIME_DIR/shotgun.sock)", "SOCKET");
+    opts.optflagopt("", "pick-color", "Print the color of the pixel under the pointer, or at a \
+                                       point, or at a click with --select, and exit", "X,Y");
+    opts.optflag("", "list-screens", "Print the name, geometry and primary flag of each \
+                  monitor and exit");
+    opts.optflag("", "list-windows", "Print the ID, geometry, class and title of each \
+                  managed window and exit");
+    opts.optflag("", "json", "Print --list-screens, --list-windows and --pick-color as JSON, \
+                 and a JSON description of the capture once it is written");
+    opts.optflag("", "no-config", "Ignore $XDG_CONFIG_HOME/shotgun/config.toml");
+    opts.optopt("", "profile", "Apply the settings of the [profiles.NAME] table of the \
+                 configuration file", "NAME");
+    opts.optflagmulti("q", "quiet", "Print no progress messages, and no warnings either when \
+                       given twice");
+    opts.optflag("", "verbose", "Print the visual, monitors and time spent in each step of the \
+                  capture to stderr");
     opts.optflag("h", "help", "Print help and exit");
     opts.optflag("v", "version", "Print version and exit");
 
-    let matches = match opts.parse(&args[1..]) {
+    // Left out of --help, being meant for packagers
+    if args.get(1).map(String::as_str) == Some("--generate") {
+        return generate::run(&opts, &args[2..], version());
+    }
+
+    // Whether an option of that name exists, and whether it is a flag
+    let known = |name: &str| {
+        !matches!(opts.parse([format!("--{}", name)]), Err(Fail::UnrecognizedOption(_)))
+    };
+    let is_flag = |name: &str| {
+        matches!(opts.parse([format!("--{}=x", name)]), Err(Fail::UnexpectedArgument(_)))
+    };
+    // --no-NAME turns off a flag the configuration or the environment turns on
+    let (cli, turned_off) = config::split_turned_off(&args[1..], &known, &is_flag);
+
+    let matches = match opts.parse(&cli) {
         Ok(m) => m,
         Err(f) => {
-            eprintln!("{}", f.to_string());
+            eprintln!("{}", f);
             usage(&progname, opts);
             return 1;
         }
@@ -59,148 +916,3424 @@ fn run() -> i32 {
     }
 
     if matches.opt_present("v") {
-        eprintln!("shotgun {}", option_env!("GIT_VERSION").unwrap_or(env!("CARGO_PKG_VERSION")));
+        eprintln!("shotgun {}", version());
         return 0;
     }
 
-    let display = match Display::open(None) {
-        Some(d) => d,
-        None => {
-            eprintln!("Failed to open display");
-            return 1;
+    // Defaults from the configuration file, then the environment, then the chosen profile, for
+    // the options not given on the command line
+    let mut layers = Vec::new();
+    let mut file = config::File::default();
+    let config = config_home().map(|d| d.join("shotgun").join("config.toml"));
+    if let Some(path) = config.filter(|_| !matches.opt_present("no-config")) {
+        match config::load(&path) {
+            Ok(f) => {
+                file = f;
+                layers.push((path.display().to_string(), mem::take(&mut file.settings)));
+            },
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", path.display(), e);
+                return EXIT_IO;
+            },
         }
-    };
-    let root = display.get_default_root();
+    }
+    let (from_env, unknown_env) = config::from_env(&known);
+    layers.push(("the environment".to_string(), from_env));
 
-    let window = match matches.opt_str("i") {
-        Some(s) => match util::parse_int::<xlib::Window>(&s) {
-            Ok(r) => r,
-            Err(_) => {
-                eprintln!("Window ID is not a valid integer");
-                eprintln!("Accepted values are decimal, hex (0x*), octal (0o*) and binary (0b*)");
+    let profile = matches.opt_str("profile").or_else(|| {
+        let setting = layers.iter().rev().flat_map(|(_, l)| l).find(|s| s.name == "profile")?;
+        match &setting.value {
+            config::Value::Text(name) => Some(name.clone()),
+            config::Value::Flag(_) => None,
+        }
+    });
+    if let Some(name) = profile {
+        let index = match file.profiles.iter().position(|(n, _)| *n == name) {
+            Some(i) => i,
+            None if matches.opt_present("no-config") => {
+                eprintln!("--profile cannot be used with --no-config");
                 return 1;
             },
-        },
-        None => root,
-    };
+            None if file.profiles.is_empty() => {
+                eprintln!("Unknown profile {}, the configuration file defines none", name);
+                return 1;
+            },
+            None => {
+                let names: Vec<&str> = file.profiles.iter().map(|(n, _)| n.as_str()).collect();
+                eprintln!("Unknown profile {}, available: {}", name, names.join(", "));
+                return 1;
+            },
+        };
+        let settings = file.profiles.swap_remove(index).1;
+        layers.push((format!("profile {}", name), settings));
+    }
 
-    let output_ext = matches.opt_str("f").unwrap_or("png".to_string()).to_lowercase();
-    let output_format = match output_ext.as_ref() {
-        "png" => image::ImageOutputFormat::Png,
-        "pam" => image::ImageOutputFormat::Pnm(image::pnm::PNMSubtype::ArbitraryMap),
-        _ => {
-            eprintln!("Invalid image format specified");
+    let mut settings: Vec<config::Setting> = Vec::new();
+    for (source, layer) in layers {
+        let defaults: Vec<String> = layer.iter().filter_map(|s| s.to_arg()).collect();
+        if let Err(f) = opts.parse(&defaults) {
+            eprintln!("Invalid setting in {}: {}", source, f);
             return 1;
         }
-    };
+        config::apply_layer(&mut settings, layer);
+    }
 
-    let window_rect = display.get_window_rect(window);
-    let sel = match matches.opt_str("g") {
-        Some(s) => match xwrap::parse_geometry(CString::new(s).expect("Failed to convert CString"))
-                         .intersection(window_rect) {
-            Some(sel) => util::Rect {
-                // Selection is relative to the root window (whole screen)
-                x: sel.x - window_rect.x,
-                y: sel.y - window_rect.y,
-                w: sel.w,
-                h: sel.h,
-            },
-            None => {
-                eprintln!("Invalid geometry");
+    let matches = if settings.is_empty() {
+        matches
+    } else {
+        // Ahead of the command line, as loose arguments may follow a --
+        let mut with_defaults = config::default_args(&settings, &|n| matches.opt_present(n),
+                                                     &turned_off);
+        with_defaults.extend_from_slice(&cli);
+        match opts.parse(&with_defaults) {
+            Ok(m) => m,
+            Err(f) => {
+                eprintln!("{}", f);
                 return 1;
             },
-        },
-        None => util::Rect {
-            x: 0,
-            y: 0,
-            w: window_rect.w,
-            h: window_rect.h,
-        },
+        }
     };
+    log::set_quiet(matches.opt_count("q"));
+    for var in unknown_env {
+        warn!("Ignoring {}, which names no option", var);
+    }
 
-    let image = match display.get_image(window, sel, xwrap::ALL_PLANES, xlib::ZPixmap) {
-        Some(i) => i,
-        None => {
-            eprintln!("Failed to get image from X");
+    // Options that would keep the daemon busy for good
+    let resident = ["daemon", #[cfg(feature = "dbus")] "portal", "stream", "record", "on-change",
+                    "hold", "every"];
+    if let (Some(_), Some(o)) = (shared, resident.iter().find(|&&o| matches.opt_present(o))) {
+        eprintln!("--{} cannot be used through --daemon", o);
+        return 1;
+    }
+    if let (Some(_), Some(o)) = (shared, ["display", "x-screen"].iter()
+                                         .find(|&&o| matches.opt_present(o))) {
+        eprintln!("--{} cannot be used through --daemon, which captures its own display", o);
+        return 1;
+    }
+
+    #[cfg(feature = "dbus")]
+    if matches.opt_present("portal") {
+        return portal::run();
+    }
+
+    // A NUL cannot be part of a display name, and makes XOpenDisplay fail on the empty one
+    let display_name = matches.opt_str("display")
+        .map(|n| CString::new(n).unwrap_or_default());
+    let x_screen = match matches.opt_str("x-screen").map(|s| s.parse::<i32>()) {
+        Some(Ok(n)) => Some(n),
+        Some(Err(_)) => {
+            eprintln!("X screen number is not a valid integer");
             return 1;
         },
+        None => None,
     };
-
-    let mut image = match image.into_image_buffer() {
-        Some(i) => image::DynamicImage::ImageRgba8(i),
-        None => {
-            eprintln!("Failed to convert captured framebuffer, only 24/32 \
-                      bit (A)RGB8 is supported");
-            return 1;
-        }
+    let open_display = || Display::open(display_name.clone(), x_screen);
+    let open_failed = || match x_screen {
+        Some(n) => eprintln!("Failed to open display, or it has no screen {}", n),
+        None => eprintln!("Failed to open display"),
     };
 
-    // When capturing the root window, attempt to mask the off-screen areas
-    if window == root {
-        match display.get_screen_rects(root) {
-            Some(screens) => {
-                let screens: Vec<util::Rect> =
-                    screens.filter_map(|s| s.intersection(sel)).collect();
+    if matches.opt_present("daemon") {
+        let path = match matches.opt_str("daemon").map(PathBuf::from)
+                             .or_else(daemon::default_socket) {
+            Some(p) => p,
+            None => {
+                eprintln!("XDG_RUNTIME_DIR is not set, give --daemon a socket path in a \
+                           directory only you can write to");
+                return 1;
+            },
+        };
+        let display = match open_display() {
+            Some(d) => d,
+            None => {
+                open_failed();
+                return EXIT_DISPLAY;
+            }
+        };
+        return daemon::serve(&path, |args| run(args, Some(&display)));
+    }
 
-                // No point in masking if we're only capturing one screen
-                if screens.len() > 1 {
-                    let mut masked = RgbaImage::from_pixel(sel.w as u32, sel.h as u32,
-                                                           Rgba::from_channels(0, 0, 0, 0));
+    // Every destination to write the capture to, "-" being stdout
+    let mut outputs = matches.opt_strs("o");
+    outputs.extend(matches.free.iter().cloned());
 
-                    for screen in screens {
-                        // Subimage is relative to the captured area
-                        let sub = util::Rect {
-                            x: screen.x - sel.x,
-                            y: screen.y - sel.y,
-                            w: screen.w,
-                            h: screen.h,
-                        };
+    let verbose = matches.opt_present("verbose");
+    let opened;
+    let open_start = time::Instant::now();
+    let display = match shared {
+        Some(d) => d,
+        None => match open_display() {
+            Some(d) => {
+                opened = d;
+                &opened
+            },
+            None => {
+                open_failed();
+                return EXIT_DISPLAY;
+            }
+        },
+    };
+    // The daemon's connection is already open
+    let open_time = if shared.is_some() { time::Duration::default() } else { open_start.elapsed() };
+    let root = display.get_default_root();
+    // Left over from an earlier request to the daemon
+    let _ = display.take_error();
 
-                        let mut sub_src = image.sub_image(sub.x as u32, sub.y as u32,
-                                                          sub.w as u32, sub.h as u32);
-                        masked.copy_from(&mut sub_src, sub.x as u32, sub.y as u32)
-                            .expect("Failed to copy sub-image");
-                    }
+    if matches.opt_present("list-screens") {
+        let outputs = shotgun::monitors(display);
+        if matches.opt_present("json") {
+            let entries: Vec<String> = outputs.iter().map(|o| {
+                format!("{{\"name\":{},\"x\":{},\"y\":{},\"width\":{},\"height\":{},\
+                         \"primary\":{},\"width_mm\":{},\"height_mm\":{},\"scale\":{}}}",
+                        util::json_string(&o.name), o.rect.x, o.rect.y, o.rect.w, o.rect.h,
+                        o.primary, o.mm_size.0, o.mm_size.1, o.scale())
+            }).collect();
+            println!("[{}]", entries.join(","));
+        } else {
+            for o in &outputs {
+                println!("{} {}x{}+{}+{}{}", o.name, o.rect.w, o.rect.h, o.rect.x, o.rect.y,
+                         if o.primary { " primary" } else { "" });
+            }
+        }
+        return 0;
+    }
 
-                    image = image::DynamicImage::ImageRgba8(masked);
-                }
+    if matches.opt_present("list-windows") {
+        let json = matches.opt_present("json");
+        let mut entries = Vec::new();
+        for w in display.get_client_list(root) {
+            let r = display.get_window_rect(w);
+            let class = display.get_class(w).map(|(_, k)| k).unwrap_or_default();
+            let name = display.get_name(w).unwrap_or_default();
+            if json {
+                entries.push(format!("{{\"id\":{},\"x\":{},\"y\":{},\"width\":{},\
+                                      \"height\":{},\"class\":{},\"title\":{}}}", w, r.x, r.y,
+                                     r.w, r.h, util::json_string(&class),
+                                     util::json_string(&name)));
+            } else {
+                println!("0x{:x} {}x{}+{}+{} {} {}", w, r.w, r.h, r.x, r.y, class, name);
+            }
+        }
+        if json {
+            println!("[{}]", entries.join(","));
+        }
+        return 0;
+    }
+
+    if matches.opt_present("pick-color") {
+        let point = match matches.opt_str("pick-color") {
+            Some(p) => match p.split_once(',').map(|(x, y)| (x.parse(), y.parse())) {
+                Some((Ok(x), Ok(y))) => (x, y),
+                _ => {
+                    eprintln!("Invalid point, expected X,Y");
+                    return 1;
+                },
+            },
+            None if matches.opt_present("s") => match display.pick_point() {
+                Some(p) => p,
+                None => {
+                    eprintln!("No point picked");
+                    return EXIT_CANCELLED;
+                },
             },
+            None => display.query_pointer(),
+        };
+        let pixel = util::Rect { x: point.0, y: point.1, w: 1, h: 1 };
+        let r = display.get_window_rect(root);
+        if pixel.intersection(util::Rect { x: 0, y: 0, w: r.w, h: r.h }).is_none() {
+            eprintln!("Point {},{} is outside of the screen", point.0, point.1);
+            return EXIT_GEOMETRY;
+        }
+        let image = display.get_image(root, pixel, xwrap::ALL_PLANES, xlib::ZPixmap)
+            .and_then(|i| i.to_image_buffer());
+        let [r, g, b, _] = match image {
+            Some(i) => i.get_pixel(0, 0).0,
             None => {
-                eprintln!("Failed to enumerate screens, not masking");
+                eprintln!("Failed to get image from X");
+                return EXIT_CAPTURE;
             },
+        };
+        if matches.opt_present("json") {
+            println!("{{\"x\":{},\"y\":{},\"color\":\"#{:02x}{:02x}{:02x}\",\"red\":{},\
+                      \"green\":{},\"blue\":{}}}", point.0, point.1, r, g, b, r, g, b);
+        } else {
+            println!("#{:02x}{:02x}{:02x} rgb({}, {}, {})", r, g, b, r, g, b);
         }
+        return 0;
     }
 
-    let ts_path = {
-        let now = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
-            Ok(n) => n.as_secs(),
-            Err(_) => 0,
-        };
-        format!("{}.{}", now, output_ext)
+    // X only sees XWayland clients under Wayland, and compositors often give it black frames
+    let wayland = if env::var_os("WAYLAND_DISPLAY").is_some() && display_name.is_none() {
+        match wayland::Connection::open() {
+            Ok(c) => Some(c),
+            Err(e) => {
+                warn!("Capturing through X, as Wayland capture is not available: {}", e);
+                None
+            },
+        }
+    } else {
+        None
     };
-    let path = match matches.free.get(0) {
-        Some(p) => p,
-        None => {
-            eprintln!("No output specified, defaulting to {}", ts_path);
-            ts_path.as_str()
-        },
+    let backend: &dyn Backend = match &wayland {
+        Some(c) => c,
+        None => display,
     };
 
-    if path == "-" {
-        image.write_to(&mut io::stdout(), output_format).expect("Writing to stdout failed");
-    } else {
-        match File::create(&Path::new(&path)) {
-            Ok(mut f) => image.write_to(&mut f, output_format).expect("Writing to file failed"),
-            Err(e) => {
-                eprintln!("Failed to create {}: {}", path, e);
-                return 1
+    if verbose {
+        match display.get_visual(root) {
+            Some((depth, id, class)) => {
+                eprintln!("Root visual: 0x{:x} {}, depth {}", id, class, depth);
             },
+            None => eprintln!("Root visual: unknown"),
+        }
+        eprintln!("Capturing through {}", if wayland.is_some() { "wlr-screencopy" } else { "X" });
+        for o in shotgun::monitors(display) {
+            eprintln!("Monitor {}: {}x{}+{}+{}, {}x{} mm, scale {}{}", o.name, o.rect.w, o.rect.h,
+                      o.rect.x, o.rect.y, o.mm_size.0, o.mm_size.1, o.scale(),
+                      if o.primary { " (primary)" } else { "" });
         }
     }
 
-    0
-}
+    let search = matches.opt_present("class") || matches.opt_present("name")
+                 || matches.opt_present("pid");
+    if !search && matches.opt_present("wait-for-window") {
+        eprintln!("--wait-for-window requires --class, --name or --pid");
+        return 1;
+    }
+    if search && matches.opt_present("i") {
+        eprintln!("--class, --name and --pid cannot be combined with -i");
+        return 1;
+    }
+    // Failing with the exit status to return
+    let resolve_window = |s: &str| -> Result<xlib::Window, i32> {
+        match s {
+            // Capture the client rather than the WM frame that was clicked
+            "pick" => match display.pick_window() {
+                Some(w) => Ok(display.get_client_window(w).unwrap_or(w)),
+                None => {
+                    eprintln!("No window picked");
+                    Err(EXIT_CANCELLED)
+                },
+            },
+            // Set by EWMH window managers to the client window, not its frame
+            "active" => match display.get_property_ids(root, "_NET_ACTIVE_WINDOW").first() {
+                Some(&w) if w != 0 => Ok(w),
+                _ => {
+                    eprintln!("No active window, or the window manager does not report it");
+                    Err(EXIT_WINDOW)
+                },
+            },
+            "under-cursor" => match display.get_window_under_pointer() {
+                Some(w) => Ok(display.get_client_window(w).unwrap_or(w)),
+                None => {
+                    eprintln!("No window under the pointer");
+                    Err(EXIT_WINDOW)
+                },
+            },
+            "root" => Ok(root),
+            _ => parse_window_id(s).ok_or_else(|| {
+                eprintln!("The special values root, active, pick and under-cursor are also \
+                          accepted");
+                1
+            }),
+        }
+    };
 
-fn main() {
-    process::exit(run());
+    // Every window given with -i, laid out on a grid
+    let montage = match matches.opt_default("montage", "0").map(|s| s.parse::<u32>()) {
+        Some(Ok(columns)) => {
+            let conflicting = ["geometry", "select", "window-rect", "between", "center-fraction",
+                               "screen", "all-screens", "frame", "trim-extents", "composite",
+                               "with-shadow", "with-transients", "with-popups", "cursor",
+                               "exclude-child", "hide-docks", "include-classes", "exclude-classes",
+                               "exclude-top", "inhibit-compositor-effects", "on-change"];
+            if let Some(o) = conflicting.iter().find(|&&o| matches.opt_present(o)) {
+                eprintln!("--montage cannot be combined with --{}", o);
+                return 1;
+            }
+            let mut windows = Vec::new();
+            for s in matches.opt_strs("i") {
+                match resolve_window(&s) {
+                    Ok(w) if w == root => {
+                        eprintln!("The root window cannot be part of a montage");
+                        return 1;
+                    },
+                    Ok(w) => windows.push(w),
+                    Err(status) => return status,
+                }
+            }
+            if windows.len() < 2 {
+                eprintln!("--montage needs at least two windows given with -i");
+                return 1;
+            }
+            // All in one row by default
+            let columns = if columns == 0 { windows.len() as u32 } else { columns };
+            Some((windows, columns))
+        },
+        Some(Err(_)) => {
+            eprintln!("Montage column count is not a valid integer");
+            return 1;
+        },
+        None if matches.opt_count("i") > 1 => {
+            eprintln!("Only --montage takes several windows");
+            return 1;
+        },
+        None => None,
+    };
+    let montage_spacing = match matches.opt_str("montage-spacing").map(|s| s.parse::<u32>()) {
+        Some(Ok(px)) => px,
+        Some(Err(_)) => {
+            eprintln!("Montage spacing is not a valid integer");
+            return 1;
+        },
+        None => 0,
+    };
+
+    let window = match matches.opt_str("i") {
+        Some(s) => match resolve_window(&s) {
+            Ok(w) => w,
+            Err(status) => return status,
+        },
+        None if search => {
+            let nth = match matches.opt_str("nth").map(|s| s.parse::<usize>()) {
+                Some(Ok(n)) => n,
+                Some(Err(_)) => {
+                    eprintln!("Match index is not a valid integer");
+                    return 1;
+                },
+                None => 0,
+            };
+            let pid = match matches.opt_str("pid").map(|s| s.parse::<xlib::XID>()) {
+                Some(Ok(p)) => Some(p),
+                Some(Err(_)) => {
+                    eprintln!("PID is not a valid integer");
+                    return 1;
+                },
+                None => None,
+            };
+            let class = matches.opt_str("class").map(|c| c.to_lowercase());
+            let name = matches.opt_str("name").map(|n| n.to_lowercase());
+
+            let find = || display.get_children(root).into_iter().rev()
+                .filter(|&top| display.is_viewable(top))
+                .map(|top| display.get_client_window(top).unwrap_or(top))
+                .filter(|&c| class.as_ref().is_none_or(|class| {
+                    display.get_class(c).is_some_and(|(i, k)| {
+                        i.to_lowercase() == *class || k.to_lowercase() == *class
+                    })
+                }))
+                .filter(|&c| name.as_ref().is_none_or(|name| {
+                    display.get_name(c).is_some_and(|n| n.to_lowercase().contains(name))
+                }))
+                .filter(|&c| pid.is_none_or(|pid| {
+                    display.get_property_ids(c, "_NET_WM_PID").first() == Some(&pid)
+                }))
+                .nth(nth);
+
+            // No timeout unless one is given
+            let wait = match matches.opt_default("wait-for-window", "") {
+                Some(s) if s.is_empty() => Some(None),
+                Some(s) => match parse_seconds(&s) {
+                    Some(t) => Some(Some(t)),
+                    None => {
+                        eprintln!("Window wait timeout must be a non-negative number of seconds");
+                        return 1;
+                    },
+                },
+                None => None,
+            };
+            let matched = match wait {
+                Some(timeout) => {
+                    // Watching first, not to miss a window mapped while looking
+                    display.watch_maps(root, true);
+                    // One too far ahead to be represented is as good as none
+                    let deadline = timeout.and_then(|t| time::Instant::now().checked_add(t));
+                    let matched = loop {
+                        if let Some(w) = find() {
+                            break Some(w);
+                        }
+                        // Titles and classes may be set after mapping, look again now and then
+                        let mut left = time::Duration::from_millis(250);
+                        if let Some(d) = deadline {
+                            match d.checked_duration_since(time::Instant::now()) {
+                                Some(l) => left = left.min(l),
+                                None => break None,
+                            }
+                        }
+                        display.wait_for_map(left);
+                    };
+                    // Not to queue up events for a daemon
+                    display.watch_maps(root, false);
+                    matched
+                },
+                None => find(),
+            };
+            match matched {
+                Some(w) => w,
+                None if wait.is_some() => {
+                    eprintln!("No matching window appeared in time");
+                    return EXIT_WINDOW;
+                },
+                None => {
+                    eprintln!("No matching window found");
+                    return EXIT_WINDOW;
+                },
+            }
+        },
+        None => root,
+    };
+
+    // Capture the area covered by a window from the root, including anything on top of it
+    let geometry_window = match matches.opt_str("window-rect") {
+        Some(_) if matches.opt_present("i") || matches.opt_present("g") => {
+            eprintln!("--window-rect cannot be combined with -i or -g");
+            return 1;
+        },
+        Some(s) => match parse_window_id(&s) {
+            Some(w) => Some(w),
+            None => return 1,
+        },
+        None => None,
+    };
+
+    // Capture both windows and everything in between from the root
+    let between = match matches.opt_str("between") {
+        Some(_) if matches.opt_present("i") || matches.opt_present("g")
+                   || geometry_window.is_some() => {
+            eprintln!("--between cannot be combined with -i, -g or --window-rect");
+            return 1;
+        },
+        Some(s) => match s.split_once(',') {
+            Some((a, b)) => match (parse_window_id(a.trim()), parse_window_id(b.trim())) {
+                (Some(a), Some(b)) => Some((a, b)),
+                _ => return 1,
+            },
+            None => {
+                eprintln!("--between expects two window IDs separated by a comma");
+                return 1;
+            },
+        },
+        None => None,
+    };
+
+    // Reparenting window managers put clients in a frame window holding the decorations, others
+    // draw them around the client and report their size
+    let frame = matches.opt_present("frame");
+    if frame && (window == root || matches.opt_present("g")) {
+        eprintln!("--frame requires a window to be selected with -i, and captures all of it");
+        return 1;
+    }
+    let (window, frame_rect) = if !frame {
+        (window, None)
+    } else {
+        match display.get_top_level(window) {
+            top if top != window => (top, None),
+            _ => match display.get_property_ids(window, "_NET_FRAME_EXTENTS")[..] {
+                [left, right, top, bottom] => {
+                    let r = display.get_window_rect(window);
+                    (root, Some(util::Rect {
+                        x: r.x - left as i32,
+                        y: r.y - top as i32,
+                        w: r.w + (left + right) as i32,
+                        h: r.h + (top + bottom) as i32,
+                    }))
+                },
+                _ => {
+                    warn!("Window 0x{:x} has no frame, capturing it alone", window);
+                    (window, None)
+                },
+            },
+        }
+    };
+
+    let shadow_margin = match matches.opt_str("shadow-margin").map(|s| s.parse::<i32>()) {
+        _ if !matches.opt_present("with-shadow") => None,
+        Some(Ok(m)) if m >= 0 => Some(m),
+        Some(_) => {
+            eprintln!("Shadow margin must be a non-negative integer");
+            return 1;
+        },
+        None => Some(32),
+    };
+    if shadow_margin.is_some() && (window == root || matches.opt_present("g")) {
+        eprintln!("--with-shadow requires a window to be selected with -i, and captures all of it");
+        return 1;
+    }
+    // The shadow lies outside of the window, so it can only be seen from the root
+    let (window, shadow_window) = match shadow_margin {
+        Some(_) => (root, Some(window)),
+        None => (window, None),
+    };
+
+    let output_ext = matches.opt_str("f").unwrap_or("png".to_string()).to_lowercase();
+
+    // Terminal image protocols always go to stdout, kitty transmits PNG data
+    let terminal = match output_ext.as_ref() {
+        "sixel" => Some(terminal::Protocol::Sixel),
+        "kitty" => Some(terminal::Protocol::Kitty),
+        _ => None,
+    };
+    if let Some(t) = terminal {
+        if outputs.iter().any(|p| p != "-") {
+            eprintln!("The {} format can only be written to stdout", output_ext);
+            return 1;
+        }
+        if !t.is_supported() {
+            warn!("Warning: this terminal does not seem to support {} images", output_ext);
+        }
+    }
+    let stream = matches.opt_present("stream");
+    if stream && (outputs.iter().any(|p| p != "-") || !["raw", "y4m"].contains(&&*output_ext)) {
+        eprintln!("--stream only writes raw or y4m frames to stdout");
+        return 1;
+    }
+    let to_stdout = terminal.is_some() || stream || outputs.iter().any(|p| p == "-");
+
+    if format_info(&output_ext).is_none() {
+        eprintln!("Invalid image format specified");
+        return 1;
+    }
+    // Without -f, files are written in the format their extension names
+    let explicit_format = matches.opt_present("f");
+    let sink_ext = |sink: &Sink| match sink {
+        Sink::File(path) if !explicit_format => {
+            format_from_path(path).unwrap_or_else(|| output_ext.clone())
+        },
+        _ => output_ext.clone(),
+    };
+    let mut formats = vec![output_ext.clone()];
+    formats.extend(outputs.iter().filter(|p| *p != "-")
+                   .map(|p| sink_ext(&Sink::File(p.clone()))));
+
+    let quality = match matches.opt_str("quality").map(|s| s.parse::<u8>()) {
+        Some(Ok(q)) if (1..=100).contains(&q) => q,
+        Some(_) => {
+            eprintln!("Quality must be an integer between 1 and 100");
+            return 1;
+        },
+        None => 90,
+    };
+    let png_compression = match matches.opt_str("png-compression").as_deref() {
+        Some("fast") => png::Compression::Fast,
+        Some("default") | None => png::Compression::Default,
+        Some("best") => png::Compression::Best,
+        Some(_) => {
+            eprintln!("Invalid PNG compression level, expected fast, default or best");
+            return 1;
+        },
+    };
+    let png_filter = match matches.opt_str("png-filter").as_deref() {
+        Some("none") => png::FilterType::NoFilter,
+        Some("sub") | None => png::FilterType::Sub,
+        Some("up") => png::FilterType::Up,
+        Some("avg") => png::FilterType::Avg,
+        Some("paeth") => png::FilterType::Paeth,
+        Some(_) => {
+            eprintln!("Invalid PNG filter, expected none, sub, up, avg or paeth");
+            return 1;
+        },
+    };
+    let threads = match matches.opt_str("threads").map(|s| s.parse::<usize>()) {
+        Some(Ok(n)) if n > 0 => n,
+        None => 1,
+        Some(_) => {
+            eprintln!("Invalid thread count, expected a positive integer");
+            return 1;
+        },
+    };
+
+    let sixteen_bit = matches.opt_present("16-bit");
+    let deep_formats = ["png", "pam", "tif", "tiff", "ff"];
+    if sixteen_bit && (formats.iter().any(|f| !deep_formats.contains(&f.as_str()))
+                       || matches.opt_present("palette") || matches.opt_present("quantize")) {
+        eprintln!("--16-bit only applies to PNG, PAM, TIFF and farbfeld output without a palette");
+        return 1;
+    }
+
+    let grayscale = match matches.opt_default("grayscale", "8").map(|s| s.parse::<u8>()) {
+        Some(Ok(bits)) if bits == 1 || bits == 8 => Some(bits),
+        None => None,
+        Some(_) => {
+            eprintln!("Invalid gray depth, expected 1 or 8");
+            return 1;
+        },
+    };
+    if grayscale.is_some() && (sixteen_bit || matches.opt_present("palette")
+                               || matches.opt_present("quantize")) {
+        eprintln!("--grayscale cannot be combined with --16-bit, --palette or --quantize");
+        return 1;
+    }
+    let dither = matches.opt_present("dither");
+    if dither && grayscale != Some(1) {
+        eprintln!("--dither only applies to --grayscale=1");
+        return 1;
+    }
+
+    let fps = match matches.opt_str("fps").map(|s| s.parse::<u32>()) {
+        Some(Ok(f)) if f > 0 => f,
+        Some(_) => {
+            eprintln!("Frame rate must be a positive integer");
+            return 1;
+        },
+        None if matches.opt_present("record") => 10,
+        None => 30,
+    };
+    // Set once the first frame of a stream is out, so later ones skip the header
+    let streaming = Cell::new(false);
+    let report = RefCell::new(Report::default());
+
+    let if_changed = matches.opt_str("if-changed");
+    if if_changed.is_some() && (stream || matches.opt_present("record")) {
+        eprintln!("--if-changed cannot be combined with --stream or --record");
+        return 1;
+    }
+    let threshold = match matches.opt_str("threshold")
+        .map(|s| s.trim_end_matches('%').parse::<f64>()) {
+        Some(Ok(t)) if (0.0..=100.0).contains(&t) => t / 100.0,
+        Some(_) => {
+            eprintln!("Threshold must be a percentage between 0 and 100");
+            return 1;
+        },
+        None => 0.0,
+    };
+    // Image the next capture is compared against, the reference file until one is written
+    let last_capture: RefCell<Option<RgbaImage>> = RefCell::new(None);
+    // Captures for --webp-anim, along with when they were taken
+    let webp_anim = matches.opt_str("webp-anim");
+    let anim_frames: RefCell<Vec<(webp::Frame, time::Instant)>> = RefCell::new(Vec::new());
+
+    // WebP is lossless unless a quality is asked for
+    let lossy_webp = matches.opt_present("quality");
+    let background = match matches.opt_str("background") {
+        Some(c) => match palette::parse_hex_color(&c) {
+            Some([r, g, b, _]) => Rgba([r, g, b, 0xFF]),
+            None => {
+                eprintln!("Invalid background color, expected RRGGBB");
+                return 1;
+            },
+        },
+        None => Rgba([0xFF, 0xFF, 0xFF, 0xFF]),
+    };
+    // A background given explicitly also applies to formats with alpha
+    let flatten_alpha = matches.opt_present("background") && !matches.opt_present("keep-alpha");
+
+    let palette = match matches.opt_str("palette") {
+        Some(p) => match palette::Palette::load(Path::new(&p)) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                eprintln!("Failed to load palette {}: {}", p, e);
+                return 1;
+            }
+        },
+        None => None,
+    };
+    // Without a count, quantize down to as many colors as PNG palettes hold
+    let quantize = match matches.opt_default("quantize", "256").map(|s| s.parse::<usize>()) {
+        Some(Ok(n)) if (2..=256).contains(&n) => Some(n),
+        Some(_) => {
+            eprintln!("Quantize color count must be an integer between 2 and 256");
+            return 1;
+        },
+        None => None,
+    };
+    if quantize.is_some() && palette.is_some() {
+        eprintln!("--quantize and --palette cannot be used together");
+        return 1;
+    }
+    let palette_space = match matches.opt_str("palette-space") {
+        Some(s) => match palette::ColorSpace::parse(&s) {
+            Some(s) => s,
+            None => {
+                eprintln!("Invalid palette color space specified");
+                return 1;
+            }
+        },
+        None => palette::ColorSpace::Rgb,
+    };
+
+    // Middle of the monitor the pointer is on
+    let center = match matches.opt_str("center-fraction").map(|s| s.parse::<f64>()) {
+        Some(_) if matches.opt_present("i") || matches.opt_present("g")
+                   || geometry_window.is_some() || between.is_some() => {
+            eprintln!("--center-fraction cannot be combined with -i, -g, --window-rect or \
+                       --between");
+            return 1;
+        },
+        Some(Ok(f)) if f > 0.0 && f <= 1.0 => {
+            let (x, y) = display.query_pointer();
+            let pointer = util::Rect { x, y, w: 1, h: 1 };
+            let monitor = display.get_screen_rects(root).and_then(|mut screens| {
+                screens.find(|s| s.intersection(pointer).is_some())
+            }).unwrap_or_else(|| display.get_window_rect(root));
+
+            let w = (monitor.w as f64 * f).round() as i32;
+            let h = (monitor.h as f64 * f).round() as i32;
+            Some(util::Rect {
+                x: monitor.x + (monitor.w - w) / 2,
+                y: monitor.y + (monitor.h - h) / 2,
+                w,
+                h,
+            })
+        },
+        Some(_) => {
+            eprintln!("Center fraction must be a number between 0 and 1");
+            return 1;
+        },
+        None => None,
+    };
+
+    // Fixed size area centered on the pointer, kept on the monitor it is on
+    let around = match matches.opt_str("around-cursor") {
+        Some(_) if matches.opt_present("i") || matches.opt_present("g")
+                   || geometry_window.is_some() || between.is_some() || center.is_some() => {
+            eprintln!("--around-cursor cannot be combined with -i, -g, --window-rect, --between \
+                       or --center-fraction");
+            return 1;
+        },
+        Some(s) => {
+            let size = s.split_once('x').and_then(|(w, h)| {
+                Some((w.parse::<i32>().ok()?, h.parse::<i32>().ok()?))
+            }).filter(|&(w, h)| w > 0 && h > 0);
+            let (w, h) = match size {
+                Some(s) => s,
+                None => {
+                    eprintln!("Invalid size around the cursor, expected WxH");
+                    return 1;
+                },
+            };
+            let (x, y) = display.query_pointer();
+            let monitor = display.get_screen_rects(root).and_then(|mut screens| {
+                screens.find(|s| s.contains(x, y))
+            }).unwrap_or_else(|| display.get_window_rect(root));
+
+            Some(util::Rect { x: x - w / 2, y: y - h / 2, w, h }.clamp_to(monitor))
+        },
+        None => None,
+    };
+
+    // A whole monitor, by RandR output name or by its position among the active outputs
+    let screen = match matches.opt_str("screen") {
+        Some(_) if matches.opt_present("i") || matches.opt_present("g")
+                   || geometry_window.is_some() || between.is_some() || center.is_some()
+                   || around.is_some() => {
+            eprintln!("--screen cannot be combined with -i, -g, --window-rect, --between, \
+                       --center-fraction or --around-cursor");
+            return 1;
+        },
+        Some(s) => {
+            let outputs = shotgun::monitors(display);
+            let output = match s.parse::<usize>() {
+                Ok(i) => outputs.get(i),
+                Err(_) => outputs.iter().find(|o| o.name == s),
+            };
+            match output {
+                Some(o) => Some((o.rect, o.rotation)),
+                None => {
+                    eprintln!("No active monitor named or numbered {}", s);
+                    return 1;
+                },
+            }
+        },
+        None => None,
+    };
+    let (screen, screen_rotation) = (screen.map(|s| s.0), screen.map_or(0, |s| s.1));
+    if matches.opt_present("natural-orientation") && screen.is_none() {
+        eprintln!("--natural-orientation only applies to --screen");
+        return 1;
+    }
+
+    // Still image of the screen that selecting is done on, and that is then captured from
+    // Darkening the screen outside the selection is done on such an image too
+    let dim = matches.opt_present("dim");
+    let frozen = if matches.opt_present("freeze") || dim {
+        if !matches.opt_present("s") || matches.opt_present("i") {
+            eprintln!("--freeze and --dim only apply to --select on the root window");
+            return 1;
+        }
+        if matches.opt_present("stream") || matches.opt_present("record")
+           || matches.opt_present("on-change") || matches.opt_present("hold")
+           || matches.opt_present("every") {
+            eprintln!("--freeze and --dim cannot be combined with --stream, --record, \
+                       --on-change, --hold or --every");
+            return 1;
+        }
+        let r = display.get_window_rect(root);
+        match display.get_image(root, util::Rect { x: 0, y: 0, w: r.w, h: r.h },
+                                xwrap::ALL_PLANES, xlib::ZPixmap) {
+            Some(i) => Some(i),
+            None => {
+                eprintln!("Failed to get image from X");
+                return EXIT_CAPTURE;
+            },
+        }
+    } else {
+        None
+    };
+
+    let selection = if matches.opt_present("s") {
+        if matches.opt_present("g") || geometry_window.is_some() || between.is_some()
+           || center.is_some() || around.is_some() || screen.is_some() {
+            eprintln!("--select cannot be combined with -g, --window-rect, --between, \
+                       --center-fraction, --around-cursor or --screen");
+            return 1;
+        }
+        let snap = match matches.opt_default("snap", "8").map(|s| s.parse::<i32>()) {
+            Some(Ok(px)) if px >= 0 => px,
+            Some(_) => {
+                eprintln!("Snapping distance must be a non-negative integer");
+                return 1;
+            },
+            None => 0,
+        };
+        let ratio = match matches.opt_str("ratio") {
+            Some(s) => match s.split_once(':').and_then(|(w, h)| {
+                Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?))
+            }).filter(|&(w, h)| w > 0 && h > 0) {
+                Some(r) => Some(r),
+                None => {
+                    eprintln!("Invalid aspect ratio, expected W:H");
+                    return 1;
+                },
+            },
+            None => None,
+        };
+        let fixed = match matches.opt_str("fixed") {
+            Some(s) => match s.split_once('x').and_then(|(w, h)| {
+                Some((w.parse::<i32>().ok()?, h.parse::<i32>().ok()?))
+            }).filter(|&(w, h)| w > 0 && h > 0) {
+                Some((w, h)) => {
+                    let root_rect = display.get_window_rect(root);
+                    if w > root_rect.w || h > root_rect.h {
+                        eprintln!("The --fixed rectangle does not fit on the screen");
+                        return 1;
+                    }
+                    Some((w, h))
+                },
+                None => {
+                    eprintln!("Invalid selection size, expected WxH");
+                    return 1;
+                },
+            },
+            None => None,
+        };
+        let shape = match (ratio, fixed) {
+            (Some(_), Some(_)) => {
+                eprintln!("--ratio and --fixed cannot be combined");
+                return 1;
+            },
+            (Some((w, h)), None) => xwrap::Shape::Ratio(w, h),
+            (None, Some((w, h))) => xwrap::Shape::Fixed(w, h),
+            (None, None) => xwrap::Shape::Free,
+        };
+        let cover = frozen.as_ref().map(|i| display.freeze(i, dim));
+        match display.select_rect(snap, cover.as_ref(), shape) {
+            Some(r) => Some(r),
+            None => {
+                eprintln!("No area selected");
+                return EXIT_CANCELLED;
+            },
+        }
+    } else if matches.opt_present("ratio") || matches.opt_present("fixed") {
+        eprintln!("--ratio and --fixed only apply to --select");
+        return 1;
+    } else {
+        None
+    };
+
+    let window_rect = display.get_window_rect(window);
+    let root_rect = display.get_window_rect(root);
+    let mut geometries = Vec::new();
+    for s in matches.opt_strs("g") {
+        // Selection tools print nothing when cancelled, which is told apart with its own status
+        let s = if s == "-" {
+            let mut line = String::new();
+            if let Err(e) = io::stdin().read_line(&mut line) {
+                eprintln!("Failed to read the geometry from stdin: {}", e);
+                return EXIT_IO;
+            }
+            if line.trim().is_empty() {
+                eprintln!("No geometry on stdin, the selection was likely cancelled");
+                return EXIT_CANCELLED;
+            }
+            line.trim().to_string()
+        } else {
+            s
+        };
+        // Geometries are relative to the root unless asked otherwise, or given as fractions of
+        // the target
+        let relative = matches.opt_present("relative") || s.contains('%');
+        let within = if relative { window_rect } else { root_rect };
+        let s = match resolve_percentages(&s, (within.w, within.h)) {
+            Some(s) => s,
+            None => {
+                eprintln!("Invalid percentage in geometry {}", s);
+                return 1;
+            },
+        };
+        let g = match util::Rect::parse(&s, (within.w, within.h)) {
+            Ok(g) => g,
+            Err(e) => {
+                eprintln!("Invalid geometry {}: {}", s, e);
+                return 1;
+            },
+        };
+        geometries.push(if relative { g.translate(window_rect.x, window_rect.y) } else { g });
+    }
+    let geometry = match (geometry_window, between, shadow_window.zip(shadow_margin)) {
+        (Some(w), _, _) => Some(display.get_window_rect(w)),
+        (_, Some((a, b)), _) => {
+            Some(display.get_window_rect(a).union(display.get_window_rect(b)))
+        },
+        (_, _, Some((w, m))) => {
+            let r = display.get_window_rect(w);
+            Some(util::Rect {
+                x: r.x - m,
+                y: r.y - m,
+                w: r.w + 2 * m,
+                h: r.h + 2 * m,
+            })
+        },
+        _ if frame_rect.is_some() => frame_rect,
+        _ if center.is_some() => center,
+        _ if around.is_some() => around,
+        _ if screen.is_some() => screen,
+        _ if selection.is_some() => selection,
+        // Several areas are captured together and cut apart afterwards
+        _ => geometries.iter().copied().reduce(|a, b| a.union(b)),
+    };
+    // Windows given by ID may not exist, or be gone by now
+    if let Some(e) = display.take_error() {
+        eprintln!("X error while looking up windows: {}", e);
+        return EXIT_WINDOW;
+    }
+    let sel = match geometry {
+        Some(g) => match g.intersection(window_rect) {
+            // Selection is relative to the root window (whole screen)
+            Some(sel) => sel.translate(-window_rect.x, -window_rect.y),
+            None => {
+                eprintln!("Invalid geometry");
+                return EXIT_GEOMETRY;
+            },
+        },
+        None => util::Rect {
+            x: 0,
+            y: 0,
+            w: window_rect.w,
+            h: window_rect.h,
+        },
+    };
+
+    // GTK windows that draw their own decorations leave room around them for their shadow
+    let trim_extents = matches.opt_present("trim-extents");
+    if trim_extents && window == root {
+        eprintln!("--trim-extents requires a window to be selected with -i");
+        return 1;
+    }
+    let sel = if trim_extents {
+        match display.get_property_ids(window, "_GTK_FRAME_EXTENTS")[..] {
+            [left, right, top, bottom] => {
+                let inner = util::Rect {
+                    x: left as i32,
+                    y: top as i32,
+                    w: window_rect.w - (left + right) as i32,
+                    h: window_rect.h - (top + bottom) as i32,
+                };
+                match sel.intersection(inner) {
+                    Some(s) => s,
+                    None => {
+                        eprintln!("Nothing is left to capture within the frame extents");
+                        return EXIT_GEOMETRY;
+                    },
+                }
+            },
+            _ => {
+                warn!("Window 0x{:x} has no _GTK_FRAME_EXTENTS, not trimming", window);
+                sel
+            },
+        }
+    } else {
+        sel
+    };
+
+    let sel = match matches.opt_str("pad").map(|s| s.parse::<i32>()) {
+        Some(Ok(p)) => {
+            let padded = util::Rect {
+                x: sel.x - p,
+                y: sel.y - p,
+                w: sel.w + 2 * p,
+                h: sel.h + 2 * p,
+            };
+            let bounds = util::Rect { x: 0, y: 0, w: window_rect.w, h: window_rect.h };
+            match Some(padded).filter(|r| r.w > 0 && r.h > 0)
+                .and_then(|r| r.intersection(bounds)) {
+                Some(s) => s,
+                None => {
+                    eprintln!("Nothing is left to capture within the padding");
+                    return EXIT_GEOMETRY;
+                },
+            }
+        },
+        Some(Err(_)) => {
+            eprintln!("Padding is not a valid integer");
+            return 1;
+        },
+        None => sel,
+    };
+
+    let redact_classes: Vec<String> = matches.opt_strs("redact-class").iter()
+        .map(|c| c.to_lowercase()).collect();
+    if !redact_classes.is_empty() && window != root {
+        eprintln!("--redact-class only applies to root captures");
+        return 1;
+    }
+    let redact_style = match matches.opt_str("redact-style").as_deref() {
+        Some("black") | None => "black",
+        Some("blur") => "blur",
+        Some("pixelate") => "pixelate",
+        Some(_) => {
+            eprintln!("Invalid redaction style, expected black, blur or pixelate");
+            return 1;
+        },
+    };
+
+    // Areas to hide, relative to the capture
+    let mut redactions = Vec::new();
+    for (name, s) in matches.opt_strs("blur").into_iter().map(|s| ("blur", s))
+        .chain(matches.opt_strs("pixelate").into_iter().map(|s| ("pixelate", s))) {
+        let r = resolve_percentages(&s, (sel.w, sel.h))
+            .ok_or_else(|| "invalid percentage".to_string())
+            .and_then(|g| util::Rect::parse(&g, (sel.w, sel.h)));
+        match r {
+            Ok(r) => redactions.push((name == "blur", r)),
+            Err(e) => {
+                eprintln!("Invalid --{} geometry {}: {}", name, s, e);
+                return 1;
+            },
+        }
+    }
+    let redact_size = |name, default| match matches.opt_str(name).map(|s| s.parse::<u32>()) {
+        Some(Ok(v)) if v > 0 => Ok(v),
+        Some(_) => Err(()),
+        None => Ok(default),
+    };
+    let (blur_radius, pixel_size) = match (redact_size("blur-radius", 12),
+                                           redact_size("pixel-size", 16)) {
+        (Ok(r), Ok(s)) => (r as f32, s),
+        _ => {
+            eprintln!("Blur radius and pixel size must be positive integers");
+            return 1;
+        },
+    };
+
+    match matches.opt_str("max-area").map(|s| s.parse::<u64>()) {
+        Some(Ok(max)) if sel.w as u64 * sel.h as u64 > max => {
+            eprintln!("Capture area of {}x{} exceeds the limit of {} pixels", sel.w, sel.h, max);
+            return EXIT_GEOMETRY;
+        },
+        Some(Err(_)) => {
+            eprintln!("Maximum area is not a valid integer");
+            return 1;
+        },
+        _ => (),
+    }
+
+    if matches.opt_present("print-im-geometry") {
+        if to_stdout {
+            eprintln!("Cannot print the geometry when writing the image to stdout");
+            return 1;
+        }
+        // ImageMagick crop geometry, relative to the root window
+        println!("{}x{}{:+}{:+}", sel.w, sel.h, sel.x + window_rect.x, sel.y + window_rect.y);
+    }
+
+    let split_classes = |name| matches.opt_str(name).map(|s| {
+        s.split(',').map(|c| c.to_lowercase()).collect::<Vec<_>>()
+    });
+    let include_classes = split_classes("include-classes");
+    let exclude_classes = split_classes("exclude-classes");
+    let exclude_top = match matches.opt_str("exclude-top").map(|s| s.parse::<usize>()) {
+        Some(Ok(n)) => Some(n),
+        Some(Err(_)) => {
+            eprintln!("Number of top-most windows to leave out is not a valid integer");
+            return 1;
+        },
+        None => None,
+    };
+
+    let autocrop = match matches.opt_str("autocrop-tolerance").map(|s| s.parse::<u8>()) {
+        _ if !matches.opt_present("autocrop") => None,
+        Some(Ok(t)) => Some(t),
+        Some(Err(_)) => {
+            eprintln!("Autocrop tolerance must be an integer between 0 and 255");
+            return 1;
+        },
+        None => Some(16),
+    };
+
+    let watermark = match matches.opt_str("watermark") {
+        Some(w) => match draw::Watermark::load(&w) {
+            Ok(w) => Some(w),
+            Err(e) => {
+                eprintln!("Failed to load watermark: {}", e);
+                return 1;
+            },
+        },
+        None => None,
+    };
+    let watermark_margin = match matches.opt_str("watermark-margin").map(|s| s.parse::<i32>()) {
+        Some(Ok(m)) => m,
+        Some(Err(_)) => {
+            eprintln!("Watermark margin is not a valid integer");
+            return 1;
+        },
+        None => 16,
+    };
+    let watermark_scale = match matches.opt_str("watermark-scale").map(|s| s.parse::<f64>()) {
+        Some(Ok(s)) if s > 0.0 => Some(s),
+        Some(_) => {
+            eprintln!("Watermark scale must be a positive number");
+            return 1;
+        },
+        None => None,
+    };
+
+    let no_mask = matches.opt_present("no-mask");
+    let normalize_dpi = matches.opt_present("normalize-dpi");
+    if normalize_dpi && (window != root || no_mask || matches.opt_present("bezel")) {
+        eprintln!("--normalize-dpi only applies to masked root captures, without --bezel");
+        return 1;
+    }
+    let mut output_scales = Vec::new();
+    for spec in matches.opt_str("normalize-dpi").iter().flat_map(|s| s.split(',')) {
+        match spec.split_once('=').map(|(o, s)| (o, s.parse::<f64>())) {
+            Some((output, Ok(scale))) if scale.is_finite() && scale > 0.0 => {
+                output_scales.push((output.to_string(), scale));
+            },
+            _ => {
+                eprintln!("Invalid output scale {}, expected OUTPUT=SCALE", spec);
+                return 1;
+            },
+        }
+    }
+    if no_mask && (matches.opt_present("mask-color") || matches.opt_present("bezel")) {
+        eprintln!("--mask-color and --bezel apply to masking, which --no-mask turns off");
+        return 1;
+    }
+    let mask_color = match matches.opt_str("mask-color").as_deref() {
+        Some("checkerboard") => None,
+        Some(c) => match palette::parse_hex_color(c) {
+            Some(c) => Some(Rgba(c)),
+            None => {
+                eprintln!("Invalid mask color, expected RRGGBB, RRGGBBAA or checkerboard");
+                return 1;
+            },
+        },
+        None => Some(Rgba([0, 0, 0, 0])),
+    };
+
+    let bezel = match matches.opt_str("bezel") {
+        Some(b) => {
+            let (width, color) = b.split_once(':').unwrap_or((&b, "000000"));
+            match (width.parse::<u32>(), palette::parse_hex_color(color)) {
+                (Ok(w), Some(c)) => Some((w, Rgba(c))),
+                _ => {
+                    eprintln!("Invalid bezel, expected a width and an optional RRGGBB color");
+                    return 1;
+                },
+            }
+        },
+        None => None,
+    };
+
+    let border = match matches.opt_str("border") {
+        Some(b) => match draw::Border::parse(&b) {
+            Ok(b) => Some(b),
+            Err(e) => {
+                eprintln!("Invalid border: {}", e);
+                return 1;
+            },
+        },
+        None => None,
+    };
+
+    // Windows to blank out, along with the areas and classes to hide
+    let mut excluded = Vec::new();
+    for s in matches.opt_strs("exclude-window") {
+        match resolve_window(&s) {
+            Ok(w) => excluded.push(w),
+            Err(status) => return status,
+        }
+    }
+    if !excluded.is_empty() && window != root {
+        eprintln!("--exclude-window only applies to root captures");
+        return 1;
+    }
+    let redact = |image: &mut RgbaImage, origin: util::Rect| {
+        for &(blur, r) in &redactions {
+            if blur {
+                draw::blur_rect(image, r, blur_radius);
+            } else {
+                draw::pixelate_rect(image, r, pixel_size);
+            }
+        }
+        if redact_classes.is_empty() && excluded.is_empty() {
+            return;
+        }
+
+        // Windows higher in the stack hide their part of the ones below
+        let tops: Vec<_> = display.get_children(root).into_iter()
+            .filter(|&t| display.is_viewable(t)).collect();
+        for (i, &top) in tops.iter().enumerate() {
+            let client = display.get_client_window(top).unwrap_or(top);
+            let (instance, class) = display.get_class(client).unwrap_or_default();
+            let exclude = excluded.contains(&top) || excluded.contains(&client);
+            if !exclude && !redact_classes.contains(&instance.to_lowercase())
+               && !redact_classes.contains(&class.to_lowercase()) {
+                continue;
+            }
+
+            let mut visible = vec![display.get_window_rect(top)];
+            for &above in &tops[i + 1..] {
+                let r = display.get_window_rect(above);
+                visible = visible.iter().flat_map(|v| v.subtract(r)).collect();
+            }
+            for v in visible {
+                let r = v.translate(-origin.x, -origin.y);
+                match redact_style {
+                    _ if exclude => match mask_color {
+                        Some(c) => draw::fill_rect(image, r, c),
+                        None => draw::fill_checkerboard(image, r),
+                    },
+                    "blur" => draw::blur_rect(image, r, blur_radius),
+                    "pixelate" => draw::pixelate_rect(image, r, pixel_size),
+                    _ => draw::fill_rect(image, r, Rgba([0, 0, 0, 255])),
+                }
+            }
+        }
+    };
+
+    let shadow = match matches.opt_default("shadow", "16").map(|s| s.parse::<u32>()) {
+        Some(Ok(r)) if r > 0 => Some(r),
+        Some(_) => {
+            eprintln!("Shadow size must be a positive integer");
+            return 1;
+        },
+        None => None,
+    };
+
+    let dominant = match matches.opt_str("dominant-method") {
+        _ if !matches.opt_present("dominant-color") => None,
+        Some(m) => match palette::DominantMethod::parse(&m) {
+            Some(m) => Some(m),
+            None => {
+                eprintln!("Invalid dominant color method specified");
+                return 1;
+            },
+        },
+        None => Some(palette::DominantMethod::Average),
+    };
+    let dominant_count = match matches.opt_str("dominant-count").map(|s| s.parse::<usize>()) {
+        Some(Ok(n)) if (1..=256).contains(&n) => n,
+        Some(_) => {
+            eprintln!("Dominant color count must be an integer between 1 and 256");
+            return 1;
+        },
+        None => 1,
+    };
+    if dominant.is_some() && to_stdout {
+        eprintln!("Cannot print the dominant color when writing the image to stdout");
+        return 1;
+    }
+
+    let rotate = match matches.opt_str("rotate").map(|s| s.parse::<u32>()) {
+        Some(Ok(d)) if [0, 90, 180, 270].contains(&d) => d,
+        Some(_) => {
+            eprintln!("Rotation must be 90, 180 or 270 degrees");
+            return 1;
+        },
+        None => 0,
+    };
+    let flip = match matches.opt_str("flip").as_deref() {
+        Some("h") => Some(draw::Flip::Horizontal),
+        Some("v") => Some(draw::Flip::Vertical),
+        Some(_) => {
+            eprintln!("Invalid flip, expected h or v");
+            return 1;
+        },
+        None => None,
+    };
+    // Undoing the counterclockwise turn of the monitor comes first
+    let rotate = if matches.opt_present("natural-orientation") {
+        (rotate + 360 - screen_rotation) % 360
+    } else {
+        rotate
+    };
+
+    let resize = match matches.opt_str("resize").map(|s| Resize::parse(&s)) {
+        Some(Some(r)) => Some(r),
+        Some(None) => {
+            eprintln!("Invalid size, expected a percentage such as 50% or a size such as 1280x720");
+            return 1;
+        },
+        None => None,
+    };
+    let resize_filter = match matches.opt_str("resize-filter").as_deref() {
+        Some("nearest") => imageops::FilterType::Nearest,
+        Some("triangle") => imageops::FilterType::Triangle,
+        Some("catmull-rom") => imageops::FilterType::CatmullRom,
+        Some("gaussian") => imageops::FilterType::Gaussian,
+        Some("lanczos") | None => imageops::FilterType::Lanczos3,
+        Some(_) => {
+            eprintln!("Invalid resize filter, expected nearest, triangle, catmull-rom, gaussian \
+                       or lanczos");
+            return 1;
+        },
+    };
+
+    let linear_resize = matches.opt_present("linear-resize");
+    if linear_resize && resize.is_none() && !matches.opt_present("sizes") {
+        eprintln!("--linear-resize only applies to --resize and --sizes");
+        return 1;
+    }
+    let scale = |image: &RgbaImage, w, h, filter| if linear_resize {
+        draw::resize_linear(image, w, h, filter)
+    } else {
+        imageops::resize(image, w, h, filter)
+    };
+
+    // Scale factors to write out, along with the file name suffix for each
+    let sizes = match matches.opt_str("sizes") {
+        Some(s) => {
+            let sizes = s.split(',').map(|f| {
+                let factor = f.trim_end_matches('x').parse::<f64>().ok().filter(|&f| f > 0.0)?;
+                Some((factor, if factor == 1.0 { String::new() } else { format!("@{}", f) }))
+            }).collect::<Option<Vec<_>>>();
+            match sizes {
+                Some(s) => s,
+                None => {
+                    eprintln!("Invalid size list, expected scale factors such as 1x,0.5x");
+                    return 1;
+                },
+            }
+        },
+        None => vec![(1.0, String::new())],
+    };
+    if sizes.len() > 1 && to_stdout {
+        eprintln!("Multiple sizes cannot be written to stdout");
+        return 1;
+    }
+
+    // Bounds of the thumbnail, and where to write it rather than next to each file
+    let thumb = match matches.opt_str("thumb") {
+        Some(s) => {
+            let (size, template) = match s.split_once(':') {
+                Some((size, t)) => (size, Some(t.to_string())),
+                None => (s.as_str(), None),
+            };
+            let size = size.split_once('x').and_then(|(w, h)| {
+                Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?))
+            }).filter(|&(w, h)| w > 0 && h > 0);
+            match size {
+                Some((w, h)) => Some((w, h, template)),
+                None => {
+                    eprintln!("Invalid thumbnail size, expected WxH");
+                    return 1;
+                },
+            }
+        },
+        None => None,
+    };
+    if thumb.is_some() && to_stdout {
+        eprintln!("Thumbnails cannot be written to stdout");
+        return 1;
+    }
+
+    let sharpen = |name, default| match matches.opt_str(name).map(|s| s.parse::<f32>()) {
+        Some(Ok(v)) if v > 0.0 => Ok(v),
+        Some(_) => Err(()),
+        None => Ok(default),
+    };
+    let sharpen = match (sharpen("sharpen-amount", 0.5), sharpen("sharpen-radius", 1.0)) {
+        _ if !matches.opt_present("sharp-downscale") => None,
+        (Ok(amount), Ok(radius)) => Some((amount, radius)),
+        _ => {
+            eprintln!("Sharpening amount and radius must be positive numbers");
+            return 1;
+        },
+    };
+
+    let split = match matches.opt_str("split") {
+        Some(s) => {
+            let grid = s.split_once('x').and_then(|(r, c)| {
+                Some((r.parse::<u32>().ok()?, c.parse::<u32>().ok()?))
+            }).filter(|&(r, c)| r > 0 && c > 0);
+            match grid {
+                Some(g) => Some(g),
+                None => {
+                    eprintln!("Invalid tile grid, expected ROWSxCOLS");
+                    return 1;
+                },
+            }
+        },
+        None => None,
+    };
+    if split.is_some() && to_stdout {
+        eprintln!("Tiles cannot be written to stdout");
+        return 1;
+    }
+
+    // Parts of the root capture to write out separately, along with the file name suffix for each
+    let crops = if matches.opt_present("all-screens") {
+        if window != root || geometries.len() > 1 {
+            eprintln!("--all-screens captures the root window and cannot be combined with -i or \
+                       multiple geometries");
+            return 1;
+        }
+        if to_stdout {
+            eprintln!("Monitors cannot be written to stdout");
+            return 1;
+        }
+        if autocrop.is_some() || border.is_some() || bezel.is_some() || watermark.is_some() {
+            eprintln!("--all-screens cannot be combined with --autocrop, --border, --bezel or \
+                       --watermark");
+            return 1;
+        }
+        let area = sel.translate(window_rect.x, window_rect.y);
+        let crops: Vec<(util::Rect, String)> = shotgun::monitors(display)
+            .into_iter()
+            .filter_map(|o| Some((o.rect.intersection(area)?, format!("-{}", o.name))))
+            .collect();
+        if crops.is_empty() {
+            eprintln!("No monitor found in the captured area");
+            return EXIT_GEOMETRY;
+        }
+        Some(crops)
+    } else if geometries.len() > 1 {
+        if to_stdout {
+            eprintln!("Multiple geometries cannot be written to stdout");
+            return 1;
+        }
+        if autocrop.is_some() || border.is_some() || bezel.is_some() || watermark.is_some() {
+            eprintln!("Multiple geometries cannot be combined with --autocrop, --border, --bezel \
+                       or --watermark");
+            return 1;
+        }
+        let area = sel.translate(window_rect.x, window_rect.y);
+        let crops: Option<Vec<(util::Rect, String)>> = geometries.iter().enumerate()
+            .map(|(i, g)| Some((g.intersection(area)?, format!("-{}", i))))
+            .collect();
+        match crops {
+            Some(c) => Some(c),
+            None => {
+                eprintln!("Invalid geometry");
+                return EXIT_GEOMETRY;
+            },
+        }
+    } else {
+        None
+    };
+
+    let fd = match matches.opt_str("fd").map(|s| s.parse::<RawFd>()) {
+        Some(Ok(fd)) if unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1 => Some(fd),
+        Some(Ok(fd)) => {
+            eprintln!("File descriptor {} is not open", fd);
+            return EXIT_IO;
+        },
+        Some(Err(_)) => {
+            eprintln!("File descriptor is not a valid integer");
+            return 1;
+        },
+        None => None,
+    };
+    if fd.is_some() && (sizes.len() > 1 || split.is_some() || crops.is_some()) {
+        eprintln!("Only a single image can be written to a file descriptor");
+        return 1;
+    }
+    if fd.is_some() && (stream || matches.opt_present("on-change")
+                        || matches.opt_present("hold") || matches.opt_present("every")) {
+        eprintln!("--fd cannot be combined with --stream, --on-change, --hold or --every");
+        return 1;
+    }
+
+    let clipboard = matches.opt_present("clipboard");
+    if clipboard && (terminal.is_some() || stream || sizes.len() > 1 || split.is_some()
+                     || crops.is_some()) {
+        eprintln!("Only a single image file can be copied to the clipboard");
+        return 1;
+    }
+
+    let existing = if matches.opt_present("unique") {
+        Existing::Rename
+    } else if matches.opt_present("no-clobber") {
+        Existing::Fail
+    } else {
+        Existing::Overwrite
+    };
+    let sync = matches.opt_present("sync");
+    let mkdir = matches.opt_present("mkdir");
+
+    let verify = matches.opt_present("verify");
+    if verify && formats.iter().any(|f| format_info(f).is_some_and(|i| i.1.is_none())) {
+        eprintln!("Only PNG, PAM and BMP output can be verified");
+        return 1;
+    }
+    if verify && sixteen_bit {
+        eprintln!("16-bit output cannot be verified");
+        return 1;
+    }
+    if verify && to_stdout {
+        eprintln!("Output written to stdout cannot be verified");
+        return 1;
+    }
+
+    let json = matches.opt_present("json");
+    if json && to_stdout {
+        eprintln!("Cannot print a JSON result when writing the image to stdout");
+        return 1;
+    }
+
+    // Clients of the daemon always get the paths back, unless they are part of the JSON result
+    let print_path = matches.opt_present("print-path") && !json
+                     || (shared.is_some() && !to_stdout && !json);
+    if print_path && to_stdout {
+        eprintln!("Cannot print file paths when writing the image to stdout");
+        return 1;
+    }
+
+    let exec = matches.opt_str("exec");
+
+    let mime_header = matches.opt_present("mime-header");
+    if mime_header && (!to_stdout || terminal.is_some()) {
+        eprintln!("--mime-header only applies when writing image files to stdout");
+        return 1;
+    }
+
+    let length_prefix = matches.opt_present("length-prefix");
+    if length_prefix && !to_stdout {
+        eprintln!("--length-prefix only applies when writing to stdout");
+        return 1;
+    }
+
+    let from_pixmap = matches.opt_present("composite");
+    let planes = match matches.opt_str("planes").map(|s| util::parse_int::<libc::c_ulong>(&s)) {
+        Some(Ok(mask)) if mask != 0 => Some(mask),
+        None => None,
+        Some(_) => {
+            eprintln!("Invalid plane mask, expected a nonzero integer such as 0x800000");
+            return 1;
+        },
+    };
+    let monochrome = matches.opt_present("monochrome");
+    if (planes.is_some() || monochrome) && (from_pixmap || wayland.is_some()) {
+        eprintln!("--planes and --monochrome read from the X screen, which rules out --composite \
+                   and Wayland capture");
+        return 1;
+    }
+    if from_pixmap && window == root {
+        eprintln!("--composite requires a window to be selected with -i");
+        return 1;
+    }
+    if from_pixmap && !display.has_composite() {
+        eprintln!("--composite requires the Composite extension");
+        return 1;
+    }
+
+    // Window managers either unmap the windows of other desktops, which leaves X with nothing to
+    // capture, or move them out of sight, where only their pixmap still has their contents
+    let from_pixmap = from_pixmap || window != root && {
+        if !display.is_viewable(window) {
+            eprintln!("Window 0x{:x} is not mapped, it may be minimized or on another desktop",
+                      window);
+            return EXIT_WINDOW;
+        }
+
+        let client = display.get_client_window(window).unwrap_or(window);
+        let desktop = display.get_property_ids(client, "_NET_WM_DESKTOP").first().cloned();
+        let current = display.get_property_ids(root, "_NET_CURRENT_DESKTOP").first().cloned();
+        // Sticky windows are on desktop 0xFFFFFFFF
+        let elsewhere = matches!((desktop, current),
+                                 (Some(d), Some(c)) if d != c && d != 0xFFFF_FFFF);
+        let hidden = display.get_screen_rects(root)
+            .is_some_and(|mut s| s.all(|s| s.intersection(window_rect).is_none()));
+
+        if (elsewhere || hidden) && !display.has_composite() {
+            eprintln!("Window 0x{:x} is not on screen, capturing it requires the Composite \
+                       extension", window);
+            return EXIT_WINDOW;
+        }
+        elsewhere || hidden
+    };
+
+    let with_popups = matches.opt_present("with-popups");
+    let with_transients = matches.opt_present("with-transients") || with_popups;
+    if with_transients && matches.opt_present("g") {
+        eprintln!("--with-transients and --with-popups always capture the whole window");
+        return 1;
+    }
+    if with_transients && window == root {
+        eprintln!("--with-transients and --with-popups require a window to be selected with -i");
+        return 1;
+    }
+    let with_transients = with_transients && if display.has_composite() {
+        true
+    } else {
+        warn!("Composite extension not available, capturing without transient windows");
+        false
+    };
+    // The target, its dialogs and, with --with-popups, the menus and tooltips of its
+    // application, told apart by WM_TRANSIENT_FOR, the client leader or the PID
+    let leader = display.get_property_ids(window, "WM_CLIENT_LEADER").first().copied();
+    let pid = display.get_property_ids(window, "_NET_WM_PID").first().copied();
+    let related = |top: xlib::Window| -> Option<xlib::Window> {
+        let client = display.get_client_window(top).unwrap_or(top);
+        if client == window || (display.get_transient_for(client) == Some(window)
+                                && !display.is_override_redirect(top)) {
+            return Some(client);
+        }
+        let same = |property, value: Option<xlib::XID>| {
+            value.is_some() && display.get_property_ids(top, property).first().copied() == value
+        };
+        Some(top).filter(|_| {
+            with_popups && display.is_override_redirect(top)
+            && (display.get_transient_for(top) == Some(window)
+                || same("WM_CLIENT_LEADER", leader) || same("_NET_WM_PID", pid))
+        })
+    };
+    // Bounding box of the target and the windows drawn along
+    let transients_rect = if with_transients {
+        display.get_children(root).into_iter()
+            .filter_map(related)
+            .filter(|&w| display.is_viewable(w))
+            .fold(window_rect, |r, w| r.union(display.get_window_rect(w)))
+    } else {
+        window_rect
+    };
+
+    let skip_blank = match matches.opt_str("skip-blank").map(|s| s.parse::<u32>()) {
+        Some(Ok(n)) => Some(n),
+        Some(Err(_)) => {
+            eprintln!("Blank capture retries is not a valid integer");
+            return 1;
+        },
+        None => None,
+    };
+
+    let retry = match matches.opt_str("retry") {
+        Some(s) => {
+            let (count, delay) = match s.split_once(':') {
+                Some((n, ms)) => (n, Some(ms)),
+                None => (s.as_str(), None),
+            };
+            let delay = delay.map_or(Ok(100), |ms| ms.parse::<u64>());
+            match (count.parse::<u32>(), delay) {
+                (Ok(n), Ok(ms)) => (n, time::Duration::from_millis(ms)),
+                _ => {
+                    eprintln!("Invalid retry policy, expected N or N:MS");
+                    return 1;
+                },
+            }
+        },
+        None => (0, time::Duration::default()),
+    };
+
+    let hover = match matches.opt_str("hover") {
+        Some(s) => {
+            let coords = s.split_once(',').and_then(|(x, y)| {
+                Some((x.trim().parse::<i32>().ok()?, y.trim().parse::<i32>().ok()?))
+            });
+            match coords {
+                Some(c) => Some(c),
+                None => {
+                    eprintln!("Invalid hover position, expected X,Y");
+                    return 1;
+                },
+            }
+        },
+        None => None,
+    };
+    if hover.is_some() && !display.has_xtest() {
+        eprintln!("--hover requires the XTEST extension");
+        return 1;
+    }
+    let hover_delay = match matches.opt_str("hover-delay").map(|s| s.parse::<u64>()) {
+        Some(Ok(ms)) => time::Duration::from_millis(ms),
+        Some(Err(_)) => {
+            eprintln!("Hover delay is not a valid integer");
+            return 1;
+        },
+        None => time::Duration::from_millis(1000),
+    };
+
+    // Pixel position, color, and whether it must match the color (or differ from it)
+    let trigger = match matches.opt_str("trigger-pixel") {
+        Some(s) => {
+            let (pos, color, matching) = match s.split_once("!=") {
+                Some((p, c)) => (p, c, false),
+                None => match s.split_once('=') {
+                    Some((p, c)) => (p, c, true),
+                    None => (s.as_str(), "", true),
+                },
+            };
+            let pos = pos.split_once(',').and_then(|(x, y)| {
+                Some((x.trim().parse::<i32>().ok()?, y.trim().parse::<i32>().ok()?))
+            });
+            match (pos, palette::parse_hex_color(color)) {
+                (Some(p), Some(c)) => Some((p, Rgba(c), matching)),
+                _ => {
+                    eprintln!("Invalid trigger pixel, expected X,Y=RRGGBB or X,Y!=RRGGBB");
+                    return 1;
+                },
+            }
+        },
+        None => None,
+    };
+    let trigger_timeout = match matches.opt_str("trigger-timeout").map(|s| s.parse::<u64>()) {
+        Some(Ok(ms)) => time::Duration::from_millis(ms),
+        Some(Err(_)) => {
+            eprintln!("Trigger timeout is not a valid integer");
+            return 1;
+        },
+        None => time::Duration::from_millis(10000),
+    };
+
+    let hide_docks = matches.opt_present("hide-docks");
+    if hide_docks && window != root {
+        eprintln!("--hide-docks only applies when capturing the root window");
+        return 1;
+    }
+    // Without compositing, paint over the docks instead of leaving them out
+    let mask_docks = hide_docks && !display.has_composite();
+    if mask_docks {
+        warn!("Composite extension not available, painting over docks");
+    }
+    let dock_type = display.intern_atom("_NET_WM_WINDOW_TYPE_DOCK");
+    let is_dock = |window| {
+        display.get_property_ids(window, "_NET_WM_WINDOW_TYPE").contains(&dock_type)
+    };
+
+    // Grab the pixels to process, along with their root-relative position
+    // Profiles are set per screen, as _ICC_PROFILE_n for all but the first, take the one of the
+    // screen covering most of the capture
+    let icc = if matches.opt_present("icc") {
+        let area = sel.translate(window_rect.x, window_rect.y);
+        let screen = display.get_screen_rects(root)
+            .and_then(|screens| {
+                screens.enumerate()
+                    .max_by_key(|(_, s)| s.intersection(area).map_or(0, |r| r.w * r.h))
+            })
+            .map_or(0, |(i, _)| i);
+        let atom = if screen == 0 {
+            "_ICC_PROFILE".to_string()
+        } else {
+            format!("_ICC_PROFILE_{}", screen)
+        };
+
+        let icc = display.get_property_bytes(root, &atom).filter(|p| !p.is_empty());
+        if icc.is_none() {
+            warn!("No ICC profile is set for this screen ({})", atom);
+        }
+        icc
+    } else {
+        None
+    };
+
+    // The image, its root-relative position and, with --16-bit, its full precision version, or
+    // the exit status to fail with
+    let capture_once = || -> Result<(RgbaImage, util::Rect, Option<xwrap::Rgba16Image>), i32> {
+        // Root-relative position of the captured image
+        let mut origin = sel.translate(window_rect.x, window_rect.y);
+
+        if let Some((windows, columns)) = &montage {
+            let mut images = Vec::new();
+            for &w in windows {
+                let (image, _) = shotgun::capture_window(display, backend, w, false)
+                    .map_err(capture_status)?;
+                images.push(image);
+            }
+            // Gaps stay transparent unless a background is given
+            let fill = if matches.opt_present("background") {
+                background
+            } else {
+                Rgba::from_channels(0, 0, 0, 0)
+            };
+            let image = draw::grid(&images, *columns, montage_spacing, fill);
+            origin.w = image.width() as i32;
+            origin.h = image.height() as i32;
+            return Ok((image, origin, None));
+        }
+
+        let mut deep = None;
+
+        let flat = matches.opt_present("inhibit-compositor-effects");
+        let composite = include_classes.is_some() || exclude_classes.is_some()
+                        || exclude_top.is_some() || (hide_docks && !mask_docks);
+        let image = if flat || composite {
+            if window != root {
+                eprintln!("Windows can only be composited when capturing the root window");
+                return Err(1);
+            }
+            if !display.has_composite() {
+                eprintln!("Compositing windows requires the Composite extension");
+                return Err(1);
+            }
+
+            // XQueryTree lists children bottom to top, so the top-most ones come last
+            let mut covering = display.get_children(root);
+            covering.retain(|&w| display.is_viewable(w));
+            let skip = exclude_top.unwrap_or(0).min(covering.len());
+            let covering = &covering[covering.len() - skip..];
+
+            // Frames usually carry no class, match against the client window they manage
+            let filter = |top| {
+                if covering.contains(&top) {
+                    return None;
+                }
+                let client = display.get_client_window(top).unwrap_or(top);
+                let (instance, class) = display.get_class(client).unwrap_or_default();
+                let matched = |list: &Vec<String>| {
+                    list.contains(&instance.to_lowercase()) || list.contains(&class.to_lowercase())
+                };
+                Some(top).filter(|_| include_classes.as_ref().is_none_or(matched)
+                                     && !exclude_classes.as_ref().is_some_and(matched)
+                                     && !(hide_docks && is_dock(client)))
+            };
+            composite_top_level(display, root, sel, &filter)
+        } else if with_transients {
+            // Draw the target and its dialogs, which may stick out of the target
+            origin = transients_rect;
+            composite_top_level(display, root, transients_rect, &related)
+        } else if from_pixmap {
+            let image = match display.get_window_pixmap_image(window, sel) {
+                Some(i) => i,
+                None => {
+                    eprintln!("Failed to get the window contents from X");
+                    return Err(EXIT_CAPTURE);
+                },
+            };
+            let (image, d) = backend::convert(&image, sixteen_bit).map_err(capture_status)?;
+            deep = d;
+            image
+        } else if let Some(frozen) = &frozen {
+            let (mut image, d) = backend::convert(frozen, sixteen_bit).map_err(capture_status)?;
+            let (x, y, w, h) = (sel.x as u32, sel.y as u32, sel.w as u32, sel.h as u32);
+            deep = d.map(|mut d| imageops::crop(&mut d, x, y, w, h).to_image());
+            imageops::crop(&mut image, x, y, w, h).to_image()
+        } else if planes.is_some() || monochrome {
+            let format = if monochrome { xlib::XYPixmap } else { xlib::ZPixmap };
+            let image = match display.get_image(window, sel, planes.unwrap_or(xwrap::ALL_PLANES),
+                                                format) {
+                Some(i) => i,
+                None => {
+                    eprintln!("Failed to get image from X");
+                    return Err(EXIT_CAPTURE);
+                },
+            };
+            if monochrome {
+                image.to_bitmap()
+            } else {
+                let (image, d) = backend::convert(&image, sixteen_bit).map_err(capture_status)?;
+                deep = d;
+                image
+            }
+        } else {
+            // Windows hanging off the screen have nothing to give for the rest
+            let (image, d, visible) = shotgun::capture_area(display, backend, window, sel,
+                                                            sixteen_bit)
+                .map_err(capture_status)?;
+            origin = visible;
+            deep = d;
+            image
+        };
+        Ok((image, origin, deep))
+    };
+
+    // Windows of applications that just started are often remapped or resized mid-capture
+    let capture = || {
+        let mut failures = 0;
+        loop {
+            let captured = capture_once();
+            match display.take_error() {
+                Some(e) if e.is_transient() && failures < retry.0 => {
+                    failures += 1;
+                    warn!("X error while capturing, trying again: {}", e);
+                    thread::sleep(retry.1);
+                },
+                // The target closing mid-capture leaves whatever was read back incomplete
+                Some(e) => {
+                    eprintln!("X error while capturing: {}", e);
+                    return Err(captured.err().unwrap_or(EXIT_CAPTURE));
+                },
+                None => return captured,
+            }
+        }
+    };
+
+    // Capture, process and write out a single image
+    // Without any sink, the capture is only sampled for its dominant color
+    let shoot = |sinks: &[Sink]| -> i32 {
+        *report.borrow_mut() = Report {
+            time: Some(time::SystemTime::now()),
+            ..Report::default()
+        };
+
+        // Left over from earlier captures
+        let _ = backend::take_timings();
+        // Some compositors hand out a blank frame right after a window maps
+        let mut retries = 0;
+        let (mut image, origin, mut deep) = loop {
+            let (image, origin, deep) = match capture() {
+                Ok(c) => c,
+                Err(status) => return status,
+            };
+            match skip_blank {
+                Some(n) if draw::is_uniform(&image) => {
+                    if retries == n {
+                        if !matches.opt_present("allow-uniform") {
+                            eprintln!("Capture is still a single color after {} retries", n);
+                            return EXIT_CAPTURE;
+                        }
+                        break (image, origin, deep);
+                    }
+                    retries += 1;
+                    thread::sleep(time::Duration::from_millis(100));
+                },
+                _ => break (image, origin, deep),
+            }
+        };
+
+        {
+            let (read, convert) = backend::take_timings();
+            let mut report = report.borrow_mut();
+            report.origin = Some(origin);
+            report.read = read;
+            report.convert = convert;
+        }
+        let geometry = format!("{}x{}+{}+{}", origin.w, origin.h, origin.x, origin.y);
+
+        // Details of the capture for PNG text chunks and JPEG EXIF
+        let mut metadata = Vec::new();
+        let mut exif = None;
+        if !matches.opt_present("no-metadata") && !matches.opt_present("deterministic") {
+            let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap_or_default();
+            let target = shadow_window.unwrap_or(window);
+            let title = display.get_name(target).filter(|n| !n.is_empty());
+            metadata.push(("Software", format!("shotgun {}", version())));
+            metadata.push(("Creation Time", util::iso8601(now.as_secs())));
+            metadata.push(("X11 Window", format!("0x{:x}", target)));
+            metadata.push(("Geometry", geometry.clone()));
+            if let Some(t) = &title {
+                metadata.push(("Title", t.clone()));
+            }
+            exif = Some(jpeg::Exif {
+                time: now.as_secs(),
+                software: format!("shotgun {}", version()),
+                description: title,
+            });
+        }
+
+        if matches.opt_present("swap-rb") {
+            draw::swap_rb(&mut image);
+            if let Some(d) = &mut deep {
+                draw::swap_rb(d);
+            }
+        }
+
+        if matches.opt_present("cursor") {
+            match display.get_cursor_image() {
+                Some((cursor, x, y)) => {
+                    draw::blend(&mut image, &cursor, x - origin.x, y - origin.y, 1.0);
+                },
+                None => warn!("Failed to get the cursor image, XFixes may be unavailable"),
+            }
+        }
+
+        let masking = time::Instant::now();
+        if mask_docks {
+            // Root window background, as set by most wallpaper setters
+            let wallpaper = display.get_property_ids(root, "_XROOTPMAP_ID").first()
+                .and_then(|&p| display.get_image(p, origin, xwrap::ALL_PLANES, xlib::ZPixmap))
+                .and_then(|i| i.to_image_buffer());
+
+            for top in display.get_children(root) {
+                let client = display.get_client_window(top).unwrap_or(top);
+                if !display.is_viewable(top) || !is_dock(client) {
+                    continue;
+                }
+
+                let r = display.get_window_rect(top).translate(-origin.x, -origin.y);
+                match &wallpaper {
+                    Some(w) => {
+                        if let Some(r) = r.intersection(util::Rect {
+                            x: 0,
+                            y: 0,
+                            w: w.width() as i32,
+                            h: w.height() as i32,
+                        }) {
+                            let patch = w.view(r.x as u32, r.y as u32, r.w as u32, r.h as u32);
+                            image.copy_from(&patch, r.x as u32, r.y as u32)
+                                .expect("Failed to copy sub-image");
+                        }
+                    },
+                    None => draw::fill_rect(&mut image, r, Rgba::from_channels(0, 0, 0, 0)),
+                }
+            }
+        }
+
+        // When capturing the root window, attempt to mask the off-screen areas
+        // --normalize-dpi masks them too, once redactions are done in screen coordinates
+        if window == root && !no_mask && !normalize_dpi {
+            match display.get_screen_rects(root) {
+                Some(screens) => {
+                    let screens: Vec<util::Rect> =
+                        screens.filter_map(|s| s.intersection(sel)).collect();
+
+                    // No point in masking if we're only capturing one screen
+                    // Without a bezel, the masked image is the same size and need not be a copy
+                    if screens.len() > 1 && bezel.is_none() {
+                        shotgun::mask_screens_in_place(&mut image, sel, &screens, mask_color);
+                        if let Some(d) = &mut deep {
+                            shotgun::mask_screens16_in_place(d, sel, &screens);
+                        }
+                    } else if screens.len() > 1 {
+                        image = mask_screens(&image, sel, &screens, bezel, mask_color);
+                        let gap = bezel.map_or(0, |b| b.0);
+                        deep = deep.map(|d| shotgun::mask_screens16(&d, sel, &screens, gap));
+                    }
+                },
+                None => {
+                    warn!("Failed to enumerate screens, not masking");
+                },
+            }
+        }
+
+        for child_path in matches.opt_strs("exclude-child") {
+            let child = child_path.split('.').map(|i| i.parse::<usize>())
+                .collect::<Result<Vec<_>, _>>()
+                .ok().and_then(|p| {
+                    display.get_child_by_path(shadow_window.unwrap_or(window), &p)
+                });
+            let child = match child {
+                Some(c) => c,
+                None => {
+                    eprintln!("No child window at path {}", child_path);
+                    return EXIT_WINDOW;
+                },
+            };
+
+            // Child rect is relative to the root
+            let child_rect = display.get_window_rect(child);
+            draw::fill_rect(&mut image, child_rect.translate(-origin.x, -origin.y), background);
+        }
+
+        redact(&mut image, origin);
+
+        if normalize_dpi {
+            let outputs = shotgun::monitors(display);
+            for (name, _) in &output_scales {
+                if !outputs.iter().any(|o| &o.name == name) {
+                    warn!("No output named {}, ignoring its scale", name);
+                }
+            }
+            let screens: Vec<(util::Rect, f64)> = outputs
+                .into_iter().filter_map(|o| {
+                    let scale = output_scales.iter().find(|s| s.0 == o.name)
+                        .map_or(o.scale() as f64, |s| s.1);
+                    Some((o.rect.intersection(sel)?, scale))
+                })
+                .collect();
+            // Down to the lowest density, so that no pixels are made up
+            let base = screens.iter().map(|s| s.1).fold(f64::INFINITY, f64::min);
+            if screens.iter().any(|s| s.1 != base) {
+                let screens: Vec<(util::Rect, f64)> = screens.iter()
+                    .map(|&(r, scale)| (r, base / scale)).collect();
+                image = shotgun::normalize_screens(&image, sel, &screens, mask_color,
+                                                   resize_filter);
+                deep = None;
+            }
+        }
+        report.borrow_mut().masking = masking.elapsed();
+
+        if let Some(tolerance) = autocrop {
+            match autocrop::detect(&image, tolerance) {
+                Some(r) => {
+                    if verbose {
+                        eprintln!("Autocrop: {}x{}+{}+{}", r.w, r.h, r.x, r.y);
+                    }
+                    let (x, y, w, h) = (r.x as u32, r.y as u32, r.w as u32, r.h as u32);
+                    image = imageops::crop(&mut image, x, y, w, h).to_image();
+                    deep = deep.map(|mut d| imageops::crop(&mut d, x, y, w, h).to_image());
+                },
+                None => warn!("Could not find the content to crop to, not cropping"),
+            }
+        }
+
+        if rotate != 0 || flip.is_some() {
+            image = draw::orient(&image, rotate, flip);
+            deep = deep.map(|d| draw::orient(&d, rotate, flip));
+        }
+
+        // Ahead of decorations, which are sized in output pixels
+        if let Some(r) = resize {
+            let (w, h) = r.apply(image.width(), image.height());
+            if (w, h) != image.dimensions() {
+                // Scaled at full precision, the 8 bit image following from it
+                match deep.take() {
+                    Some(d) => {
+                        let d = draw::widen(&image, Some(&d));
+                        let d = if linear_resize {
+                            draw::resize_linear16(&d, w, h, resize_filter)
+                        } else {
+                            imageops::resize(&d, w, h, resize_filter)
+                        };
+                        image = draw::narrow(&d);
+                        deep = Some(d);
+                    },
+                    None => image = scale(&image, w, h, resize_filter),
+                }
+            }
+        }
+
+        if let Some(w) = &watermark {
+            w.apply(&mut image, watermark_margin, watermark_scale);
+        }
+
+        if let Some(b) = &border {
+            image = b.apply(&image);
+            let (x, y) = b.offset();
+            deep = deep.map(|d| draw::place(&d, image.width(), image.height(), x, y));
+        }
+        if let Some(r) = shadow {
+            image = draw::drop_shadow(&image, r);
+            let offset = draw::shadow_offset(r);
+            deep = deep.map(|d| draw::place(&d, image.width(), image.height(), offset, offset));
+        }
+        // Behind translucent windows, masked gaps and decorations alike
+        if flatten_alpha {
+            draw::fill_behind(&mut image, background);
+        }
+        // Last, so that decorations are converted along
+        match grayscale {
+            Some(1) => image = draw::black_and_white(&image, background, dither),
+            Some(_) => image = draw::grayscale(&image),
+            None => (),
+        }
+
+        if let Some(reference) = &if_changed {
+            let mut last = last_capture.borrow_mut();
+            if last.is_none() {
+                *last = image::open(reference).ok().map(|i| i.to_rgba());
+            }
+            if last.as_ref().is_some_and(|l| draw::changed_share(l, &image) <= threshold) {
+                info!("Capture is unchanged, not writing it");
+                return EXIT_UNCHANGED;
+            }
+            *last = Some(image.clone());
+        }
+        if webp_anim.is_some() {
+            match webp::encode_frame(&image) {
+                Ok(f) => anim_frames.borrow_mut().push((f, time::Instant::now())),
+                Err(e) => {
+                    eprintln!("Failed to encode animation frame: {}", e);
+                    return 1;
+                },
+            }
+        }
+
+        if let Some(method) = dominant {
+            let colors = palette::dominant_colors(&image, method, dominant_count);
+            if colors.is_empty() {
+                eprintln!("Capture has no visible pixels to sample");
+                return 1;
+            }
+            for [r, g, b] in colors {
+                println!("#{:02x}{:02x}{:02x}", r, g, b);
+            }
+        }
+        if sinks.is_empty() {
+            return 0;
+        }
+
+        // Encode one image, then write it out to every sink, suffixing file names
+        let save_to = |image: RgbaImage, suffix: &str, sinks: &[Sink]| -> i32 {
+            let quantized;
+            let palette = match quantize {
+                Some(n) => {
+                    quantized = palette::Palette::quantize(&image, n);
+                    Some(&quantized)
+                },
+                None => palette.as_ref(),
+            };
+            // Only PNG can store the palette itself, other formats get the remapped colors
+            let indexed = palette.map(|p| p.remap(&image, palette_space));
+            let image = match &indexed {
+                Some(i) => i.to_rgba(),
+                None => image,
+            };
+            let encode = |mut w: &mut dyn io::Write, ext: &str| -> Result<(), String> {
+                let settings = pngenc::Settings {
+                    compression: png_compression.clone(),
+                    filter: png_filter,
+                    text: metadata.clone(),
+                    icc: icc.clone(),
+                    threads,
+                };
+                let output_format = format_info(ext).and_then(|i| i.0);
+                match (&indexed, &output_format) {
+                    (Some(i), _) if ext == "png" => {
+                        i.write_png(w, &settings).map_err(|e| e.to_string())
+                    },
+                    (_, Some(image::ImageOutputFormat::Png)) if grayscale.is_some() => {
+                        pngenc::write_gray(w, &image, grayscale == Some(1), &settings)
+                            .map_err(|e| e.to_string())
+                    },
+                    (_, Some(image::ImageOutputFormat::Png)) if sixteen_bit => {
+                        let image = draw::widen(&image, deep.as_ref());
+                        pngenc::write_rgba16(w, &image, &settings).map_err(|e| e.to_string())
+                    },
+                    (_, Some(image::ImageOutputFormat::Png)) => {
+                        pngenc::write_rgba(w, &image, &settings).map_err(|e| e.to_string())
+                    },
+                    (_, Some(image::ImageOutputFormat::Pnm(_))) if sixteen_bit => {
+                        let image = draw::widen(&image, deep.as_ref());
+                        let samples: Vec<u8> = image.iter().flat_map(|v| v.to_be_bytes()).collect();
+                        write!(w, "P7\nWIDTH {}\nHEIGHT {}\nDEPTH 4\nMAXVAL 65535\n\
+                                   TUPLTYPE RGB_ALPHA\nENDHDR\n", image.width(), image.height())
+                            .and_then(|_| w.write_all(&samples)).map_err(|e| e.to_string())
+                    },
+                    // The image crate's encoders take their own copy of the pixels
+                    (_, Some(f)) => image::DynamicImage::ImageRgba8(image.clone())
+                        .write_to(&mut w, f.clone()).map_err(|e| e.to_string()),
+                    (_, None) => {
+                        match ext {
+                            "raw" => {
+                                // The bare pixels need their geometry passed on separately,
+                                // which -q does not hold back
+                                if !streaming.get() {
+                                    eprintln!("{{\"width\":{},\"height\":{},\"stride\":{},\
+                                               \"format\":\"rgba\"}}", image.width(),
+                                              image.height(), image.width() * 4);
+                                }
+                                w.write_all(&image)
+                            },
+                            "y4m" => {
+                                y4m::write_y4m(w, &draw::flatten(&image, background), fps,
+                                               !streaming.get())
+                            },
+                            "ppm" => {
+                                let flat = draw::flatten(&image, background);
+                                write!(w, "P6\n{} {}\n255\n", flat.width(), flat.height())
+                                    .and_then(|_| w.write_all(&flat))
+                            },
+                            "tif" | "tiff" if sixteen_bit => {
+                                tiff::write_tiff16(w, &draw::widen(&image, deep.as_ref()))
+                            },
+                            "tif" | "tiff" => tiff::write_tiff(w, &image),
+                            // Always at 16 bits, which the capture only has past 8 with --16-bit
+                            "ff" => {
+                                farbfeld::write_farbfeld(w, &draw::widen(&image, deep.as_ref()))
+                            },
+                            "qoi" => qoi::write_qoi(w, &image),
+                            "webp" if lossy_webp => webp::write_lossy(w, &image, quality),
+                            "webp" => webp::write_webp(w, &image),
+                            "gif" => gif::write_gif(w, &[(gif::encode_frame(&image), 0)]),
+                            "apng" => {
+                                let mut png = Vec::new();
+                                pngenc::write_rgba(&mut png, &image, &settings)
+                                    .map_err(io::Error::other)
+                                    .and_then(|_| pngenc::write_apng(w, &[(png, 0)]))
+                            },
+                            _ => {
+                                jpeg::write_jpeg(w, &image, quality, background, exif.as_ref(),
+                                                 icc.as_deref())
+                            },
+                        }.map_err(|e| e.to_string())
+                    },
+                }
+            };
+            let write = |w: &mut dyn io::Write, ext: &str| -> Result<(), String> {
+                match terminal {
+                    Some(terminal::Protocol::Kitty) => {
+                        let mut png = Vec::new();
+                        encode(&mut png, "png")?;
+                        terminal::write_kitty(w, &png).map_err(|e| e.to_string())
+                    },
+                    Some(terminal::Protocol::Sixel) => {
+                        terminal::write_sixel(w, &image).map_err(|e| e.to_string())
+                    },
+                    None => encode(w, ext),
+                }
+            };
+
+            // Once for every format the sinks are written in
+            let mut encoded: Vec<(String, Vec<u8>)> = Vec::new();
+            for sink in sinks {
+                let ext = sink_ext(sink);
+                let (kind, mime_type) = match format_info(&ext) {
+                    Some((_, kind, mime_type)) => (kind, mime_type),
+                    None => {
+                        eprintln!("Invalid image format specified");
+                        return 1;
+                    },
+                };
+                // Nothing else needs the bytes, so they can go out as soon as they are encoded
+                let alone = sinks.iter().filter(|s| sink_ext(s) == ext).count() == 1;
+                if let (Sink::Stdout, true, false) = (sink, alone, length_prefix) {
+                    let start = time::Instant::now();
+                    let stdout = io::stdout();
+                    let mut stdout = io::BufWriter::new(stdout.lock());
+                    if mime_header {
+                        if let Err(e) = write!(stdout, "Content-Type: {}\r\n\r\n", mime_type) {
+                            eprintln!("Failed to write to stdout: {}", e);
+                            return EXIT_IO;
+                        }
+                    }
+                    if let Err(e) = write(&mut stdout, &ext) {
+                        eprintln!("Failed to encode image: {}", e);
+                        return 1;
+                    }
+                    if let Err(e) = stdout.flush() {
+                        eprintln!("Failed to write to stdout: {}", e);
+                        return EXIT_IO;
+                    }
+                    report.borrow_mut().encoding += start.elapsed();
+                    continue;
+                }
+                let index = match encoded.iter().position(|(e, _)| *e == ext) {
+                    Some(i) => i,
+                    None => {
+                        let start = time::Instant::now();
+                        let mut data = Vec::new();
+                        if let Err(e) = write(&mut data, &ext) {
+                            eprintln!("Failed to encode image: {}", e);
+                            return 1;
+                        }
+                        report.borrow_mut().encoding += start.elapsed();
+                        encoded.push((ext.clone(), data));
+                        encoded.len() - 1
+                    },
+                };
+                let data = &encoded[index].1;
+                match sink {
+                    Sink::Stdout => {
+                        let mut header = Vec::new();
+                        if mime_header {
+                            header.extend(format!("Content-Type: {}\r\n\r\n", mime_type).bytes());
+                        }
+                        if length_prefix {
+                            header.extend_from_slice(&(data.len() as u64).to_be_bytes());
+                        }
+
+                        let mut stdout = io::stdout();
+                        if let Err(e) = stdout.write_all(&header)
+                                              .and_then(|_| stdout.write_all(data)) {
+                            eprintln!("Failed to write to stdout: {}", e);
+                            return EXIT_IO;
+                        }
+                    },
+                    Sink::File(path) => {
+                        let path = suffixed_path(path, suffix);
+                        if mkdir {
+                            if let Some(dir) = Path::new(&path).parent() {
+                                if let Err(e) = fs::create_dir_all(dir) {
+                                    eprintln!("Failed to create {}: {}", dir.display(), e);
+                                    return EXIT_IO;
+                                }
+                            }
+                        }
+                        let path = match write_file(&path, data, existing, sync) {
+                            Ok(p) => p,
+                            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                                eprintln!("{} already exists, not overwriting it", path);
+                                return EXIT_IO;
+                            },
+                            Err(e) => {
+                                eprintln!("Failed to write {}: {}", path, e);
+                                return EXIT_IO;
+                            },
+                        };
+
+                        if let (true, Some(kind)) = (verify, kind) {
+                            if let Err(e) = verify_output(Path::new(&path), kind, &image) {
+                                eprintln!("Verification of {} failed: {}", path, e);
+                                return EXIT_IO;
+                            }
+                        }
+                        if print_path {
+                            println!("{}", path);
+                        }
+                        if let Some(command) = &exec {
+                            let title = display.get_name(shadow_window.unwrap_or(window))
+                                .unwrap_or_default();
+                            if let Err(e) = exec_hook(command, &path, &geometry, &title,
+                                                      to_stdout) {
+                                eprintln!("Failed to run --exec for {}: {}", path, e);
+                                return 1;
+                            }
+                        }
+                        report.borrow_mut().paths.push(path);
+                    },
+                    Sink::Fd(fd) => {
+                        // Owned by whoever passed it, not to be closed
+                        let file = unsafe { fs::File::from_raw_fd(*fd) };
+                        let mut file = mem::ManuallyDrop::new(file);
+                        if let Err(e) = file.write_all(data) {
+                            eprintln!("Failed to write to file descriptor {}: {}", fd, e);
+                            return EXIT_IO;
+                        }
+                    },
+                    Sink::Clipboard => {
+                        // Serve the selection from the background so that we can return now
+                        match unsafe { libc::fork() } {
+                            -1 => {
+                                eprintln!("Failed to fork: {}", io::Error::last_os_error());
+                                return 1;
+                            },
+                            0 => {
+                                // Let pipelines and command substitutions finish without us
+                                // Signals held back for sigwait would never reach it otherwise
+                                unsafe {
+                                    let mut none = mem::zeroed::<libc::sigset_t>();
+                                    libc::sigemptyset(&mut none);
+                                    libc::pthread_sigmask(libc::SIG_SETMASK, &none,
+                                                          ptr::null_mut());
+                                    libc::setsid();
+                                    libc::close(libc::STDIN_FILENO);
+                                    libc::close(libc::STDOUT_FILENO);
+                                }
+                                // Not to keep a daemon client waiting for the end of the reply
+                                if shared.is_some() {
+                                    let null = fs::OpenOptions::new().write(true)
+                                        .open("/dev/null");
+                                    if let Ok(null) = null {
+                                        unsafe {
+                                            libc::dup2(null.as_raw_fd(), libc::STDERR_FILENO);
+                                        }
+                                    }
+                                }
+                                let served = open_display().is_some_and(|d| {
+                                    d.serve_selection("CLIPBOARD", mime_type, data)
+                                });
+                                if !served {
+                                    eprintln!("Failed to take ownership of the clipboard");
+                                }
+                                process::exit(if served { 0 } else { 1 });
+                            },
+                            _ => (),
+                        }
+                    },
+                }
+            }
+
+            0
+        };
+        let save = |image: RgbaImage, suffix: &str| save_to(image, suffix, sinks);
+
+        // Cut into tiles of equal size, except for the ones on the right and bottom edges
+        let save = |mut image: RgbaImage, suffix: &str| -> i32 {
+            let (rows, cols) = match split {
+                Some(g) => g,
+                None => return save(image, suffix),
+            };
+            let tile_w = image.width().div_ceil(cols);
+            let tile_h = image.height().div_ceil(rows);
+
+            for (r, y) in (0..image.height()).step_by(tile_h as usize).enumerate() {
+                for (c, x) in (0..image.width()).step_by(tile_w as usize).enumerate() {
+                    let tile = imageops::crop(&mut image, x, y, tile_w, tile_h).to_image();
+                    let status = save(tile, &format!("{}-r{}-c{}", suffix, r, c));
+                    if status != 0 {
+                        return status;
+                    }
+                }
+            }
+
+            0
+        };
+
+        // Scaled copies first, so that the full size image can be handed over without a copy
+        let save = |image: RgbaImage, suffix: &str| -> i32 {
+            for &(factor, ref size) in sizes.iter().filter(|s| s.0 != 1.0) {
+                let w = (image.width() as f64 * factor).round().max(1.0) as u32;
+                let h = (image.height() as f64 * factor).round().max(1.0) as u32;
+                let mut scaled = scale(&image, w, h, imageops::FilterType::Lanczos3);
+                if let Some((amount, radius)) = sharpen.filter(|_| factor < 1.0) {
+                    scaled = draw::unsharp_mask(&scaled, amount, radius);
+                }
+
+                let status = save(scaled, &format!("{}{}", suffix, size));
+                if status != 0 {
+                    return status;
+                }
+            }
+
+            match sizes.iter().find(|s| s.0 == 1.0) {
+                Some((_, size)) => save(image, &format!("{}{}", suffix, size)),
+                None => 0,
+            }
+        };
+
+        // Of the whole capture, next to each file or at the paths the template gives
+        if let Some((w, h, template)) = &thumb {
+            let scale = (*w as f64 / image.width() as f64).min(*h as f64 / image.height() as f64)
+                .min(1.0);
+            let side = |s: u32| (s as f64 * scale).round().max(1.0) as u32;
+            let small = imageops::thumbnail(&image, side(image.width()), side(image.height()));
+            let files: Vec<&String> = sinks.iter().filter_map(|s| match s {
+                Sink::File(p) => Some(p),
+                _ => None,
+            }).collect();
+            let status = match template {
+                None => {
+                    let sinks: Vec<Sink> = files.iter().map(|&p| Sink::File(p.clone())).collect();
+                    save_to(small, "-thumb", &sinks)
+                },
+                Some(t) if t.contains("{name}") => {
+                    let mut paths: Vec<String> = files.iter().filter_map(|p| {
+                        let name = Path::new(p).file_stem()?.to_string_lossy();
+                        Some(t.replace("{name}", &name))
+                    }).collect();
+                    paths.dedup();
+                    save_to(small, "", &paths.into_iter().map(Sink::File).collect::<Vec<_>>())
+                },
+                Some(t) => save_to(small, "", &[Sink::File(t.clone())]),
+            };
+            if status != 0 {
+                return status;
+            }
+        }
+
+        // Cut from the one capture, so that every part shows the same instant
+        match &crops {
+            Some(crops) => {
+                for (r, suffix) in crops {
+                    let part = imageops::crop(&mut image, (r.x - origin.x) as u32,
+                                              (r.y - origin.y) as u32, r.w as u32, r.h as u32);
+                    let status = save(part.to_image(), suffix);
+                    if status != 0 {
+                        return status;
+                    }
+                }
+                0
+            },
+            None => save(image, ""),
+        }
+    };
+
+    let settle = match matches.opt_str("settle").map(|s| s.parse::<u64>()) {
+        Some(Ok(ms)) => Some(time::Duration::from_millis(ms)),
+        Some(Err(_)) => {
+            eprintln!("Settle interval is not a valid integer");
+            return 1;
+        },
+        None => None,
+    };
+    let settle = match settle.map(|s| (s, display.create_damage(window))) {
+        Some((s, Some(damage))) => Some((s, damage)),
+        Some((_, None)) => {
+            eprintln!("--settle requires the DAMAGE extension");
+            return 1;
+        },
+        None => None,
+    };
+
+    let delay = match matches.opt_str("d").map(|s| parse_seconds(&s)) {
+        Some(Some(d)) => d,
+        Some(None) => {
+            eprintln!("Delay must be a non-negative number of seconds");
+            return 1;
+        },
+        None => time::Duration::from_secs(0),
+    };
+    let grab = if matches.opt_present("abortable") {
+        if !matches.opt_present("d") {
+            eprintln!("--abortable only applies to --delay");
+            return 1;
+        }
+        let grab = display.grab_keyboard();
+        if grab.is_none() {
+            warn!("Could not grab the keyboard, Escape will not cancel the delay");
+        }
+        grab
+    } else {
+        None
+    };
+    // Returns whether Escape cut the wait short
+    let wait = |time| match &grab {
+        Some(grab) => grab.wait_for_escape(time),
+        None => {
+            thread::sleep(time);
+            false
+        },
+    };
+    let aborted = if matches.opt_present("countdown") {
+        // Tick on whole seconds left, the fractional part goes first
+        let mut left = delay;
+        loop {
+            if left == time::Duration::from_secs(0) {
+                break false;
+            }
+            let secs = left.as_secs_f64().ceil() as u64;
+            info!("{}...", secs);
+            let tick = left - time::Duration::from_secs(secs - 1);
+            if wait(tick) {
+                break true;
+            }
+            left -= tick;
+        }
+    } else {
+        wait(delay)
+    };
+    drop(grab);
+    if aborted {
+        eprintln!("Delay cancelled");
+        return EXIT_CANCELLED;
+    }
+
+    // Monitors in standby would only give us black frames
+    let dpms_level = if matches.opt_present("wake") {
+        let level = display.get_dpms_level();
+        if level.is_none() {
+            warn!("DPMS not available, not waking the display");
+        }
+        level
+    } else {
+        None
+    };
+    let woken = dpms_level.is_some_and(|l| l != dpms::DPMSModeOn);
+    let mut changes = Changes::default();
+    if woken {
+        display.set_dpms_level(dpms::DPMSModeOn);
+        changes.dpms = dpms_level;
+    }
+    let reset = matches.opt_present("reset-screensaver");
+    if reset {
+        display.reset_screensaver();
+    }
+    // Point at something
+    if let Some((x, y)) = hover {
+        if matches.opt_present("hover-restore") {
+            changes.pointer = Some(display.query_pointer());
+        }
+        display.fake_motion(x, y);
+    }
+    // --stream and --hold stop on those signals themselves, and the daemon only takes single
+    // captures, which return
+    let hold = matches.opt_present("hold");
+    if (changes.dpms.is_some() || changes.pointer.is_some()) && !stream && !hold
+       && shared.is_none() {
+        changes.undo_on_signals(display_name.clone(), x_screen);
+    }
+    let _restore = Restore { display, changes };
+
+    // Give monitors time to come back and windows to redraw, and the hovered window time to
+    // show its tooltip
+    if woken || reset {
+        thread::sleep(time::Duration::from_millis(1000));
+    }
+    if hover.is_some() {
+        thread::sleep(hover_delay);
+    }
+
+    if let Some(((x, y), color, matching)) = trigger {
+        let start = time::Instant::now();
+        loop {
+            let pixel = display.get_image(root, util::Rect { x, y, w: 1, h: 1 },
+                                          xwrap::ALL_PLANES, xlib::ZPixmap)
+                .and_then(|i| i.to_image_buffer());
+            let pixel = match pixel {
+                Some(p) => *p.get_pixel(0, 0),
+                None => {
+                    eprintln!("Failed to read the trigger pixel");
+                    return EXIT_CAPTURE;
+                },
+            };
+            // Captures of the root window have no meaningful alpha
+            if (pixel.to_rgb() == color.to_rgb()) == matching {
+                info!("Trigger fired after {} ms", start.elapsed().as_millis());
+                break;
+            }
+            if start.elapsed() >= trigger_timeout {
+                eprintln!("Trigger timed out, not capturing");
+                return 1;
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+    }
+
+    // Let the window finish drawing, giving up on ones that never stop such as videos
+    if let Some((interval, damage)) = &settle {
+        let start = time::Instant::now();
+        while damage.wait(Some(*interval)) {
+            if start.elapsed() >= time::Duration::from_secs(10) {
+                warn!("The window is still redrawing after 10 s, capturing anyway");
+                break;
+            }
+        }
+    }
+
+    // Name of the file written when none is given
+    let target = shadow_window.unwrap_or(window);
+    let tokens = [
+        ("wid", format!("0x{:x}", target)),
+        ("wm_class", display.get_class(target).map(|(_, k)| k).unwrap_or_default()),
+        ("wm_name", display.get_name(target).unwrap_or_default()),
+        ("geometry", format!("{}x{}+{}+{}", sel.w, sel.h, window_rect.x + sel.x,
+                             window_rect.y + sel.y)),
+    ];
+    let template = matches.opt_str("template");
+    let counter_width = match matches.opt_str("counter-width").map(|s| s.parse::<usize>()) {
+        Some(Ok(n)) if n <= 20 => n,
+        Some(_) => {
+            eprintln!("Counter width must be a number of digits up to 20");
+            return 1;
+        },
+        None => 3,
+    };
+    if template.is_none() && matches.opt_present("counter-width") {
+        eprintln!("--counter-width only applies to --template");
+        return 1;
+    }
+    let counters = state_home().map(|d| d.join("shotgun").join("counters"));
+    let dir = matches.opt_str("dir").map(PathBuf::from)
+        .or_else(|| pictures_dir().map(|d| d.join("Screenshots")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    let deterministic = matches.opt_present("deterministic");
+    let default_path = |millis| {
+        let name = match &template {
+            Some(t) => template_path(t, &output_ext, &tokens, &mut || {
+                let count = counters.as_ref().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "no XDG_STATE_HOME or HOME")
+                }).and_then(|path| next_count(path, t));
+                match count {
+                    Ok(n) => format!("{:01$}", n, counter_width),
+                    Err(e) => {
+                        // Not to overwrite the capture taken with the last count
+                        warn!("Failed to update the %c counter, using the time instead: {}", e);
+                        timestamp(millis)
+                    },
+                }
+            }),
+            // Repeated captures keep their time-stamped names, not to overwrite each other
+            None if deterministic && !millis => format!("shotgun.{}", output_ext),
+            None => timestamp_path(&output_ext, millis),
+        };
+        if dir == Path::new(".") {
+            return name;
+        }
+        // Failing to create it is reported when writing to it
+        let _ = fs::create_dir_all(&dir);
+        dir.join(name).to_string_lossy().into_owned()
+    };
+
+    // Tell about a successful capture
+    // Failing to notify does not fail the capture, which is already written out
+    #[cfg(feature = "dbus")]
+    let notify = matches.opt_present("notify");
+    #[cfg_attr(not(feature = "dbus"), allow(unused_variables))]
+    let announce = |sinks: &[Sink]| {
+        let report = report.borrow();
+        let written = &report.paths;
+
+        if let (true, Some(o)) = (json, report.origin) {
+            let (cx, cy) = (o.x + o.w / 2, o.y + o.h / 2);
+            let monitor = shotgun::monitors(display).into_iter().find(|m| {
+                let r = m.rect;
+                r.x <= cx && cx < r.x + r.w && r.y <= cy && cy < r.y + r.h
+            });
+            let time = report.time.and_then(|t| t.duration_since(time::UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            let paths: Vec<String> = written.iter().map(|p| util::json_string(p)).collect();
+            println!("{{\"paths\":[{}],\"x\":{},\"y\":{},\"width\":{},\"height\":{},\
+                      \"window\":{},\"monitor\":{},\"time\":{}.{:03},\"encode_ms\":{}}}",
+                     paths.join(","), o.x, o.y, o.w, o.h, shadow_window.unwrap_or(window),
+                     monitor.map_or("null".to_string(), |m| util::json_string(&m.name)),
+                     time.as_secs(), time.subsec_millis(), report.encoding.as_millis());
+        }
+        if verbose {
+            let ms = |d: time::Duration| d.as_secs_f64() * 1000.0;
+            eprintln!("Timings: open {:.1} ms, read {:.1} ms, convert {:.1} ms, masking {:.1} ms, \
+                       encoding {:.1} ms", ms(open_time), ms(report.read), ms(report.convert),
+                      ms(report.masking), ms(report.encoding));
+        }
+
+        #[cfg(feature = "dbus")]
+        if notify {
+            let image = written.first().and_then(|p| fs::canonicalize(p).ok());
+            let body = if !written.is_empty() {
+                written.join("\n")
+            } else if sinks.iter().any(|s| matches!(s, Sink::Clipboard)) {
+                "Copied to the clipboard".to_string()
+            } else {
+                return;
+            };
+            let image = image.as_ref().map(|p| p.to_string_lossy());
+            if let Err(e) = notify::send("Screenshot taken", &body, image.as_deref()) {
+                warn!("Failed to send a notification: {}", e);
+            }
+        }
+    };
+
+    if !matches.opt_present("every") && ["count", "duration", "drop-late", "webp-anim"].iter()
+                                            .any(|&o| matches.opt_present(o)) {
+        eprintln!("--count, --duration, --drop-late and --webp-anim only apply to --every");
+        return 1;
+    }
+
+    // Sinks of the nth capture of --hold and --every
+    let numbered_sinks = |n| {
+        let mut sinks: Vec<Sink> = if outputs.is_empty() && !clipboard {
+            vec![Sink::File(default_path(true))]
+        } else {
+            outputs.iter().map(|t| Sink::File(hold_path(t, n))).collect()
+        };
+        if clipboard {
+            sinks.push(Sink::Clipboard);
+        }
+        sinks
+    };
+
+    if hold {
+        if stream || matches.opt_present("on-change") || terminal.is_some()
+           || outputs.iter().any(|p| p == "-") {
+            eprintln!("--hold only writes files, and cannot be combined with --on-change or \
+                       --stream");
+            return 1;
+        }
+
+        // Only taken by sigwait, so that a signal coming in during a capture is not lost
+        // SIGINT and SIGTERM stop waiting, so that the display is put back as it was
+        let mut signals = unsafe { mem::zeroed::<libc::sigset_t>() };
+        unsafe {
+            libc::sigemptyset(&mut signals);
+            libc::sigaddset(&mut signals, libc::SIGUSR1);
+            libc::sigaddset(&mut signals, libc::SIGINT);
+            libc::sigaddset(&mut signals, libc::SIGTERM);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+        }
+        info!("Waiting for SIGUSR1, pid {}", process::id());
+
+        let mut n = 0;
+        loop {
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+                eprintln!("Failed to wait for a signal");
+                return 1;
+            }
+            if signal != libc::SIGUSR1 {
+                return 0;
+            }
+            n += 1;
+
+            let sinks = numbered_sinks(n);
+            let status = shoot(&sinks);
+            if status == EXIT_UNCHANGED {
+                continue;
+            }
+            if status != 0 {
+                return status;
+            }
+            announce(&sinks);
+            // Where the files ended up, after --unique
+            for path in report.borrow().paths.iter() {
+                info!("Saved {}", path);
+            }
+        }
+    }
+
+    // Write out an animation of `frames` frames
+    let write_anim = |path: &str, data: &[u8], frames: usize| -> i32 {
+        if mkdir {
+            if let Some(dir) = Path::new(path).parent() {
+                if let Err(e) = fs::create_dir_all(dir) {
+                    eprintln!("Failed to create {}: {}", dir.display(), e);
+                    return EXIT_IO;
+                }
+            }
+        }
+        let path = match write_file(path, data, existing, sync) {
+            Ok(p) => p,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                eprintln!("{} already exists, not overwriting it", path);
+                return EXIT_IO;
+            },
+            Err(e) => {
+                eprintln!("Failed to write {}: {}", path, e);
+                return EXIT_IO;
+            },
+        };
+        if print_path {
+            println!("{}", path);
+        } else if !json {
+            info!("Saved {} frames to {}", frames, path);
+        }
+        report.borrow_mut().paths.push(path);
+        0
+    };
+
+    if let Some(every) = matches.opt_str("every") {
+        let interval = match parse_seconds(&every).filter(|i| !i.is_zero()) {
+            Some(i) => i,
+            None => {
+                eprintln!("Capture interval must be a positive number of seconds");
+                return 1;
+            },
+        };
+        let count = match matches.opt_str("count").map(|s| s.parse::<u64>()) {
+            Some(Ok(n)) => Some(n),
+            Some(Err(_)) => {
+                eprintln!("Capture count is not a valid integer");
+                return 1;
+            },
+            None => None,
+        };
+        let duration = match matches.opt_str("duration").map(|s| parse_seconds(&s)) {
+            Some(Some(d)) => Some(d),
+            Some(None) => {
+                eprintln!("Duration must be a non-negative number of seconds");
+                return 1;
+            },
+            None => None,
+        };
+        if stream || matches.opt_present("on-change") || matches.opt_present("hold")
+           || terminal.is_some() || outputs.iter().any(|p| p == "-") {
+            eprintln!("--every only writes files, and cannot be combined with --on-change, \
+                       --hold or --stream");
+            return 1;
+        }
+
+        if webp_anim.is_some() {
+            // Stop cleanly between captures for the animation to be written
+            let handler: extern "C" fn(libc::c_int) = on_sigint;
+            unsafe {
+                libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+                libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+            }
+        }
+
+        // Captures are scheduled from the start rather than from the previous one, not to drift
+        let drop_late = matches.opt_present("drop-late");
+        let start = time::Instant::now();
+        let mut n = 0;
+        // Place in the schedule, ahead of the count once captures are dropped
+        let mut slot: u64 = 0;
+        let mut late = 0;
+        let mut dropped = 0;
+        while count.is_none_or(|c| n < c) && !INTERRUPTED.load(Ordering::SeqCst) {
+            // Past the --duration, or so far along that the time cannot be represented
+            let due = u32::try_from(slot).ok().and_then(|n| interval.checked_mul(n))
+                .filter(|&offset| duration.is_none_or(|d| offset <= d))
+                .and_then(|offset| start.checked_add(offset));
+            let due = match due {
+                Some(d) => d,
+                None => break,
+            };
+            let now = time::Instant::now();
+            match due.checked_duration_since(now) {
+                Some(_) => {
+                    // A little at a time, to notice interruptions
+                    while let Some(left) = due.checked_duration_since(time::Instant::now()) {
+                        if INTERRUPTED.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        thread::sleep(left.min(time::Duration::from_millis(100)));
+                    }
+                    if INTERRUPTED.load(Ordering::SeqCst) {
+                        break;
+                    }
+                },
+                // Only an earlier capture running past this one's time makes it more than a
+                // little late
+                None if now - due > interval / 10 => {
+                    if late == 0 && dropped == 0 {
+                        warn!("Captures are taking longer than the interval");
+                    }
+                    let passed = (now - start).as_nanos() / interval.as_nanos();
+                    let passed = u64::try_from(passed).unwrap_or(u64::MAX);
+                    if drop_late && passed > slot {
+                        dropped += passed - slot;
+                        slot = passed;
+                    } else {
+                        late += 1;
+                    }
+                },
+                None => (),
+            }
+            slot += 1;
+            n += 1;
+
+            let sinks = numbered_sinks(n);
+            let status = shoot(&sinks);
+            if status == EXIT_UNCHANGED {
+                continue;
+            }
+            if status != 0 {
+                return status;
+            }
+            announce(&sinks);
+            // Where the files ended up, after --unique
+            for path in report.borrow().paths.iter() {
+                info!("Saved {}", path);
+            }
+        }
+        if late > 0 || dropped > 0 {
+            warn!("{} captures were taken late and {} were dropped", late, dropped);
+        }
+
+        if let Some(path) = &webp_anim {
+            let (frames, times): (Vec<_>, Vec<_>) = anim_frames.take().into_iter().unzip();
+            let frames: Vec<_> = frames.into_iter().zip(frame_delays(&times, interval)).collect();
+            let mut data = Vec::new();
+            if let Err(e) = webp::write_animation(&mut data, &frames) {
+                eprintln!("Failed to encode animation: {}", e);
+                return 1;
+            }
+            return write_anim(path, &data, frames.len());
+        }
+        return 0;
+    }
+    if matches.opt_present("on-change") {
+        if !outputs.is_empty() || terminal.is_some() || clipboard {
+            eprintln!("--on-change writes time-stamped files and takes no file name");
+            return 1;
+        }
+        let debounce = match matches.opt_str("debounce").map(|s| s.parse::<u64>()) {
+            Some(Ok(ms)) => time::Duration::from_millis(ms),
+            Some(Err(_)) => {
+                eprintln!("Debounce interval is not a valid integer");
+                return 1;
+            },
+            None => time::Duration::from_millis(500),
+        };
+        let damage = match display.create_damage(window) {
+            Some(d) => d,
+            None => {
+                eprintln!("--on-change requires the DAMAGE extension");
+                return 1;
+            },
+        };
+
+        loop {
+            damage.wait(None);
+            // Wait for the window to settle before capturing
+            while damage.wait(Some(debounce)) {}
+
+            let status = shoot(&[Sink::File(default_path(true))]);
+            if status == EXIT_UNCHANGED {
+                continue;
+            }
+            if status != 0 {
+                return status;
+            }
+            announce(&[]);
+            for path in report.borrow().paths.iter() {
+                info!("Saved {}", path);
+            }
+        }
+    }
+
+    if let Some(seconds) = matches.opt_str("record") {
+        let length = match parse_seconds(&seconds).filter(|l| !l.is_zero()) {
+            Some(l) => l,
+            None => {
+                eprintln!("Recording length must be a positive number of seconds");
+                return 1;
+            },
+        };
+        if stream || terminal.is_some() || clipboard || fd.is_some() || sizes.len() > 1
+           || split.is_some() || crops.is_some() || thumb.is_some() {
+            eprintln!("--record writes a single animation to files or stdout");
+            return 1;
+        }
+
+        let sinks: Vec<Sink> = if outputs.is_empty() {
+            vec![Sink::File(default_path(false))]
+        } else {
+            outputs.iter().map(|p| if p == "-" {
+                Sink::Stdout
+            } else {
+                Sink::File(p.clone())
+            }).collect()
+        };
+        // An animated PNG is still a PNG to anything that cannot play it
+        let anim_ext = |sink: &Sink| match sink_ext(sink).as_str() {
+            "png" | "apng" => Some("apng"),
+            "gif" => Some("gif"),
+            "webp" => Some("webp"),
+            _ => None,
+        };
+        if sinks.iter().any(|s| anim_ext(s).is_none()) {
+            eprintln!("--record only writes GIF, APNG or WebP");
+            return 1;
+        }
+        let want_gif = sinks.iter().any(|s| anim_ext(s) == Some("gif"));
+        let want_apng = sinks.iter().any(|s| anim_ext(s) == Some("apng"));
+        let want_webp = sinks.iter().any(|s| anim_ext(s) == Some("webp"));
+        let settings = pngenc::Settings {
+            compression: png_compression.clone(),
+            filter: png_filter,
+            text: Vec::new(),
+            icc: icc.clone(),
+            threads,
+        };
+
+        *report.borrow_mut() = Report {
+            time: Some(time::SystemTime::now()),
+            ..Report::default()
+        };
+        let _ = backend::take_timings();
+        let interval = time::Duration::from_secs(1) / fps;
+        let start = time::Instant::now();
+        let mut times = Vec::new();
+        let mut gif_frames = Vec::new();
+        let mut apng_frames = Vec::new();
+        let mut webp_frames = Vec::new();
+        loop {
+            let due = interval * times.len() as u32;
+            if due >= length {
+                break;
+            }
+            if let Some(rest) = due.checked_sub(start.elapsed()) {
+                thread::sleep(rest);
+            }
+            let (mut image, origin, _) = match capture() {
+                Ok(c) => c,
+                Err(status) => return status,
+            };
+            times.push(time::Instant::now());
+            {
+                let (read, convert) = backend::take_timings();
+                let mut report = report.borrow_mut();
+                report.origin = Some(origin);
+                report.read += read;
+                report.convert += convert;
+            }
+
+            let masking = time::Instant::now();
+            if matches.opt_present("cursor") {
+                if let Some((cursor, x, y)) = display.get_cursor_image() {
+                    draw::blend(&mut image, &cursor, x - origin.x, y - origin.y, 1.0);
+                }
+            }
+            redact(&mut image, origin);
+            report.borrow_mut().masking += masking.elapsed();
+            if flatten_alpha {
+                draw::fill_behind(&mut image, background);
+            }
+            if rotate != 0 || flip.is_some() {
+                image = draw::orient(&image, rotate, flip);
+            }
+            if let Some(r) = resize {
+                let (w, h) = r.apply(image.width(), image.height());
+                image = scale(&image, w, h, resize_filter);
+            }
+
+            // Encoded as they come, rather than holding on to every full frame
+            let encoding = time::Instant::now();
+            if want_gif {
+                gif_frames.push(gif::encode_frame(&image));
+            }
+            if want_apng {
+                let mut png = Vec::new();
+                if let Err(e) = pngenc::write_rgba(&mut png, &image, &settings) {
+                    eprintln!("Failed to encode image: {}", e);
+                    return 1;
+                }
+                apng_frames.push(png);
+            }
+            if want_webp {
+                match webp::encode_frame(&image) {
+                    Ok(f) => webp_frames.push(f),
+                    Err(e) => {
+                        eprintln!("Failed to encode image: {}", e);
+                        return 1;
+                    },
+                }
+            }
+            report.borrow_mut().encoding += encoding.elapsed();
+        }
+
+        // Each frame lasts until the next one was captured, the last one a frame interval
+        let delays = frame_delays(&times, interval);
+        let mut gif_data = Vec::new();
+        let mut apng_data = Vec::new();
+        let mut webp_data = Vec::new();
+        let written = if want_gif {
+            let frames: Vec<_> = gif_frames.into_iter().zip(delays.iter().copied()).collect();
+            gif::write_gif(&mut gif_data, &frames)
+        } else {
+            Ok(())
+        }.and_then(|_| if want_apng {
+            let frames: Vec<_> = apng_frames.into_iter().zip(delays.iter().copied()).collect();
+            pngenc::write_apng(&mut apng_data, &frames)
+        } else {
+            Ok(())
+        }).and_then(|_| if want_webp {
+            let frames: Vec<_> = webp_frames.into_iter().zip(delays.iter().copied()).collect();
+            webp::write_animation(&mut webp_data, &frames)
+        } else {
+            Ok(())
+        });
+        if let Err(e) = written {
+            eprintln!("Failed to encode animation: {}", e);
+            return 1;
+        }
+
+        for sink in &sinks {
+            let data = match anim_ext(sink) {
+                Some("gif") => &gif_data,
+                Some("webp") => &webp_data,
+                _ => &apng_data,
+            };
+            match sink {
+                Sink::File(path) => {
+                    let status = write_anim(path, data, times.len());
+                    if status != 0 {
+                        return status;
+                    }
+                },
+                _ => {
+                    if let Err(e) = io::stdout().write_all(data) {
+                        eprintln!("Failed to write to stdout: {}", e);
+                        return EXIT_IO;
+                    }
+                },
+            }
+        }
+        announce(&sinks);
+        return 0;
+    }
+
+    if stream {
+        // Stop cleanly between frames rather than mid-write
+        let handler: extern "C" fn(libc::c_int) = on_sigint;
+        unsafe {
+            libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+            libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+        }
+
+        let interval = time::Duration::from_secs(1) / fps;
+        while !INTERRUPTED.load(Ordering::SeqCst) {
+            let start = time::Instant::now();
+            let status = shoot(&[Sink::Stdout]);
+            if status != 0 {
+                return status;
+            }
+            streaming.set(true);
+
+            if let Some(rest) = interval.checked_sub(start.elapsed()) {
+                thread::sleep(rest);
+            }
+        }
+        return 0;
+    }
+
+    let mut sinks = if outputs.is_empty() {
+        if terminal.is_some() {
+            vec![Sink::Stdout]
+        } else if dominant.is_some() || clipboard || fd.is_some() {
+            vec![]
+        } else {
+            let ts_path = default_path(false);
+            info!("No output specified, defaulting to {}", ts_path);
+            vec![Sink::File(ts_path)]
+        }
+    } else {
+        outputs.iter().map(|p| if p == "-" {
+            Sink::Stdout
+        } else {
+            Sink::File(p.clone())
+        }).collect()
+    };
+    if let Some(fd) = fd {
+        sinks.push(Sink::Fd(fd));
+    }
+    // Last, as it leaves a child process behind
+    if clipboard {
+        sinks.push(Sink::Clipboard);
+    }
+
+    let status = shoot(&sinks);
+    if status == 0 {
+        announce(&sinks);
+    }
+    status
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    process::exit(run(&args, None));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seconds_must_be_finite_and_non_negative() {
+        assert_eq!(parse_seconds("0"), Some(time::Duration::from_secs(0)));
+        assert_eq!(parse_seconds("1.5"), Some(time::Duration::from_millis(1500)));
+        assert_eq!(parse_seconds("1e3"), Some(time::Duration::from_secs(1000)));
+        for s in ["-1", "inf", "-inf", "NaN", "1e30", "", "2s"] {
+            assert_eq!(parse_seconds(s), None, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn verification_checks_pixels_and_refuses_empty_images() {
+        let path = env::temp_dir().join(format!("shotgun-verify-{}.png", process::id()));
+        let image = RgbaImage::from_fn(20, 3, |x, y| Rgba([x as u8, y as u8, 0, 255]));
+        image.save(&path).unwrap();
+        assert_eq!(verify_output(&path, image::ImageFormat::Png, &image), Ok(()));
+        let mut other = image.clone();
+        other.put_pixel(19, 2, Rgba([0, 0, 0, 0]));
+        assert!(verify_output(&path, image::ImageFormat::Png, &other).is_err());
+        fs::remove_file(&path).unwrap();
+
+        for &(w, h) in [(0, 3), (3, 0), (0, 0)].iter() {
+            assert!(verify_output(&path, image::ImageFormat::Png, &RgbaImage::new(w, h))
+                    .unwrap_err().contains("empty"));
+        }
+    }
+
+    #[test]
+    fn templates_take_tokens_and_a_counter() {
+        let tokens = [("wm_class", "a/b%c".to_string())];
+        let mut calls = 0;
+        let mut counter = || {
+            calls += 1;
+            "007".to_string()
+        };
+        assert_eq!(template_path("shot-%c_%{wm_class}_%%c", "png", &tokens, &mut counter),
+                   "shot-007_a_b%c_%c.png");
+        assert_eq!(template_path("%{wm_class}", "jpg", &tokens, &mut counter), "a_b%c.jpg");
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn counts_persist_for_each_key() {
+        let path = env::temp_dir().join(format!("shotgun-counters-{}", process::id()))
+            .join("counters");
+        assert_eq!(next_count(&path, "shot-%c").unwrap(), 1);
+        assert_eq!(next_count(&path, "shot-%c").unwrap(), 2);
+        assert_eq!(next_count(&path, "other %c\ttab").unwrap(), 1);
+        assert_eq!(next_count(&path, "shot-%c").unwrap(), 3);
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, "1\tother %c\ttab\n3\tshot-%c\n");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn frames_last_until_the_next_one() {
+        let start = time::Instant::now();
+        let times: Vec<_> = [0, 100, 350].iter()
+            .map(|&ms| start + time::Duration::from_millis(ms)).collect();
+        assert_eq!(frame_delays(&times, time::Duration::from_millis(60)), [100, 250, 60]);
+        assert_eq!(frame_delays(&times[..1], time::Duration::from_secs(2)), [2000]);
+    }
 }
@@ -0,0 +1,96 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Desktop notifications through org.freedesktop.Notifications
+
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::os::raw::c_void;
+use std::ptr;
+
+use crate::dbus::*;
+
+fn cstr(s: &str) -> CString {
+    // Interior NULs cannot go over the bus, and would only come from odd window titles
+    CString::new(s.replace('\0', "")).expect("Failed to convert CString")
+}
+
+unsafe fn append_string(iter: &mut DBusMessageIter, s: &str) {
+    let s = cstr(s);
+    dbus_message_iter_append_basic(iter, DBUS_TYPE_STRING,
+                                   &s.as_ptr() as *const _ as *const c_void);
+}
+
+/// Show `summary` and `body`, with `image` (a file) as the picture of the notification
+pub fn send(summary: &str, body: &str, image: Option<&str>) -> Result<(), String> {
+    unsafe {
+        let mut error = DBusError::new();
+        let error_message = |error: &mut DBusError| {
+            let reason = if error.message.is_null() {
+                "unknown error".to_string()
+            } else {
+                CStr::from_ptr(error.message).to_string_lossy().into_owned()
+            };
+            dbus_error_free(error);
+            reason
+        };
+
+        let connection = dbus_bus_get(DBUS_BUS_SESSION, &mut error);
+        if dbus_error_is_set(&error) != 0 || connection.is_null() {
+            return Err(error_message(&mut error));
+        }
+
+        let name = cstr("org.freedesktop.Notifications");
+        let message = dbus_message_new_method_call(name.as_ptr(),
+                                                   cstr("/org/freedesktop/Notifications").as_ptr(),
+                                                   name.as_ptr(), cstr("Notify").as_ptr());
+        if message.is_null() {
+            return Err("out of memory".to_string());
+        }
+
+        // Notify(s app_name, u replaces_id, s app_icon, s summary, s body, as actions,
+        //        a{sv} hints, i expire_timeout)
+        let mut iter = DBusMessageIter::new();
+        let mut array = DBusMessageIter::new();
+        let replaces: u32 = 0;
+        let timeout: i32 = -1;
+        dbus_message_iter_init_append(message, &mut iter);
+        append_string(&mut iter, "shotgun");
+        dbus_message_iter_append_basic(&mut iter, DBUS_TYPE_UINT32,
+                                       &replaces as *const u32 as *const c_void);
+        append_string(&mut iter, "");
+        append_string(&mut iter, summary);
+        append_string(&mut iter, body);
+        dbus_message_iter_open_container(&mut iter, DBUS_TYPE_ARRAY, cstr("s").as_ptr(),
+                                         &mut array);
+        dbus_message_iter_close_container(&mut iter, &mut array);
+        dbus_message_iter_open_container(&mut iter, DBUS_TYPE_ARRAY, cstr("{sv}").as_ptr(),
+                                         &mut array);
+        if let Some(path) = image {
+            let mut entry = DBusMessageIter::new();
+            let mut variant = DBusMessageIter::new();
+            dbus_message_iter_open_container(&mut array, DBUS_TYPE_DICT_ENTRY, ptr::null(),
+                                             &mut entry);
+            append_string(&mut entry, "image-path");
+            dbus_message_iter_open_container(&mut entry, DBUS_TYPE_VARIANT, cstr("s").as_ptr(),
+                                             &mut variant);
+            append_string(&mut variant, &format!("file://{}", path));
+            dbus_message_iter_close_container(&mut entry, &mut variant);
+            dbus_message_iter_close_container(&mut array, &mut entry);
+        }
+        dbus_message_iter_close_container(&mut iter, &mut array);
+        dbus_message_iter_append_basic(&mut iter, DBUS_TYPE_INT32,
+                                       &timeout as *const i32 as *const c_void);
+
+        // Waiting for the reply tells whether a notification server is there at all
+        let reply = dbus_connection_send_with_reply_and_block(connection, message, 1000,
+                                                              &mut error);
+        dbus_message_unref(message);
+        if reply.is_null() {
+            return Err(error_message(&mut error));
+        }
+        dbus_message_unref(reply);
+        Ok(())
+    }
+}
@@ -0,0 +1,374 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use image::RgbaImage;
+
+use crate::pngenc;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorSpace {
+    Rgb,
+    Lab,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DominantMethod {
+    Average,
+    Mode,
+    /// Clusters grown from a median cut palette
+    KMeans,
+}
+
+pub struct Palette {
+    colors: Vec<[u8; 3]>,
+}
+
+/// An image reduced to indices into a palette
+pub struct IndexedImage {
+    pub width: u32,
+    pub height: u32,
+    pub palette: Vec<[u8; 3]>,
+    /// Index of the palette entry standing in for fully transparent pixels, if any
+    pub transparent: Option<u8>,
+    pub indices: Vec<u8>,
+}
+
+impl ColorSpace {
+    pub fn parse(s: &str) -> Option<ColorSpace> {
+        match s.to_lowercase().as_ref() {
+            "rgb" => Some(ColorSpace::Rgb),
+            "lab" => Some(ColorSpace::Lab),
+            _ => None,
+        }
+    }
+}
+
+impl DominantMethod {
+    pub fn parse(s: &str) -> Option<DominantMethod> {
+        match s.to_lowercase().as_ref() {
+            "average" => Some(DominantMethod::Average),
+            "mode" => Some(DominantMethod::Mode),
+            "kmeans" => Some(DominantMethod::KMeans),
+            _ => None,
+        }
+    }
+}
+
+impl Palette {
+    /// Load a palette file: hex colors (`#RRGGBB` or `RRGGBB`) separated by whitespace
+    pub fn load(path: &Path) -> Result<Palette, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut colors = Vec::new();
+
+        for token in contents.split_whitespace() {
+            match parse_hex_color(token) {
+                Some([r, g, b, _]) => colors.push([r, g, b]),
+                None => return Err(format!("invalid color {:?}", token)),
+            }
+        }
+
+        if colors.is_empty() {
+            return Err("palette is empty".to_string());
+        }
+        if colors.len() > 256 {
+            return Err("palette has more than 256 colors".to_string());
+        }
+
+        Ok(Palette {
+            colors,
+        })
+    }
+
+    /// Build a palette of at most `count` colors for `image`, by median cut
+    /// Images with few enough colors get them all back exactly
+    pub fn quantize(image: &RgbaImage, count: usize) -> Palette {
+        // Leave room for the entry of transparent pixels
+        let count = if image.pixels().any(|p| p[3] == 0) { count - 1 } else { count }.max(1);
+
+        let mut histogram: HashMap<[u8; 3], u32> = HashMap::new();
+        for p in image.pixels().filter(|p| p[3] != 0) {
+            *histogram.entry([p[0], p[1], p[2]]).or_insert(0) += 1;
+        }
+        // Sorted so that the same image always gets the same palette
+        let mut colors: Vec<_> = histogram.into_iter().collect();
+        colors.sort_unstable();
+        let mut boxes = vec![colors];
+
+        // Split the box with the widest channel at its median pixel, until there are enough
+        let range = |b: &[([u8; 3], u32)], c: usize| {
+            let (min, max) = b.iter().fold((255, 0), |(lo, hi), (rgb, _)| {
+                (rgb[c].min(lo), rgb[c].max(hi))
+            });
+            max.saturating_sub(min)
+        };
+        while boxes.len() < count {
+            let widest = (0..boxes.len()).flat_map(|i| (0..3).map(move |c| (i, c)))
+                .max_by_key(|&(i, c)| range(&boxes[i], c))
+                .filter(|&(i, c)| range(&boxes[i], c) > 0);
+            let (i, c) = match widest {
+                Some(w) => w,
+                None => break,
+            };
+
+            let mut b = boxes.swap_remove(i);
+            b.sort_unstable_by_key(|(rgb, _)| rgb[c]);
+            let total: u32 = b.iter().map(|(_, n)| n).sum();
+            let mut seen = 0;
+            let median = b.iter().position(|(_, n)| {
+                seen += n;
+                seen * 2 >= total
+            }).unwrap_or(0).clamp(0, b.len() - 2);
+            let upper = b.split_off(median + 1);
+            boxes.push(b);
+            boxes.push(upper);
+        }
+
+        let colors = boxes.iter().filter(|b| !b.is_empty()).map(|b| {
+            let total: u64 = b.iter().map(|&(_, n)| n as u64).sum();
+            let mut sum = [0u64; 3];
+            for (rgb, n) in b {
+                for c in 0..3 {
+                    sum[c] += rgb[c] as u64 * *n as u64;
+                }
+            }
+            sum.map(|s| ((s + total / 2) / total) as u8)
+        }).collect::<Vec<_>>();
+
+        Palette {
+            colors: if colors.is_empty() { vec![[0, 0, 0]] } else { colors },
+        }
+    }
+
+    /// Map every pixel of `image` to its nearest palette entry
+    pub fn remap(&self, image: &RgbaImage, space: ColorSpace) -> IndexedImage {
+        let keys: Vec<[f32; 3]> = self.colors.iter().map(|&c| to_space(c, space)).collect();
+        let mut cache: HashMap<[u8; 3], u8> = HashMap::new();
+
+        let mut palette = self.colors.clone();
+        let mut transparent = None;
+        if palette.len() < 256 && image.pixels().any(|p| p[3] == 0) {
+            transparent = Some(palette.len() as u8);
+            palette.push([0, 0, 0]);
+        }
+
+        let indices = image.pixels().map(|p| {
+            if p[3] == 0 {
+                if let Some(t) = transparent {
+                    return t;
+                }
+            }
+            let rgb = [p[0], p[1], p[2]];
+            *cache.entry(rgb).or_insert_with(|| nearest(&keys, to_space(rgb, space)))
+        }).collect();
+
+        IndexedImage {
+            width: image.width(),
+            height: image.height(),
+            palette,
+            transparent,
+            indices,
+        }
+    }
+}
+
+impl IndexedImage {
+    /// Expand back into a truecolor image, e.g. for formats without palette support
+    pub fn to_rgba(&self) -> RgbaImage {
+        RgbaImage::from_fn(self.width, self.height, |x, y| {
+            let i = self.indices[(y * self.width + x) as usize];
+            let [r, g, b] = self.palette[i as usize];
+            let a = if Some(i) == self.transparent { 0 } else { 0xFF };
+            image::Rgba([r, g, b, a])
+        })
+    }
+
+    pub fn write_png<W: io::Write>(&self, w: W, settings: &pngenc::Settings)
+                                   -> Result<(), png::EncodingError> {
+        let mut writer = settings.start(w, self.width, self.height, png::ColorType::Indexed,
+                                        png::BitDepth::Eight)?;
+
+        let plte: Vec<u8> = self.palette.iter().flatten().cloned().collect();
+        writer.write_chunk(*b"PLTE", &plte)?;
+        if let Some(t) = self.transparent {
+            // Entries past the end of tRNS are opaque
+            let mut trns = vec![0xFF; t as usize + 1];
+            trns[t as usize] = 0;
+            writer.write_chunk(*b"tRNS", &trns)?;
+        }
+
+        let width = self.width as usize;
+        pngenc::write_image_data(&mut writer, settings, self.height as usize, 1, &|y, row| {
+            row.extend_from_slice(&self.indices[y * width..(y + 1) * width])
+        })
+    }
+}
+
+/// Parse `#RRGGBB[AA]` (the `#` is optional), alpha defaults to opaque
+pub fn parse_hex_color(s: &str) -> Option<[u8; 4]> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut c = [0xFF; 4];
+    for (i, channel) in c.iter_mut().take(hex.len() / 2).enumerate() {
+        *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(c)
+}
+
+/// Average, most common or most representative colors of the non-transparent pixels of an
+/// image, up to `count` of them, the most widespread first
+/// The most common colors are found among colors quantized to 4 bits per channel, then averaged
+/// back within their bucket, the average is a single color
+pub fn dominant_colors(image: &RgbaImage, method: DominantMethod, count: usize)
+                       -> Vec<[u8; 3]> {
+    if method == DominantMethod::KMeans {
+        return kmeans(image, count);
+    }
+    let mut buckets: HashMap<[u8; 3], ([u64; 3], u64)> = HashMap::new();
+    for p in image.pixels().filter(|p| p[3] != 0) {
+        let key = match method {
+            DominantMethod::Mode => [p[0] >> 4, p[1] >> 4, p[2] >> 4],
+            _ => [0; 3],
+        };
+        let (sum, n) = buckets.entry(key).or_insert(([0; 3], 0));
+        for i in 0..3 {
+            sum[i] += p[i] as u64;
+        }
+        *n += 1;
+    }
+
+    let mut buckets: Vec<_> = buckets.into_values().collect();
+    buckets.sort_by_key(|&(sum, n)| (cmp::Reverse(n), sum));
+    buckets.iter().take(count).map(|(sum, n)| {
+        [(sum[0] / n) as u8, (sum[1] / n) as u8, (sum[2] / n) as u8]
+    }).collect()
+}
+
+/// Centers of `k` clusters of the colors of an image, refined from a median cut for a few rounds
+fn kmeans(image: &RgbaImage, k: usize) -> Vec<[u8; 3]> {
+    let mut histogram: HashMap<[u8; 3], u64> = HashMap::new();
+    for p in image.pixels().filter(|p| p[3] != 0) {
+        *histogram.entry([p[0], p[1], p[2]]).or_insert(0) += 1;
+    }
+    if histogram.is_empty() {
+        return Vec::new();
+    }
+
+    let distance = |a: [u8; 3], b: [u8; 3]| {
+        (0..3).map(|c| (a[c] as i32 - b[c] as i32).pow(2)).sum::<i32>()
+    };
+    let mut centers = Palette::quantize(image, k.max(1)).colors;
+    let mut sizes = vec![0; centers.len()];
+    for _ in 0..10 {
+        let mut sums = vec![([0u64; 3], 0u64); centers.len()];
+        for (&rgb, &n) in &histogram {
+            let nearest = (0..centers.len()).min_by_key(|&i| distance(rgb, centers[i]))
+                .unwrap_or(0);
+            let (sum, total) = &mut sums[nearest];
+            for c in 0..3 {
+                sum[c] += rgb[c] as u64 * n;
+            }
+            *total += n;
+        }
+
+        let moved: Vec<[u8; 3]> = sums.iter().zip(&centers).map(|(&(sum, n), &old)| {
+            if n == 0 { old } else { sum.map(|s| ((s + n / 2) / n) as u8) }
+        }).collect();
+        sizes = sums.iter().map(|&(_, n)| n).collect();
+        if moved == centers {
+            break;
+        }
+        centers = moved;
+    }
+
+    let mut clusters: Vec<_> = sizes.into_iter().zip(centers).filter(|&(n, _)| n > 0).collect();
+    clusters.sort_by_key(|&(n, rgb)| (cmp::Reverse(n), rgb));
+    clusters.into_iter().map(|(_, rgb)| rgb).collect()
+}
+
+fn nearest(keys: &[[f32; 3]], c: [f32; 3]) -> u8 {
+    let dist = |k: &[f32; 3]| (0..3).map(|i| (k[i] - c[i]).powi(2)).sum::<f32>();
+    let mut best = 0;
+    for (i, k) in keys.iter().enumerate() {
+        if dist(k) < dist(&keys[best]) {
+            best = i;
+        }
+    }
+    best as u8
+}
+
+fn to_space(c: [u8; 3], space: ColorSpace) -> [f32; 3] {
+    match space {
+        ColorSpace::Rgb => [c[0] as f32, c[1] as f32, c[2] as f32],
+        ColorSpace::Lab => srgb_to_lab(c),
+    }
+}
+
+fn srgb_to_lab(c: [u8; 3]) -> [f32; 3] {
+    let lin = |v: u8| {
+        let v = v as f32 / 255.0;
+        if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+    };
+    let (r, g, b) = (lin(c[0]), lin(c[1]), lin(c[2]));
+
+    // Linear sRGB to CIE XYZ, normalized to the D65 white point
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+    let f = |t: f32| {
+        if t > 216.0 / 24389.0 { t.cbrt() } else { (24389.0 / 27.0 * t + 16.0) / 116.0 }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indexed_png_round_trips_with_every_filter() {
+        let image = IndexedImage {
+            width: 21,
+            height: 9,
+            palette: (0..16).map(|i| [i * 16, 255 - i * 16, i * 7]).collect(),
+            transparent: Some(15),
+            indices: (0..21 * 9).map(|i| ((i * i / 7 + i / 21) % 16) as u8).collect(),
+        };
+        for &filter in [png::FilterType::NoFilter, png::FilterType::Sub, png::FilterType::Up,
+                        png::FilterType::Avg, png::FilterType::Paeth].iter() {
+            for &threads in [1, 2].iter() {
+                let settings = pngenc::Settings {
+                    compression: png::Compression::Fast,
+                    filter,
+                    text: Vec::new(),
+                    icc: None,
+                    threads,
+                };
+                let mut out = Vec::new();
+                image.write_png(&mut out, &settings).unwrap();
+
+                let mut decoder = png::Decoder::new(out.as_slice());
+                decoder.set_transformations(png::Transformations::IDENTITY);
+                let (info, mut reader) = decoder.read_info().unwrap();
+                let mut data = vec![0; info.buffer_size()];
+                reader.next_frame(&mut data).unwrap();
+                assert_eq!(info.color_type, png::ColorType::Indexed);
+                assert_eq!(data, image.indices, "{:?} on {} threads", filter, threads);
+                let plte: Vec<u8> = image.palette.iter().flatten().cloned().collect();
+                assert_eq!(reader.info().palette.as_deref(), Some(plte.as_slice()));
+            }
+        }
+    }
+}
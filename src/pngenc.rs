@@ -0,0 +1,554 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// PNG encoding through the png crate, for the settings and chunks the image crate's own encoder
+// does not expose, and animated PNGs put together from its output
+
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::io;
+use std::io::Write;
+use std::iter;
+use std::ops::Range;
+use std::thread;
+
+use adler32::RollingAdler32;
+use image::RgbaImage;
+
+use crate::xwrap::Rgba16Image;
+
+pub struct Settings {
+    pub compression: png::Compression,
+    pub filter: png::FilterType,
+    /// Keyword and text pairs, written before the image data
+    pub text: Vec<(&'static str, String)>,
+    /// ICC profile of the display the image came from
+    pub icc: Option<Vec<u8>>,
+    /// Threads to compress the image data with, each taking a band of rows
+    pub threads: usize,
+}
+
+/// Checksum of two pieces of data put together, `len` being the length of the second one
+fn adler32_combine(first: u32, second: u32, len: usize) -> u32 {
+    const BASE: u32 = 65521;
+    let rem = (len % BASE as usize) as u32;
+    let a = ((first & 0xFFFF) + (second & 0xFFFF) + BASE - 1) % BASE;
+    let b = (rem * (first & 0xFFFF) % BASE + (first >> 16) + (second >> 16) + BASE - rem) % BASE;
+    b << 16 | a
+}
+
+/// Wrap `data` in a zlib stream of stored blocks, as chunks like iCCP must be compressed
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        out.push(blocks.peek().is_none() as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    out.extend_from_slice(&RollingAdler32::from_buffer(data).hash().to_be_bytes());
+    out
+}
+
+impl Settings {
+    /// Write the header and text chunks of an image with the given layout
+    pub fn start<W: io::Write>(&self, w: W, width: u32, height: u32, color: png::ColorType,
+                               depth: png::BitDepth)
+                               -> Result<png::Writer<W>, png::EncodingError> {
+        let mut encoder = png::Encoder::new(w, width, height);
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        encoder.set_compression(self.compression.clone());
+        encoder.set_filter(self.filter);
+        let mut writer = encoder.write_header()?;
+
+        if let Some(icc) = &self.icc {
+            let data = [&b"ICC profile\0\0"[..], &zlib_stored(icc)].concat();
+            writer.write_chunk(*b"iCCP", &data)?;
+        }
+        for (keyword, text) in self.text.iter() {
+            // tEXt is Latin-1, anything else needs iTXt
+            if text.is_ascii() {
+                let data = [keyword.as_bytes(), b"\0", text.as_bytes()].concat();
+                writer.write_chunk(*b"tEXt", &data)?;
+            } else {
+                // Uncompressed, no language tag nor translated keyword
+                let data = [keyword.as_bytes(), b"\0\0\0\0\0", text.as_bytes()].concat();
+                writer.write_chunk(*b"iTXt", &data)?;
+            }
+        }
+        Ok(writer)
+    }
+}
+
+/// Rows are compressed and written out as they are fed, rather than the whole image data being
+/// compressed in memory first
+pub fn write_rgba<W: io::Write>(w: W, image: &RgbaImage, settings: &Settings)
+                                -> Result<(), png::EncodingError> {
+    let mut writer = settings.start(w, image.width(), image.height(), png::ColorType::RGBA,
+                                    png::BitDepth::Eight)?;
+    let row_len = image.width() as usize * 4;
+    let data: &[u8] = image;
+    write_image_data(&mut writer, settings, image.height() as usize, 4, &|y, row| {
+        row.extend_from_slice(&data[y * row_len..(y + 1) * row_len])
+    })
+}
+
+/// Same as `write_rgba`, with 16 bits per channel
+pub fn write_rgba16<W: io::Write>(w: W, image: &Rgba16Image, settings: &Settings)
+                                  -> Result<(), png::EncodingError> {
+    let mut writer = settings.start(w, image.width(), image.height(), png::ColorType::RGBA,
+                                    png::BitDepth::Sixteen)?;
+    let row_len = image.width() as usize * 4;
+    let data: &[u16] = image;
+    let bytes = |y: usize, row: &mut Vec<u8>| {
+        row.extend(data[y * row_len..(y + 1) * row_len].iter().flat_map(|v| v.to_be_bytes()))
+    };
+    write_image_data(&mut writer, settings, image.height() as usize, 8, &bytes)
+}
+
+/// Write an image that is gray already as such, from its red channel, at one bit per pixel with
+/// `one_bit` and otherwise with alpha if any pixel is not opaque
+pub fn write_gray<W: io::Write>(w: W, image: &RgbaImage, one_bit: bool, settings: &Settings)
+                                -> Result<(), png::EncodingError> {
+    let opaque = image.pixels().all(|p| p[3] == 0xFF);
+    let (color, depth, bpp) = match (one_bit, opaque) {
+        (true, _) => (png::ColorType::Grayscale, png::BitDepth::One, 1),
+        (false, true) => (png::ColorType::Grayscale, png::BitDepth::Eight, 1),
+        (false, false) => (png::ColorType::GrayscaleAlpha, png::BitDepth::Eight, 2),
+    };
+    let mut writer = settings.start(w, image.width(), image.height(), color, depth)?;
+
+    let data: &[u8] = image;
+    let row_len = image.width() as usize * 4;
+    let row = |y: usize, out: &mut Vec<u8>| {
+        let pixels = &data[y * row_len..(y + 1) * row_len];
+        match (one_bit, opaque) {
+            // Eight pixels to a byte, the leftmost in the high bit
+            (true, _) => out.extend(pixels.chunks(32).map(|c| {
+                c.chunks(4).enumerate().fold(0, |b, (i, p)| b | ((p[0] >= 0x80) as u8) << (7 - i))
+            })),
+            (false, true) => out.extend(pixels.chunks(4).map(|p| p[0])),
+            (false, false) => out.extend(pixels.chunks(4).flat_map(|p| [p[0], p[3]])),
+        }
+    };
+    write_image_data(&mut writer, settings, image.height() as usize, bpp, &row)
+}
+
+/// Lets the output of an encoder be looked at while the encoder holds on to it
+struct Shared<'a>(&'a RefCell<Vec<u8>>);
+
+impl io::Write for Shared<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Append `row` filtered against the unfiltered `prev` one, pixels being `bpp` bytes
+fn filter(method: png::FilterType, bpp: usize, prev: &[u8], row: &[u8], out: &mut Vec<u8>) {
+    out.push(method as u8);
+    out.extend(row.iter().enumerate().map(|(i, &x)| {
+        let a = if i >= bpp { row[i - bpp] } else { 0 };
+        let b = prev[i];
+        let c = if i >= bpp { prev[i - bpp] } else { 0 };
+        x.wrapping_sub(match method {
+            png::FilterType::NoFilter => 0,
+            png::FilterType::Sub => a,
+            png::FilterType::Up => b,
+            png::FilterType::Avg => ((a as u16 + b as u16) / 2) as u8,
+            png::FilterType::Paeth => paeth(a, b, c),
+        })
+    }));
+}
+
+/// Hand each of `rows` to `out` filtered, `row` giving the bytes of a row by index
+fn filter_rows(settings: &Settings, rows: Range<usize>, bpp: usize,
+               row: &(dyn Fn(usize, &mut Vec<u8>) + Sync),
+               mut out: impl FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> {
+    let (mut prev, mut current, mut filtered) = (Vec::new(), Vec::new(), Vec::new());
+    if rows.start > 0 {
+        row(rows.start - 1, &mut prev);
+    }
+    for y in rows {
+        current.clear();
+        row(y, &mut current);
+        prev.resize(current.len(), 0);
+        filtered.clear();
+        filter(settings.filter, bpp, &prev, &current, &mut filtered);
+        out(&filtered)?;
+        std::mem::swap(&mut prev, &mut current);
+    }
+    Ok(())
+}
+
+/// Filter and deflate `rows`, ending on a sync flush rather than the final block so that bands
+/// can follow each other in one stream
+/// Gives the compressed data along with the checksum and length of the filtered data
+fn compress_band(settings: &Settings, rows: Range<usize>, bpp: usize,
+                 row: &(dyn Fn(usize, &mut Vec<u8>) + Sync)) -> io::Result<(Vec<u8>, u32, usize)> {
+    let out = RefCell::new(Vec::new());
+    let mut encoder = deflate::write::DeflateEncoder::new(Shared(&out),
+                                                          settings.compression.clone());
+    let (mut adler, mut len) = (RollingAdler32::new(), 0);
+    filter_rows(settings, rows, bpp, row, |filtered| {
+        adler.update_buffer(filtered);
+        len += filtered.len();
+        encoder.write_all(filtered)
+    })?;
+    encoder.flush()?;
+    // Finishing the encoder ends the stream, which is left to the last band
+    let flushed = out.borrow().len();
+    encoder.finish()?;
+    let mut data = out.into_inner();
+    data.truncate(flushed);
+    Ok((data, adler.hash(), len))
+}
+
+/// Compressed data is sent out in IDAT chunks of this size
+const IDAT_SIZE: usize = 1 << 16;
+
+/// Wraps whatever is written to it in IDAT chunks
+struct Idat<'a, W: io::Write> {
+    writer: &'a mut png::Writer<W>,
+    pending: Vec<u8>,
+}
+
+impl<W: io::Write> Idat<'_, W> {
+    fn write_pending(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            self.writer.write_chunk(*b"IDAT", &self.pending).map_err(|e| match e {
+                png::EncodingError::IoError(e) => e,
+                e => io::Error::other(e.to_string()),
+            })?;
+            self.pending.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<W: io::Write> io::Write for Idat<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        if self.pending.len() >= IDAT_SIZE {
+            self.write_pending()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()
+    }
+}
+
+/// Write the image data filtered with `settings.filter`, `row` giving the bytes of a row by index
+/// It is compressed over `settings.threads` threads, each taking a band of rows, and otherwise
+/// written out as the rows are fed rather than the whole of it being compressed in memory first
+/// The png crate's own writers are not used, as they filter against the filtered previous row
+pub fn write_image_data<W: io::Write>(writer: &mut png::Writer<W>, settings: &Settings,
+                                      height: usize, bpp: usize,
+                                      row: &(dyn Fn(usize, &mut Vec<u8>) + Sync))
+                                      -> Result<(), png::EncodingError> {
+    if settings.threads > 1 && height > 1 {
+        return write_bands(writer, settings, height, bpp, row);
+    }
+    let mut idat = Idat { writer, pending: Vec::with_capacity(IDAT_SIZE) };
+    let mut encoder = deflate::write::ZlibEncoder::new(&mut idat, settings.compression.clone());
+    filter_rows(settings, 0..height, bpp, row, |filtered| encoder.write_all(filtered))?;
+    encoder.finish()?;
+    idat.write_pending()?;
+    Ok(())
+}
+
+fn write_bands<W: io::Write>(writer: &mut png::Writer<W>, settings: &Settings, height: usize,
+                             bpp: usize, row: &(dyn Fn(usize, &mut Vec<u8>) + Sync))
+                             -> Result<(), png::EncodingError> {
+    let band = height.div_ceil(settings.threads).max(1);
+    let bands = thread::scope(|s| {
+        let threads: Vec<_> = (0..height).step_by(band).map(|start| {
+            s.spawn(move || compress_band(settings, start..(start + band).min(height), bpp, row))
+        }).collect();
+        threads.into_iter().map(|t| t.join().unwrap()).collect::<io::Result<Vec<_>>>()
+    })?;
+
+    writer.write_chunk(*b"IDAT", &[0x78, 0x9C])?;
+    let mut adler = 1;
+    for (data, band_adler, len) in bands {
+        writer.write_chunk(*b"IDAT", &data)?;
+        adler = adler32_combine(adler, band_adler, len);
+    }
+    // An empty final stored block, then the checksum of it all
+    let end = [&[1, 0, 0, 0xFF, 0xFF][..], &adler.to_be_bytes()].concat();
+    writer.write_chunk(*b"IDAT", &end)
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&out[start..]);
+    out.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+/// The chunks of a PNG file, past the signature
+fn chunks(png: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut rest = png.get(8..).unwrap_or_default();
+    iter::from_fn(move || {
+        let len = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let kind = rest.get(4..8)?.try_into().ok()?;
+        let data = rest.get(8..8 + len)?;
+        rest = rest.get(12 + len..)?;
+        Some((kind, data))
+    })
+}
+
+/// Write an animated PNG out of PNG files of the same size, each shown for its delay in
+/// milliseconds and looping forever
+/// The chunks preceding the image data of the first one are kept for the whole animation
+pub fn write_apng(w: &mut dyn io::Write, frames: &[(Vec<u8>, u32)]) -> io::Result<()> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Frame is not a PNG file");
+    let first = &frames.first().ok_or_else(invalid)?.0;
+    let ihdr = chunks(first).next().filter(|(k, _)| k == b"IHDR").ok_or_else(invalid)?.1;
+
+    let mut out = first.get(..8).ok_or_else(invalid)?.to_vec();
+    for (kind, data) in chunks(first).take_while(|(k, _)| k != b"IDAT") {
+        write_chunk(&mut out, &kind, data);
+        if &kind == b"IHDR" {
+            let actl = [(frames.len() as u32).to_be_bytes(), 0u32.to_be_bytes()].concat();
+            write_chunk(&mut out, b"acTL", &actl);
+        }
+    }
+
+    // Frame control and frame data chunks share one sequence
+    let mut sequence = 0u32;
+    for (i, (png, delay)) in frames.iter().enumerate() {
+        let delay = (*delay).min(u16::MAX as u32) as u16;
+        let mut fctl = Vec::with_capacity(26);
+        fctl.extend_from_slice(&sequence.to_be_bytes());
+        // Width and height, then a zero offset
+        fctl.extend_from_slice(&ihdr[..8]);
+        fctl.extend_from_slice(&[0; 8]);
+        fctl.extend_from_slice(&delay.to_be_bytes());
+        fctl.extend_from_slice(&1000u16.to_be_bytes());
+        // No disposal, replacing the previous frame
+        fctl.extend_from_slice(&[0, 0]);
+        write_chunk(&mut out, b"fcTL", &fctl);
+        sequence += 1;
+
+        for (_, data) in chunks(png).filter(|(k, _)| k == b"IDAT") {
+            if i == 0 {
+                write_chunk(&mut out, b"IDAT", data);
+            } else {
+                write_chunk(&mut out, b"fdAT", &[&sequence.to_be_bytes()[..], data].concat());
+                sequence += 1;
+            }
+        }
+    }
+    write_chunk(&mut out, b"IEND", &[]);
+
+    w.write_all(&out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [png::FilterType; 5] = [png::FilterType::NoFilter, png::FilterType::Sub,
+                                           png::FilterType::Up, png::FilterType::Avg,
+                                           png::FilterType::Paeth];
+
+    fn settings(filter: png::FilterType, threads: usize) -> Settings {
+        Settings {
+            compression: png::Compression::Fast,
+            filter,
+            text: Vec::new(),
+            icc: None,
+            threads,
+        }
+    }
+
+    /// Colors that change along both axes, so that every filter has something to predict
+    fn pattern(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            let v = |k: u32| ((x * k + y * y * 3 + x * y) % 256) as u8;
+            image::Rgba([v(37), v(11), v(253), if (x + y) % 5 == 0 { 0x80 } else { 0xFF }])
+        })
+    }
+
+    /// The image data of a PNG as stored, unexpanded
+    fn decode(data: &[u8]) -> (png::OutputInfo, Vec<u8>) {
+        let mut decoder = png::Decoder::new(data);
+        decoder.set_transformations(png::Transformations::IDENTITY);
+        let (info, mut reader) = decoder.read_info().expect("Invalid PNG header");
+        let mut buf = vec![0; info.buffer_size()];
+        reader.next_frame(&mut buf).expect("Invalid PNG image data");
+        (info, buf)
+    }
+
+    #[test]
+    fn rgba_round_trips_with_every_filter() {
+        let image = pattern(23, 17);
+        for &filter in FILTERS.iter() {
+            for &threads in [1, 3].iter() {
+                let mut out = Vec::new();
+                write_rgba(&mut out, &image, &settings(filter, threads)).unwrap();
+                let (info, data) = decode(&out);
+                assert_eq!((info.width, info.height), (23, 17));
+                assert_eq!(data, image.to_vec(), "{:?} on {} threads", filter, threads);
+            }
+        }
+    }
+
+    #[test]
+    fn rgba16_round_trips_with_every_filter() {
+        let image = Rgba16Image::from_fn(9, 11, |x, y| {
+            let p = *pattern(9, 11).get_pixel(x, y);
+            image::Rgba([p[0] as u16 * 257, p[1] as u16 * 131, p[2] as u16, 0xFFFF - y as u16])
+        });
+        let expected: Vec<u8> = image.iter().flat_map(|v| v.to_be_bytes()).collect();
+        for &filter in FILTERS.iter() {
+            for &threads in [1, 4].iter() {
+                let mut out = Vec::new();
+                write_rgba16(&mut out, &image, &settings(filter, threads)).unwrap();
+                let (info, data) = decode(&out);
+                assert_eq!(info.bit_depth, png::BitDepth::Sixteen);
+                assert_eq!(data, expected, "{:?} on {} threads", filter, threads);
+            }
+        }
+    }
+
+    #[test]
+    fn gray_round_trips_with_every_filter() {
+        let image = pattern(19, 6);
+        let gray: Vec<u8> = image.pixels().map(|p| p[0]).collect();
+        let gray_alpha: Vec<u8> = image.pixels().flat_map(|p| [p[0], p[3]]).collect();
+        let opaque = RgbaImage::from_fn(19, 6, |x, y| {
+            let v = image.get_pixel(x, y)[0];
+            image::Rgba([v, v, v, 0xFF])
+        });
+        for &filter in FILTERS.iter() {
+            let mut out = Vec::new();
+            write_gray(&mut out, &image, false, &settings(filter, 1)).unwrap();
+            let (info, data) = decode(&out);
+            assert_eq!(info.color_type, png::ColorType::GrayscaleAlpha);
+            assert_eq!(data, gray_alpha, "{:?}", filter);
+
+            let mut out = Vec::new();
+            write_gray(&mut out, &opaque, false, &settings(filter, 2)).unwrap();
+            let (info, data) = decode(&out);
+            assert_eq!(info.color_type, png::ColorType::Grayscale);
+            assert_eq!(data, gray, "{:?}", filter);
+
+            // Rows of 19 pixels take 3 bytes, the last one padded with zeroes
+            let mut out = Vec::new();
+            write_gray(&mut out, &opaque, true, &settings(filter, 1)).unwrap();
+            let (info, data) = decode(&out);
+            assert_eq!(info.bit_depth, png::BitDepth::One);
+            for (y, row) in data.chunks(3).enumerate() {
+                for x in 0..19 {
+                    let bit = row[x / 8] >> (7 - x % 8) & 1;
+                    assert_eq!(bit == 1, gray[y * 19 + x] >= 0x80, "{:?}", filter);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn apng_round_trips() {
+        let frames: Vec<RgbaImage> = (0..3).map(|i| pattern(12 + i, 7)).map(|f| {
+            RgbaImage::from_fn(12, 7, |x, y| *f.get_pixel(x, y))
+        }).collect();
+        let mut settings = settings(png::FilterType::Paeth, 1);
+        settings.text.push(("Software", "shotgun".to_string()));
+        let pngs: Vec<(Vec<u8>, u32)> = frames.iter().zip([100, 250, 70000]).map(|(f, d)| {
+            let mut out = Vec::new();
+            write_rgba(&mut out, f, &settings).unwrap();
+            (out, d)
+        }).collect();
+        let mut out = Vec::new();
+        write_apng(&mut out, &pngs).unwrap();
+
+        // Chunks before the image data come from the first frame, and sequence numbers count up
+        let kinds: Vec<[u8; 4]> = chunks(&out).map(|(k, _)| k).collect();
+        assert_eq!(&kinds[..2], &[*b"IHDR", *b"acTL"]);
+        assert!(kinds.contains(b"tEXt"));
+        let sequence: Vec<u32> = chunks(&out).filter(|(k, _)| k == b"fcTL" || k == b"fdAT")
+            .map(|(_, d)| u32::from_be_bytes(d[..4].try_into().unwrap())).collect();
+        assert_eq!(sequence, (0..sequence.len() as u32).collect::<Vec<_>>());
+        // Delays in thousandths of a second, the longest one held to what fits
+        let delays: Vec<&[u8]> = chunks(&out).filter(|(k, _)| k == b"fcTL")
+            .map(|(_, d)| &d[20..24]).collect();
+        assert_eq!(delays, [[0, 100, 3, 0xE8], [0, 250, 3, 0xE8], [0xFF, 0xFF, 3, 0xE8]]);
+
+        let decoder = png::Decoder::new(&out[..]);
+        let (info, mut reader) = decoder.read_info().unwrap();
+        assert_eq!(reader.info().animation_control().map(|a| (a.num_frames, a.num_plays)),
+                   Some((3, 0)));
+        let mut buf = vec![0; info.buffer_size()];
+        for frame in frames.iter() {
+            reader.next_frame(&mut buf).unwrap();
+            assert_eq!(buf, frame.to_vec());
+        }
+    }
+
+    #[test]
+    fn chunk_checksums() {
+        // The check value of the CRC-32 used by PNG
+        let mut out = Vec::new();
+        write_chunk(&mut out, b"IEND", &[]);
+        assert_eq!(&out[8..], &[0xAE, 0x42, 0x60, 0x82]);
+        let mut out = Vec::new();
+        write_chunk(&mut out, b"tEXt", b"123456789");
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(b"tEXt123456789");
+        assert_eq!(&out[17..], &crc.finalize().to_be_bytes());
+    }
+
+    #[test]
+    fn adler32_of_pieces_adds_up() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i * 7 + i / 13) as u8).collect();
+        let whole = RollingAdler32::from_buffer(&data).hash();
+        for &split in [0, 1, 5552, 65521, 100_000, data.len()].iter() {
+            let (a, b) = data.split_at(split);
+            let first = RollingAdler32::from_buffer(a).hash();
+            let second = RollingAdler32::from_buffer(b).hash();
+            assert_eq!(adler32_combine(first, second, b.len()), whole, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn bands_round_trip() {
+        // More threads than rows, bands of a single row, and one band per thread
+        let image = pattern(31, 40);
+        for &threads in [2, 7, 40, 64].iter() {
+            let mut out = Vec::new();
+            write_rgba(&mut out, &image, &settings(png::FilterType::Avg, threads)).unwrap();
+            assert_eq!(decode(&out).1, image.to_vec(), "{} threads", threads);
+        }
+    }
+}
@@ -0,0 +1,224 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Backend for the xdg-desktop-portal Screenshot interface
+// Each request runs shotgun again with the usual options, so it gets the regular capture path
+
+use std::env;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::os::raw::c_void;
+use std::path::PathBuf;
+use std::process::Command;
+use std::ptr;
+
+use crate::dbus::*;
+
+pub const BUS_NAME: &str = "org.freedesktop.impl.portal.desktop.shotgun";
+const INTERFACE: &str = "org.freedesktop.impl.portal.Screenshot";
+const VERSION: u32 = 1;
+
+// Response codes of the portal Request interface
+const RESPONSE_SUCCESS: u32 = 0;
+const RESPONSE_CANCELLED: u32 = 1;
+const RESPONSE_OTHER: u32 = 2;
+
+fn cstr(s: &str) -> CString {
+    CString::new(s).expect("Failed to convert CString")
+}
+
+/// Whether `options` (an `a{sv}` iterator) has `key` set to true
+unsafe fn option_flag(options: &mut DBusMessageIter, key: &str) -> bool {
+    let mut entry = DBusMessageIter::new();
+    loop {
+        if dbus_message_iter_get_arg_type(options) != DBUS_TYPE_DICT_ENTRY {
+            return false;
+        }
+        dbus_message_iter_recurse(options, &mut entry);
+
+        let mut name: *const c_char = ptr::null();
+        dbus_message_iter_get_basic(&mut entry, &mut name as *mut _ as *mut c_void);
+        dbus_message_iter_next(&mut entry);
+        let mut variant = DBusMessageIter::new();
+        dbus_message_iter_recurse(&mut entry, &mut variant);
+
+        if CStr::from_ptr(name).to_bytes() == key.as_bytes()
+           && dbus_message_iter_get_arg_type(&mut variant) == DBUS_TYPE_BOOLEAN {
+            let mut value: u32 = 0;
+            dbus_message_iter_get_basic(&mut variant, &mut value as *mut _ as *mut c_void);
+            return value != 0;
+        }
+        if dbus_message_iter_next(options) == 0 {
+            return false;
+        }
+    }
+}
+
+/// Append `{key: <string value>}` to an open `a{sv}` container
+unsafe fn append_string_entry(dict: &mut DBusMessageIter, key: &str, value: &str) {
+    let key = cstr(key);
+    let value = cstr(value);
+    let mut entry = DBusMessageIter::new();
+    let mut variant = DBusMessageIter::new();
+    dbus_message_iter_open_container(dict, DBUS_TYPE_DICT_ENTRY, ptr::null(), &mut entry);
+    dbus_message_iter_append_basic(&mut entry, DBUS_TYPE_STRING,
+                                   &key.as_ptr() as *const _ as *const c_void);
+    dbus_message_iter_open_container(&mut entry, DBUS_TYPE_VARIANT, cstr("s").as_ptr(),
+                                     &mut variant);
+    dbus_message_iter_append_basic(&mut variant, DBUS_TYPE_STRING,
+                                   &value.as_ptr() as *const _ as *const c_void);
+    dbus_message_iter_close_container(&mut entry, &mut variant);
+    dbus_message_iter_close_container(dict, &mut entry);
+}
+
+/// Capture into a new time-stamped file in the runtime directory, as the service is started with
+/// / as its working directory
+/// Interactive requests let the user select the area first
+fn capture(interactive: bool) -> (u32, Option<String>) {
+    let dir = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from).unwrap_or_else(env::temp_dir);
+    let path = dir.join(crate::timestamp_path("png", true));
+    let exe = env::current_exe().unwrap_or_else(|_| "shotgun".into());
+
+    let mut command = Command::new(exe);
+    if interactive {
+        command.arg("-s");
+    }
+    match command.arg(&path).status() {
+        Ok(s) if s.success() => {
+            (RESPONSE_SUCCESS, Some(format!("file://{}", path.display())))
+        },
+        // Failing a selection mostly means it was dismissed
+        Ok(_) if interactive => (RESPONSE_CANCELLED, None),
+        Ok(_) => (RESPONSE_OTHER, None),
+        Err(e) => {
+            eprintln!("Failed to run the capture: {}", e);
+            (RESPONSE_OTHER, None)
+        },
+    }
+}
+
+/// Reply to `Screenshot(o handle, s app_id, s parent_window, a{sv} options)`
+unsafe fn screenshot(message: *mut DBusMessage) -> *mut DBusMessage {
+    let mut args = DBusMessageIter::new();
+    let mut interactive = false;
+    if dbus_message_iter_init(message, &mut args) != 0 {
+        // Skip to the options
+        for _ in 0..3 {
+            dbus_message_iter_next(&mut args);
+        }
+        if dbus_message_iter_get_arg_type(&mut args) == DBUS_TYPE_ARRAY {
+            let mut options = DBusMessageIter::new();
+            dbus_message_iter_recurse(&mut args, &mut options);
+            interactive = option_flag(&mut options, "interactive");
+        }
+    }
+
+    let (response, uri) = capture(interactive);
+
+    let reply = dbus_message_new_method_return(message);
+    let mut iter = DBusMessageIter::new();
+    let mut results = DBusMessageIter::new();
+    dbus_message_iter_init_append(reply, &mut iter);
+    dbus_message_iter_append_basic(&mut iter, DBUS_TYPE_UINT32,
+                                   &response as *const u32 as *const c_void);
+    dbus_message_iter_open_container(&mut iter, DBUS_TYPE_ARRAY, cstr("{sv}").as_ptr(),
+                                     &mut results);
+    if let Some(uri) = uri {
+        append_string_entry(&mut results, "uri", &uri);
+    }
+    dbus_message_iter_close_container(&mut iter, &mut results);
+    reply
+}
+
+/// Reply to `org.freedesktop.DBus.Properties.Get`, only `version` exists
+unsafe fn get_property(message: *mut DBusMessage) -> *mut DBusMessage {
+    let mut args = DBusMessageIter::new();
+    let mut names = [ptr::null::<c_char>(); 2];
+    if dbus_message_iter_init(message, &mut args) != 0 {
+        for name in names.iter_mut() {
+            if dbus_message_iter_get_arg_type(&mut args) == DBUS_TYPE_STRING {
+                dbus_message_iter_get_basic(&mut args, name as *mut _ as *mut c_void);
+            }
+            dbus_message_iter_next(&mut args);
+        }
+    }
+    let is = |s: *const c_char, expected: &str| {
+        !s.is_null() && CStr::from_ptr(s).to_bytes() == expected.as_bytes()
+    };
+    if !is(names[0], INTERFACE) || !is(names[1], "version") {
+        return dbus_message_new_error(message, cstr("org.freedesktop.DBus.Error.UnknownProperty")
+                                      .as_ptr(), cstr("No such property").as_ptr());
+    }
+
+    let reply = dbus_message_new_method_return(message);
+    let mut iter = DBusMessageIter::new();
+    let mut variant = DBusMessageIter::new();
+    dbus_message_iter_init_append(reply, &mut iter);
+    dbus_message_iter_open_container(&mut iter, DBUS_TYPE_VARIANT, cstr("u").as_ptr(),
+                                     &mut variant);
+    dbus_message_iter_append_basic(&mut variant, DBUS_TYPE_UINT32,
+                                   &VERSION as *const u32 as *const c_void);
+    dbus_message_iter_close_container(&mut iter, &mut variant);
+    reply
+}
+
+/// Own the backend name on the session bus and serve requests until the bus goes away
+pub fn run() -> i32 {
+    unsafe {
+        let mut error = DBusError::new();
+        let connection = dbus_bus_get(DBUS_BUS_SESSION, &mut error);
+        if dbus_error_is_set(&error) != 0 || connection.is_null() {
+            let reason = if error.message.is_null() {
+                "unknown error".into()
+            } else {
+                CStr::from_ptr(error.message).to_string_lossy()
+            };
+            eprintln!("Failed to connect to the session bus: {}", reason);
+            dbus_error_free(&mut error);
+            return 1;
+        }
+
+        let owner = dbus_bus_request_name(connection, cstr(BUS_NAME).as_ptr(),
+                                          DBUS_NAME_FLAG_DO_NOT_QUEUE, &mut error);
+        if owner != DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER {
+            eprintln!("Failed to acquire {}, is another backend running?", BUS_NAME);
+            dbus_error_free(&mut error);
+            return 1;
+        }
+
+        let properties = cstr("org.freedesktop.DBus.Properties");
+        let interface = cstr(INTERFACE);
+        while dbus_connection_read_write(connection, -1) != 0 {
+            loop {
+                let message = dbus_connection_pop_message(connection);
+                if message.is_null() {
+                    break;
+                }
+
+                let reply = if dbus_message_is_method_call(message, interface.as_ptr(),
+                                                           cstr("Screenshot").as_ptr()) != 0 {
+                    screenshot(message)
+                } else if dbus_message_is_method_call(message, properties.as_ptr(),
+                                                      cstr("Get").as_ptr()) != 0 {
+                    get_property(message)
+                } else if dbus_message_get_type(message) == DBUS_MESSAGE_TYPE_METHOD_CALL {
+                    dbus_message_new_error(message,
+                                           cstr("org.freedesktop.DBus.Error.UnknownMethod")
+                                           .as_ptr(), cstr("Unknown method").as_ptr())
+                } else {
+                    ptr::null_mut()
+                };
+
+                if !reply.is_null() {
+                    dbus_connection_send(connection, reply, ptr::null_mut());
+                    dbus_connection_flush(connection);
+                    dbus_message_unref(reply);
+                }
+                dbus_message_unref(message);
+            }
+        }
+        0
+    }
+}
@@ -0,0 +1,179 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// QOI encoder, see https://qoiformat.org/qoi-specification.pdf
+
+use std::io;
+
+use image::RgbaImage;
+
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const OP_RUN: u8 = 0xC0;
+const OP_RGB: u8 = 0xFE;
+const OP_RGBA: u8 = 0xFF;
+
+/// Encode `image` as a QOI file
+pub fn write_qoi(w: &mut dyn io::Write, image: &RgbaImage) -> io::Result<()> {
+    let (width, height) = image.dimensions();
+    let mut out = Vec::with_capacity(14 + image.len() / 2);
+    out.extend_from_slice(b"qoif");
+    out.extend_from_slice(&width.to_be_bytes());
+    out.extend_from_slice(&height.to_be_bytes());
+    // RGBA, sRGB with linear alpha
+    out.extend_from_slice(&[4, 0]);
+
+    let mut index = [[0u8; 4]; 64];
+    let mut prev = [0, 0, 0, 0xFF];
+    let mut run = 0;
+    let count = image.pixels().len();
+    for (i, p) in image.pixels().enumerate() {
+        let px = p.0;
+        if px == prev {
+            run += 1;
+            if run == 62 || i + 1 == count {
+                out.push(OP_RUN | (run - 1));
+                run = 0;
+            }
+            continue;
+        }
+        if run > 0 {
+            out.push(OP_RUN | (run - 1));
+            run = 0;
+        }
+
+        let [r, g, b, a] = px;
+        let hash = (r as usize * 3 + g as usize * 5 + b as usize * 7 + a as usize * 11) % 64;
+        if index[hash] == px {
+            out.push(OP_INDEX | hash as u8);
+        } else {
+            index[hash] = px;
+            if a == prev[3] {
+                let dr = r.wrapping_sub(prev[0]) as i8;
+                let dg = g.wrapping_sub(prev[1]) as i8;
+                let db = b.wrapping_sub(prev[2]) as i8;
+                let dr_dg = dr.wrapping_sub(dg);
+                let db_dg = db.wrapping_sub(dg);
+                let small = |d: i8| (-2..=1).contains(&d);
+                if small(dr) && small(dg) && small(db) {
+                    out.push(OP_DIFF | ((dr + 2) as u8) << 4 | ((dg + 2) as u8) << 2
+                             | (db + 2) as u8);
+                } else if (-32..=31).contains(&dg) && (-8..=7).contains(&dr_dg)
+                          && (-8..=7).contains(&db_dg) {
+                    out.push(OP_LUMA | (dg + 32) as u8);
+                    out.push(((dr_dg + 8) as u8) << 4 | (db_dg + 8) as u8);
+                } else {
+                    out.extend_from_slice(&[OP_RGB, r, g, b]);
+                }
+            } else {
+                out.extend_from_slice(&[OP_RGBA, r, g, b, a]);
+            }
+        }
+        prev = px;
+    }
+
+    out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+    w.write_all(&out)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    /// Decoder following the specification
+    fn decode(data: &[u8]) -> RgbaImage {
+        assert_eq!(&data[..4], b"qoif");
+        let width = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        let height = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+        assert_eq!(&data[12..14], &[4, 0]);
+        assert_eq!(&data[data.len() - 8..], &[0, 0, 0, 0, 0, 0, 0, 1]);
+
+        let mut index = [[0u8; 4]; 64];
+        let mut px = [0, 0, 0, 0xFF];
+        let mut pixels = Vec::new();
+        let mut chunks = data[14..data.len() - 8].iter().copied();
+        while let Some(b) = chunks.next() {
+            let mut run = 1;
+            match b {
+                OP_RGB => {
+                    px = [chunks.next().unwrap(), chunks.next().unwrap(), chunks.next().unwrap(),
+                          px[3]];
+                },
+                OP_RGBA => px = [0; 4].map(|_| chunks.next().unwrap()),
+                _ => match b & 0xC0 {
+                    OP_INDEX => px = index[b as usize],
+                    OP_DIFF => {
+                        for (c, shift) in [(0, 4), (1, 2), (2, 0)] {
+                            px[c] = px[c].wrapping_add((b >> shift) & 3).wrapping_sub(2);
+                        }
+                    },
+                    OP_LUMA => {
+                        let dg = (b & 0x3F).wrapping_sub(32);
+                        let next = chunks.next().unwrap();
+                        px[0] = px[0].wrapping_add(dg).wrapping_add(next >> 4).wrapping_sub(8);
+                        px[1] = px[1].wrapping_add(dg);
+                        px[2] = px[2].wrapping_add(dg).wrapping_add(next & 0xF).wrapping_sub(8);
+                    },
+                    _ => run = (b & 0x3F) as usize + 1,
+                },
+            }
+            let [r, g, b, a] = px;
+            index[(r as usize * 3 + g as usize * 5 + b as usize * 7 + a as usize * 11) % 64] = px;
+            for _ in 0..run {
+                pixels.extend_from_slice(&px);
+            }
+        }
+        RgbaImage::from_raw(width, height, pixels).unwrap()
+    }
+
+    fn round_trip(image: &RgbaImage) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_qoi(&mut out, image).unwrap();
+        let decoded = decode(&out);
+        assert_eq!(decoded.dimensions(), image.dimensions());
+        assert_eq!(decoded.into_raw(), image.to_vec());
+        out
+    }
+
+    #[test]
+    fn noise_round_trips() {
+        let mut state = 0x1234_5678u32;
+        let image = RgbaImage::from_fn(33, 17, |_, _| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let v = state >> 8;
+            // A few alpha levels, so that both RGB and RGBA chunks come up
+            let a = [255, 255, 128, 0][v as usize % 4];
+            Rgba([v as u8, (v >> 8) as u8, (v >> 16) as u8, a])
+        });
+        round_trip(&image);
+    }
+
+    #[test]
+    fn small_steps_round_trip() {
+        // Differences that wrap around, and ones just in and out of the short chunks' range
+        let image = RgbaImage::from_fn(64, 4, |x, y| {
+            let step = [1u8, 255, 2, 30, 33, 250][(x % 6) as usize];
+            let r = (x as u8).wrapping_mul(step);
+            let g = (x as u8).wrapping_mul(step).wrapping_add(y as u8 * 40);
+            Rgba([r, g, r.wrapping_sub(g), 255])
+        });
+        round_trip(&image);
+    }
+
+    #[test]
+    fn runs_are_split_and_closed() {
+        // Longer than one run chunk, and running up to the last pixel
+        let image = RgbaImage::from_fn(200, 1, |x, _| {
+            Rgba(if x < 10 { [9, 9, 9, 255] } else { [0, 0, 0, 255] })
+        });
+        let out = round_trip(&image);
+        assert!(out.len() < 14 + 8 + 10);
+        round_trip(&RgbaImage::from_pixel(1, 1, Rgba([0, 0, 0, 255])));
+        round_trip(&RgbaImage::new(3, 3));
+    }
+}
@@ -0,0 +1,155 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Inline image protocols for displaying captures right in the terminal
+
+use std::env;
+use std::io;
+
+use image::RgbaImage;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Protocol {
+    Sixel,
+    Kitty,
+}
+
+impl Protocol {
+    /// Best guess at whether the terminal we're running in understands the protocol
+    pub fn is_supported(self) -> bool {
+        let term = env::var("TERM").unwrap_or_default();
+        let program = env::var("TERM_PROGRAM").unwrap_or_default();
+
+        match self {
+            Protocol::Sixel => {
+                term.contains("sixel")
+                    || ["mlterm", "foot", "yaft-256color", "contour"].contains(&term.as_str())
+                    || ["WezTerm", "mintty", "iTerm.app"].contains(&program.as_str())
+            },
+            Protocol::Kitty => {
+                env::var_os("KITTY_WINDOW_ID").is_some()
+                    || term.contains("kitty")
+                    || ["WezTerm", "ghostty"].contains(&program.as_str())
+            },
+        }
+    }
+}
+
+/// Write an escape sequence, wrapping it for passthrough when running inside tmux
+fn write_sequence(w: &mut dyn io::Write, seq: &[u8]) -> io::Result<()> {
+    if env::var_os("TMUX").is_none() {
+        return w.write_all(seq);
+    }
+
+    w.write_all(b"\x1bPtmux;")?;
+    for &b in seq {
+        if b == 0x1b {
+            w.write_all(b"\x1b")?;
+        }
+        w.write_all(&[b])?;
+    }
+    w.write_all(b"\x1b\\")
+}
+
+fn base64(data: &[u8]) -> Vec<u8> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = Vec::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+        out.push(ALPHABET[n >> 18 & 0x3F]);
+        out.push(ALPHABET[n >> 12 & 0x3F]);
+        out.push(if chunk.len() > 1 { ALPHABET[n >> 6 & 0x3F] } else { b'=' });
+        out.push(if chunk.len() > 2 { ALPHABET[n & 0x3F] } else { b'=' });
+    }
+
+    out
+}
+
+/// Display an encoded PNG using the kitty graphics protocol
+pub fn write_kitty(w: &mut dyn io::Write, png: &[u8]) -> io::Result<()> {
+    let data = base64(png);
+    let chunks: Vec<&[u8]> = data.chunks(4096).collect();
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = if i + 1 < chunks.len() { 1 } else { 0 };
+        let mut seq = if i == 0 {
+            format!("\x1b_Gf=100,a=T,m={};", more).into_bytes()
+        } else {
+            format!("\x1b_Gm={};", more).into_bytes()
+        };
+        seq.extend_from_slice(chunk);
+        seq.extend_from_slice(b"\x1b\\");
+        write_sequence(w, &seq)?;
+    }
+
+    w.write_all(b"\n")
+}
+
+/// Display an image as sixels, reduced to a 6x6x6 color cube
+/// Fully transparent pixels are left undrawn
+pub fn write_sixel(w: &mut dyn io::Write, image: &RgbaImage) -> io::Result<()> {
+    let (width, height) = image.dimensions();
+    let level = |c: u8| (c as u32 * 5 + 127) / 255;
+    let index = |x: u32, y: u32| {
+        let p = image.get_pixel(x, y);
+        if p[3] == 0 {
+            None
+        } else {
+            Some((level(p[0]) * 36 + level(p[1]) * 6 + level(p[2])) as usize)
+        }
+    };
+
+    // Transparent background, then the palette with channels in percent
+    let mut seq = format!("\x1bP0;1;0q\"1;1;{};{}", width, height).into_bytes();
+    for i in 0..216 {
+        let percent = |l: u32| l * 100 / 5;
+        seq.extend(format!("#{};2;{};{};{}", i, percent(i / 36), percent(i / 6 % 6),
+                           percent(i % 6)).bytes());
+    }
+
+    for band in (0..height).step_by(6) {
+        let rows = (height - band).min(6);
+
+        // Sixel bitmasks of every color, for every column of the band
+        let mut masks: Vec<Option<Vec<u8>>> = vec![None; 216];
+        for x in 0..width {
+            for dy in 0..rows {
+                if let Some(i) = index(x, band + dy) {
+                    let row = masks[i].get_or_insert_with(|| vec![0; width as usize]);
+                    row[x as usize] |= 1 << dy;
+                }
+            }
+        }
+
+        for (i, row) in masks.iter().enumerate() {
+            let row = match row {
+                Some(r) => r,
+                None => continue,
+            };
+            seq.extend(format!("#{}", i).bytes());
+
+            // Run-length encode, rewinding to the start of the band for the next color
+            let mut x = 0;
+            while x < row.len() {
+                let run = row[x..].iter().take_while(|&&m| m == row[x]).count();
+                let c = 0x3F + row[x];
+                if run > 3 {
+                    seq.extend(format!("!{}", run).bytes());
+                    seq.push(c);
+                } else {
+                    seq.extend(std::iter::repeat_n(c, run));
+                }
+                x += run;
+            }
+            seq.push(b'$');
+        }
+        seq.push(b'-');
+    }
+    seq.extend_from_slice(b"\x1b\\");
+
+    write_sequence(w, &seq)?;
+    w.write_all(b"\n")
+}
@@ -0,0 +1,70 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Baseline TIFF encoder, uncompressed RGBA in a single strip
+
+use std::convert::TryFrom;
+use std::io;
+
+use image::RgbaImage;
+
+use crate::xwrap::Rgba16Image;
+
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+
+/// Encode `image` as a little-endian TIFF file with unassociated alpha
+pub fn write_tiff(w: &mut dyn io::Write, image: &RgbaImage) -> io::Result<()> {
+    write(w, image.dimensions(), 8, image)
+}
+
+/// Encode `image` at 16 bits per sample
+pub fn write_tiff16(w: &mut dyn io::Write, image: &Rgba16Image) -> io::Result<()> {
+    let samples: Vec<u8> = image.iter().flat_map(|v| v.to_le_bytes()).collect();
+    write(w, image.dimensions(), 16, &samples)
+}
+
+fn write(w: &mut dyn io::Write, (width, height): (u32, u32), bits: u16, samples: &[u8])
+         -> io::Result<()> {
+    let size = u32::try_from(samples.len()).ok().filter(|&s| s < u32::MAX - 1024)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                                      "image too large for TIFF"))?;
+
+    // Header, then the directory, the bits per sample array and the pixels
+    const ENTRIES: u32 = 11;
+    let bits_offset = 8 + 2 + ENTRIES * 12 + 4;
+    let data_offset = bits_offset + 8;
+    let entries: [(u16, u16, u32, u32); ENTRIES as usize] = [
+        (256, LONG, 1, width),
+        (257, LONG, 1, height),
+        (258, SHORT, 4, bits_offset), // BitsPerSample
+        (259, SHORT, 1, 1), // No compression
+        (262, SHORT, 1, 2), // RGB
+        (273, LONG, 1, data_offset),
+        (277, SHORT, 1, 4), // SamplesPerPixel
+        (278, LONG, 1, height), // RowsPerStrip
+        (279, LONG, 1, size),
+        (284, SHORT, 1, 1), // Chunky
+        (338, SHORT, 1, 2), // Unassociated alpha
+    ];
+
+    let mut out = Vec::with_capacity((data_offset + size) as usize);
+    out.extend_from_slice(b"II*\0");
+    out.extend_from_slice(&8u32.to_le_bytes());
+    out.extend_from_slice(&(ENTRIES as u16).to_le_bytes());
+    for &(tag, kind, count, value) in entries.iter() {
+        out.extend_from_slice(&tag.to_le_bytes());
+        out.extend_from_slice(&kind.to_le_bytes());
+        out.extend_from_slice(&count.to_le_bytes());
+        // Single shorts sit in the low bytes of the value field
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    out.extend_from_slice(&0u32.to_le_bytes());
+    for _ in 0..4 {
+        out.extend_from_slice(&bits.to_le_bytes());
+    }
+    out.extend_from_slice(samples);
+    w.write_all(&out)
+}
+
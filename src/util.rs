@@ -1,6 +1,9 @@
 use std::cmp;
+use std::ffi;
+use std::mem;
+use std::ptr;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Rect {
     pub x: i32,
     pub y: i32,
@@ -9,6 +12,122 @@ pub struct Rect {
 }
 
 impl Rect {
+    /// Rectangle spanning two opposite corners, both included
+    pub fn from_corners(a: (i32, i32), b: (i32, i32)) -> Rect {
+        Rect {
+            x: cmp::min(a.0, b.0),
+            y: cmp::min(a.1, b.1),
+            w: (a.0 - b.0).abs() + 1,
+            h: (a.1 - b.1).abs() + 1,
+        }
+    }
+
+    /// Smallest rectangle containing both
+    pub fn union(&self, other: Rect) -> Rect {
+        let x = cmp::min(self.x, other.x);
+        let y = cmp::min(self.y, other.y);
+
+        Rect {
+            x,
+            y,
+            w: cmp::max(self.x + self.w, other.x + other.w) - x,
+            h: cmp::max(self.y + self.h, other.y + other.h) - y,
+        }
+    }
+
+    /// Parse an X geometry string, `[=]<width>x<height>[{+-}<x>{+-}<y>]`, negative offsets such as
+    /// `-0-0` being taken from the right and bottom edges of an area of size `within`
+    /// As with XParseGeometry, the number after the sign can have a sign of its own, `+-5` being
+    /// 5 pixels left of the left edge
+    pub fn parse(geometry: &str, within: (i32, i32)) -> Result<Rect, String> {
+        let number = |s: &str, what: &str| -> Result<i32, String> {
+            if s.is_empty() {
+                return Err(format!("missing {}", what));
+            }
+            match s.parse::<u32>() {
+                Ok(n) if n <= i32::MAX as u32 => Ok(n as i32),
+                _ => Err(format!("invalid {} {}", what, s)),
+            }
+        };
+
+        let s = geometry.strip_prefix('=').unwrap_or(geometry);
+        let (size, offsets) = s.split_at(s.find(['+', '-']).unwrap_or(s.len()));
+        let (w, h) = match size.split_once(['x', 'X']) {
+            Some((w, h)) => (number(w, "width")?, number(h, "height")?),
+            None if size.is_empty() => return Err("missing size".to_string()),
+            None => return Err(format!("expected WIDTHxHEIGHT, not {}", size)),
+        };
+        if w == 0 || h == 0 {
+            return Err("width and height must be positive".to_string());
+        }
+
+        // The sign giving the edge of the next offset is the first one after a digit
+        let next_offset = |s: &str| s.char_indices().skip(1).find(|&(i, c)| {
+            (c == '+' || c == '-') && s[..i].ends_with(|c: char| c.is_ascii_digit())
+        }).map(|(i, _)| i);
+        let offset = |s: &str, len: i32, within: i32, what: &str| -> Result<i32, String> {
+            let digits = s[1..].strip_prefix(['+', '-']).unwrap_or(&s[1..]);
+            number(digits, what)?;
+            let n = s[1..].parse::<i32>().map_err(|_| format!("invalid {} {}", what, &s[1..]))?;
+            if !s.starts_with('-') {
+                return Ok(n);
+            }
+            (within - len).checked_sub(n).ok_or_else(|| format!("{} out of range", what))
+        };
+        let (x, y) = match next_offset(offsets) {
+            None if offsets.is_empty() => (0, 0),
+            None => return Err("expected both an X and a Y offset".to_string()),
+            Some(i) if next_offset(&offsets[i..]).is_some() => {
+                return Err(format!("too many offsets in {}", offsets));
+            },
+            Some(i) => {
+                let (x, y) = offsets.split_at(i);
+                (offset(x, w, within.0, "X offset")?, offset(y, h, within.1, "Y offset")?)
+            },
+        };
+
+        Ok(Rect { x, y, w, h })
+    }
+
+    /// Whether the pixel at `x`, `y` lies inside
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && x < self.x + self.w && y >= self.y && y < self.y + self.h
+    }
+
+    /// Same rectangle moved by `dx` and `dy`
+    pub fn translate(&self, dx: i32, dy: i32) -> Rect {
+        Rect {
+            x: self.x + dx,
+            y: self.y + dy,
+            ..*self
+        }
+    }
+
+    /// Position and size multiplied by `factor`, covering every pixel the original touches
+    pub fn scale(&self, factor: f64) -> Rect {
+        let (x, y) = ((self.x as f64 * factor).floor(), (self.y as f64 * factor).floor());
+        let right = ((self.x + self.w) as f64 * factor).ceil();
+        let bottom = ((self.y + self.h) as f64 * factor).ceil();
+        Rect {
+            x: x as i32,
+            y: y as i32,
+            w: (right - x) as i32,
+            h: (bottom - y) as i32,
+        }
+    }
+
+    /// Moved inside `bounds`, and shrunk to fit it if larger
+    pub fn clamp_to(&self, bounds: Rect) -> Rect {
+        let w = cmp::min(self.w, bounds.w);
+        let h = cmp::min(self.h, bounds.h);
+        Rect {
+            x: self.x.clamp(bounds.x, bounds.x + bounds.w - w),
+            y: self.y.clamp(bounds.y, bounds.y + bounds.h - h),
+            w,
+            h,
+        }
+    }
+
     pub fn intersection(&self, other: Rect) -> Option<Rect> {
         let ix = cmp::max(self.x, other.x);
         let iy = cmp::max(self.y, other.y);
@@ -26,6 +145,23 @@ impl Rect {
             None
         }
     }
+
+    /// Parts of this rectangle that `other` leaves uncovered, at most four
+    pub fn subtract(&self, other: Rect) -> Vec<Rect> {
+        let i = match self.intersection(other) {
+            Some(i) => i,
+            None => return vec![*self],
+        };
+        let (right, bottom) = (self.x + self.w, self.y + self.h);
+        let (i_right, i_bottom) = (i.x + i.w, i.y + i.h);
+        let parts = [
+            Rect { x: self.x, y: self.y, w: self.w, h: i.y - self.y },
+            Rect { x: self.x, y: i_bottom, w: self.w, h: bottom - i_bottom },
+            Rect { x: self.x, y: i.y, w: i.x - self.x, h: i.h },
+            Rect { x: i_right, y: i.y, w: right - i_right, h: i.h },
+        ];
+        parts.iter().copied().filter(|r| r.w > 0 && r.h > 0).collect()
+    }
 }
 
 pub fn parse_int<T: num_traits::Num>(string: &str) -> Result<T, T::FromStrRadixErr> {
@@ -39,3 +175,95 @@ pub fn parse_int<T: num_traits::Num>(string: &str) -> Result<T, T::FromStrRadixE
         _ => T::from_str_radix(string, 10)
     }
 }
+
+/// Format seconds since the epoch as an ISO 8601 UTC date
+pub fn iso8601(secs: u64) -> String {
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let time = secs % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3600,
+            time / 60 % 60, time % 60)
+}
+
+extern "C" {
+    // Not bound by the libc crate yet
+    fn strftime(s: *mut libc::c_char, max: libc::size_t, format: *const libc::c_char,
+                tm: *const libc::tm) -> libc::size_t;
+}
+
+/// Format the current local time with strftime
+pub fn local_time(format: &str) -> String {
+    let format = ffi::CString::new(format).unwrap_or_default();
+    let mut formatted = vec![0u8; 4096];
+    let len = unsafe {
+        let now = libc::time(ptr::null_mut());
+        let mut tm = mem::zeroed::<libc::tm>();
+        libc::localtime_r(&now, &mut tm);
+        strftime(formatted.as_mut_ptr() as *mut libc::c_char, formatted.len(), format.as_ptr(),
+                 &tm)
+    };
+    String::from_utf8_lossy(&formatted[..len]).into_owned()
+}
+
+/// Quote a string for JSON output
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(geometry: &str) -> Result<(i32, i32, i32, i32), String> {
+        Rect::parse(geometry, (1920, 1080)).map(|r| (r.x, r.y, r.w, r.h))
+    }
+
+    #[test]
+    fn parse_geometry() {
+        assert_eq!(parse("100x50"), Ok((0, 0, 100, 50)));
+        assert_eq!(parse("=100X50+10+20"), Ok((10, 20, 100, 50)));
+        assert_eq!(parse("1920x1080+0+0"), Ok((0, 0, 1920, 1080)));
+        // From the right and bottom edges
+        assert_eq!(parse("100x50-0-0"), Ok((1820, 1030, 100, 50)));
+        assert_eq!(parse("100x50-10+20"), Ok((1810, 20, 100, 50)));
+        // Signed numbers after the edge, as XParseGeometry takes them
+        assert_eq!(parse("100x100+-5+0"), Ok((-5, 0, 100, 100)));
+        assert_eq!(parse("100x100+0+-7"), Ok((0, -7, 100, 100)));
+        assert_eq!(parse("100x100--5-+5"), Ok((1825, 975, 100, 100)));
+        assert_eq!(parse("100x100++5+5"), Ok((5, 5, 100, 100)));
+        assert_eq!(parse("2147483647x1"), Ok((0, 0, i32::MAX, 1)));
+    }
+
+    #[test]
+    fn parse_malformed_geometry() {
+        for g in ["", "=", "100", "100x", "x100", "axb", "-10x10", "10x10x10", "0x10", "10x0",
+                  "10x10+5", "10x10+5+", "10x10+5+5+5", "10x10+a+5", "10x10+5+5a", "10x10+",
+                  "10x10+--5+0", "2147483648x1", "10x10+99999999999+0", "10x10--2147483647+0",
+                  "10x10 +0+0"] {
+            assert!(parse(g).is_err(), "{:?} parsed as {:?}", g, parse(g));
+        }
+    }
+}
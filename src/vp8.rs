@@ -0,0 +1,1107 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Lossy VP8 key frame encoder for WebP, see RFC 6386
+//
+// Each macroblock is predicted as a whole, 16x16 for luma and 8x8 for chroma, and the tokens are
+// coded with the default probabilities. There is no loop filter, so decoders reconstruct exactly
+// the pixels that later macroblocks were predicted from.
+
+use std::io;
+
+use image::RgbaImage;
+
+type TokenProbTables = [[[[u8; 11]; 3]; 8]; 4];
+
+// Tables from the RFC
+static COEFF_UPDATE_PROBS: TokenProbTables = [
+    [
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [176, 246, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [223, 241, 252, 255, 255, 255, 255, 255, 255, 255, 255],
+            [249, 253, 253, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 244, 252, 255, 255, 255, 255, 255, 255, 255, 255],
+            [234, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [253, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 246, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [239, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 255, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 248, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [251, 255, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [251, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 255, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 254, 253, 255, 254, 255, 255, 255, 255, 255, 255],
+            [250, 255, 254, 255, 254, 255, 255, 255, 255, 255, 255],
+            [254, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+    ],
+    [
+        [
+            [217, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [225, 252, 241, 253, 255, 255, 254, 255, 255, 255, 255],
+            [234, 250, 241, 250, 253, 255, 253, 254, 255, 255, 255],
+        ],
+        [
+            [255, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [223, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [238, 253, 254, 254, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 248, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [249, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 253, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [247, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [252, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [253, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 254, 253, 255, 255, 255, 255, 255, 255, 255, 255],
+            [250, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+    ],
+    [
+        [
+            [186, 251, 250, 255, 255, 255, 255, 255, 255, 255, 255],
+            [234, 251, 244, 254, 255, 255, 255, 255, 255, 255, 255],
+            [251, 251, 243, 253, 254, 255, 254, 255, 255, 255, 255],
+        ],
+        [
+            [255, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [236, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [251, 253, 253, 254, 254, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+    ],
+    [
+        [
+            [248, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [250, 254, 252, 254, 255, 255, 255, 255, 255, 255, 255],
+            [248, 254, 249, 253, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 253, 253, 255, 255, 255, 255, 255, 255, 255, 255],
+            [246, 253, 253, 255, 255, 255, 255, 255, 255, 255, 255],
+            [252, 254, 251, 254, 254, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 254, 252, 255, 255, 255, 255, 255, 255, 255, 255],
+            [248, 254, 253, 255, 255, 255, 255, 255, 255, 255, 255],
+            [253, 255, 254, 254, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 251, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [245, 251, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [253, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 251, 253, 255, 255, 255, 255, 255, 255, 255, 255],
+            [252, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 252, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [249, 255, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 253, 255, 255, 255, 255, 255, 255, 255, 255],
+            [250, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+    ],
+];
+
+static COEFF_PROBS: TokenProbTables = [
+    [
+        [
+            [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128],
+            [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128],
+            [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128],
+        ],
+        [
+            [253, 136, 254, 255, 228, 219, 128, 128, 128, 128, 128],
+            [189, 129, 242, 255, 227, 213, 255, 219, 128, 128, 128],
+            [106, 126, 227, 252, 214, 209, 255, 255, 128, 128, 128],
+        ],
+        [
+            [1, 98, 248, 255, 236, 226, 255, 255, 128, 128, 128],
+            [181, 133, 238, 254, 221, 234, 255, 154, 128, 128, 128],
+            [78, 134, 202, 247, 198, 180, 255, 219, 128, 128, 128],
+        ],
+        [
+            [1, 185, 249, 255, 243, 255, 128, 128, 128, 128, 128],
+            [184, 150, 247, 255, 236, 224, 128, 128, 128, 128, 128],
+            [77, 110, 216, 255, 236, 230, 128, 128, 128, 128, 128],
+        ],
+        [
+            [1, 101, 251, 255, 241, 255, 128, 128, 128, 128, 128],
+            [170, 139, 241, 252, 236, 209, 255, 255, 128, 128, 128],
+            [37, 116, 196, 243, 228, 255, 255, 255, 128, 128, 128],
+        ],
+        [
+            [1, 204, 254, 255, 245, 255, 128, 128, 128, 128, 128],
+            [207, 160, 250, 255, 238, 128, 128, 128, 128, 128, 128],
+            [102, 103, 231, 255, 211, 171, 128, 128, 128, 128, 128],
+        ],
+        [
+            [1, 152, 252, 255, 240, 255, 128, 128, 128, 128, 128],
+            [177, 135, 243, 255, 234, 225, 128, 128, 128, 128, 128],
+            [80, 129, 211, 255, 194, 224, 128, 128, 128, 128, 128],
+        ],
+        [
+            [1, 1, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+            [246, 1, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+            [255, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128],
+        ],
+    ],
+    [
+        [
+            [198, 35, 237, 223, 193, 187, 162, 160, 145, 155, 62],
+            [131, 45, 198, 221, 172, 176, 220, 157, 252, 221, 1],
+            [68, 47, 146, 208, 149, 167, 221, 162, 255, 223, 128],
+        ],
+        [
+            [1, 149, 241, 255, 221, 224, 255, 255, 128, 128, 128],
+            [184, 141, 234, 253, 222, 220, 255, 199, 128, 128, 128],
+            [81, 99, 181, 242, 176, 190, 249, 202, 255, 255, 128],
+        ],
+        [
+            [1, 129, 232, 253, 214, 197, 242, 196, 255, 255, 128],
+            [99, 121, 210, 250, 201, 198, 255, 202, 128, 128, 128],
+            [23, 91, 163, 242, 170, 187, 247, 210, 255, 255, 128],
+        ],
+        [
+            [1, 200, 246, 255, 234, 255, 128, 128, 128, 128, 128],
+            [109, 178, 241, 255, 231, 245, 255, 255, 128, 128, 128],
+            [44, 130, 201, 253, 205, 192, 255, 255, 128, 128, 128],
+        ],
+        [
+            [1, 132, 239, 251, 219, 209, 255, 165, 128, 128, 128],
+            [94, 136, 225, 251, 218, 190, 255, 255, 128, 128, 128],
+            [22, 100, 174, 245, 186, 161, 255, 199, 128, 128, 128],
+        ],
+        [
+            [1, 182, 249, 255, 232, 235, 128, 128, 128, 128, 128],
+            [124, 143, 241, 255, 227, 234, 128, 128, 128, 128, 128],
+            [35, 77, 181, 251, 193, 211, 255, 205, 128, 128, 128],
+        ],
+        [
+            [1, 157, 247, 255, 236, 231, 255, 255, 128, 128, 128],
+            [121, 141, 235, 255, 225, 227, 255, 255, 128, 128, 128],
+            [45, 99, 188, 251, 195, 217, 255, 224, 128, 128, 128],
+        ],
+        [
+            [1, 1, 251, 255, 213, 255, 128, 128, 128, 128, 128],
+            [203, 1, 248, 255, 255, 128, 128, 128, 128, 128, 128],
+            [137, 1, 177, 255, 224, 255, 128, 128, 128, 128, 128],
+        ],
+    ],
+    [
+        [
+            [253, 9, 248, 251, 207, 208, 255, 192, 128, 128, 128],
+            [175, 13, 224, 243, 193, 185, 249, 198, 255, 255, 128],
+            [73, 17, 171, 221, 161, 179, 236, 167, 255, 234, 128],
+        ],
+        [
+            [1, 95, 247, 253, 212, 183, 255, 255, 128, 128, 128],
+            [239, 90, 244, 250, 211, 209, 255, 255, 128, 128, 128],
+            [155, 77, 195, 248, 188, 195, 255, 255, 128, 128, 128],
+        ],
+        [
+            [1, 24, 239, 251, 218, 219, 255, 205, 128, 128, 128],
+            [201, 51, 219, 255, 196, 186, 128, 128, 128, 128, 128],
+            [69, 46, 190, 239, 201, 218, 255, 228, 128, 128, 128],
+        ],
+        [
+            [1, 191, 251, 255, 255, 128, 128, 128, 128, 128, 128],
+            [223, 165, 249, 255, 213, 255, 128, 128, 128, 128, 128],
+            [141, 124, 248, 255, 255, 128, 128, 128, 128, 128, 128],
+        ],
+        [
+            [1, 16, 248, 255, 255, 128, 128, 128, 128, 128, 128],
+            [190, 36, 230, 255, 236, 255, 128, 128, 128, 128, 128],
+            [149, 1, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+        ],
+        [
+            [1, 226, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+            [247, 192, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+            [240, 128, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+        ],
+        [
+            [1, 134, 252, 255, 255, 128, 128, 128, 128, 128, 128],
+            [213, 62, 250, 255, 255, 128, 128, 128, 128, 128, 128],
+            [55, 93, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+        ],
+        [
+            [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128],
+            [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128],
+            [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128],
+        ],
+    ],
+    [
+        [
+            [202, 24, 213, 235, 186, 191, 220, 160, 240, 175, 255],
+            [126, 38, 182, 232, 169, 184, 228, 174, 255, 187, 128],
+            [61, 46, 138, 219, 151, 178, 240, 170, 255, 216, 128],
+        ],
+        [
+            [1, 112, 230, 250, 199, 191, 247, 159, 255, 255, 128],
+            [166, 109, 228, 252, 211, 215, 255, 174, 128, 128, 128],
+            [39, 77, 162, 232, 172, 180, 245, 178, 255, 255, 128],
+        ],
+        [
+            [1, 52, 220, 246, 198, 199, 249, 220, 255, 255, 128],
+            [124, 74, 191, 243, 183, 193, 250, 221, 255, 255, 128],
+            [24, 71, 130, 219, 154, 170, 243, 182, 255, 255, 128],
+        ],
+        [
+            [1, 182, 225, 249, 219, 240, 255, 224, 128, 128, 128],
+            [149, 150, 226, 252, 216, 205, 255, 171, 128, 128, 128],
+            [28, 108, 170, 242, 183, 194, 254, 223, 255, 255, 128],
+        ],
+        [
+            [1, 81, 230, 252, 204, 203, 255, 192, 128, 128, 128],
+            [123, 102, 209, 247, 188, 196, 255, 233, 128, 128, 128],
+            [20, 95, 153, 243, 164, 173, 255, 203, 128, 128, 128],
+        ],
+        [
+            [1, 222, 248, 255, 216, 213, 128, 128, 128, 128, 128],
+            [168, 175, 246, 252, 235, 205, 255, 255, 128, 128, 128],
+            [47, 116, 215, 255, 211, 212, 255, 255, 128, 128, 128],
+        ],
+        [
+            [1, 121, 236, 253, 212, 214, 255, 255, 128, 128, 128],
+            [141, 84, 213, 252, 201, 202, 255, 219, 128, 128, 128],
+            [42, 80, 160, 240, 162, 185, 255, 205, 128, 128, 128],
+        ],
+        [
+            [1, 1, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+            [244, 1, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+            [238, 1, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+        ],
+    ],
+];
+
+static DC_QUANT: [i16; 128] = [
+      4,   5,   6,   7,   8,   9,  10,  10,
+     11,  12,  13,  14,  15,  16,  17,  17,
+     18,  19,  20,  20,  21,  21,  22,  22,
+     23,  23,  24,  25,  25,  26,  27,  28,
+     29,  30,  31,  32,  33,  34,  35,  36,
+     37,  37,  38,  39,  40,  41,  42,  43,
+     44,  45,  46,  46,  47,  48,  49,  50,
+     51,  52,  53,  54,  55,  56,  57,  58,
+     59,  60,  61,  62,  63,  64,  65,  66,
+     67,  68,  69,  70,  71,  72,  73,  74,
+     75,  76,  76,  77,  78,  79,  80,  81,
+     82,  83,  84,  85,  86,  87,  88,  89,
+     91,  93,  95,  96,  98, 100, 101, 102,
+    104, 106, 108, 110, 112, 114, 116, 118,
+    122, 124, 126, 128, 130, 132, 134, 136,
+    138, 140, 143, 145, 148, 151, 154, 157,
+];
+
+static AC_QUANT: [i16; 128] = [
+      4,   5,   6,   7,   8,    9,  10,  11,
+      12,  13,  14,  15,  16,  17,  18,  19,
+      20,  21,  22,  23,  24,  25,  26,  27,
+      28,  29,  30,  31,  32,  33,  34,  35,
+      36,  37,  38,  39,  40,  41,  42,  43,
+      44,  45,  46,  47,  48,  49,  50,  51,
+      52,  53,  54,  55,  56,  57,  58,  60,
+      62,  64,  66,  68,  70,  72,  74,  76,
+      78,  80,  82,  84,  86,  88,  90,  92,
+      94,  96,  98, 100, 102, 104, 106, 108,
+     110, 112, 114, 116, 119, 122, 125, 128,
+     131, 134, 137, 140, 143, 146, 149, 152,
+     155, 158, 161, 164, 167, 170, 173, 177,
+     181, 185, 189, 193, 197, 201, 205, 209,
+     213, 217, 221, 225, 229, 234, 239, 245,
+     249, 254, 259, 264, 269, 274, 279, 284,
+];
+
+static PROB_DCT_CAT: [[u8; 12]; 6] = [
+    [159, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [165, 145, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [173, 148, 140, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [176, 155, 140, 135, 0, 0, 0, 0, 0, 0, 0, 0],
+    [180, 157, 141, 134, 130, 0, 0, 0, 0, 0, 0, 0],
+    [254, 254, 243, 230, 196, 177, 153, 140, 133, 130, 129, 0],
+];
+
+static COEFF_BANDS: [usize; 16] = [0, 1, 2, 3, 6, 4, 5, 6, 6, 6, 6, 6, 6, 6, 6, 7];
+static ZIGZAG: [usize; 16] = [0, 1, 4, 8, 5, 2, 3, 6, 9, 12, 13, 10, 7, 11, 14, 15];
+static DCT_CAT_BASE: [i32; 6] = [5, 7, 11, 19, 35, 67];
+
+// Trees as in the RFC, leaves are stored negated
+const DCT_0: i8 = 0;
+const DCT_CAT1: i8 = 5;
+const DCT_EOB: i8 = 11;
+static DCT_TOKEN_TREE: [i8; 22] = [
+    -DCT_EOB, 2, -DCT_0, 4, -1, 6, 8, 12, -2, 10, -3, -4, 14, 16, -DCT_CAT1, -6, 18, 20, -7, -8,
+    -9, -10,
+];
+static YMODE_TREE: [i8; 8] = [-4, 2, 4, 6, 0, -1, -2, -3];
+static YMODE_PROBS: [u8; 4] = [145, 156, 163, 128];
+static UV_MODE_TREE: [i8; 6] = [0, 2, -1, 4, -2, -3];
+static UV_MODE_PROBS: [u8; 3] = [142, 114, 183];
+
+// Token probabilities for each type of block
+const PLANE_Y_AFTER_Y2: usize = 0;
+const PLANE_Y2: usize = 1;
+const PLANE_UV: usize = 2;
+
+/// Whole block prediction modes, numbered as in the trees
+#[derive(Clone, Copy)]
+enum Mode {
+    Dc = 0,
+    V = 1,
+    H = 2,
+    Tm = 3,
+}
+
+const MODES: [Mode; 4] = [Mode::Dc, Mode::V, Mode::H, Mode::Tm];
+
+/// Boolean entropy encoder from section 7.3 of the RFC
+struct BoolWriter {
+    out: Vec<u8>,
+    range: u32,
+    bottom: u32,
+    bit_count: i32,
+}
+
+impl BoolWriter {
+    fn new() -> BoolWriter {
+        BoolWriter {
+            out: Vec::new(),
+            range: 255,
+            bottom: 0,
+            bit_count: 24,
+        }
+    }
+
+    fn carry(&mut self) {
+        for b in self.out.iter_mut().rev() {
+            if *b == 255 {
+                *b = 0;
+            } else {
+                *b += 1;
+                break;
+            }
+        }
+    }
+
+    fn put(&mut self, prob: u8, bit: bool) {
+        let split = 1 + (((self.range - 1) * prob as u32) >> 8);
+        if bit {
+            self.bottom = self.bottom.wrapping_add(split);
+            self.range -= split;
+        } else {
+            self.range = split;
+        }
+        while self.range < 128 {
+            self.range <<= 1;
+            if self.bottom & 1 << 31 != 0 {
+                self.carry();
+            }
+            self.bottom <<= 1;
+            self.bit_count -= 1;
+            if self.bit_count == 0 {
+                self.out.push((self.bottom >> 24) as u8);
+                self.bottom &= (1 << 24) - 1;
+                self.bit_count = 8;
+            }
+        }
+    }
+
+    /// An unsigned value of `n` bits, most significant first
+    fn literal(&mut self, v: u32, n: u32) {
+        for i in (0..n).rev() {
+            self.put(128, v >> i & 1 != 0);
+        }
+    }
+
+    /// The path through `tree` from node `start` to `leaf`
+    fn tree(&mut self, tree: &[i8], probs: &[u8], leaf: i8, start: usize) {
+        let mut path = [(0, false); 11];
+        let mut n = 0;
+        let mut k = tree.iter().position(|&t| t == -leaf).unwrap();
+        loop {
+            let node = k & !1;
+            path[n] = (probs[node >> 1], k & 1 == 1);
+            n += 1;
+            if node == start {
+                break;
+            }
+            k = tree.iter().position(|&t| t as usize == node).unwrap();
+        }
+        for &(prob, bit) in path[..n].iter().rev() {
+            self.put(prob, bit);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let c = self.bit_count;
+        let mut v = self.bottom;
+        if v & 1 << (32 - c) != 0 {
+            self.carry();
+        }
+        v <<= c & 7;
+        for _ in 0..c >> 3 {
+            v <<= 8;
+        }
+        for _ in 0..4 {
+            self.out.push((v >> 24) as u8);
+            v <<= 8;
+        }
+        self.out
+    }
+}
+
+/// Forward DCT as in libvpx, scaled so that idct inverts it
+fn fdct(input: &[i32; 16]) -> [i32; 16] {
+    let mut tmp = [0; 16];
+    for i in 0..4 {
+        let ip = &input[i * 4..i * 4 + 4];
+        let a1 = (ip[0] + ip[3]) * 8;
+        let b1 = (ip[1] + ip[2]) * 8;
+        let c1 = (ip[1] - ip[2]) * 8;
+        let d1 = (ip[0] - ip[3]) * 8;
+        tmp[i * 4] = a1 + b1;
+        tmp[i * 4 + 2] = a1 - b1;
+        tmp[i * 4 + 1] = (c1 * 2217 + d1 * 5352 + 14500) >> 12;
+        tmp[i * 4 + 3] = (d1 * 2217 - c1 * 5352 + 7500) >> 12;
+    }
+    let mut out = [0; 16];
+    for i in 0..4 {
+        let a1 = tmp[i] + tmp[12 + i];
+        let b1 = tmp[4 + i] + tmp[8 + i];
+        let c1 = tmp[4 + i] - tmp[8 + i];
+        let d1 = tmp[i] - tmp[12 + i];
+        out[i] = (a1 + b1 + 7) >> 4;
+        out[8 + i] = (a1 - b1 + 7) >> 4;
+        out[4 + i] = ((c1 * 2217 + d1 * 5352 + 12000) >> 16) + (d1 != 0) as i32;
+        out[12 + i] = (d1 * 2217 - c1 * 5352 + 51000) >> 16;
+    }
+    out
+}
+
+/// Inverse DCT from section 14.3 of the RFC, which decoders must match exactly
+fn idct(input: &[i32; 16]) -> [i32; 16] {
+    let mul1 = |a: i64| a + ((a * 20091) >> 16);
+    let mul2 = |a: i64| (a * 35468) >> 16;
+    let mut tmp = [0i64; 16];
+    for i in 0..4 {
+        let a1 = input[i] as i64 + input[8 + i] as i64;
+        let b1 = input[i] as i64 - input[8 + i] as i64;
+        let c1 = mul2(input[4 + i] as i64) - mul1(input[12 + i] as i64);
+        let d1 = mul1(input[4 + i] as i64) + mul2(input[12 + i] as i64);
+        tmp[i] = a1 + d1;
+        tmp[4 + i] = b1 + c1;
+        tmp[8 + i] = b1 - c1;
+        tmp[12 + i] = a1 - d1;
+    }
+    let mut out = [0; 16];
+    for i in 0..4 {
+        let row = &tmp[i * 4..i * 4 + 4];
+        let a1 = row[0] + row[2];
+        let b1 = row[0] - row[2];
+        let c1 = mul2(row[1]) - mul1(row[3]);
+        let d1 = mul1(row[1]) + mul2(row[3]);
+        out[i * 4] = ((a1 + d1 + 4) >> 3) as i32;
+        out[i * 4 + 3] = ((a1 - d1 + 4) >> 3) as i32;
+        out[i * 4 + 1] = ((b1 + c1 + 4) >> 3) as i32;
+        out[i * 4 + 2] = ((b1 - c1 + 4) >> 3) as i32;
+    }
+    out
+}
+
+/// Forward Walsh-Hadamard transform of the luma DC coefficients, as in libvpx
+fn fwht(input: &[i32; 16]) -> [i32; 16] {
+    let mut tmp = [0; 16];
+    for i in 0..4 {
+        let ip = &input[i * 4..i * 4 + 4];
+        let a1 = (ip[0] + ip[2]) * 4;
+        let d1 = (ip[1] + ip[3]) * 4;
+        let c1 = (ip[1] - ip[3]) * 4;
+        let b1 = (ip[0] - ip[2]) * 4;
+        tmp[i * 4] = a1 + d1 + (a1 != 0) as i32;
+        tmp[i * 4 + 1] = b1 + c1;
+        tmp[i * 4 + 2] = b1 - c1;
+        tmp[i * 4 + 3] = a1 - d1;
+    }
+    let mut out = [0; 16];
+    for i in 0..4 {
+        let a1 = tmp[i] + tmp[8 + i];
+        let d1 = tmp[4 + i] + tmp[12 + i];
+        let c1 = tmp[4 + i] - tmp[12 + i];
+        let b1 = tmp[i] - tmp[8 + i];
+        let round = |v: i32| (v + (v < 0) as i32 + 3) >> 3;
+        out[i] = round(a1 + d1);
+        out[4 + i] = round(b1 + c1);
+        out[8 + i] = round(b1 - c1);
+        out[12 + i] = round(a1 - d1);
+    }
+    out
+}
+
+/// Inverse Walsh-Hadamard transform from section 14.3 of the RFC
+fn iwht(input: &[i32; 16]) -> [i32; 16] {
+    let mut tmp = [0; 16];
+    for i in 0..4 {
+        let a1 = input[i] + input[12 + i];
+        let b1 = input[4 + i] + input[8 + i];
+        let c1 = input[4 + i] - input[8 + i];
+        let d1 = input[i] - input[12 + i];
+        tmp[i] = a1 + b1;
+        tmp[4 + i] = c1 + d1;
+        tmp[8 + i] = a1 - b1;
+        tmp[12 + i] = d1 - c1;
+    }
+    let mut out = [0; 16];
+    for i in 0..4 {
+        let row = &tmp[i * 4..i * 4 + 4];
+        let a1 = row[0] + row[3];
+        let b1 = row[1] + row[2];
+        let c1 = row[1] - row[2];
+        let d1 = row[0] - row[3];
+        out[i * 4] = (a1 + b1 + 3) >> 3;
+        out[i * 4 + 1] = (c1 + d1 + 3) >> 3;
+        out[i * 4 + 2] = (a1 - b1 + 3) >> 3;
+        out[i * 4 + 3] = (d1 - c1 + 3) >> 3;
+    }
+    out
+}
+
+fn quantize(c: i32, step: i32) -> i32 {
+    ((c.abs() + step / 2) / step).min(2048) * c.signum()
+}
+
+/// A plane padded to whole macroblocks, with what decoders will reconstruct of it so far
+struct Plane {
+    src: Vec<u8>,
+    rec: Vec<u8>,
+    stride: usize,
+}
+
+impl Plane {
+    /// The prediction of the `size` square at (x, y) from the reconstructed pixels around it
+    fn predict(&self, x: usize, y: usize, size: usize, mode: Mode) -> Vec<u8> {
+        let stride = self.stride;
+        let above = |i: usize| if y == 0 { 127 } else { self.rec[(y - 1) * stride + x + i] as i32 };
+        let left = |j: usize| if x == 0 { 129 } else { self.rec[(y + j) * stride + x - 1] as i32 };
+        let corner = match (x, y) {
+            (_, 0) => 127,
+            (0, _) => 129,
+            _ => self.rec[(y - 1) * stride + x - 1] as i32,
+        };
+        let dc = {
+            let mut sum = 0;
+            let mut shift = if size == 8 { 2 } else { 3 };
+            if x > 0 {
+                sum += (0..size).map(left).sum::<i32>();
+                shift += 1;
+            }
+            if y > 0 {
+                sum += (0..size).map(above).sum::<i32>();
+                shift += 1;
+            }
+            if x == 0 && y == 0 { 128 } else { (sum + (1 << (shift - 1))) >> shift }
+        };
+        let mut out = Vec::with_capacity(size * size);
+        for j in 0..size {
+            for i in 0..size {
+                out.push(match mode {
+                    Mode::Dc => dc,
+                    Mode::V => above(i),
+                    Mode::H => left(j),
+                    Mode::Tm => (left(j) + above(i) - corner).clamp(0, 255),
+                } as u8);
+            }
+        }
+        out
+    }
+
+    fn sad(&self, x: usize, y: usize, size: usize, pred: &[u8]) -> u32 {
+        (0..size * size).map(|k| {
+            let s = self.src[(y + k / size) * self.stride + x + k % size];
+            (s as i32 - pred[k] as i32).unsigned_abs()
+        }).sum()
+    }
+
+    /// DCT coefficients of the residue of each 4x4 block, in raster order
+    fn residue(&self, x: usize, y: usize, size: usize, pred: &[u8]) -> Vec<[i32; 16]> {
+        let blocks = size / 4;
+        (0..blocks * blocks).map(|b| {
+            let mut r = [0; 16];
+            for (k, r) in r.iter_mut().enumerate() {
+                let (i, j) = (b % blocks * 4 + k % 4, b / blocks * 4 + k / 4);
+                *r = self.src[(y + j) * self.stride + x + i] as i32 - pred[j * size + i] as i32;
+            }
+            fdct(&r)
+        }).collect()
+    }
+
+    /// Add the dequantized residue of each block to the prediction, as decoders do
+    fn reconstruct(&mut self, x: usize, y: usize, size: usize, pred: &[u8], coeffs: &[[i32; 16]]) {
+        let blocks = size / 4;
+        for (b, c) in coeffs.iter().enumerate() {
+            let r = idct(c);
+            for (k, r) in r.iter().enumerate() {
+                let (i, j) = (b % blocks * 4 + k % 4, b / blocks * 4 + k / 4);
+                let p = pred[j * size + i] as i32 + r;
+                self.rec[(y + j) * self.stride + x + i] = p.clamp(0, 255) as u8;
+            }
+        }
+    }
+}
+
+/// Quantizer steps for a quantizer index, derived as decoders do
+struct Quant {
+    y: (i32, i32),
+    y2: (i32, i32),
+    uv: (i32, i32),
+}
+
+impl Quant {
+    fn new(qi: usize) -> Quant {
+        let (dc, ac) = (DC_QUANT[qi] as i32, AC_QUANT[qi] as i32);
+        Quant {
+            y: (dc, ac),
+            y2: (dc * 2, (ac * 155 / 100).max(8)),
+            uv: (dc.min(132), ac),
+        }
+    }
+}
+
+/// Quantize the coefficients of blocks in place, leaving their dequantized values in `deq`
+fn quantize_blocks(blocks: &mut [[i32; 16]], deq: &mut Vec<[i32; 16]>, (dc, ac): (i32, i32)) {
+    deq.clear();
+    for b in blocks.iter_mut() {
+        let mut d = [0; 16];
+        for (k, c) in b.iter_mut().enumerate() {
+            let step = if k == 0 { dc } else { ac };
+            *c = quantize(*c, step);
+            d[k] = *c * step;
+        }
+        deq.push(d);
+    }
+}
+
+/// Tokens for the quantized coefficients of a block, returning whether any were coded
+fn put_coefficients(bw: &mut BoolWriter, coeffs: &[i32; 16], plane: usize, ctx: usize) -> bool {
+    let probs = &COEFF_PROBS[plane];
+    let first = (plane == PLANE_Y_AFTER_Y2) as usize;
+    let last = match (first..16).rev().find(|&i| coeffs[ZIGZAG[i]] != 0) {
+        Some(last) => last,
+        None => {
+            bw.tree(&DCT_TOKEN_TREE, &probs[COEFF_BANDS[first]][ctx], DCT_EOB, 0);
+            return false;
+        },
+    };
+
+    let mut ctx = ctx;
+    let mut after_zero = false;
+    for i in first..=last {
+        let p = &probs[COEFF_BANDS[i]][ctx];
+        let start = if after_zero { 2 } else { 0 };
+        let v = coeffs[ZIGZAG[i]];
+        let abs = v.abs();
+        if abs == 0 {
+            bw.tree(&DCT_TOKEN_TREE, p, DCT_0, start);
+            after_zero = true;
+            ctx = 0;
+            continue;
+        }
+        if abs <= 4 {
+            bw.tree(&DCT_TOKEN_TREE, p, abs as i8, start);
+        } else {
+            let cat = DCT_CAT_BASE.iter().rposition(|&base| abs >= base).unwrap();
+            bw.tree(&DCT_TOKEN_TREE, p, DCT_CAT1 + cat as i8, start);
+            let extra = abs - DCT_CAT_BASE[cat];
+            let bits: Vec<u8> = PROB_DCT_CAT[cat].iter().copied().take_while(|&p| p > 0).collect();
+            for (j, &prob) in bits.iter().enumerate() {
+                bw.put(prob, extra >> (bits.len() - 1 - j) & 1 != 0);
+            }
+        }
+        bw.put(128, v < 0);
+        after_zero = false;
+        ctx = if abs == 1 { 1 } else { 2 };
+    }
+    if last < 15 {
+        bw.tree(&DCT_TOKEN_TREE, &probs[COEFF_BANDS[last + 1]][ctx], DCT_EOB, 0);
+    }
+    true
+}
+
+/// The encoded frame, and the luma plane decoders will reconstruct from it
+fn encode_frame(image: &RgbaImage, quality: u8) -> io::Result<(Vec<u8>, Plane)> {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 || width >= 1 << 14 || height >= 1 << 14 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "Lossy WebP images are limited to 16383x16383 pixels"));
+    }
+    let (mbw, mbh) = ((width as usize).div_ceil(16), (height as usize).div_ceil(16));
+
+    // BT.601 studio range, chroma from the average of each 2x2 square, repeating the edges out to
+    // whole macroblocks
+    let pixel = |x: usize, y: usize| {
+        let p = image.get_pixel((x as u32).min(width - 1), (y as u32).min(height - 1));
+        [p[0] as i32, p[1] as i32, p[2] as i32]
+    };
+    let plane = |stride: usize, rows: usize, f: &dyn Fn(usize, usize) -> i32| {
+        let src: Vec<u8> = (0..stride * rows).map(|k| f(k % stride, k / stride) as u8).collect();
+        Plane {
+            rec: vec![0; src.len()],
+            src,
+            stride,
+        }
+    };
+    let mut luma = plane(mbw * 16, mbh * 16, &|x, y| {
+        let [r, g, b] = pixel(x, y);
+        ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16
+    });
+    let average = |x: usize, y: usize| {
+        let mut sum = [2; 3];
+        for &(dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+            for (s, c) in sum.iter_mut().zip(pixel(x * 2 + dx, y * 2 + dy).iter()) {
+                *s += c;
+            }
+        }
+        [sum[0] >> 2, sum[1] >> 2, sum[2] >> 2]
+    };
+    let mut u = plane(mbw * 8, mbh * 8, &|x, y| {
+        let [r, g, b] = average(x, y);
+        ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128
+    });
+    let mut v = plane(mbw * 8, mbh * 8, &|x, y| {
+        let [r, g, b] = average(x, y);
+        ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128
+    });
+
+    let qi = (100 - quality.clamp(1, 100) as usize) * 127 / 100;
+    let quant = Quant::new(qi);
+
+    // Nonzero flags for the Y2, the four Y columns or rows, and two each of U and V
+    let mut above = vec![[false; 9]; mbw];
+    let mut modes = Vec::with_capacity(mbw * mbh);
+    let mut tokens = BoolWriter::new();
+    let mut deq = Vec::new();
+    for mby in 0..mbh {
+        let mut left = [false; 9];
+        for (mbx, top) in above.iter_mut().enumerate() {
+            let (x, y) = (mbx * 16, mby * 16);
+            let (ymode, pred) = MODES.iter().map(|&m| (m, luma.predict(x, y, 16, m)))
+                .min_by_key(|(_, pred)| luma.sad(x, y, 16, pred))
+                .unwrap();
+            let mut ys = luma.residue(x, y, 16, &pred);
+            let mut dc = [0; 16];
+            for (d, b) in dc.iter_mut().zip(ys.iter_mut()) {
+                *d = b[0];
+                b[0] = 0;
+            }
+            let mut y2 = [fwht(&dc)];
+            quantize_blocks(&mut y2, &mut deq, quant.y2);
+            let dc = iwht(&deq[0]);
+            quantize_blocks(&mut ys, &mut deq, quant.y);
+            for (d, &dc) in deq.iter_mut().zip(dc.iter()) {
+                d[0] = dc;
+            }
+            luma.reconstruct(x, y, 16, &pred, &deq);
+
+            let (x, y) = (mbx * 8, mby * 8);
+            let (uvmode, upred, vpred) = MODES.iter()
+                .map(|&m| (m, u.predict(x, y, 8, m), v.predict(x, y, 8, m)))
+                .min_by_key(|(_, up, vp)| u.sad(x, y, 8, up) + v.sad(x, y, 8, vp))
+                .unwrap();
+            let mut us = u.residue(x, y, 8, &upred);
+            quantize_blocks(&mut us, &mut deq, quant.uv);
+            u.reconstruct(x, y, 8, &upred, &deq);
+            let mut vs = v.residue(x, y, 8, &vpred);
+            quantize_blocks(&mut vs, &mut deq, quant.uv);
+            v.reconstruct(x, y, 8, &vpred, &deq);
+
+            let blocks = y2.iter().chain(ys.iter()).chain(us.iter()).chain(vs.iter());
+            let skip = blocks.flatten().all(|&c| c == 0);
+            modes.push((ymode, uvmode, skip));
+            if skip {
+                *top = [false; 9];
+                left = [false; 9];
+                continue;
+            }
+
+            let mut put = |c: &[i32; 16], plane: usize, a: usize, l: usize| {
+                let ctx = top[a] as usize + left[l] as usize;
+                let nonzero = put_coefficients(&mut tokens, c, plane, ctx);
+                top[a] = nonzero;
+                left[l] = nonzero;
+            };
+            put(&y2[0], PLANE_Y2, 0, 0);
+            for (b, c) in ys.iter().enumerate() {
+                put(c, PLANE_Y_AFTER_Y2, 1 + b % 4, 1 + b / 4);
+            }
+            for (b, c) in us.iter().enumerate() {
+                put(c, PLANE_UV, 5 + b % 2, 5 + b / 2);
+            }
+            for (b, c) in vs.iter().enumerate() {
+                put(c, PLANE_UV, 7 + b % 2, 7 + b / 2);
+            }
+        }
+    }
+
+    let mut header = BoolWriter::new();
+    // YUV colour space and clamping, then segments that all take the frame's quantizer. The
+    // image crate only applies the quantizer index to segments, which is why they are there.
+    header.literal(0, 2);
+    header.literal(0b101, 3);
+    header.literal(0, 1 + 4 + 4);
+    // No loop filter, a single token partition
+    header.literal(0, 1 + 6 + 3 + 1);
+    header.literal(0, 2);
+    // The quantizer index with no deltas, then keeping the default token probabilities
+    header.literal(qi as u32, 7);
+    header.literal(0, 5);
+    header.literal(0, 1);
+    for &p in COEFF_UPDATE_PROBS.iter().flatten().flatten().flatten() {
+        header.put(p, false);
+    }
+    let skipped = modes.iter().filter(|m| m.2).count();
+    let prob_skip = (255 * (modes.len() - skipped) / modes.len()).clamp(1, 255) as u8;
+    header.literal(1, 1);
+    header.literal(prob_skip as u32, 8);
+    for &(ymode, uvmode, skip) in modes.iter() {
+        header.put(prob_skip, skip);
+        header.tree(&YMODE_TREE, &YMODE_PROBS, ymode as i8, 0);
+        header.tree(&UV_MODE_TREE, &UV_MODE_PROBS, uvmode as i8, 0);
+    }
+    let header = header.finish();
+    if header.len() >= 1 << 19 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "The image is too large for a lossy WebP frame"));
+    }
+
+    let mut frame = Vec::new();
+    // Key frame, version 0, shown
+    frame.extend_from_slice(&((header.len() as u32) << 5 | 1 << 4).to_le_bytes()[..3]);
+    frame.extend_from_slice(&[0x9D, 0x01, 0x2A]);
+    frame.extend_from_slice(&(width as u16).to_le_bytes());
+    frame.extend_from_slice(&(height as u16).to_le_bytes());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(&tokens.finish());
+    Ok((frame, luma))
+}
+
+/// Encode `image` as a VP8 key frame, ignoring its alpha channel
+pub fn encode(image: &RgbaImage, quality: u8) -> io::Result<Vec<u8>> {
+    encode_frame(image, quality).map(|(frame, _)| frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    /// Boolean decoder from section 7.3 of the RFC
+    struct BoolReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+        value: u32,
+        range: u32,
+        bit_count: u32,
+    }
+
+    impl BoolReader<'_> {
+        fn new(data: &[u8]) -> BoolReader<'_> {
+            BoolReader {
+                data,
+                pos: 2,
+                value: (data[0] as u32) << 8 | data[1] as u32,
+                range: 255,
+                bit_count: 0,
+            }
+        }
+
+        fn get(&mut self, prob: u8) -> bool {
+            let split = 1 + (((self.range - 1) * prob as u32) >> 8);
+            let big_split = split << 8;
+            let bit = self.value >= big_split;
+            if bit {
+                self.range -= split;
+                self.value -= big_split;
+            } else {
+                self.range = split;
+            }
+            while self.range < 128 {
+                self.value <<= 1;
+                self.range <<= 1;
+                self.bit_count += 1;
+                if self.bit_count == 8 {
+                    self.bit_count = 0;
+                    self.value |= *self.data.get(self.pos).unwrap_or(&0) as u32;
+                    self.pos += 1;
+                }
+            }
+            bit
+        }
+    }
+
+    #[test]
+    fn bools_round_trip() {
+        let mut seed = 1u32;
+        let mut next = || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            seed >> 16
+        };
+        let bits: Vec<(u8, bool)> = (0..10_000).map(|_| {
+            let prob = (next() % 255 + 1) as u8;
+            (prob, (next() % 256) as u8 >= prob)
+        }).collect();
+        let mut w = BoolWriter::new();
+        for &(prob, bit) in bits.iter() {
+            w.put(prob, bit);
+        }
+        let data = w.finish();
+        let mut r = BoolReader::new(&data);
+        for &(prob, bit) in bits.iter() {
+            assert_eq!(r.get(prob), bit);
+        }
+    }
+
+    #[test]
+    fn transforms_round_trip() {
+        let block: [i32; 16] = [
+            -255, 0, 17, 128, 40, 41, 42, 43, 255, -7, 3, -100, 0, 0, 64, 200,
+        ];
+        let back = idct(&fdct(&block));
+        for (a, b) in block.iter().zip(back.iter()) {
+            assert!((a - b).abs() <= 1, "{:?} became {:?}", block, back);
+        }
+        let dc: [i32; 16] = [
+            2040, -2040, 0, 5, 1000, 999, -3, 17, 64, 64, 64, 64, -1, 0, 1, 700,
+        ];
+        assert_eq!(iwht(&fwht(&dc)), dc);
+    }
+
+    fn sample(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            if (10..30).contains(&x) && (8..20).contains(&y) {
+                // Some text-like detail in a flat box
+                let on = (x + y * 3) % 5 == 0;
+                if on { Rgba([20, 20, 20, 255]) } else { Rgba([240, 240, 240, 255]) }
+            } else {
+                Rgba([(x * 5) as u8, (y * 6) as u8, 128, 255])
+            }
+        })
+    }
+
+    #[test]
+    fn decoders_reconstruct_the_same_luma() {
+        let image = sample(45, 37);
+        for &quality in [1, 50, 90, 100].iter() {
+            let (frame, luma) = encode_frame(&image, quality).unwrap();
+            let mut file = Vec::new();
+            crate::webp::write_lossy(&mut file, &image, quality).unwrap();
+            let padded = file.len() - frame.len() % 2;
+            assert_eq!(&file[padded - frame.len()..padded], &frame[..]);
+            let decoded = image::load_from_memory_with_format(&file, image::ImageFormat::WebP)
+                .unwrap()
+                .to_luma();
+            assert_eq!(decoded.dimensions(), (45, 37));
+
+            let mut error = 0.0;
+            for (x, y, p) in decoded.enumerate_pixels() {
+                let offset = y as usize * luma.stride + x as usize;
+                assert_eq!(p[0], luma.rec[offset], "at {}, {} with quality {}", x, y, quality);
+                error += (p[0] as f64 - luma.src[offset] as f64).powi(2);
+            }
+            let psnr = 10.0 * (255.0f64.powi(2) * (45.0 * 37.0) / error.max(1.0)).log10();
+            if quality >= 90 {
+                assert!(psnr > 35.0, "PSNR of {} with quality {}", psnr, quality);
+            }
+        }
+    }
+
+    #[test]
+    fn flat_images_are_skipped() {
+        let image = RgbaImage::from_pixel(64, 64, Rgba([128, 128, 128, 255]));
+        let (frame, luma) = encode_frame(&image, 90).unwrap();
+        assert!(frame.len() < 64, "{} bytes", frame.len());
+        assert!(luma.rec.iter().zip(luma.src.iter()).all(|(&a, &b)| a.max(b) - a.min(b) <= 1));
+    }
+}
@@ -0,0 +1,451 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Minimal Wayland client for the wlr-screencopy protocol of wlroots based compositors, speaking
+// the wire protocol directly
+
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::io::Write;
+use std::mem;
+use std::os::raw::c_int;
+use std::os::raw::c_void;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process;
+use std::ptr;
+
+use image::imageops;
+use image::GenericImage;
+use image::Rgba;
+use image::RgbaImage;
+
+use crate::util;
+
+const DISPLAY: u32 = 1;
+
+// wl_shm formats, only the first two are not named after their fourcc code
+const FORMAT_ARGB8888: u32 = 0;
+const FORMAT_XRGB8888: u32 = 1;
+const FORMAT_ABGR8888: u32 = 0x3432_4241;
+const FORMAT_XBGR8888: u32 = 0x3432_4258;
+
+const FRAME_FLAG_Y_INVERT: u32 = 1;
+const OUTPUT_MODE_CURRENT: u32 = 1;
+
+enum Arg<'a> {
+    Uint(u32),
+    Int(i32),
+    Str(&'a str),
+}
+
+struct Event {
+    object: u32,
+    opcode: u16,
+    args: Vec<u8>,
+}
+
+/// Reads the arguments of an event in order
+struct Args<'a> {
+    data: &'a [u8],
+}
+
+struct Output {
+    id: u32,
+    x: i32,
+    y: i32,
+    w: i32,
+    h: i32,
+    scale: i32,
+    transform: i32,
+}
+
+pub struct Connection {
+    socket: UnixStream,
+    inbox: RefCell<Vec<u8>>,
+    next_id: Cell<u32>,
+    shm: u32,
+    manager: u32,
+    outputs: Vec<Output>,
+}
+
+impl Event {
+    fn args(&self) -> Args<'_> {
+        Args {
+            data: &self.args,
+        }
+    }
+}
+
+impl<'a> Args<'a> {
+    fn uint(&mut self) -> u32 {
+        if self.data.len() < 4 {
+            return 0;
+        }
+        let (word, rest) = self.data.split_at(4);
+        self.data = rest;
+        u32::from_ne_bytes([word[0], word[1], word[2], word[3]])
+    }
+
+    fn int(&mut self) -> i32 {
+        self.uint() as i32
+    }
+
+    fn string(&mut self) -> String {
+        // Length with the terminating NUL, contents padded to 32 bits
+        let len = self.uint() as usize;
+        let padded = (len + 3) & !3;
+        if len == 0 || self.data.len() < padded {
+            return String::new();
+        }
+        let s = String::from_utf8_lossy(&self.data[..len - 1]).into_owned();
+        self.data = &self.data[padded..];
+        s
+    }
+}
+
+impl Output {
+    /// Area covered in the compositor's global space
+    fn rect(&self) -> util::Rect {
+        // Odd transforms rotate by 90 or 270 degrees
+        let (w, h) = if self.transform % 2 == 1 { (self.h, self.w) } else { (self.w, self.h) };
+        let scale = self.scale.max(1);
+        util::Rect {
+            x: self.x,
+            y: self.y,
+            w: w / scale,
+            h: h / scale,
+        }
+    }
+}
+
+fn message(object: u32, opcode: u16, args: &[Arg]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for arg in args {
+        match arg {
+            Arg::Uint(v) => body.extend_from_slice(&v.to_ne_bytes()),
+            Arg::Int(v) => body.extend_from_slice(&v.to_ne_bytes()),
+            Arg::Str(s) => {
+                body.extend_from_slice(&(s.len() as u32 + 1).to_ne_bytes());
+                body.extend_from_slice(s.as_bytes());
+                body.push(0);
+                while body.len() % 4 != 0 {
+                    body.push(0);
+                }
+            },
+        }
+    }
+
+    let size = 8 + body.len() as u32;
+    let mut m = Vec::with_capacity(size as usize);
+    m.extend_from_slice(&object.to_ne_bytes());
+    m.extend_from_slice(&(size << 16 | opcode as u32).to_ne_bytes());
+    m.extend_from_slice(&body);
+    m
+}
+
+/// A file only reachable through its descriptor, for sharing memory with the compositor
+fn anonymous_file(size: u64) -> Result<fs::File, String> {
+    let dir = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from).unwrap_or_else(env::temp_dir);
+    let path = dir.join(format!("shotgun-shm-{}", process::id()));
+    let file = fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path)
+        .map_err(|e| format!("failed to create {}: {}", path.display(), e))?;
+    let _ = fs::remove_file(&path);
+    file.set_len(size).map_err(|e| e.to_string())?;
+    Ok(file)
+}
+
+impl Connection {
+    /// Connect to the compositor named by `WAYLAND_DISPLAY` and look up what screencopy needs
+    pub fn open() -> Result<Connection, String> {
+        let name = env::var_os("WAYLAND_DISPLAY").ok_or("WAYLAND_DISPLAY is not set")?;
+        let mut path = PathBuf::from(name);
+        if !path.is_absolute() {
+            let runtime = env::var_os("XDG_RUNTIME_DIR").ok_or("XDG_RUNTIME_DIR is not set")?;
+            path = PathBuf::from(runtime).join(path);
+        }
+        let socket = UnixStream::connect(&path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        let mut connection = Connection {
+            socket,
+            inbox: RefCell::new(Vec::new()),
+            next_id: Cell::new(DISPLAY + 1),
+            shm: 0,
+            manager: 0,
+            outputs: Vec::new(),
+        };
+
+        let registry = connection.new_id();
+        connection.send(DISPLAY, 1, &[Arg::Uint(registry)])?;
+        let mut globals = Vec::new();
+        connection.roundtrip(|e| {
+            if e.object == registry && e.opcode == 0 {
+                let mut a = e.args();
+                globals.push((a.uint(), a.string(), a.uint()));
+            }
+        })?;
+
+        let mut outputs = Vec::new();
+        for (name, interface, version) in globals {
+            // Version 2 of wl_output adds the scale
+            let version = match interface.as_ref() {
+                "wl_shm" | "zwlr_screencopy_manager_v1" => 1,
+                "wl_output" => version.min(2),
+                _ => continue,
+            };
+            let id = connection.new_id();
+            connection.send(registry, 0, &[Arg::Uint(name), Arg::Str(&interface),
+                                           Arg::Uint(version), Arg::Uint(id)])?;
+            match interface.as_ref() {
+                "wl_shm" => connection.shm = id,
+                "zwlr_screencopy_manager_v1" => connection.manager = id,
+                _ => outputs.push(Output {
+                    id,
+                    x: 0,
+                    y: 0,
+                    w: 0,
+                    h: 0,
+                    scale: 1,
+                    transform: 0,
+                }),
+            }
+        }
+        if connection.manager == 0 {
+            return Err("the compositor does not support wlr-screencopy".to_string());
+        }
+        if connection.shm == 0 {
+            return Err("the compositor does not support wl_shm".to_string());
+        }
+
+        // Outputs describe themselves right after being bound
+        connection.roundtrip(|e| {
+            let output = match outputs.iter_mut().find(|o| o.id == e.object) {
+                Some(o) => o,
+                None => return,
+            };
+            let mut a = e.args();
+            match e.opcode {
+                // Geometry: position, physical size, subpixel layout, make, model, transform
+                0 => {
+                    output.x = a.int();
+                    output.y = a.int();
+                    a.int();
+                    a.int();
+                    a.int();
+                    a.string();
+                    a.string();
+                    output.transform = a.int();
+                },
+                1 => {
+                    let flags = a.uint();
+                    let (w, h) = (a.int(), a.int());
+                    if flags & OUTPUT_MODE_CURRENT != 0 {
+                        output.w = w;
+                        output.h = h;
+                    }
+                },
+                3 => output.scale = a.int(),
+                _ => (),
+            }
+        })?;
+        connection.outputs = outputs;
+
+        Ok(connection)
+    }
+
+    fn new_id(&self) -> u32 {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        id
+    }
+
+    fn send(&self, object: u32, opcode: u16, args: &[Arg]) -> Result<(), String> {
+        (&self.socket).write_all(&message(object, opcode, args)).map_err(|e| e.to_string())
+    }
+
+    /// Send a request that also carries a file descriptor
+    fn send_fd(&self, object: u32, opcode: u16, args: &[Arg], fd: c_int) -> Result<(), String> {
+        let m = message(object, opcode, args);
+        let sent = unsafe {
+            let mut iov = libc::iovec {
+                iov_base: m.as_ptr() as *mut c_void,
+                iov_len: m.len(),
+            };
+            let space = libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) as usize;
+            let mut control = vec![0u8; space];
+            let mut header: libc::msghdr = mem::zeroed();
+            header.msg_iov = &mut iov;
+            header.msg_iovlen = 1;
+            header.msg_control = control.as_mut_ptr() as *mut c_void;
+            header.msg_controllen = space as _;
+
+            let cmsg = libc::CMSG_FIRSTHDR(&header);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<c_int>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut c_int, fd);
+
+            libc::sendmsg(self.socket.as_raw_fd(), &header, 0)
+        };
+        if sent != m.len() as isize {
+            return Err("failed to send a file descriptor".to_string());
+        }
+        Ok(())
+    }
+
+    /// Wait for the next event, protocol errors end up as errors
+    fn event(&self) -> Result<Event, String> {
+        let mut inbox = self.inbox.borrow_mut();
+        loop {
+            if inbox.len() >= 8 {
+                let word = |i: usize| u32::from_ne_bytes([inbox[i], inbox[i + 1], inbox[i + 2],
+                                                          inbox[i + 3]]);
+                let (object, header) = (word(0), word(4));
+                let size = (header >> 16) as usize;
+                if size < 8 {
+                    return Err("malformed message from the compositor".to_string());
+                }
+                if inbox.len() >= size {
+                    let event = Event {
+                        object,
+                        opcode: header as u16,
+                        args: inbox[8..size].to_vec(),
+                    };
+                    inbox.drain(..size);
+
+                    if object != DISPLAY {
+                        return Ok(event);
+                    }
+                    // Errors name the object and an error code before the message, the other
+                    // event is delete_id
+                    if event.opcode == 0 {
+                        let mut a = event.args();
+                        a.uint();
+                        a.uint();
+                        return Err(a.string());
+                    }
+                    continue;
+                }
+            }
+
+            let mut buf = [0; 4096];
+            let n = (&self.socket).read(&mut buf).map_err(|e| e.to_string())?;
+            if n == 0 {
+                return Err("the compositor closed the connection".to_string());
+            }
+            inbox.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// Hand events to `f` until the compositor has processed every request sent so far
+    fn roundtrip<F: FnMut(&Event)>(&self, mut f: F) -> Result<(), String> {
+        let callback = self.new_id();
+        self.send(DISPLAY, 0, &[Arg::Uint(callback)])?;
+        loop {
+            let event = self.event()?;
+            if event.object == callback {
+                return Ok(());
+            }
+            f(&event);
+        }
+    }
+
+    /// Wait for one of `opcodes` on `object`, skipping the events of other objects
+    fn wait(&self, object: u32, opcodes: &[u16]) -> Result<Event, String> {
+        loop {
+            let event = self.event()?;
+            if event.object == object && opcodes.contains(&event.opcode) {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Copy part of an output, in its own logical coordinates
+    fn capture_output(&self, output: &Output, area: util::Rect) -> Result<RgbaImage, String> {
+        let frame = self.new_id();
+        self.send(self.manager, 1, &[Arg::Uint(frame), Arg::Int(0), Arg::Uint(output.id),
+                                     Arg::Int(area.x), Arg::Int(area.y), Arg::Int(area.w),
+                                     Arg::Int(area.h)])?;
+
+        // The compositor picks the buffer layout, or fails right away
+        let event = self.wait(frame, &[0, 3])?;
+        if event.opcode == 3 {
+            return Err("the compositor refused to capture the output".to_string());
+        }
+        let mut a = event.args();
+        let (format, width, height, stride) = (a.uint(), a.uint(), a.uint(), a.uint());
+        let order = match format {
+            FORMAT_ARGB8888 | FORMAT_XRGB8888 => [2, 1, 0],
+            FORMAT_ABGR8888 | FORMAT_XBGR8888 => [0, 1, 2],
+            _ => return Err(format!("unsupported pixel format 0x{:08x}", format)),
+        };
+
+        let size = stride as u64 * height as u64;
+        let file = anonymous_file(size)?;
+        let pool = self.new_id();
+        self.send_fd(self.shm, 0, &[Arg::Uint(pool), Arg::Int(size as i32)], file.as_raw_fd())?;
+        let buffer = self.new_id();
+        self.send(pool, 0, &[Arg::Uint(buffer), Arg::Int(0), Arg::Int(width as i32),
+                             Arg::Int(height as i32), Arg::Int(stride as i32),
+                             Arg::Uint(format)])?;
+        self.send(frame, 0, &[Arg::Uint(buffer)])?;
+
+        let mut flags = 0;
+        let result = loop {
+            let event = self.wait(frame, &[1, 2, 3])?;
+            match event.opcode {
+                1 => flags = event.args().uint(),
+                2 => break Ok(()),
+                _ => break Err("the compositor failed to copy the output".to_string()),
+            }
+        };
+        // Destroy the frame, buffer and pool
+        self.send(frame, 1, &[])?;
+        self.send(buffer, 0, &[])?;
+        self.send(pool, 1, &[])?;
+        result?;
+
+        let mut data = vec![0; size as usize];
+        file.read_exact_at(&mut data, 0).map_err(|e| e.to_string())?;
+        Ok(RgbaImage::from_fn(width, height, |x, y| {
+            let row = if flags & FRAME_FLAG_Y_INVERT != 0 { height - 1 - y } else { y };
+            let p = &data[(row * stride + x * 4) as usize..][..4];
+            Rgba([p[order[0]], p[order[1]], p[order[2]], 0xFF])
+        }))
+    }
+
+    /// Capture an area of the compositor's global space, areas outside of any output are left
+    /// transparent
+    pub fn capture(&self, area: util::Rect) -> Result<RgbaImage, String> {
+        let mut image = RgbaImage::from_pixel(area.w as u32, area.h as u32, Rgba([0, 0, 0, 0]));
+        for output in self.outputs.iter() {
+            let rect = output.rect();
+            let part = match rect.intersection(area) {
+                Some(p) => p,
+                None => continue,
+            };
+
+            let mut shot = self.capture_output(output, util::Rect {
+                x: part.x - rect.x,
+                y: part.y - rect.y,
+                w: part.w,
+                h: part.h,
+            })?;
+            // Scaled outputs give more pixels than the logical size
+            if shot.dimensions() != (part.w as u32, part.h as u32) {
+                shot = imageops::resize(&shot, part.w as u32, part.h as u32,
+                                        imageops::FilterType::Triangle);
+            }
+            image.copy_from(&shot, (part.x - area.x) as u32, (part.y - area.y) as u32)
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(image)
+    }
+}
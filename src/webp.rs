@@ -0,0 +1,770 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Lossless WebP (VP8L) encoder, the image crate only decodes WebP
+// Animations hold one such bitstream per frame, in ANMF chunks, and lossy files compress their
+// alpha channel with it
+// Keeps to the green subtraction transform and greedy LZ77 matching, which goes a long way on
+// the flat areas and repeated rows of screenshots
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+
+use image::RgbaImage;
+
+use crate::vp8;
+
+const NUM_LENGTH_CODES: usize = 24;
+const NUM_DISTANCE_CODES: usize = 40;
+const MAX_LENGTH: usize = 4096;
+const MIN_LENGTH: usize = 3;
+// Linear distances come after the 120 codes for 2D neighbors
+const PLANE_CODES: usize = 120;
+const WINDOW: usize = 1 << 18;
+
+const CODE_LENGTH_ORDER: [usize; 19] = [17, 18, 0, 1, 2, 3, 4, 5, 16, 6, 7, 8, 9, 10, 11, 12, 13,
+                                        14, 15];
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    n: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, bits: u32, len: u32) {
+        self.acc |= (bits as u64) << self.n;
+        self.n += len;
+        while self.n >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.n -= 8;
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.n > 0 {
+            self.out.push(self.acc as u8);
+            self.acc = 0;
+            self.n = 0;
+        }
+    }
+}
+
+/// A canonical prefix code, with codes bit-reversed for LSB-first output
+struct PrefixCode {
+    lengths: Vec<u8>,
+    codes: Vec<u32>,
+}
+
+impl PrefixCode {
+    /// Code lengths limited to `max_len` bits for the given symbol counts
+    fn new(counts: &[u32], max_len: u8) -> PrefixCode {
+        let mut counts = counts.to_vec();
+        // Codes must have at least one symbol
+        if counts.iter().all(|&c| c == 0) {
+            counts[0] = 1;
+        }
+
+        let lengths = loop {
+            let lengths = huffman_lengths(&counts);
+            if lengths.iter().all(|&l| l <= max_len) {
+                break lengths;
+            }
+            // Flatten the distribution until the tree is shallow enough
+            for c in counts.iter_mut().filter(|c| **c > 0) {
+                *c = (*c / 2).max(1);
+            }
+        };
+
+        let mut bl_count = [0u32; 16];
+        for &l in lengths.iter().filter(|&&l| l > 0) {
+            bl_count[l as usize] += 1;
+        }
+        let mut next = [0u32; 16];
+        for len in 1..16 {
+            next[len] = (next[len - 1] + bl_count[len - 1]) << 1;
+        }
+
+        let codes = lengths.iter().map(|&l| {
+            if l == 0 {
+                return 0;
+            }
+            let code = next[l as usize];
+            next[l as usize] += 1;
+            code.reverse_bits() >> (32 - l as u32)
+        }).collect();
+
+        PrefixCode {
+            lengths,
+            codes,
+        }
+    }
+
+    /// A code with a single symbol takes no bits at all
+    fn is_trivial(&self) -> bool {
+        self.lengths.iter().filter(|&&l| l > 0).count() <= 1
+    }
+
+    fn put(&self, w: &mut BitWriter, symbol: usize) {
+        if !self.is_trivial() {
+            w.put(self.codes[symbol], self.lengths[symbol] as u32);
+        }
+    }
+
+    /// Write out the code lengths, themselves compressed with a prefix code
+    fn write(&self, w: &mut BitWriter) {
+        // Zero runs are coded as 17 (3 to 10 times) or 18 (11 to 138 times)
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < self.lengths.len() {
+            let l = self.lengths[i];
+            let run = self.lengths[i..].iter().take_while(|&&x| x == l).count();
+            if l == 0 && run >= 11 {
+                let n = run.min(138);
+                tokens.push((18, (n - 11) as u32, 7));
+                i += n;
+            } else if l == 0 && run >= 3 {
+                tokens.push((17, (run - 3) as u32, 3));
+                i += run;
+            } else {
+                tokens.push((l as usize, 0, 0));
+                i += 1;
+            }
+        }
+
+        let mut counts = [0; 19];
+        for &(t, _, _) in tokens.iter() {
+            counts[t] += 1;
+        }
+        let code_lengths = PrefixCode::new(&counts, 7);
+
+        let num = CODE_LENGTH_ORDER.iter().rposition(|&s| code_lengths.lengths[s] > 0)
+            .map_or(4, |i| (i + 1).max(4));
+        w.put(0, 1); // Normal code
+        w.put((num - 4) as u32, 4);
+        for &s in CODE_LENGTH_ORDER[..num].iter() {
+            w.put(code_lengths.lengths[s] as u32, 3);
+        }
+        w.put(0, 1); // Lengths for the whole alphabet
+        for (t, extra, bits) in tokens {
+            code_lengths.put(w, t);
+            w.put(extra, bits);
+        }
+    }
+}
+
+/// Plain Huffman code lengths, without any limit
+fn huffman_lengths(counts: &[u32]) -> Vec<u8> {
+    let mut lengths = vec![0; counts.len()];
+    let used: Vec<usize> = (0..counts.len()).filter(|&s| counts[s] > 0).collect();
+    if used.len() == 1 {
+        lengths[used[0]] = 1;
+        return lengths;
+    }
+
+    // Leaves first, then internal nodes, each pointing to its parent
+    let mut parents = vec![0; used.len()];
+    let mut heap: BinaryHeap<_> = used.iter().enumerate()
+        .map(|(i, &s)| Reverse((counts[s] as u64, i)))
+        .collect();
+    while heap.len() > 1 {
+        let Reverse((a, i)) = heap.pop().unwrap_or_default();
+        let Reverse((b, j)) = heap.pop().unwrap_or_default();
+        let node = parents.len();
+        parents.push(0);
+        parents[i] = node;
+        parents[j] = node;
+        heap.push(Reverse((a + b, node)));
+    }
+
+    let root = parents.len() - 1;
+    for (leaf, &s) in used.iter().enumerate() {
+        let mut depth = 0;
+        let mut n = leaf;
+        while n != root {
+            n = parents[n];
+            depth += 1;
+        }
+        lengths[s] = depth.min(u8::MAX as usize) as u8;
+    }
+    lengths
+}
+
+/// Prefix symbol and extra bits of a length or distance
+fn prefix(value: usize) -> (usize, u32, u32) {
+    let v = value - 1;
+    if v < 4 {
+        return (v, 0, 0);
+    }
+    let high = usize::BITS - 1 - v.leading_zeros();
+    let second = (v >> (high - 1)) & 1;
+    let extra_bits = high - 1;
+    ((2 * high as usize) + second, (v & ((1 << extra_bits) - 1)) as u32, extra_bits)
+}
+
+enum Token {
+    Literal(u32),
+    Copy(usize, usize),
+}
+
+/// Greedy LZ77, trying the previous pixel, the previous row and the last position with the
+/// same upcoming pixels
+fn backward_references(pixels: &[u32], width: usize) -> Vec<Token> {
+    const HASH_BITS: u32 = 16;
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let hash = |i: usize| {
+        let h = pixels[i].wrapping_mul(0x9E3779B1) ^ pixels[i + 1].wrapping_mul(0x85EBCA6B)
+                ^ pixels[i + 2];
+        (h.wrapping_mul(0x9E3779B1) >> (32 - HASH_BITS)) as usize
+    };
+    let match_len = |i: usize, dist: usize| {
+        pixels[i..].iter().zip(pixels[i - dist..].iter())
+            .take(MAX_LENGTH).take_while(|(a, b)| a == b).count()
+    };
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < pixels.len() {
+        let mut best = (0, 0);
+        if i + MIN_LENGTH <= pixels.len() {
+            let h = hash(i);
+            let candidates = [1, width, i.wrapping_sub(table[h])];
+            for &d in candidates.iter() {
+                if d == 0 || d > i || d > WINDOW {
+                    continue;
+                }
+                let len = match_len(i, d);
+                if len > best.0 {
+                    best = (len, d);
+                }
+            }
+            table[h] = i;
+        }
+
+        if best.0 >= MIN_LENGTH {
+            tokens.push(Token::Copy(best.0, best.1));
+            i += best.0;
+        } else {
+            tokens.push(Token::Literal(pixels[i]));
+            i += 1;
+        }
+    }
+    tokens
+}
+
+/// A frame of an animation, compressed as it comes
+pub struct Frame {
+    width: u32,
+    height: u32,
+    /// Its VP8L chunk
+    chunk: Vec<u8>,
+}
+
+/// Append a chunk holding `data`, padded to an even size
+fn put_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// The RIFF container around chunks, starting with the WEBP tag
+fn riff(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 8);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(body);
+    out
+}
+
+/// Encode `image` as a lossless WebP file
+pub fn write_webp(w: &mut dyn io::Write, image: &RgbaImage) -> io::Result<()> {
+    let mut body = b"WEBP".to_vec();
+    body.extend_from_slice(&vp8l_chunk(image)?);
+    w.write_all(&riff(&body))
+}
+
+/// Encode `image` as a lossy WebP file, any alpha channel being kept losslessly
+pub fn write_lossy(w: &mut dyn io::Write, image: &RgbaImage, quality: u8) -> io::Result<()> {
+    let frame = vp8::encode(image, quality)?;
+    let mut body = b"WEBP".to_vec();
+    if image.pixels().any(|p| p[3] != 0xFF) {
+        let (width, height) = image.dimensions();
+        let mut header = vec![0x10, 0, 0, 0];
+        header.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        header.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        put_chunk(&mut body, b"VP8X", &header);
+
+        // The alpha values in the green channel of a VP8L bitstream, less its 5 byte header
+        let mut alpha = image.clone();
+        for p in alpha.pixels_mut() {
+            p.0 = [0, p[3], 0, 0xFF];
+        }
+        let mut data = vec![1];
+        data.extend_from_slice(&vp8l_bitstream(&alpha)?[5..]);
+        put_chunk(&mut body, b"ALPH", &data);
+    }
+    put_chunk(&mut body, b"VP8 ", &frame);
+    w.write_all(&riff(&body))
+}
+
+/// Compress `image` for write_animation
+pub fn encode_frame(image: &RgbaImage) -> io::Result<Frame> {
+    Ok(Frame {
+        width: image.width(),
+        height: image.height(),
+        chunk: vp8l_chunk(image)?,
+    })
+}
+
+/// Write `frames`, each shown for its delay in milliseconds, looping forever
+/// The canvas is as large as the largest frame, frames being placed at its top left corner and
+/// replacing what was there rather than blending over it
+pub fn write_animation(w: &mut dyn io::Write, frames: &[(Frame, u32)]) -> io::Result<()> {
+    let width = frames.iter().map(|(f, _)| f.width).max();
+    let height = frames.iter().map(|(f, _)| f.height).max();
+    let (width, height) = match (width, height) {
+        (Some(w), Some(h)) => (w, h),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "No frames to write")),
+    };
+    let u24 = |v: u32| [v as u8, (v >> 8) as u8, (v >> 16) as u8];
+
+    let mut body = b"WEBP".to_vec();
+    // Animation and alpha, as areas frames leave uncovered are transparent
+    let mut header = vec![0x12, 0, 0, 0];
+    header.extend_from_slice(&u24(width - 1));
+    header.extend_from_slice(&u24(height - 1));
+    put_chunk(&mut body, b"VP8X", &header);
+    // Transparent background, looping forever
+    put_chunk(&mut body, b"ANIM", &[0, 0, 0, 0, 0, 0]);
+
+    for (frame, delay) in frames {
+        let mut data = Vec::with_capacity(frame.chunk.len() + 16);
+        data.extend_from_slice(&[0; 6]);
+        data.extend_from_slice(&u24(frame.width - 1));
+        data.extend_from_slice(&u24(frame.height - 1));
+        data.extend_from_slice(&u24((*delay).min(0xFF_FFFF)));
+        // Not blended, and cleared to the background once shown
+        data.push(0b11);
+        data.extend_from_slice(&frame.chunk);
+        put_chunk(&mut body, b"ANMF", &data);
+    }
+    w.write_all(&riff(&body))
+}
+
+/// The VP8L chunk holding `image`
+fn vp8l_chunk(image: &RgbaImage) -> io::Result<Vec<u8>> {
+    let bitstream = vp8l_bitstream(image)?;
+    let mut chunk = Vec::with_capacity(bitstream.len() + 9);
+    put_chunk(&mut chunk, b"VP8L", &bitstream);
+    Ok(chunk)
+}
+
+fn vp8l_bitstream(image: &RgbaImage) -> io::Result<Vec<u8>> {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 || width > 1 << 14 || height > 1 << 14 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "WebP images are limited to 16384x16384 pixels"));
+    }
+
+    // ARGB with the green subtraction transform applied
+    let mut alpha = false;
+    let pixels: Vec<u32> = image.pixels().map(|p| {
+        alpha |= p[3] != 0xFF;
+        let [r, g, b, a] = p.0;
+        (a as u32) << 24 | (r.wrapping_sub(g) as u32) << 16 | (g as u32) << 8
+            | b.wrapping_sub(g) as u32
+    }).collect();
+    let tokens = backward_references(&pixels, width as usize);
+
+    let mut green = vec![0; 256 + NUM_LENGTH_CODES];
+    let mut red = vec![0; 256];
+    let mut blue = vec![0; 256];
+    let mut alphas = vec![0; 256];
+    let mut dist = vec![0; NUM_DISTANCE_CODES];
+    for t in tokens.iter() {
+        match *t {
+            Token::Literal(p) => {
+                green[(p >> 8 & 0xFF) as usize] += 1;
+                red[(p >> 16 & 0xFF) as usize] += 1;
+                blue[(p & 0xFF) as usize] += 1;
+                alphas[(p >> 24) as usize] += 1;
+            },
+            Token::Copy(len, d) => {
+                green[256 + prefix(len).0] += 1;
+                dist[prefix(d + PLANE_CODES).0] += 1;
+            },
+        }
+    }
+    let codes: Vec<PrefixCode> = [green, red, blue, alphas, dist].iter()
+        .map(|c| PrefixCode::new(c, 15))
+        .collect();
+
+    let mut bits = BitWriter {
+        out: Vec::new(),
+        acc: 0,
+        n: 0,
+    };
+    bits.put(0x2F, 8);
+    bits.put(width - 1, 14);
+    bits.put(height - 1, 14);
+    bits.put(alpha as u32, 1);
+    bits.put(0, 3);
+
+    // Subtract green transform, then no more transforms
+    bits.put(1, 1);
+    bits.put(2, 2);
+    bits.put(0, 1);
+
+    // No color cache, a single set of prefix codes for the whole image
+    bits.put(0, 1);
+    bits.put(0, 1);
+    for c in codes.iter() {
+        c.write(&mut bits);
+    }
+
+    for t in tokens {
+        match t {
+            Token::Literal(p) => {
+                codes[0].put(&mut bits, (p >> 8 & 0xFF) as usize);
+                codes[1].put(&mut bits, (p >> 16 & 0xFF) as usize);
+                codes[2].put(&mut bits, (p & 0xFF) as usize);
+                codes[3].put(&mut bits, (p >> 24) as usize);
+            },
+            Token::Copy(len, d) => {
+                let (symbol, extra, n) = prefix(len);
+                codes[0].put(&mut bits, 256 + symbol);
+                bits.put(extra, n);
+                let (symbol, extra, n) = prefix(d + PLANE_CODES);
+                codes[4].put(&mut bits, symbol);
+                bits.put(extra, n);
+            },
+        }
+    }
+    bits.flush();
+    Ok(bits.out)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, len: u32) -> u32 {
+            let mut v = 0;
+            for i in 0..len {
+                let bit = self.data[self.pos / 8] >> (self.pos % 8) & 1;
+                v |= (bit as u32) << i;
+                self.pos += 1;
+            }
+            v
+        }
+    }
+
+    /// Canonical code for the given lengths, read a bit at a time from the most significant
+    struct Decoder {
+        codes: Vec<(u32, u8, usize)>,
+    }
+
+    impl Decoder {
+        fn new(lengths: &[u8]) -> Decoder {
+            let mut order: Vec<usize> = (0..lengths.len()).filter(|&s| lengths[s] > 0).collect();
+            order.sort_by_key(|&s| (lengths[s], s));
+            let mut codes = Vec::new();
+            let mut code = 0;
+            let mut prev_len = 0;
+            for s in order {
+                code <<= lengths[s] - prev_len;
+                prev_len = lengths[s];
+                codes.push((code, lengths[s], s));
+                code += 1;
+            }
+            // Kraft's inequality must hold with equality for a complete code
+            if codes.len() > 1 {
+                assert_eq!(code, 1 << prev_len, "incomplete prefix code");
+            }
+            Decoder {
+                codes,
+            }
+        }
+
+        fn read(&self, r: &mut BitReader) -> usize {
+            if self.codes.len() == 1 {
+                return self.codes[0].2;
+            }
+            let mut code = 0;
+            for len in 1..=15 {
+                code = code << 1 | r.bits(1);
+                if let Some(&(_, _, s)) = self.codes.iter().find(|c| c.0 == code && c.1 == len) {
+                    return s;
+                }
+            }
+            panic!("invalid prefix code");
+        }
+
+        fn read_code(r: &mut BitReader, alphabet: usize) -> Decoder {
+            assert_eq!(r.bits(1), 0, "simple codes are not written");
+            let num = r.bits(4) as usize + 4;
+            let mut code_lengths = [0; 19];
+            for &s in CODE_LENGTH_ORDER[..num].iter() {
+                code_lengths[s] = r.bits(3) as u8;
+            }
+            let code_lengths = Decoder::new(&code_lengths);
+            assert_eq!(r.bits(1), 0, "max_symbol is not written");
+
+            let mut lengths = Vec::new();
+            let mut prev = 8;
+            while lengths.len() < alphabet {
+                match code_lengths.read(r) {
+                    l @ 0..=15 => {
+                        lengths.push(l as u8);
+                        if l != 0 {
+                            prev = l as u8;
+                        }
+                    },
+                    16 => lengths.extend(std::iter::repeat_n(prev, 3 + r.bits(2) as usize)),
+                    17 => lengths.extend(std::iter::repeat_n(0, 3 + r.bits(3) as usize)),
+                    _ => lengths.extend(std::iter::repeat_n(0, 11 + r.bits(7) as usize)),
+                }
+            }
+            assert_eq!(lengths.len(), alphabet);
+            Decoder::new(&lengths)
+        }
+    }
+
+    fn read_prefixed(r: &mut BitReader, symbol: usize) -> usize {
+        if symbol < 4 {
+            return symbol + 1;
+        }
+        let extra = (symbol as u32 - 2) >> 1;
+        let offset = (2 + (symbol & 1)) << extra;
+        offset + r.bits(extra) as usize + 1
+    }
+
+    fn le32(d: &[u8]) -> usize {
+        u32::from_le_bytes([d[0], d[1], d[2], d[3]]) as usize
+    }
+
+    /// Chunks of a WebP file, checking that they make up the whole of it
+    fn chunks(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(le32(&data[4..]) + 8, data.len());
+        assert_eq!(&data[8..12], b"WEBP");
+        chunks_of(&data[12..])
+    }
+
+    fn chunks_of(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut chunks = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let size = le32(&rest[4..]);
+            chunks.push(([rest[0], rest[1], rest[2], rest[3]], &rest[8..8 + size]));
+            rest = &rest[8 + size + size % 2..];
+        }
+        chunks
+    }
+
+    /// Decoder for what write_webp produces, and whether the alpha hint is set
+    fn decode(data: &[u8]) -> (RgbaImage, bool) {
+        let chunks = chunks(data);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(&chunks[0].0, b"VP8L");
+        decode_vp8l(chunks[0].1)
+    }
+
+    fn decode_vp8l(data: &[u8]) -> (RgbaImage, bool) {
+        let mut r = BitReader {
+            data,
+            pos: 0,
+        };
+        assert_eq!(r.bits(8), 0x2F);
+        let width = r.bits(14) + 1;
+        let height = r.bits(14) + 1;
+        let alpha = r.bits(1) == 1;
+        assert_eq!(r.bits(3), 0);
+        // Only the subtract green transform is used
+        assert_eq!(r.bits(1), 1);
+        assert_eq!(r.bits(2), 2);
+        assert_eq!(r.bits(1), 0);
+        assert_eq!(r.bits(1), 0, "no color cache");
+        assert_eq!(r.bits(1), 0, "no meta prefix codes");
+
+        let green = Decoder::read_code(&mut r, 256 + NUM_LENGTH_CODES);
+        let red = Decoder::read_code(&mut r, 256);
+        let blue = Decoder::read_code(&mut r, 256);
+        let alphas = Decoder::read_code(&mut r, 256);
+        let dist = Decoder::read_code(&mut r, NUM_DISTANCE_CODES);
+
+        let count = (width * height) as usize;
+        let mut pixels: Vec<u32> = Vec::with_capacity(count);
+        while pixels.len() < count {
+            let g = green.read(&mut r);
+            if g < 256 {
+                let red = red.read(&mut r) as u32;
+                let blue = blue.read(&mut r) as u32;
+                let alpha = alphas.read(&mut r) as u32;
+                pixels.push(alpha << 24 | red << 16 | (g as u32) << 8 | blue);
+            } else {
+                let len = read_prefixed(&mut r, g - 256);
+                let symbol = dist.read(&mut r);
+                let code = read_prefixed(&mut r, symbol);
+                assert!(code > PLANE_CODES, "2D distances are not written");
+                let d = code - PLANE_CODES;
+                for _ in 0..len {
+                    pixels.push(pixels[pixels.len() - d]);
+                }
+            }
+        }
+        assert_eq!(pixels.len(), count);
+
+        let raw = pixels.iter().flat_map(|&p| {
+            let [a, r, g, b] = p.to_be_bytes();
+            vec![r.wrapping_add(g), g, b.wrapping_add(g), a]
+        }).collect();
+        (RgbaImage::from_raw(width, height, raw).unwrap(), alpha)
+    }
+
+    fn round_trip(image: &RgbaImage) -> (Vec<u8>, bool) {
+        let mut out = Vec::new();
+        write_webp(&mut out, image).unwrap();
+        let (decoded, alpha) = decode(&out);
+        assert_eq!(decoded.dimensions(), image.dimensions());
+        assert_eq!(decoded.into_raw(), image.to_vec());
+        (out, alpha)
+    }
+
+    #[test]
+    fn noise_round_trips() {
+        let mut state = 0x2545_F491u32;
+        let image = RgbaImage::from_fn(41, 23, |_, _| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let v = state >> 8;
+            Rgba([v as u8, (v >> 8) as u8, (v >> 16) as u8, 255])
+        });
+        assert!(!round_trip(&image).1);
+    }
+
+    #[test]
+    fn repeated_rows_and_runs_round_trip() {
+        // Rows copied from the one above, and flat areas longer than the longest copy
+        let image = RgbaImage::from_fn(300, 40, |x, y| {
+            if y < 20 {
+                Rgba([(x % 7 * 30) as u8, (x % 5 * 50) as u8, (x % 3) as u8, (x % 2 * 255) as u8])
+            } else {
+                Rgba([40, 50, 60, 255])
+            }
+        });
+        let (out, alpha) = round_trip(&image);
+        assert!(alpha);
+        assert!(out.len() < 1000);
+    }
+
+    #[test]
+    fn single_color_round_trips() {
+        round_trip(&RgbaImage::from_pixel(1, 1, Rgba([1, 2, 3, 4])));
+        round_trip(&RgbaImage::from_pixel(5, 3, Rgba([255, 255, 255, 255])));
+    }
+
+    #[test]
+    fn code_lengths_are_limited() {
+        // Fibonacci counts give the deepest possible Huffman tree
+        let mut counts = vec![1u32, 1];
+        while counts.len() < 30 {
+            counts.push(counts[counts.len() - 1] + counts[counts.len() - 2]);
+        }
+        let code = PrefixCode::new(&counts, 15);
+        assert!(code.lengths.iter().all(|&l| (1..=15).contains(&l)));
+        Decoder::new(&code.lengths);
+    }
+
+    #[test]
+    fn oversized_images_are_refused() {
+        let mut out = Vec::new();
+        assert!(write_webp(&mut out, &RgbaImage::new(0, 4)).is_err());
+        assert!(write_webp(&mut out, &RgbaImage::new(16385, 1)).is_err());
+        assert!(write_animation(&mut out, &[]).is_err());
+    }
+
+    #[test]
+    fn animations_round_trip() {
+        let u24 = |d: &[u8]| d[0] as u32 | (d[1] as u32) << 8 | (d[2] as u32) << 16;
+        let images = [
+            RgbaImage::from_fn(20, 10, |x, y| Rgba([x as u8 * 10, y as u8 * 20, 0, 255])),
+            RgbaImage::from_pixel(7, 12, Rgba([0, 0, 255, 0])),
+        ];
+        let frames: Vec<_> = images.iter().map(|i| encode_frame(i).unwrap())
+            .zip([100, 40_000_000]).collect();
+        let mut out = Vec::new();
+        write_animation(&mut out, &frames).unwrap();
+
+        let chunks = chunks(&out);
+        assert_eq!(chunks.len(), 4);
+        let (fourcc, header) = chunks[0];
+        assert_eq!(&fourcc, b"VP8X");
+        assert_eq!(header[0], 0x12);
+        assert_eq!((u24(&header[4..]), u24(&header[7..])), (19, 11));
+        assert_eq!(chunks[1], (*b"ANIM", &[0u8, 0, 0, 0, 0, 0][..]));
+
+        for ((fourcc, data), (image, delay)) in chunks[2..].iter().zip(images.iter()
+                                                                    .zip([100, 0xFF_FFFF])) {
+            assert_eq!(fourcc, b"ANMF");
+            assert_eq!((u24(data), u24(&data[3..])), (0, 0));
+            assert_eq!(u24(&data[6..]) + 1, image.width());
+            assert_eq!(u24(&data[9..]) + 1, image.height());
+            assert_eq!(u24(&data[12..]), delay);
+            assert_eq!(data[15], 0b11);
+            let inner = chunks_of(&data[16..]);
+            assert_eq!(inner.len(), 1);
+            assert_eq!(&inner[0].0, b"VP8L");
+            let (decoded, _) = decode_vp8l(inner[0].1);
+            assert_eq!(decoded.into_raw(), image.to_vec());
+        }
+    }
+
+    #[test]
+    fn lossy_files_keep_alpha_losslessly() {
+        let image = RgbaImage::from_fn(30, 20, |x, y| Rgba([x as u8 * 8, 0, 200, (x * y) as u8]));
+        let mut out = Vec::new();
+        write_lossy(&mut out, &image, 80).unwrap();
+        let found = chunks(&out);
+        let fourccs: Vec<_> = found.iter().map(|c| c.0).collect();
+        assert_eq!(fourccs, [*b"VP8X", *b"ALPH", *b"VP8 "]);
+        assert_eq!(found[0].1[0], 0x10);
+        assert_eq!(found[1].1[0], 1);
+
+        // Put back the header left out of alpha bitstreams
+        let mut bits = BitWriter {
+            out: Vec::new(),
+            acc: 0,
+            n: 0,
+        };
+        bits.put(0x2F, 8);
+        bits.put(29, 14);
+        bits.put(19, 14);
+        bits.put(0, 4);
+        bits.flush();
+        bits.out.extend_from_slice(&found[1].1[1..]);
+        let (alpha, _) = decode_vp8l(&bits.out);
+        for (a, p) in alpha.pixels().zip(image.pixels()) {
+            assert_eq!(a[1], p[3]);
+        }
+
+        let opaque = RgbaImage::from_pixel(3, 3, Rgba([1, 2, 3, 255]));
+        out.clear();
+        write_lossy(&mut out, &opaque, 80).unwrap();
+        assert_eq!(chunks(&out).iter().map(|c| c.0).collect::<Vec<_>>(), [*b"VP8 "]);
+    }
+}
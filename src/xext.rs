@@ -0,0 +1,90 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Bindings for the X extensions that the x11 crate does not cover
+
+#![allow(non_snake_case, non_upper_case_globals)]
+
+use std::os::raw::c_char;
+use std::os::raw::c_int;
+use std::os::raw::c_short;
+use std::os::raw::c_uint;
+use std::os::raw::c_ulong;
+use std::os::raw::c_ushort;
+
+use x11::xlib;
+
+pub const CompositeRedirectAutomatic: c_int = 0;
+
+#[link(name = "Xcomposite")]
+extern "C" {
+    pub fn XCompositeQueryExtension(dpy: *mut xlib::Display, event_base: *mut c_int,
+                                    error_base: *mut c_int) -> xlib::Bool;
+    pub fn XCompositeQueryVersion(dpy: *mut xlib::Display, major: *mut c_int,
+                                  minor: *mut c_int) -> xlib::Status;
+    pub fn XCompositeRedirectWindow(dpy: *mut xlib::Display, window: xlib::Window,
+                                    update: c_int);
+    pub fn XCompositeUnredirectWindow(dpy: *mut xlib::Display, window: xlib::Window,
+                                      update: c_int);
+    pub fn XCompositeNameWindowPixmap(dpy: *mut xlib::Display, window: xlib::Window)
+                                      -> xlib::Pixmap;
+}
+
+pub type Damage = xlib::XID;
+
+pub const XDamageNotify: c_int = 0;
+pub const XDamageReportNonEmpty: c_int = 3;
+
+#[link(name = "Xdamage")]
+extern "C" {
+    pub fn XDamageQueryExtension(dpy: *mut xlib::Display, event_base: *mut c_int,
+                                 error_base: *mut c_int) -> xlib::Bool;
+    pub fn XDamageCreate(dpy: *mut xlib::Display, drawable: xlib::Drawable, level: c_int)
+                         -> Damage;
+    pub fn XDamageDestroy(dpy: *mut xlib::Display, damage: Damage);
+    pub fn XDamageSubtract(dpy: *mut xlib::Display, damage: Damage, repair: xlib::XID,
+                           parts: xlib::XID);
+}
+
+#[repr(C)]
+pub struct XFixesCursorImage {
+    pub x: c_short,
+    pub y: c_short,
+    pub width: c_ushort,
+    pub height: c_ushort,
+    pub xhot: c_ushort,
+    pub yhot: c_ushort,
+    pub cursor_serial: c_ulong,
+    pub pixels: *mut c_ulong,
+    pub atom: xlib::Atom,
+    pub name: *const c_char,
+}
+
+#[link(name = "Xfixes")]
+extern "C" {
+    pub fn XFixesQueryExtension(dpy: *mut xlib::Display, event_base: *mut c_int,
+                                error_base: *mut c_int) -> xlib::Bool;
+    pub fn XFixesGetCursorImage(dpy: *mut xlib::Display) -> *mut XFixesCursorImage;
+}
+
+#[repr(C)]
+pub struct XShmSegmentInfo {
+    pub shmseg: xlib::XID,
+    pub shmid: c_int,
+    pub shmaddr: *mut c_char,
+    pub readOnly: xlib::Bool,
+}
+
+#[link(name = "Xext")]
+extern "C" {
+    pub fn XShmQueryExtension(dpy: *mut xlib::Display) -> xlib::Bool;
+    pub fn XShmCreateImage(dpy: *mut xlib::Display, visual: *mut xlib::Visual, depth: c_uint,
+                           format: c_int, data: *mut c_char, shminfo: *mut XShmSegmentInfo,
+                           width: c_uint, height: c_uint) -> *mut xlib::XImage;
+    pub fn XShmAttach(dpy: *mut xlib::Display, shminfo: *mut XShmSegmentInfo) -> xlib::Bool;
+    pub fn XShmDetach(dpy: *mut xlib::Display, shminfo: *mut XShmSegmentInfo) -> xlib::Bool;
+    pub fn XShmGetImage(dpy: *mut xlib::Display, drawable: xlib::Drawable,
+                        image: *mut xlib::XImage, x: c_int, y: c_int, plane_mask: c_ulong)
+                        -> xlib::Bool;
+}
@@ -2,29 +2,222 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::cmp;
 use std::ffi;
+use std::fmt;
 use std::mem;
 use std::os::raw;
 use std::ptr;
 use std::slice;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::thread;
+use std::time;
 
+use image::ImageBuffer;
 use image::Pixel;
 use image::RgbaImage;
 use image::Rgba;
-use libc;
+use x11::dpms;
+use x11::keysym;
 use x11::xlib;
 use x11::xrandr;
+use x11::xtest;
 
+use crate::draw;
 use crate::util;
+use crate::xext;
 
 pub const ALL_PLANES: libc::c_ulong = !0;
 
+pub type Rgba16Image = ImageBuffer<Rgba<u16>, Vec<u16>>;
+
+// From X11/cursorfont.h
+const XC_CROSSHAIR: raw::c_uint = 34;
+
+static SHM_ATTACH_FAILED: AtomicBool = AtomicBool::new(false);
+
+unsafe extern "C" fn on_shm_attach_error(_: *mut xlib::Display, _: *mut xlib::XErrorEvent)
+                                         -> raw::c_int {
+    SHM_ATTACH_FAILED.store(true, Ordering::SeqCst);
+    0
+}
+
+// First error since it was last taken: error code, request code and resource ID
+static X_ERROR: Mutex<Option<(u8, u8, xlib::XID)>> = Mutex::new(None);
+
+/// Keep errors from being fatal, such as a BadWindow for a window that went away
+unsafe extern "C" fn on_error(_: *mut xlib::Display, event: *mut xlib::XErrorEvent)
+                              -> raw::c_int {
+    if let Ok(mut e) = X_ERROR.lock() {
+        let event = &*event;
+        e.get_or_insert((event.error_code, event.request_code, event.resourceid));
+    }
+    0
+}
+
+/// ARGB windows hold colors multiplied by their alpha, as the Render extension expects
+fn unpremultiply(value: u32, alpha: u32, max: u32) -> u32 {
+    if alpha == 0 {
+        return 0;
+    }
+    ((value * max + alpha / 2) / alpha).min(max)
+}
+
+/// Turn rows of `width` pixels of `stride` bytes, `bpl` bytes apart, into RGBA
+/// The channels are found at `offsets`, red, green, blue then alpha, which is left out when
+/// `opaque` and otherwise premultiplied
+fn convert_rows(src: &[u8], dst: &mut [u8], bpl: usize, width: usize, stride: usize,
+                offsets: [usize; 4], opaque: bool) {
+    let [r, g, b, a] = offsets;
+    for (src, dst) in src.chunks(bpl).zip(dst.chunks_exact_mut(width * 4)) {
+        let pixels = src.chunks_exact(stride).zip(dst.chunks_exact_mut(4));
+        // The usual little endian BGRX layout, which the compiler can vectorize
+        if opaque && stride == 4 && offsets[..3] == [2, 1, 0] {
+            for (s, d) in pixels {
+                d.copy_from_slice(&[s[2], s[1], s[0], 0xFF]);
+            }
+        } else if opaque {
+            for (s, d) in pixels {
+                d.copy_from_slice(&[s[r], s[g], s[b], 0xFF]);
+            }
+        } else {
+            for (s, d) in pixels {
+                let alpha = s[a];
+                if alpha == 0xFF {
+                    d.copy_from_slice(&[s[r], s[g], s[b], 0xFF]);
+                } else {
+                    let [r, g, b] = [s[r], s[g], s[b]]
+                        .map(|v| unpremultiply(v as u32, alpha as u32, 0xFF) as u8);
+                    d.copy_from_slice(&[r, g, b, alpha]);
+                }
+            }
+        }
+    }
+}
+
+/// Milliseconds for poll to wait until `deadline`, forever (-1) without one or when it lies
+/// too far ahead, none once it has passed
+fn poll_timeout(deadline: Option<time::Instant>) -> Option<raw::c_int> {
+    match deadline {
+        Some(d) => d.checked_duration_since(time::Instant::now())
+            .map(|left| left.as_millis().min(raw::c_int::MAX as u128) as raw::c_int),
+        None => Some(-1),
+    }
+}
+
+unsafe fn is_escape(event: &mut xlib::XEvent) -> bool {
+    xlib::XLookupKeysym(&mut event.key, 0) == keysym::XK_Escape as raw::c_ulong
+}
+
 pub struct Display {
     handle: *mut xlib::Display,
+    /// Screen whose root is the default one
+    screen: raw::c_int,
 }
 
 pub struct Image {
     handle: *mut xlib::XImage,
+    /// Whether the data is a shared memory segment rather than allocated by Xlib
+    shm: bool,
+    /// Colors of the colormap, when pixels index into one rather than hold their color
+    colors: Option<Vec<[u16; 3]>>,
+}
+
+pub struct Damage<'a> {
+    dpy: &'a Display,
+    handle: xext::Damage,
+    event_type: raw::c_int,
+}
+
+/// The keyboard, held until dropped so that Escape reaches us
+pub struct KeyboardGrab<'a> {
+    dpy: &'a Display,
+}
+
+/// A RandR output that is driving a monitor
+pub struct Output {
+    pub name: String,
+    pub rect: util::Rect,
+    pub primary: bool,
+    /// Counterclockwise turn of the picture on the monitor, in degrees
+    pub rotation: u32,
+    /// Physical size in millimeters, across and down the picture, zero when unknown
+    pub mm_size: (u32, u32),
+}
+
+impl Output {
+    /// Integer scale a desktop is likely meant to be shown at on this monitor, the DPI over 96
+    /// rounded to the nearest, and 1 when the physical size is unknown or implausible
+    pub fn scale(&self) -> u32 {
+        let (mm_w, mm_h) = self.mm_size;
+        // Some EDIDs only carry the aspect ratio or a projector's nominal size
+        if mm_w < 100 || mm_h < 50 {
+            return 1;
+        }
+        let dpi = self.rect.w as f64 * 25.4 / mm_w as f64;
+        let aspect = (self.rect.w as f64 / self.rect.h as f64) / (mm_w as f64 / mm_h as f64);
+        if !(50.0..=500.0).contains(&dpi) || !(0.8..=1.25).contains(&aspect) {
+            return 1;
+        }
+        ((dpi / 96.0).round() as u32).max(1)
+    }
+}
+
+/// What the rectangle of `select_rect` is held to
+#[derive(Clone, Copy)]
+pub enum Shape {
+    Free,
+    /// Width to height, such as 16:9
+    Ratio(u32, u32),
+    /// A rectangle of this size, moved around with the pointer and placed with a click
+    Fixed(i32, i32),
+}
+
+/// The part of `screen` dragged from `start` to `end`, made to the ratio of `rw` to `rh`
+/// It grows past the pointer along the shorter side, and shrinks to stay on the screen
+fn ratio_rect(start: (i32, i32), end: (i32, i32), rw: f64, rh: f64, screen: util::Rect)
+              -> util::Rect {
+    let dir_x = if end.0 < start.0 { -1 } else { 1 };
+    let dir_y = if end.1 < start.1 { -1 } else { 1 };
+    let room_x = if dir_x < 0 { start.0 - screen.x + 1 } else { screen.x + screen.w - start.0 };
+    let room_y = if dir_y < 0 { start.1 - screen.y + 1 } else { screen.y + screen.h - start.1 };
+
+    let (w, h) = ((end.0 - start.0).abs() + 1, (end.1 - start.1).abs() + 1);
+    let w = (w as f64).max(h as f64 * rw / rh).min(room_x as f64).min(room_y as f64 * rw / rh);
+    let (w, h) = (w.round().max(1.0) as i32, (w * rh / rw).round().max(1.0) as i32);
+    util::Rect::from_corners(start, (start.0 + dir_x * (w - 1), start.1 + dir_y * (h - 1)))
+}
+
+/// An error the server sent back for one of our requests
+pub struct XError {
+    code: u8,
+    text: String,
+}
+
+impl XError {
+    /// Whether the same request may well succeed a moment later, as when the window is unmapped
+    /// while it is resized or a restarting compositor lets go of its pixmaps
+    /// A window that is gone for good gives BadWindow or BadDrawable instead
+    pub fn is_transient(&self) -> bool {
+        [xlib::BadMatch, xlib::BadAlloc, xlib::BadPixmap].contains(&self.code)
+    }
+}
+
+impl fmt::Display for XError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// Window covering the screen with a still image of it
+pub struct Frozen<'a> {
+    dpy: &'a Display,
+    window: xlib::Window,
+    pixmap: xlib::Pixmap,
+    /// Undarkened image when the window shows a darkened one
+    bright: Option<xlib::Pixmap>,
 }
 
 pub struct ScreenRectIter<'a> {
@@ -35,35 +228,57 @@ pub struct ScreenRectIter<'a> {
 }
 
 impl Display {
-    pub fn open(name: Option<ffi::CString>) -> Option<Display> {
+    /// Connect to `name`, or `$DISPLAY`, with `screen` or the one the name gives as the default
+    pub fn open(name: Option<ffi::CString>, screen: Option<i32>) -> Option<Display> {
         unsafe {
-            let name = match name {
-                None => ptr::null(),
-                Some(cstr) => cstr.as_ptr(),
-            };
-            let d = xlib::XOpenDisplay(name);
+            let d = xlib::XOpenDisplay(name.as_ref().map_or(ptr::null(), |n| n.as_ptr()));
 
             if d.is_null() {
                 return None;
             }
+            let screen = screen.unwrap_or_else(|| xlib::XDefaultScreen(d));
+            if screen < 0 || screen >= xlib::XScreenCount(d) {
+                xlib::XCloseDisplay(d);
+                return None;
+            }
+            xlib::XSetErrorHandler(Some(on_error));
 
             Some(Display {
                 handle: d,
+                screen,
             })
         }
     }
 
+    /// Description of the first error the server sent back since the last call, if any
+    pub fn take_error(&self) -> Option<XError> {
+        let (code, request, resource) = unsafe {
+            xlib::XSync(self.handle, xlib::False);
+            X_ERROR.lock().ok()?.take()?
+        };
+
+        let mut text = [0 as raw::c_char; 256];
+        unsafe {
+            xlib::XGetErrorText(self.handle, code.into(), text.as_mut_ptr(), text.len() as i32);
+        }
+        let text = unsafe { ffi::CStr::from_ptr(text.as_ptr()) }.to_string_lossy();
+        Some(XError {
+            code,
+            text: format!("{} (request {}, resource 0x{:x})", text, request, resource),
+        })
+    }
+
     pub fn get_default_root(&self) -> xlib::Window {
         unsafe {
-            xlib::XDefaultRootWindow(self.handle)
+            xlib::XRootWindow(self.handle, self.screen)
         }
     }
 
+    /// Empty if the window does not exist
     pub fn get_window_rect(&self, window: xlib::Window) -> util::Rect {
         unsafe {
-            let mut attrs = mem::MaybeUninit::uninit();
-            xlib::XGetWindowAttributes(self.handle, window, attrs.as_mut_ptr());
-            let attrs = attrs.assume_init();
+            let mut attrs: xlib::XWindowAttributes = mem::zeroed();
+            xlib::XGetWindowAttributes(self.handle, window, &mut attrs);
 
             let mut root = 0;
             let mut parent = 0;
@@ -85,17 +300,845 @@ impl Display {
             }
 
             util::Rect {
-                x: x,
-                y: y,
+                x,
+                y,
                 w: attrs.width,
                 h: attrs.height,
             }
         }
     }
 
+    /// Children of `window` in stacking order, bottom-most first
+    pub fn get_children(&self, window: xlib::Window) -> Vec<xlib::Window> {
+        unsafe {
+            let mut root = 0;
+            let mut parent = 0;
+            let mut children: *mut xlib::Window = ptr::null_mut();
+            let mut nchildren = 0;
+            let status = xlib::XQueryTree(self.handle, window, &mut root, &mut parent,
+                                          &mut children, &mut nchildren);
+            if status == 0 || children.is_null() {
+                return Vec::new();
+            }
+
+            let v = slice::from_raw_parts(children, nchildren as usize).to_vec();
+            xlib::XFree(children as *mut raw::c_void);
+            v
+        }
+    }
+
+    /// Walk down the window tree following a list of child indices
+    pub fn get_child_by_path(&self, window: xlib::Window, path: &[usize])
+                             -> Option<xlib::Window> {
+        path.iter().try_fold(window, |w, &i| self.get_children(w).get(i).cloned())
+    }
+
+    pub fn intern_atom(&self, name: &str) -> xlib::Atom {
+        let name = ffi::CString::new(name).expect("Failed to convert CString");
+        unsafe {
+            xlib::XInternAtom(self.handle, name.as_ptr(), xlib::False)
+        }
+    }
+
+    pub fn has_property(&self, window: xlib::Window, property: &str) -> bool {
+        unsafe {
+            let mut type_ = 0;
+            let mut format = 0;
+            let mut nitems = 0;
+            let mut bytes_after = 0;
+            let mut data: *mut raw::c_uchar = ptr::null_mut();
+            xlib::XGetWindowProperty(self.handle, window, self.intern_atom(property), 0, 0,
+                                     xlib::False, xlib::AnyPropertyType as xlib::Atom,
+                                     &mut type_, &mut format, &mut nitems, &mut bytes_after,
+                                     &mut data);
+            if !data.is_null() {
+                xlib::XFree(data as *mut raw::c_void);
+            }
+            type_ != 0
+        }
+    }
+
+    /// Contents of a property made of 32-bit items, such as atoms, windows or pixmaps
+    pub fn get_property_ids(&self, window: xlib::Window, property: &str) -> Vec<xlib::XID> {
+        unsafe {
+            let mut type_ = 0;
+            let mut format = 0;
+            let mut nitems = 0;
+            let mut bytes_after = 0;
+            let mut data: *mut raw::c_uchar = ptr::null_mut();
+            xlib::XGetWindowProperty(self.handle, window, self.intern_atom(property), 0, 1024,
+                                     xlib::False, xlib::AnyPropertyType as xlib::Atom,
+                                     &mut type_, &mut format, &mut nitems, &mut bytes_after,
+                                     &mut data);
+            if data.is_null() {
+                return Vec::new();
+            }
+
+            // Xlib hands out 32-bit items as longs
+            let v = if format == 32 {
+                slice::from_raw_parts(data as *const raw::c_ulong, nitems as usize).to_vec()
+            } else {
+                Vec::new()
+            };
+            xlib::XFree(data as *mut raw::c_void);
+            v
+        }
+    }
+
+    /// Contents of a property made of bytes, such as `_ICC_PROFILE`
+    pub fn get_property_bytes(&self, window: xlib::Window, property: &str) -> Option<Vec<u8>> {
+        unsafe {
+            let mut type_ = 0;
+            let mut format = 0;
+            let mut nitems = 0;
+            let mut bytes_after = 0;
+            let mut data: *mut raw::c_uchar = ptr::null_mut();
+            // Length is in 32-bit units, ask for everything
+            xlib::XGetWindowProperty(self.handle, window, self.intern_atom(property), 0,
+                                     raw::c_long::MAX / 4, xlib::False,
+                                     xlib::AnyPropertyType as xlib::Atom, &mut type_, &mut format,
+                                     &mut nitems, &mut bytes_after, &mut data);
+            if data.is_null() {
+                return None;
+            }
+
+            let v = if format == 8 {
+                Some(slice::from_raw_parts(data, nitems as usize).to_vec())
+            } else {
+                None
+            };
+            xlib::XFree(data as *mut raw::c_void);
+            v
+        }
+    }
+
+    /// Instance and class names from `WM_CLASS`
+    pub fn get_class(&self, window: xlib::Window) -> Option<(String, String)> {
+        unsafe {
+            let mut hint = mem::MaybeUninit::<xlib::XClassHint>::uninit();
+            if xlib::XGetClassHint(self.handle, window, hint.as_mut_ptr()) == 0 {
+                return None;
+            }
+            let hint = hint.assume_init();
+
+            let take = |s: *mut raw::c_char| {
+                if s.is_null() {
+                    return String::new();
+                }
+                let owned = ffi::CStr::from_ptr(s).to_string_lossy().into_owned();
+                xlib::XFree(s as *mut raw::c_void);
+                owned
+            };
+            Some((take(hint.res_name), take(hint.res_class)))
+        }
+    }
+
+    /// Window title, from `_NET_WM_NAME` or else `WM_NAME`
+    pub fn get_name(&self, window: xlib::Window) -> Option<String> {
+        unsafe {
+            let mut type_ = 0;
+            let mut format = 0;
+            let mut nitems = 0;
+            let mut bytes_after = 0;
+            let mut data: *mut raw::c_uchar = ptr::null_mut();
+            xlib::XGetWindowProperty(self.handle, window, self.intern_atom("_NET_WM_NAME"), 0,
+                                     1024, xlib::False, self.intern_atom("UTF8_STRING"),
+                                     &mut type_, &mut format, &mut nitems, &mut bytes_after,
+                                     &mut data);
+            if !data.is_null() {
+                let name = if format == 8 {
+                    let bytes = slice::from_raw_parts(data, nitems as usize);
+                    Some(String::from_utf8_lossy(bytes).into_owned())
+                } else {
+                    None
+                };
+                xlib::XFree(data as *mut raw::c_void);
+                if name.is_some() {
+                    return name;
+                }
+            }
+
+            let mut name: *mut raw::c_char = ptr::null_mut();
+            if xlib::XFetchName(self.handle, window, &mut name) == 0 || name.is_null() {
+                return None;
+            }
+            let owned = ffi::CStr::from_ptr(name).to_string_lossy().into_owned();
+            xlib::XFree(name as *mut raw::c_void);
+            Some(owned)
+        }
+    }
+
+    /// Ancestor of `window` right below the root, the WM frame of a client window when the
+    /// window manager reparents them
+    pub fn get_top_level(&self, window: xlib::Window) -> xlib::Window {
+        let mut window = window;
+        unsafe {
+            loop {
+                let mut root = 0;
+                let mut parent = 0;
+                let mut children: *mut xlib::Window = ptr::null_mut();
+                let mut nchildren = 0;
+                let status = xlib::XQueryTree(self.handle, window, &mut root, &mut parent,
+                                              &mut children, &mut nchildren);
+                if !children.is_null() {
+                    xlib::XFree(children as *mut raw::c_void);
+                }
+                if status == 0 || parent == 0 || parent == root {
+                    return window;
+                }
+                window = parent;
+            }
+        }
+    }
+
+    /// Find the client window managed under `window`, which may be a WM frame
+    pub fn get_client_window(&self, window: xlib::Window) -> Option<xlib::Window> {
+        if self.has_property(window, "WM_STATE") {
+            return Some(window);
+        }
+
+        self.get_children(window).into_iter().rev().find_map(|c| self.get_client_window(c))
+    }
+
+    /// Client windows managed by the window manager, from the EWMH `_NET_CLIENT_LIST`, or the
+    /// viewable ones found under the top-level windows if the window manager does not set it
+    pub fn get_client_list(&self, root: xlib::Window) -> Vec<xlib::Window> {
+        let clients = self.get_property_ids(root, "_NET_CLIENT_LIST");
+        if !clients.is_empty() {
+            return clients;
+        }
+
+        self.get_children(root).into_iter()
+            .filter(|&top| self.is_viewable(top))
+            .filter_map(|top| self.get_client_window(top))
+            .collect()
+    }
+
+    /// The window `window` is a dialog for, from `WM_TRANSIENT_FOR`
+    pub fn get_transient_for(&self, window: xlib::Window) -> Option<xlib::Window> {
+        unsafe {
+            let mut parent = 0;
+            if xlib::XGetTransientForHint(self.handle, window, &mut parent) == 0 || parent == 0 {
+                return None;
+            }
+            Some(parent)
+        }
+    }
+
+    /// Whether `window` is mapped and has contents that can be captured
+    pub fn is_viewable(&self, window: xlib::Window) -> bool {
+        unsafe {
+            let mut attrs = mem::MaybeUninit::uninit();
+            if xlib::XGetWindowAttributes(self.handle, window, attrs.as_mut_ptr()) == 0 {
+                return false;
+            }
+            let attrs = attrs.assume_init();
+            attrs.map_state == xlib::IsViewable && attrs.class == xlib::InputOutput
+        }
+    }
+
+    /// Whether `window` bypasses the window manager, as menus and tooltips do
+    pub fn is_override_redirect(&self, window: xlib::Window) -> bool {
+        unsafe {
+            let mut attrs = mem::MaybeUninit::uninit();
+            if xlib::XGetWindowAttributes(self.handle, window, attrs.as_mut_ptr()) == 0 {
+                return false;
+            }
+            attrs.assume_init().override_redirect != 0
+        }
+    }
+
+    pub fn has_composite(&self) -> bool {
+        unsafe {
+            let mut event_base = 0;
+            let mut error_base = 0;
+            if xext::XCompositeQueryExtension(self.handle, &mut event_base,
+                                              &mut error_base) == 0 {
+                return false;
+            }
+
+            // NameWindowPixmap appeared in 0.2
+            let mut major = 0;
+            let mut minor = 2;
+            xext::XCompositeQueryVersion(self.handle, &mut major, &mut minor);
+            major > 0 || minor >= 2
+        }
+    }
+
+    /// Capture the contents of `window` from its off-screen pixmap, unobscured by other windows
+    pub fn get_window_pixmap_image(&self, window: xlib::Window, rect: util::Rect)
+                                   -> Option<Image> {
+        unsafe {
+            xext::XCompositeRedirectWindow(self.handle, window, xext::CompositeRedirectAutomatic);
+            let pixmap = xext::XCompositeNameWindowPixmap(self.handle, window);
+            let image = self.get_image(pixmap, rect, ALL_PLANES, xlib::ZPixmap);
+            xlib::XFreePixmap(self.handle, pixmap);
+            xext::XCompositeUnredirectWindow(self.handle, window,
+                                             xext::CompositeRedirectAutomatic);
+            image
+        }
+    }
+
+    /// Pointer position relative to the root window
+    pub fn query_pointer(&self) -> (i32, i32) {
+        unsafe {
+            let mut root = 0;
+            let mut child = 0;
+            let mut x = 0;
+            let mut y = 0;
+            let mut win_x = 0;
+            let mut win_y = 0;
+            let mut mask = 0;
+            xlib::XQueryPointer(self.handle, self.get_default_root(), &mut root, &mut child,
+                                &mut x, &mut y, &mut win_x, &mut win_y, &mut mask);
+            (x, y)
+        }
+    }
+
+    /// Top-level window under the pointer, which may be a WM frame
+    pub fn get_window_under_pointer(&self) -> Option<xlib::Window> {
+        unsafe {
+            let mut root = 0;
+            let mut child = 0;
+            let mut x = 0;
+            let mut y = 0;
+            let mut win_x = 0;
+            let mut win_y = 0;
+            let mut mask = 0;
+            xlib::XQueryPointer(self.handle, self.get_default_root(), &mut root, &mut child,
+                                &mut x, &mut y, &mut win_x, &mut win_y, &mut mask);
+            Some(child).filter(|&c| c != 0)
+        }
+    }
+
+    pub fn has_xtest(&self) -> bool {
+        unsafe {
+            let mut event_base = 0;
+            let mut error_base = 0;
+            let mut major = 0;
+            let mut minor = 0;
+            xtest::XTestQueryExtension(self.handle, &mut event_base, &mut error_base,
+                                       &mut major, &mut minor) != 0
+        }
+    }
+
+    /// Move the pointer as if the user did, through XTEST
+    pub fn fake_motion(&self, x: i32, y: i32) {
+        unsafe {
+            xtest::XTestFakeMotionEvent(self.handle, -1, x, y, 0);
+            xlib::XFlush(self.handle);
+        }
+    }
+
+    /// Grab the pointer with a crosshair cursor, and the keyboard for Escape to cancel
+    unsafe fn grab_input(&self, mask: raw::c_long) -> Option<xlib::Cursor> {
+        let root = self.get_default_root();
+        let cursor = xlib::XCreateFontCursor(self.handle, XC_CROSSHAIR);
+        if xlib::XGrabPointer(self.handle, root, xlib::False, mask as raw::c_uint,
+                              xlib::GrabModeAsync, xlib::GrabModeAsync, root, cursor,
+                              xlib::CurrentTime) != xlib::GrabSuccess {
+            xlib::XFreeCursor(self.handle, cursor);
+            return None;
+        }
+        xlib::XGrabKeyboard(self.handle, root, xlib::False, xlib::GrabModeAsync,
+                            xlib::GrabModeAsync, xlib::CurrentTime);
+        Some(cursor)
+    }
+
+    unsafe fn ungrab_input(&self, cursor: xlib::Cursor) {
+        xlib::XUngrabKeyboard(self.handle, xlib::CurrentTime);
+        xlib::XUngrabPointer(self.handle, xlib::CurrentTime);
+        xlib::XFreeCursor(self.handle, cursor);
+        xlib::XSync(self.handle, xlib::False);
+    }
+
+    /// Own `selection` and hand out `data` as `target` until another client takes over, such as
+    /// a clipboard manager keeping a copy
+    /// Returns false if the selection could not be acquired
+    pub fn serve_selection(&self, selection: &str, target: &str, data: &[u8]) -> bool {
+        unsafe {
+            let root = self.get_default_root();
+            let window = xlib::XCreateSimpleWindow(self.handle, root, 0, 0, 1, 1, 0, 0, 0);
+            let selection = self.intern_atom(selection);
+            let target = self.intern_atom(target);
+            let targets = self.intern_atom("TARGETS");
+            let incr = self.intern_atom("INCR");
+            let manager = self.intern_atom("CLIPBOARD_MANAGER");
+
+            xlib::XSetSelectionOwner(self.handle, selection, window, xlib::CurrentTime);
+            if xlib::XGetSelectionOwner(self.handle, selection) != window {
+                return false;
+            }
+            if xlib::XGetSelectionOwner(self.handle, manager) != 0 {
+                xlib::XConvertSelection(self.handle, manager, self.intern_atom("SAVE_TARGETS"),
+                                        self.intern_atom("SHOTGUN_SAVE"), window,
+                                        xlib::CurrentTime);
+            }
+
+            // Anything bigger than a request goes out in pieces (INCR), one every time the
+            // requestor deletes the property holding the previous one
+            let max_request = match xlib::XExtendedMaxRequestSize(self.handle) {
+                0 => xlib::XMaxRequestSize(self.handle),
+                n => n,
+            } as usize * 4;
+            let chunk = max_request.saturating_sub(1024).clamp(4096, 1 << 18);
+            let mut transfers: Vec<(xlib::Window, xlib::Atom, usize)> = Vec::new();
+
+            let put = |requestor, property, type_, format, bytes: &[u8], items: usize| {
+                xlib::XChangeProperty(self.handle, requestor, property, type_, format,
+                                      xlib::PropModeReplace, bytes.as_ptr(), items as raw::c_int);
+            };
+
+            let mut done = false;
+            while !done || !transfers.is_empty() {
+                let mut event: xlib::XEvent = mem::zeroed();
+                xlib::XNextEvent(self.handle, &mut event);
+                match event.get_type() {
+                    xlib::SelectionRequest => {
+                        let req = event.selection_request;
+                        // Obsolete clients pass no property
+                        let mut property = match req.property {
+                            0 => req.target,
+                            p => p,
+                        };
+                        if req.target == targets {
+                            let atoms: [raw::c_ulong; 2] = [targets, target];
+                            put(req.requestor, property, xlib::XA_ATOM, 32,
+                                slice::from_raw_parts(atoms.as_ptr() as *const u8,
+                                                      mem::size_of_val(&atoms)), 2);
+                        } else if req.target == target && data.len() <= chunk {
+                            put(req.requestor, property, target, 8, data, data.len());
+                        } else if req.target == target {
+                            xlib::XSelectInput(self.handle, req.requestor,
+                                               xlib::PropertyChangeMask);
+                            let len: [raw::c_ulong; 1] = [data.len() as raw::c_ulong];
+                            put(req.requestor, property, incr, 32,
+                                slice::from_raw_parts(len.as_ptr() as *const u8,
+                                                      mem::size_of_val(&len)), 1);
+                            transfers.push((req.requestor, property, 0));
+                        } else {
+                            property = 0;
+                        }
+
+                        let mut notify: xlib::XEvent = mem::zeroed();
+                        notify.selection = xlib::XSelectionEvent {
+                            type_: xlib::SelectionNotify,
+                            serial: 0,
+                            send_event: xlib::True,
+                            display: self.handle,
+                            requestor: req.requestor,
+                            selection: req.selection,
+                            target: req.target,
+                            property,
+                            time: req.time,
+                        };
+                        xlib::XSendEvent(self.handle, req.requestor, xlib::False, 0, &mut notify);
+                    },
+                    xlib::PropertyNotify if event.property.state == xlib::PropertyDelete => {
+                        let ev = event.property;
+                        if let Some(i) = transfers.iter().position(|&(w, a, _)| {
+                            w == ev.window && a == ev.atom
+                        }) {
+                            // An empty piece marks the end
+                            let (w, a, offset) = transfers[i];
+                            let end = (offset + chunk).min(data.len());
+                            put(w, a, target, 8, &data[offset..end], end - offset);
+                            if offset == end {
+                                transfers.swap_remove(i);
+                            } else {
+                                transfers[i].2 = end;
+                            }
+                        }
+                    },
+                    xlib::SelectionClear => done = true,
+                    // The clipboard manager has its own copy now
+                    xlib::SelectionNotify if event.selection.selection == manager => {
+                        done = event.selection.property != 0;
+                    },
+                    _ => (),
+                }
+                xlib::XFlush(self.handle);
+            }
+
+            xlib::XDestroyWindow(self.handle, window);
+            true
+        }
+    }
+
+    /// Let the user click a window, returning the top-level window under the pointer
+    pub fn pick_window(&self) -> Option<xlib::Window> {
+        let root = self.get_default_root();
+        // Clicking the background picks the root window
+        self.click().map(|e| if e.subwindow == 0 { root } else { e.subwindow })
+    }
+
+    /// Let the user click on a point of the screen, `None` if they cancel
+    pub fn pick_point(&self) -> Option<(i32, i32)> {
+        self.click().map(|e| (e.x_root, e.y_root))
+    }
+
+    /// Wait for a left click with the pointer grabbed, any other button or Escape cancelling
+    fn click(&self) -> Option<xlib::XButtonEvent> {
+        unsafe {
+            let cursor = self.grab_input(xlib::ButtonPressMask)?;
+
+            let click = loop {
+                let mut event: xlib::XEvent = mem::zeroed();
+                xlib::XNextEvent(self.handle, &mut event);
+                match event.get_type() {
+                    xlib::ButtonPress if event.button.button == xlib::Button1 => {
+                        break Some(event.button);
+                    },
+                    xlib::ButtonPress => break None,
+                    xlib::KeyPress if is_escape(&mut event) => break None,
+                    _ => (),
+                }
+            };
+
+            self.ungrab_input(cursor);
+            click
+        }
+    }
+
+    /// Let the user drag a rectangle on the screen, `None` if they cancel or just click
+    /// With `snap`, corners within that many pixels of a window or monitor edge are moved onto it,
+    /// unless Shift is held
+    /// Over a darkened `cover`, the selection is shown bright along with its geometry
+    /// With a `Fixed` shape, its top left corner is what snaps
+    pub fn select_rect(&self, snap: i32, cover: Option<&Frozen>, shape: Shape)
+                       -> Option<util::Rect> {
+        let root = self.get_default_root();
+        let screen = self.get_window_rect(root);
+
+        // First and last pixels of every top-level window and monitor, along each axis
+        let mut edges_x = Vec::new();
+        let mut edges_y = Vec::new();
+        if snap > 0 {
+            let windows = self.get_children(root).into_iter()
+                .filter(|&w| self.is_viewable(w))
+                .map(|w| self.get_window_rect(w));
+            let screens = self.get_screen_rects(root).into_iter().flatten();
+            for r in windows.chain(screens).filter(|r| r.w > 0 && r.h > 0) {
+                edges_x.extend([r.x, r.x + r.w - 1]);
+                edges_y.extend([r.y, r.y + r.h - 1]);
+            }
+        }
+        let nearest = |edges: &[i32], v: i32| {
+            edges.iter().copied().filter(|e| (e - v).abs() <= snap)
+                .min_by_key(|e| (e - v).abs()).unwrap_or(v)
+        };
+        // Given the modifiers held at the time
+        let snapped = |x, y, state: raw::c_uint| if state & xlib::ShiftMask != 0 {
+            (x, y)
+        } else {
+            (nearest(&edges_x, x), nearest(&edges_y, y))
+        };
+
+        unsafe {
+            let mask = xlib::ButtonPressMask | xlib::ButtonReleaseMask | xlib::PointerMotionMask;
+            let cursor = self.grab_input(mask)?;
+
+            // XOR drawing, so that drawing the rectangle again erases it
+            let mut values: xlib::XGCValues = mem::zeroed();
+            values.function = xlib::GXxor;
+            values.foreground = 0xFFFFFF;
+            values.line_width = 1;
+            values.subwindow_mode = xlib::IncludeInferiors;
+            let gc = xlib::XCreateGC(self.handle, root,
+                                     (xlib::GCFunction | xlib::GCForeground | xlib::GCLineWidth
+                                      | xlib::GCSubwindowMode) as raw::c_ulong, &mut values);
+            let draw = |r: util::Rect| {
+                xlib::XDrawRectangle(self.handle, root, gc, r.x, r.y, r.w as raw::c_uint - 1,
+                                     r.h as raw::c_uint - 1);
+            };
+
+            let dim = cover.and_then(|c| Some((c.window, c.bright?)));
+            let (plain, font) = match dim {
+                Some((window, _)) => {
+                    let mut values: xlib::XGCValues = mem::zeroed();
+                    values.foreground = 0xFFFFFF;
+                    values.background = 0;
+                    let plain = xlib::XCreateGC(self.handle, window,
+                                                (xlib::GCForeground | xlib::GCBackground)
+                                                as raw::c_ulong, &mut values);
+                    (plain, xlib::XQueryFont(self.handle, xlib::XGContextFromGC(plain)))
+                },
+                None => (ptr::null_mut(), ptr::null_mut()),
+            };
+            // Bright selection with its outline, and its geometry next to the pointer
+            // Returns the area taken by the geometry, to be cleared along with the selection
+            let show = |r: util::Rect, pointer: (i32, i32)| -> util::Rect {
+                let (window, bright) = dim.expect("Selection is not dimmed");
+                xlib::XCopyArea(self.handle, bright, window, plain, r.x, r.y, r.w as raw::c_uint,
+                                r.h as raw::c_uint, r.x, r.y);
+                xlib::XDrawRectangle(self.handle, window, plain, r.x, r.y,
+                                     r.w as raw::c_uint - 1, r.h as raw::c_uint - 1);
+                if font.is_null() {
+                    return r;
+                }
+
+                let text = format!("{}x{}+{}+{}", r.w, r.h, r.x, r.y);
+                let width = xlib::XTextWidth(font, text.as_ptr() as *const raw::c_char,
+                                             text.len() as raw::c_int);
+                let height = (*font).ascent + (*font).descent;
+                let x = cmp::min(pointer.0 + 16, screen.w - width);
+                let y = cmp::min(pointer.1 + 16, screen.h - height);
+                xlib::XDrawImageString(self.handle, window, plain, x, y + (*font).ascent,
+                                       text.as_ptr() as *const raw::c_char,
+                                       text.len() as raw::c_int);
+                util::Rect { x, y, w: width, h: height }
+            };
+            let hide = |r: util::Rect| {
+                let (window, _) = dim.expect("Selection is not dimmed");
+                xlib::XClearArea(self.handle, window, r.x, r.y, r.w as raw::c_uint,
+                                 r.h as raw::c_uint, xlib::False);
+            };
+
+            // The rectangle for a drag from `start` to `end`, or around the pointer at `end`
+            let shaped = |start: (i32, i32), end: (i32, i32), state| match shape {
+                Shape::Free => util::Rect::from_corners(start, end),
+                Shape::Ratio(w, h) => ratio_rect(start, end, w as f64, h as f64, screen),
+                Shape::Fixed(w, h) => {
+                    let (x, y) = snapped(end.0 - w / 2, end.1 - h / 2, state);
+                    util::Rect {
+                        x: x.min(screen.x + screen.w - w).max(screen.x),
+                        y: y.min(screen.y + screen.h - h).max(screen.y),
+                        w,
+                        h,
+                    }
+                },
+            };
+
+            let mut start = None;
+            let mut drawn = None;
+            let mut shown: Option<(util::Rect, util::Rect)> = None;
+            let mut present = |r: util::Rect, pointer: (i32, i32)| {
+                if dim.is_some() {
+                    if let Some((r, label)) = shown.take() {
+                        hide(r);
+                        hide(label);
+                    }
+                    shown = Some((r, show(r, pointer)));
+                } else {
+                    if let Some(d) = drawn.replace(r) {
+                        draw(d);
+                    }
+                    draw(r);
+                }
+            };
+            let selection = loop {
+                let mut event: xlib::XEvent = mem::zeroed();
+                xlib::XNextEvent(self.handle, &mut event);
+                match event.get_type() {
+                    xlib::ButtonPress if event.button.button == xlib::Button1 => {
+                        let pointer = (event.button.x_root, event.button.y_root);
+                        let state = event.button.state;
+                        if let Shape::Fixed(..) = shape {
+                            break Some(shaped(pointer, pointer, state));
+                        }
+                        start = Some(snapped(pointer.0, pointer.1, state));
+                    },
+                    // Any other button cancels
+                    xlib::ButtonPress => break None,
+                    xlib::MotionNotify => {
+                        let pointer = (event.motion.x_root, event.motion.y_root);
+                        let state = event.motion.state;
+                        match (shape, start) {
+                            (Shape::Fixed(..), _) => {
+                                present(shaped(pointer, pointer, state), pointer)
+                            },
+                            (_, Some(s)) => {
+                                let end = snapped(pointer.0, pointer.1, state);
+                                present(shaped(s, end, state), pointer)
+                            },
+                            _ => (),
+                        }
+                    },
+                    xlib::ButtonRelease => if let Some(s) = start {
+                        let state = event.button.state;
+                        let end = snapped(event.button.x_root, event.button.y_root, state);
+                        break Some(shaped(s, end, state)).filter(|_| end != s);
+                    },
+                    xlib::KeyPress if is_escape(&mut event) => break None,
+                    _ => (),
+                }
+            };
+
+            if let Some(d) = drawn {
+                draw(d);
+            }
+            if !font.is_null() {
+                xlib::XFreeFontInfo(ptr::null_mut(), font, 1);
+            }
+            if !plain.is_null() {
+                xlib::XFreeGC(self.handle, plain);
+            }
+            xlib::XFreeGC(self.handle, gc);
+            self.ungrab_input(cursor);
+            selection
+        }
+    }
+
+    /// Cover the screen with `image`, a capture of the whole root window, until dropped
+    /// With `dim`, it is shown at half brightness
+    pub fn freeze(&self, image: &Image, dim: bool) -> Frozen<'_> {
+        unsafe {
+            let root = self.get_default_root();
+            let screen = self.screen;
+            let w = (*image.handle).width as raw::c_uint;
+            let h = (*image.handle).height as raw::c_uint;
+
+            let upload = |image: *mut xlib::XImage| {
+                let pixmap = xlib::XCreatePixmap(self.handle, root, w, h,
+                                                 (*image).depth as raw::c_uint);
+                let gc = xlib::XCreateGC(self.handle, pixmap, 0, ptr::null_mut());
+                xlib::XPutImage(self.handle, pixmap, gc, image, 0, 0, 0, 0, w, h);
+                xlib::XFreeGC(self.handle, gc);
+                pixmap
+            };
+            let bright = upload(image.handle);
+            let (pixmap, bright) = if dim {
+                // Halve every channel, shifting each pixel down and dropping the bits that
+                // crossed over into the next channel
+                let dimmed = Image::from_raw_ximage(xlib::XSubImage(image.handle, 0, 0, w, h));
+                let i = &*dimmed.handle;
+                let keep = (i.red_mask >> 1 & i.red_mask) | (i.green_mask >> 1 & i.green_mask)
+                           | (i.blue_mask >> 1 & i.blue_mask);
+                for y in 0..h as raw::c_int {
+                    for x in 0..w as raw::c_int {
+                        let p = xlib::XGetPixel(dimmed.handle, x, y);
+                        xlib::XPutPixel(dimmed.handle, x, y, p >> 1 & keep);
+                    }
+                }
+                (upload(dimmed.handle), Some(bright))
+            } else {
+                (bright, None)
+            };
+
+            // Override-redirect, so that the window manager leaves it alone
+            let mut attrs: xlib::XSetWindowAttributes = mem::zeroed();
+            attrs.override_redirect = xlib::True;
+            attrs.background_pixmap = pixmap;
+            let window = xlib::XCreateWindow(self.handle, root, 0, 0, w, h, 0,
+                                             xlib::XDefaultDepth(self.handle, screen),
+                                             xlib::InputOutput as raw::c_uint,
+                                             xlib::XDefaultVisual(self.handle, screen),
+                                             xlib::CWOverrideRedirect | xlib::CWBackPixmap,
+                                             &mut attrs);
+            xlib::XMapRaised(self.handle, window);
+            xlib::XSync(self.handle, xlib::False);
+
+            Frozen {
+                dpy: self,
+                window,
+                pixmap,
+                bright,
+            }
+        }
+    }
+
+    /// Image of the mouse cursor and the root position of its top-left corner
+    pub fn get_cursor_image(&self) -> Option<(RgbaImage, i32, i32)> {
+        unsafe {
+            let mut event_base = 0;
+            let mut error_base = 0;
+            if xext::XFixesQueryExtension(self.handle, &mut event_base, &mut error_base) == 0 {
+                return None;
+            }
+            let cursor = xext::XFixesGetCursorImage(self.handle);
+            if cursor.is_null() {
+                return None;
+            }
+
+            let c = &*cursor;
+            let (w, h) = (c.width as u32, c.height as u32);
+            let pixels = slice::from_raw_parts(c.pixels, (w * h) as usize);
+            // Premultiplied ARGB, one pixel per long
+            let image = RgbaImage::from_fn(w, h, |x, y| {
+                let p = pixels[(y * w + x) as usize] as u32;
+                let a = p >> 24;
+                let channel = |shift: u32| {
+                    let v = (p >> shift) & 0xFF;
+                    (v * 255).checked_div(a).unwrap_or(0).min(255) as u8
+                };
+                Rgba::from_channels(channel(16), channel(8), channel(0), a as u8)
+            });
+            let position = (c.x as i32 - c.xhot as i32, c.y as i32 - c.yhot as i32);
+            xlib::XFree(cursor as *mut raw::c_void);
+
+            Some((image, position.0, position.1))
+        }
+    }
+
+    /// Current DPMS power level, if the display supports DPMS and has it enabled
+    pub fn get_dpms_level(&self) -> Option<u16> {
+        unsafe {
+            let mut event_base = 0;
+            let mut error_base = 0;
+            if dpms::DPMSQueryExtension(self.handle, &mut event_base, &mut error_base) == 0
+               || dpms::DPMSCapable(self.handle) == 0 {
+                return None;
+            }
+
+            let mut level = 0;
+            let mut enabled = 0;
+            dpms::DPMSInfo(self.handle, &mut level, &mut enabled);
+            Some(level).filter(|_| enabled != 0)
+        }
+    }
+
+    pub fn set_dpms_level(&self, level: u16) {
+        unsafe {
+            dpms::DPMSForceLevel(self.handle, level);
+            xlib::XFlush(self.handle);
+        }
+    }
+
+    /// Deactivate the screen saver as input would, which also starts its timer over
+    pub fn reset_screensaver(&self) {
+        unsafe {
+            xlib::XResetScreenSaver(self.handle);
+            xlib::XFlush(self.handle);
+        }
+    }
+
+    /// Grab the keyboard, failing if another client holds it
+    pub fn grab_keyboard(&self) -> Option<KeyboardGrab<'_>> {
+        unsafe {
+            let root = self.get_default_root();
+            if xlib::XGrabKeyboard(self.handle, root, xlib::False, xlib::GrabModeAsync,
+                                   xlib::GrabModeAsync, xlib::CurrentTime) != xlib::GrabSuccess {
+                return None;
+            }
+        }
+        Some(KeyboardGrab { dpy: self })
+    }
+
+    /// Start tracking damage to `drawable`, if the server supports it
+    pub fn create_damage(&self, drawable: xlib::Drawable) -> Option<Damage<'_>> {
+        unsafe {
+            let mut event_base = 0;
+            let mut error_base = 0;
+            if xext::XDamageQueryExtension(self.handle, &mut event_base, &mut error_base) == 0 {
+                return None;
+            }
+
+            Some(Damage {
+                dpy: self,
+                handle: xext::XDamageCreate(self.handle, drawable, xext::XDamageReportNonEmpty),
+                event_type: event_base + xext::XDamageNotify,
+            })
+        }
+    }
+
     pub fn get_image(&self, window: xlib::Window, rect: util::Rect, plane_mask: libc::c_ulong,
                      format: libc::c_int) -> Option<Image> {
-        unsafe {
+        let shm = if format == xlib::ZPixmap {
+            self.get_image_shm(window, rect, plane_mask)
+        } else {
+            None
+        };
+        let mut image = shm.or_else(|| unsafe {
             let image = xlib::XGetImage(self.handle, window,
                                         rect.x, rect.y,
                                         rect.w as libc::c_uint, rect.h as libc::c_uint,
@@ -106,9 +1149,161 @@ impl Display {
             }
 
             Some(Image::from_raw_ximage(image))
+        })?;
+
+        let depth = unsafe { (*image.handle).depth };
+        if depth <= 8 {
+            image.colors = Some(self.query_colors(window, 1 << depth));
+        }
+        Some(image)
+    }
+
+    /// The first `count` entries of the colormap of `window`, or of the default one
+    fn query_colors(&self, window: xlib::Window, count: usize) -> Vec<[u16; 3]> {
+        unsafe {
+            let mut attrs: xlib::XWindowAttributes = mem::zeroed();
+            let colormap = if xlib::XGetWindowAttributes(self.handle, window, &mut attrs) != 0
+                              && attrs.colormap != 0 {
+                attrs.colormap
+            } else {
+                xlib::XDefaultColormap(self.handle, self.screen)
+            };
+            let mut colors: Vec<xlib::XColor> = (0..count).map(|i| xlib::XColor {
+                pixel: i as raw::c_ulong,
+                red: 0,
+                green: 0,
+                blue: 0,
+                flags: 0,
+                pad: 0,
+            }).collect();
+            xlib::XQueryColors(self.handle, colormap, colors.as_mut_ptr(), count as raw::c_int);
+            colors.iter().map(|c| [c.red, c.green, c.blue]).collect()
+        }
+    }
+
+    /// Same as `get_image` through MIT-SHM, which saves copying the image over the connection
+    /// Fails when the server cannot attach our memory, e.g. over SSH forwarding
+    fn get_image_shm(&self, window: xlib::Window, rect: util::Rect, plane_mask: libc::c_ulong)
+                     -> Option<Image> {
+        unsafe {
+            if xext::XShmQueryExtension(self.handle) == 0 {
+                return None;
+            }
+            let mut attrs: xlib::XWindowAttributes = mem::zeroed();
+            if xlib::XGetWindowAttributes(self.handle, window, &mut attrs) == 0 {
+                return None;
+            }
+
+            let mut info: xext::XShmSegmentInfo = mem::zeroed();
+            let image = xext::XShmCreateImage(self.handle, attrs.visual, attrs.depth as raw::c_uint,
+                                              xlib::ZPixmap, ptr::null_mut(), &mut info,
+                                              rect.w as raw::c_uint, rect.h as raw::c_uint);
+            if image.is_null() {
+                return None;
+            }
+            let size = (*image).bytes_per_line as usize * (*image).height as usize;
+            info.shmid = libc::shmget(libc::IPC_PRIVATE, size, libc::IPC_CREAT | 0o600);
+            if info.shmid < 0 {
+                xlib::XDestroyImage(image);
+                return None;
+            }
+            info.shmaddr = libc::shmat(info.shmid, ptr::null(), 0) as *mut raw::c_char;
+            if info.shmaddr as isize == -1 {
+                libc::shmctl(info.shmid, libc::IPC_RMID, ptr::null_mut());
+                xlib::XDestroyImage(image);
+                return None;
+            }
+            (*image).data = info.shmaddr;
+            let image = Image {
+                handle: image,
+                shm: true,
+                colors: None,
+            };
+
+            // Catch the error of a failed attach instead of exiting
+            xlib::XSync(self.handle, xlib::False);
+            SHM_ATTACH_FAILED.store(false, Ordering::SeqCst);
+            let previous = xlib::XSetErrorHandler(Some(on_shm_attach_error));
+            xext::XShmAttach(self.handle, &mut info);
+            xlib::XSync(self.handle, xlib::False);
+            xlib::XSetErrorHandler(previous);
+            // Removed once both sides have detached
+            libc::shmctl(info.shmid, libc::IPC_RMID, ptr::null_mut());
+            if SHM_ATTACH_FAILED.load(Ordering::SeqCst) {
+                return None;
+            }
+
+            let status = xext::XShmGetImage(self.handle, window, image.handle, rect.x, rect.y,
+                                            plane_mask);
+            xext::XShmDetach(self.handle, &mut info);
+            if status == 0 {
+                return None;
+            }
+            Some(image)
+        }
+    }
+
+    /// Depth, ID and class of the visual of `window`
+    pub fn get_visual(&self, window: xlib::Window) -> Option<(i32, xlib::VisualID, &'static str)> {
+        unsafe {
+            let mut attrs: xlib::XWindowAttributes = mem::zeroed();
+            if xlib::XGetWindowAttributes(self.handle, window, &mut attrs) == 0 {
+                return None;
+            }
+            let class = match (*attrs.visual).class {
+                xlib::StaticGray => "StaticGray",
+                xlib::GrayScale => "GrayScale",
+                xlib::StaticColor => "StaticColor",
+                xlib::PseudoColor => "PseudoColor",
+                xlib::TrueColor => "TrueColor",
+                xlib::DirectColor => "DirectColor",
+                _ => "unknown",
+            };
+            Some((attrs.depth, xlib::XVisualIDFromVisual(attrs.visual), class))
+        }
+    }
+
+    /// Start or stop receiving the events `wait_for_map` waits for on `root`
+    pub fn watch_maps(&self, root: xlib::Window, watch: bool) {
+        let mask = if watch { xlib::SubstructureNotifyMask } else { xlib::NoEventMask };
+        unsafe {
+            xlib::XSelectInput(self.handle, root, mask);
         }
     }
 
+    /// Block until a window is mapped on the root given to `watch_maps`, or until `timeout`
+    /// runs out
+    /// Returns whether one was
+    pub fn wait_for_map(&self, timeout: time::Duration) -> bool {
+        let deadline = time::Instant::now().checked_add(timeout);
+
+        unsafe {
+            loop {
+                while xlib::XPending(self.handle) > 0 {
+                    let mut event = mem::MaybeUninit::uninit();
+                    xlib::XNextEvent(self.handle, event.as_mut_ptr());
+                    if event.assume_init().get_type() == xlib::MapNotify {
+                        return true;
+                    }
+                }
+
+                let left = match poll_timeout(deadline) {
+                    Some(left) => left,
+                    None => return false,
+                };
+                let mut fd = libc::pollfd {
+                    fd: xlib::XConnectionNumber(self.handle),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                if libc::poll(&mut fd, 1, left) == 0 {
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Areas of the monitors of the X screen `root` is the root window of
     pub fn get_screen_rects(&self, root: xlib::Window) -> Option<ScreenRectIter<'_>> {
         unsafe {
             let xrr_res = xrandr::XRRGetScreenResourcesCurrent(self.handle, root);
@@ -118,13 +1313,66 @@ impl Display {
             }
 
             Some(ScreenRectIter {
-                dpy: &self,
+                dpy: self,
                 res: xrr_res,
                 crtcs: slice::from_raw_parts((*xrr_res).crtcs, (*xrr_res).ncrtc as usize),
                 i: 0,
             })
         }
     }
+
+    /// Names and areas of the RandR outputs that are driving a monitor, in server order
+    pub fn get_outputs(&self, root: xlib::Window) -> Option<Vec<Output>> {
+        unsafe {
+            let xrr_res = xrandr::XRRGetScreenResourcesCurrent(self.handle, root);
+
+            if xrr_res.is_null() {
+                return None
+            }
+            let primary = xrandr::XRRGetOutputPrimary(self.handle, root);
+
+            let mut outputs = Vec::new();
+            for &output in slice::from_raw_parts((*xrr_res).outputs, (*xrr_res).noutput as usize) {
+                let info = xrandr::XRRGetOutputInfo(self.handle, xrr_res, output);
+                if info.is_null() {
+                    continue;
+                }
+
+                if (*info).crtc != 0 {
+                    let crtc = xrandr::XRRGetCrtcInfo(self.handle, xrr_res, (*info).crtc);
+                    if !crtc.is_null() {
+                        let name = slice::from_raw_parts((*info).name as *const u8,
+                                                         (*info).nameLen as usize);
+                        let rotation = match (*crtc).rotation as raw::c_int {
+                            xrandr::RR_Rotate_90 => 90,
+                            xrandr::RR_Rotate_180 => 180,
+                            xrandr::RR_Rotate_270 => 270,
+                            _ => 0,
+                        };
+                        let mm = ((*info).mm_width as u32, (*info).mm_height as u32);
+                        outputs.push(Output {
+                            name: String::from_utf8_lossy(name).into_owned(),
+                            rect: util::Rect {
+                                x: (*crtc).x,
+                                y: (*crtc).y,
+                                w: (*crtc).width as i32,
+                                h: (*crtc).height as i32,
+                            },
+                            primary: output == primary,
+                            rotation,
+                            // The panel turns along with the picture
+                            mm_size: if rotation % 180 == 0 { mm } else { (mm.1, mm.0) },
+                        });
+                        xrandr::XRRFreeCrtcInfo(crtc);
+                    }
+                }
+                xrandr::XRRFreeOutputInfo(info);
+            }
+            xrandr::XRRFreeScreenResources(xrr_res);
+
+            Some(outputs)
+        }
+    }
 }
 
 impl Drop for Display {
@@ -139,10 +1387,26 @@ impl Image {
     pub fn from_raw_ximage(ximage: *mut xlib::XImage) -> Image {
         Image {
             handle: ximage,
+            shm: false,
+            colors: None,
         }
     }
 
-    pub fn into_image_buffer(&self) -> Option<RgbaImage> {
+    /// White where any of the planes read is set and black elsewhere, in any image format
+    pub fn to_bitmap(&self) -> RgbaImage {
+        unsafe {
+            let (width, height) = ((*self.handle).width, (*self.handle).height);
+            RgbaImage::from_fn(width as u32, height as u32, |x, y| {
+                if xlib::XGetPixel(self.handle, x as raw::c_int, y as raw::c_int) != 0 {
+                    Rgba([0xFF, 0xFF, 0xFF, 0xFF])
+                } else {
+                    Rgba([0, 0, 0, 0xFF])
+                }
+            })
+        }
+    }
+
+    pub fn to_image_buffer(&self) -> Option<RgbaImage> {
         unsafe {
             // Extract values from the XImage into our own scope
             macro_rules! get {
@@ -152,6 +1416,12 @@ impl Image {
                  byte_order, depth, bytes_per_line, bits_per_pixel,
                  red_mask, green_mask, blue_mask);
 
+            // Deep and low depth channels are not byte-aligned and colormapped pixels hold no
+            // channels at all, take the slow path and round them down
+            if depth != 24 && depth != 32 {
+                return self.to_image_buffer16().as_ref().map(draw::narrow);
+            }
+
             // Pixel size
             let stride = match (depth, bits_per_pixel) {
                 (24, 24) => 3,
@@ -159,39 +1429,119 @@ impl Image {
                 _ => return None,
             };
 
-            // Compute subpixel offsets into each pixel according the the bitmasks X gives us
+            // Compute subpixel offsets into each pixel according the the bitmasks X gives us,
+            // counting bytes from the most significant end with MSBFirst servers
             // Only 8 bit, byte-aligned values are supported
             // Truncate masks to the lower 32 bits as that is the maximum pixel size
             macro_rules! channel_offset {
-                ($mask:expr) => (match (byte_order, $mask & 0xFFFFFFFF) {
-                    (0, 0xFF) | (1, 0xFF000000) => 0,
-                    (0, 0xFF00) | (1, 0xFF0000) => 1,
-                    (0, 0xFF0000) | (1, 0xFF00) => 2,
-                    (0, 0xFF000000) | (1, 0xFF) => 3,
-                    _ => return None,
+                ($mask:expr) => ({
+                    let mask = $mask & 0xFFFFFFFF;
+                    let byte = mask.trailing_zeros() as usize / 8;
+                    if byte >= stride || mask != 0xFF << (byte * 8) {
+                        return None;
+                    }
+                    if byte_order == 0 { byte } else { stride - 1 - byte }
                 })
             }
             let red_offset = channel_offset!(red_mask);
             let green_offset = channel_offset!(green_mask);
             let blue_offset = channel_offset!(blue_mask);
-            let alpha_offset = channel_offset!(!(red_mask | green_mask | blue_mask));
+            // Packed 24 bit pixels have no room for alpha, which is ignored at depth 24 anyway
+            let alpha_offset = if stride == 4 {
+                channel_offset!(!(red_mask | green_mask | blue_mask))
+            } else {
+                0
+            };
 
             // Wrap the pixel buffer into a slice
             let size = (bytes_per_line * height) as usize;
             let data = slice::from_raw_parts((*self.handle).data as *const u8, size);
 
-            // Finally, generate the image object
-            Some(RgbaImage::from_fn(width as u32, height as u32, |x, y| {
-                macro_rules! subpixel {
-                    ($channel_offset:ident) => (data[(y * bytes_per_line as u32
-                                                + x * stride as u32
-                                                + $channel_offset) as usize]);
+            // Rows are independent, large captures are split into bands converted in parallel
+            let (width, height, bpl) = (width as usize, height as usize, bytes_per_line as usize);
+            let offsets = [red_offset, green_offset, blue_offset, alpha_offset];
+            let mut out = vec![0; width * height * 4];
+            let threads = if width * height < 1 << 20 {
+                1
+            } else {
+                thread::available_parallelism().map_or(1, |n| n.get()).min(8)
+            };
+            let band = height.div_ceil(threads).max(1);
+            thread::scope(|scope| {
+                for (src, dst) in data.chunks(band * bpl).zip(out.chunks_mut(band * width * 4)) {
+                    scope.spawn(move || {
+                        convert_rows(src, dst, bpl, width, stride, offsets, depth == 24)
+                    });
+                }
+            });
+
+            RgbaImage::from_raw(width as u32, height as u32, out)
+        }
+    }
+}
+
+impl Image {
+    /// Convert to 16 bits per channel, for framebuffers with more than 8 of them
+    /// Slower than `to_image_buffer` but handles any channel layout within 32 bit pixels
+    pub fn to_image_buffer16(&self) -> Option<Rgba16Image> {
+        unsafe {
+            macro_rules! get {
+                ($($a:ident),+) => ($(let $a = (*self.handle).$a;)+);
+            }
+            get!(width, height,
+                 byte_order, depth, bytes_per_line, bits_per_pixel,
+                 red_mask, green_mask, blue_mask);
+
+            let stride = match (depth, bits_per_pixel) {
+                (1..=8, 8) => 1,
+                (15, 16) | (16, 16) => 2,
+                (24, 24) => 3,
+                (24, 32) | (30, 32) | (32, 32) => 4,
+                _ => return None,
+            };
+
+            let size = (bytes_per_line * height) as usize;
+            let data = slice::from_raw_parts((*self.handle).data as *const u8, size);
+
+            // Pseudo-color and gray scale visuals look pixels up in the colormap
+            if let (1, Some(colors)) = (stride, &self.colors) {
+                return Some(Rgba16Image::from_fn(width as u32, height as u32, |x, y| {
+                    let index = data[(y * bytes_per_line as u32 + x) as usize] as usize;
+                    let [r, g, b] = colors.get(index).copied().unwrap_or_default();
+                    Rgba([r, g, b, 0xFFFF])
+                }));
+            }
+
+            let masks = [red_mask, green_mask, blue_mask].map(|m| (m & 0xFFFFFFFF) as u32);
+            let alpha_mask = if depth == 32 { !(masks[0] | masks[1] | masks[2]) } else { 0 };
+            if masks.contains(&0) {
+                return None;
+            }
+
+            // Scale a masked channel to the full 16 bit range
+            let channel = |pixel: u32, mask: u32| -> u16 {
+                let max = mask >> mask.trailing_zeros();
+                (((pixel & mask) >> mask.trailing_zeros()) as u64 * 0xFFFF / max as u64) as u16
+            };
+
+            Some(Rgba16Image::from_fn(width as u32, height as u32, |x, y| {
+                let offset = (y * bytes_per_line as u32 + x * stride) as usize;
+                let bytes = &data[offset..offset + stride as usize];
+                // 0 is LSBFirst
+                let pixel = if byte_order == 0 {
+                    bytes.iter().rev().fold(0, |p, &b| p << 8 | b as u32)
+                } else {
+                    bytes.iter().fold(0, |p, &b| p << 8 | b as u32)
+                };
+                if alpha_mask == 0 {
+                    return Rgba([channel(pixel, masks[0]), channel(pixel, masks[1]),
+                                 channel(pixel, masks[2]), 0xFFFF]);
                 }
-                Rgba::from_channels(subpixel!(red_offset),
-                                    subpixel!(green_offset),
-                                    subpixel!(blue_offset),
-                                    // Make the alpha channel fully opaque if none is provided
-                                    if depth == 24 { 0xFF } else { subpixel!(alpha_offset) })
+                let alpha = channel(pixel, alpha_mask);
+                let [r, g, b] = masks.map(|m| {
+                    unpremultiply(channel(pixel, m) as u32, alpha as u32, 0xFFFF) as u16
+                });
+                Rgba([r, g, b, alpha])
             }))
         }
     }
@@ -200,11 +1550,115 @@ impl Image {
 impl Drop for Image {
     fn drop(&mut self) {
         unsafe {
+            if self.shm {
+                libc::shmdt((*self.handle).data as *const libc::c_void);
+                (*self.handle).data = ptr::null_mut();
+            }
             xlib::XDestroyImage(self.handle);
         }
     }
 }
 
+impl<'a> Damage<'a> {
+    /// Block until the drawable is damaged, or until `timeout` runs out
+    /// Returns whether damage occurred
+    pub fn wait(&self, timeout: Option<time::Duration>) -> bool {
+        let deadline = timeout.and_then(|t| time::Instant::now().checked_add(t));
+
+        unsafe {
+            let handle = self.dpy.handle;
+            loop {
+                while xlib::XPending(handle) > 0 {
+                    let mut event = mem::MaybeUninit::uninit();
+                    xlib::XNextEvent(handle, event.as_mut_ptr());
+                    if event.assume_init().get_type() == self.event_type {
+                        // Acknowledge so that the next change generates a new event
+                        xext::XDamageSubtract(handle, self.handle, 0, 0);
+                        return true;
+                    }
+                }
+
+                let ms = match poll_timeout(deadline) {
+                    Some(ms) => ms,
+                    None => return false,
+                };
+                let mut fd = libc::pollfd {
+                    fd: xlib::XConnectionNumber(handle),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                if libc::poll(&mut fd, 1, ms) == 0 {
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+impl<'a> KeyboardGrab<'a> {
+    /// Block until Escape is pressed, or until `timeout` runs out
+    /// Returns whether it was
+    pub fn wait_for_escape(&self, timeout: time::Duration) -> bool {
+        let deadline = time::Instant::now().checked_add(timeout);
+
+        unsafe {
+            let handle = self.dpy.handle;
+            loop {
+                while xlib::XPending(handle) > 0 {
+                    let mut event = mem::zeroed();
+                    xlib::XNextEvent(handle, &mut event);
+                    if event.get_type() == xlib::KeyPress && is_escape(&mut event) {
+                        return true;
+                    }
+                }
+
+                let left = match poll_timeout(deadline) {
+                    Some(left) => left,
+                    None => return false,
+                };
+                let mut fd = libc::pollfd {
+                    fd: xlib::XConnectionNumber(handle),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                if libc::poll(&mut fd, 1, left) == 0 {
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Drop for KeyboardGrab<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            xlib::XUngrabKeyboard(self.dpy.handle, xlib::CurrentTime);
+            xlib::XSync(self.dpy.handle, xlib::False);
+        }
+    }
+}
+
+impl<'a> Drop for Damage<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            xext::XDamageDestroy(self.dpy.handle, self.handle);
+        }
+    }
+}
+
+impl<'a> Drop for Frozen<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            xlib::XDestroyWindow(self.dpy.handle, self.window);
+            xlib::XFreePixmap(self.dpy.handle, self.pixmap);
+            if let Some(b) = self.bright {
+                xlib::XFreePixmap(self.dpy.handle, b);
+            }
+            xlib::XSync(self.dpy.handle, xlib::False);
+        }
+    }
+}
+
 impl<'a> Iterator for ScreenRectIter<'a> {
     type Item = util::Rect;
 
@@ -215,7 +1669,7 @@ impl<'a> Iterator for ScreenRectIter<'a> {
 
         unsafe {
             // TODO Handle failure here?
-            let crtc = xrandr::XRRGetCrtcInfo((*self.dpy).handle, self.res, self.crtcs[self.i]);
+            let crtc = xrandr::XRRGetCrtcInfo(self.dpy.handle, self.res, self.crtcs[self.i]);
             let x = (*crtc).x;
             let y = (*crtc).y;
             let w = (*crtc).width;
@@ -226,8 +1680,8 @@ impl<'a> Iterator for ScreenRectIter<'a> {
 
             //Some((w as i32, h as i32, x as i32, y as i32))
             Some(util::Rect {
-                x: x,
-                y: y,
+                x,
+                y,
                 w: w as i32,
                 h: h as i32,
             })
@@ -243,19 +1697,48 @@ impl<'a> Drop for ScreenRectIter<'a> {
     }
 }
 
-pub fn parse_geometry(g: ffi::CString) -> util::Rect {
-    unsafe {
-        let mut x = 0;
-        let mut y = 0;
-        let mut w = 0;
-        let mut h = 0;
-        xlib::XParseGeometry(g.as_ptr() as *const raw::c_char, &mut x, &mut y, &mut w, &mut h);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        util::Rect {
-            x: x,
-            y: y,
-            w: w as i32,
-            h: h as i32,
+    fn output(w: i32, h: i32, mm_size: (u32, u32)) -> Output {
+        Output {
+            name: "DP-1".to_string(),
+            rect: util::Rect { x: 0, y: 0, w, h },
+            primary: false,
+            rotation: 0,
+            mm_size,
         }
     }
+
+    #[test]
+    fn scale_follows_density() {
+        // 24" 1080p, 27" 1440p, 27" 4K and 13" 2880x1800
+        assert_eq!(output(1920, 1080, (531, 299)).scale(), 1);
+        assert_eq!(output(2560, 1440, (597, 336)).scale(), 1);
+        assert_eq!(output(3840, 2160, (597, 336)).scale(), 2);
+        assert_eq!(output(2880, 1800, (286, 179)).scale(), 3);
+    }
+
+    #[test]
+    fn implausible_sizes_give_scale_one() {
+        assert_eq!(output(3840, 2160, (0, 0)).scale(), 1);
+        // Aspect ratio only, as some EDIDs report it
+        assert_eq!(output(3840, 2160, (16, 9)).scale(), 1);
+        // Projectors and TVs with a made-up size
+        assert_eq!(output(1920, 1080, (1600, 900)).scale(), 1);
+        // A size that does not match the picture's shape
+        assert_eq!(output(3840, 2160, (400, 400)).scale(), 1);
+    }
+
+    #[test]
+    fn poll_timeouts_stay_in_range() {
+        let now = time::Instant::now();
+        assert_eq!(poll_timeout(None), Some(-1));
+        assert_eq!(poll_timeout(Some(now - time::Duration::from_secs(1))), None);
+        let soon = poll_timeout(Some(now + time::Duration::from_secs(1))).unwrap();
+        assert!((900..=1000).contains(&soon));
+        let far = now + time::Duration::from_secs(1 << 40);
+        assert_eq!(poll_timeout(Some(far)), Some(raw::c_int::MAX));
+    }
 }
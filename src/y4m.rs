@@ -0,0 +1,33 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// YUV4MPEG2 writer, full resolution chroma in BT.601 limited range
+
+use std::io;
+
+use image::RgbImage;
+
+/// Write `image` as a Y4M frame, preceded by the stream header if `header` is set
+pub fn write_y4m(w: &mut dyn io::Write, image: &RgbImage, fps: u32, header: bool)
+                 -> io::Result<()> {
+    let (width, height) = image.dimensions();
+    let mut out = Vec::with_capacity(64 + image.len());
+    if header {
+        out.extend(format!("YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444\n", width, height, fps)
+                   .bytes());
+    }
+    out.extend_from_slice(b"FRAME\n");
+
+    let planes: [fn(f32, f32, f32) -> f32; 3] = [
+        |r, g, b| 16.0 + 0.2568 * r + 0.5041 * g + 0.0979 * b,
+        |r, g, b| 128.0 - 0.1482 * r - 0.2910 * g + 0.4392 * b,
+        |r, g, b| 128.0 + 0.4392 * r - 0.3678 * g - 0.0714 * b,
+    ];
+    for plane in planes.iter() {
+        out.extend(image.pixels().map(|p| {
+            plane(p[0] as f32, p[1] as f32, p[2] as f32).round() as u8
+        }));
+    }
+    w.write_all(&out)
+}